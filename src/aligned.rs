@@ -0,0 +1,104 @@
+use crate::Block;
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+
+/// A fixed-size heap buffer of bytes with a guaranteed alignment.
+///
+/// Buffers aligned on [Block]s let [StorageBlockDevice](crate::StorageBlockDevice) transfer whole
+/// blocks directly between them and the device, instead of going through a temporary block.
+/// Larger alignments are useful for unbuffered I/O, which requires buffers aligned on the sector
+/// size of the device.
+pub struct AlignedBox {
+    /// The start of the buffer.
+    ptr: *mut u8,
+
+    /// The layout the buffer was allocated with.
+    layout: Layout,
+}
+
+impl AlignedBox {
+    /// Allocate a zeroed buffer of ``len`` bytes aligned for [Block]s.
+    pub fn new(len: usize) -> Self {
+        Self::with_alignment(len, core::mem::align_of::<Block>())
+    }
+
+    /// Allocate a zeroed buffer of ``len`` bytes aligned on ``align`` bytes.
+    ///
+    /// ``align`` must be a power of two.
+    pub fn with_alignment(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len, align).expect("invalid aligned buffer layout");
+
+        let ptr = if len == 0 {
+            // Empty buffers are never dereferenced, any aligned pointer will do.
+            align as *mut u8
+        } else {
+            let ptr = unsafe { alloc_zeroed(layout) };
+            if ptr.is_null() {
+                handle_alloc_error(layout);
+            }
+            ptr
+        };
+
+        AlignedBox { ptr, layout }
+    }
+
+    /// Return the alignment of the buffer.
+    pub fn alignment(&self) -> usize {
+        self.layout.align()
+    }
+
+    /// View the buffer as a slice of blocks.
+    ///
+    /// Return None if the length of the buffer isn't a multiple of [Block::LEN].
+    pub fn as_blocks(&self) -> Option<&[Block]> {
+        Block::from_bytes(self)
+    }
+
+    /// View the buffer as a mutable slice of blocks.
+    ///
+    /// Return None if the length of the buffer isn't a multiple of [Block::LEN].
+    pub fn as_blocks_mut(&mut self) -> Option<&mut [Block]> {
+        Block::from_bytes_mut(self)
+    }
+}
+
+impl core::ops::Deref for AlignedBox {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl core::ops::DerefMut for AlignedBox {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Clone for AlignedBox {
+    fn clone(&self) -> Self {
+        let mut clone = Self::with_alignment(self.layout.size(), self.layout.align());
+        clone.copy_from_slice(self);
+        clone
+    }
+}
+
+impl core::fmt::Debug for AlignedBox {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+        fmt.debug_struct("AlignedBox")
+            .field("len", &self.layout.size())
+            .field("alignment", &self.layout.align())
+            .finish()
+    }
+}
+
+// The buffer is uniquely owned, like a Box<[u8]>.
+unsafe impl Send for AlignedBox {}
+unsafe impl Sync for AlignedBox {}
+
+impl Drop for AlignedBox {
+    fn drop(&mut self) {
+        if self.layout.size() != 0 {
+            unsafe { dealloc(self.ptr, self.layout) }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::aligned::AlignedBox;
+use crate::sys::{read_at, write_all_at};
+use crate::{StorageDeviceError, StorageDeviceResult};
+use std::fs::File;
+
+/// A file that only accepts I/O aligned in offset, size and memory, accessed through an aligned
+/// bounce buffer.
+pub struct AlignedFile {
+    /// The backing file.
+    pub file: File,
+
+    /// The alignment of every transfer.
+    pub alignment: usize,
+
+    /// The bounce buffer used for every transfer.
+    pub buffer: AlignedBox,
+}
+
+impl AlignedFile {
+    /// Wrap ``file``, transferring at most ``buffer_size`` bytes at once.
+    ///
+    /// ``alignment`` must be a power of two, and ``buffer_size`` is rounded up to a multiple of it.
+    pub fn new(file: File, alignment: usize, buffer_size: usize) -> Self {
+        assert!(
+            alignment.is_power_of_two(),
+            "alignment must be a power of two"
+        );
+        let buffer_size = core::cmp::max(buffer_size, 1).div_ceil(alignment) * alignment;
+
+        AlignedFile {
+            file,
+            alignment,
+            buffer: AlignedBox::with_alignment(buffer_size, alignment),
+        }
+    }
+
+    /// Round ``offset`` down to the alignment.
+    fn align_down(&self, offset: u64) -> u64 {
+        offset & !(self.alignment as u64 - 1)
+    }
+
+    /// Fill ``buf`` with the data at ``offset``, stopping early at the end of the file.
+    ///
+    /// ``offset`` and ``buf`` must be aligned. Return how many bytes were read.
+    fn read_aligned(file: &File, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        let mut read_size = 0;
+        while read_size < buf.len() {
+            match read_at(file, &mut buf[read_size..], offset + read_size as u64) {
+                Ok(0) => break,
+                Ok(size) => read_size += size,
+                Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    return Err(crate::sys::storage_error(
+                        &err,
+                        StorageDeviceError::ReadError,
+                    ))
+                }
+            }
+        }
+        Ok(read_size)
+    }
+
+    /// Read ``buf.len()`` bytes at ``offset``, whatever their alignment.
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        let mut read_size = 0;
+
+        while read_size < buf.len() {
+            let current_offset = offset + read_size as u64;
+            let aligned_offset = self.align_down(current_offset);
+            let skip = (current_offset - aligned_offset) as usize;
+
+            // Only transfer the aligned units we need.
+            let len = core::cmp::min(self.buffer.len() - skip, buf.len() - read_size);
+            let transfer_len = (skip + len).div_ceil(self.alignment) * self.alignment;
+
+            let bounce = &mut self.buffer[..transfer_len];
+            let available = Self::read_aligned(&self.file, aligned_offset, bounce)?;
+            if available < skip + len {
+                return Err(StorageDeviceError::OutOfBounds);
+            }
+
+            buf[read_size..read_size + len].copy_from_slice(&bounce[skip..skip + len]);
+            read_size += len;
+        }
+
+        Ok(())
+    }
+
+    /// Write ``buf`` at ``offset``, whatever its alignment, reading the partially covered aligned
+    /// units first.
+    ///
+    /// Aligned units past the end of the file are written whole, padded with zeroes.
+    pub fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let mut write_size = 0;
+
+        while write_size < buf.len() {
+            let current_offset = offset + write_size as u64;
+            let aligned_offset = self.align_down(current_offset);
+            let skip = (current_offset - aligned_offset) as usize;
+
+            let len = core::cmp::min(self.buffer.len() - skip, buf.len() - write_size);
+            let transfer_len = (skip + len).div_ceil(self.alignment) * self.alignment;
+
+            let bounce = &mut self.buffer[..transfer_len];
+
+            // Read the aligned units we only partially overwrite, zeroing what lies past the end of the file.
+            if skip != 0 || len != transfer_len {
+                let available = Self::read_aligned(&self.file, aligned_offset, bounce)?;
+                for byte in bounce[available..].iter_mut() {
+                    *byte = 0;
+                }
+            }
+
+            bounce[skip..skip + len].copy_from_slice(&buf[write_size..write_size + len]);
+
+            write_all_at(&self.file, &self.buffer[..transfer_len], aligned_offset)
+                .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))?;
+            write_size += len;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,447 @@
+use crate::sha256::{sha256, Sha256, DIGEST_LEN};
+use crate::{
+    Capabilities, DeviceIdentity, DeviceInfo, StorageDevice, StorageDeviceError,
+    StorageDeviceResult,
+};
+
+/// The magic identifying an [AuditRecord] stored by a [StorageAuditLog].
+const AUDIT_MAGIC: u32 = u32::from_le_bytes(*b"AUDT");
+
+/// The kind of operation recorded in an [AuditRecord].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AuditOperation {
+    /// Data was written.
+    Write,
+
+    /// The range was zeroed.
+    WriteZeroes,
+
+    /// The range was filled with the given byte.
+    Fill(u8),
+
+    /// The range was discarded.
+    Discard,
+
+    /// The whole device was sanitized.
+    Sanitize,
+}
+
+impl AuditOperation {
+    /// Return the values identifying the operation in the binary format.
+    fn to_bytes(self) -> [u8; 2] {
+        match self {
+            AuditOperation::Write => [0, 0],
+            AuditOperation::WriteZeroes => [1, 0],
+            AuditOperation::Fill(byte) => [2, byte],
+            AuditOperation::Discard => [3, 0],
+            AuditOperation::Sanitize => [4, 0],
+        }
+    }
+
+    /// Return the operation identified by ``bytes`` in the binary format.
+    fn from_bytes(bytes: [u8; 2]) -> Option<Self> {
+        match bytes {
+            [0, 0] => Some(AuditOperation::Write),
+            [1, 0] => Some(AuditOperation::WriteZeroes),
+            [2, byte] => Some(AuditOperation::Fill(byte)),
+            [3, 0] => Some(AuditOperation::Discard),
+            [4, 0] => Some(AuditOperation::Sanitize),
+            _ => None,
+        }
+    }
+}
+
+/// A single operation modifying an audited device, chained to the records before it.
+///
+/// Every record holds the SHA-256 hash of the record before it and its own fields, so modifying,
+/// removing or reordering records breaks the chain from there on, which
+/// [AuditRecord::follows] detects. Keep the [AuditRecord::chain_hash] of the latest record
+/// somewhere the attacker can't reach, such as a TPM or a remote server, to detect the log being
+/// truncated or rewritten whole.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// The position of the record in the log, starting at 0.
+    pub counter: u64,
+
+    /// The kind of operation.
+    pub operation: AuditOperation,
+
+    /// The offset of the range modified.
+    pub offset: u64,
+
+    /// The size of the range modified, in bytes.
+    pub len: u64,
+
+    /// The SHA-256 hash of the data written, or zeroes for operations other than writes.
+    pub data_hash: [u8; DIGEST_LEN],
+
+    /// The SHA-256 hash of the chain hash of the previous record, or zeroes for the first one,
+    /// followed by the first 64 bytes of this record.
+    pub chain_hash: [u8; DIGEST_LEN],
+}
+
+impl AuditRecord {
+    /// The size of an encoded record in bytes.
+    ///
+    /// Records are encoded in little endian as follow:
+    ///
+    /// | Offset | Size | Field      |
+    /// |--------|------|------------|
+    /// | 0      | 4    | magic      |
+    /// | 4      | 2    | operation  |
+    /// | 6      | 2    | reserved   |
+    /// | 8      | 8    | counter    |
+    /// | 16     | 8    | offset     |
+    /// | 24     | 8    | len        |
+    /// | 32     | 32   | data_hash  |
+    /// | 64     | 32   | chain_hash |
+    /// | 96     | 32   | reserved   |
+    pub const LEN: usize = 128;
+
+    /// Create the record following ``previous``, or the first one if None, computing its chain
+    /// hash.
+    pub fn new(
+        previous: Option<&AuditRecord>,
+        operation: AuditOperation,
+        offset: u64,
+        len: u64,
+        data_hash: [u8; DIGEST_LEN],
+    ) -> Self {
+        let mut record = AuditRecord {
+            counter: previous.map_or(0, |previous| previous.counter + 1),
+            operation,
+            offset,
+            len,
+            data_hash,
+            chain_hash: [0; DIGEST_LEN],
+        };
+        record.chain_hash = record.compute_chain_hash(previous);
+        record
+    }
+
+    /// Check whether this record directly follows ``previous``, or is the first one if None,
+    /// and wasn't modified.
+    pub fn follows(&self, previous: Option<&AuditRecord>) -> bool {
+        self.counter == previous.map_or(0, |previous| previous.counter.wrapping_add(1))
+            && self.chain_hash == self.compute_chain_hash(previous)
+    }
+
+    /// Encode the record in the binary format.
+    pub fn to_bytes(&self) -> [u8; AuditRecord::LEN] {
+        let mut bytes = [0u8; AuditRecord::LEN];
+        bytes[0..4].copy_from_slice(&AUDIT_MAGIC.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.operation.to_bytes());
+        bytes[8..16].copy_from_slice(&self.counter.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.len.to_le_bytes());
+        bytes[32..64].copy_from_slice(&self.data_hash);
+        bytes[64..96].copy_from_slice(&self.chain_hash);
+        bytes
+    }
+
+    /// Decode a record from the binary format.
+    ///
+    /// Return None if the magic or the operation is unknown.
+    pub fn from_bytes(bytes: &[u8; AuditRecord::LEN]) -> Option<Self> {
+        let mut u64_bytes = [0u8; 8];
+        let mut magic = [0u8; 4];
+        let mut data_hash = [0u8; DIGEST_LEN];
+        let mut chain_hash = [0u8; DIGEST_LEN];
+
+        magic.copy_from_slice(&bytes[0..4]);
+        if u32::from_le_bytes(magic) != AUDIT_MAGIC {
+            return None;
+        }
+        let operation = AuditOperation::from_bytes([bytes[4], bytes[5]])?;
+        u64_bytes.copy_from_slice(&bytes[8..16]);
+        let counter = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&bytes[16..24]);
+        let offset = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&bytes[24..32]);
+        let len = u64::from_le_bytes(u64_bytes);
+        data_hash.copy_from_slice(&bytes[32..64]);
+        chain_hash.copy_from_slice(&bytes[64..96]);
+
+        Some(AuditRecord {
+            counter,
+            operation,
+            offset,
+            len,
+            data_hash,
+            chain_hash,
+        })
+    }
+
+    /// Compute the chain hash of this record, following ``previous``.
+    fn compute_chain_hash(&self, previous: Option<&AuditRecord>) -> [u8; DIGEST_LEN] {
+        let mut hasher = Sha256::new();
+        hasher.update(&previous.map_or([0; DIGEST_LEN], |previous| previous.chain_hash));
+        hasher.update(&self.to_bytes()[..64]);
+        hasher.finalize()
+    }
+}
+
+/// Destination of the records produced by an [AuditedDevice].
+///
+/// It is implemented for closures taking an [AuditRecord], e.g. to send the records over a
+/// serial port.
+pub trait AuditLog {
+    /// Store a record durably.
+    ///
+    /// The operation audited is only performed once this succeeds.
+    fn append(&mut self, record: &AuditRecord) -> StorageDeviceResult<()>;
+}
+
+impl<F: FnMut(&AuditRecord) -> StorageDeviceResult<()>> AuditLog for F {
+    fn append(&mut self, record: &AuditRecord) -> StorageDeviceResult<()> {
+        self(record)
+    }
+}
+
+/// An [AuditLog] storing records one after the other on a storage device, such as a region
+/// reserved for it.
+///
+/// Records are flushed as soon as they are written. Appending a record which doesn't follow the
+/// latest one stored fails with [StorageDeviceError::WriteError], so the chain is never broken.
+/// Once the device is full, appending fails with [StorageDeviceError::OutOfSpace], and so does
+/// the operation audited.
+#[derive(Debug)]
+pub struct StorageAuditLog<S: StorageDevice> {
+    /// The device holding the records.
+    storage_device: S,
+
+    /// The latest record stored, if any.
+    last: Option<AuditRecord>,
+}
+
+impl<S: StorageDevice> StorageAuditLog<S> {
+    /// Store records on ``storage_device`` from its start, overwriting any previous log.
+    pub fn create(mut storage_device: S) -> StorageDeviceResult<Self> {
+        if storage_device.len()? >= AuditRecord::LEN as u64 {
+            storage_device.write_zeroes(0, AuditRecord::LEN as u64)?;
+            storage_device.flush()?;
+        }
+        Ok(StorageAuditLog {
+            storage_device,
+            last: None,
+        })
+    }
+
+    /// Open the log previously stored on ``storage_device``, appending records after the
+    /// existing ones.
+    ///
+    /// Every record is read and checked against the one before it. Return
+    /// [StorageDeviceError::Corrupted] if a record doesn't follow the one before it, e.g. because
+    /// it was tampered with.
+    pub fn open(mut storage_device: S) -> StorageDeviceResult<Self> {
+        let len = storage_device.len()?;
+        let mut last: Option<AuditRecord> = None;
+        let mut offset = 0;
+        let mut bytes = [0u8; AuditRecord::LEN];
+        while offset + AuditRecord::LEN as u64 <= len {
+            storage_device.read(offset, &mut bytes)?;
+            if bytes[0..4] != AUDIT_MAGIC.to_le_bytes() {
+                break;
+            }
+            let record = AuditRecord::from_bytes(&bytes).ok_or(StorageDeviceError::Corrupted)?;
+            if !record.follows(last.as_ref()) {
+                return Err(StorageDeviceError::Corrupted);
+            }
+            last = Some(record);
+            offset += AuditRecord::LEN as u64;
+        }
+        Ok(StorageAuditLog {
+            storage_device,
+            last,
+        })
+    }
+
+    /// Return the latest record stored, if any.
+    pub fn last(&self) -> Option<&AuditRecord> {
+        self.last.as_ref()
+    }
+
+    /// Return a reference to the device holding the records.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Consume the log, returning the device holding the records.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+}
+
+impl<S: StorageDevice> AuditLog for StorageAuditLog<S> {
+    fn append(&mut self, record: &AuditRecord) -> StorageDeviceResult<()> {
+        if !record.follows(self.last.as_ref()) {
+            return Err(StorageDeviceError::WriteError);
+        }
+        let offset = record.counter * AuditRecord::LEN as u64;
+        let end = offset + AuditRecord::LEN as u64;
+        let len = self.storage_device.len()?;
+        if end > len {
+            return Err(StorageDeviceError::OutOfSpace);
+        }
+        // Mark the end of the log, unless it is the end of the device.
+        if end + AuditRecord::LEN as u64 <= len {
+            self.storage_device
+                .write_zeroes(end, AuditRecord::LEN as u64)?;
+        }
+        self.storage_device.write(offset, &record.to_bytes())?;
+        self.storage_device.flush()?;
+        self.last = Some(*record);
+        Ok(())
+    }
+}
+
+/// A storage device appending a record of every operation modifying it to an [AuditLog].
+///
+/// Writes, fills, discards and sanitizations are recorded with a counter and the range they
+/// modify, writes with the SHA-256 hash of their data too. The records are chained by their
+/// hashes, so the log is tamper-evident, see [AuditRecord]. An operation is only performed once
+/// its record is stored, so it fails if the record can't be, and a failed operation is still
+/// recorded, as it may have modified part of its range. Reads aren't recorded.
+pub struct AuditedDevice<S: StorageDevice, L: AuditLog> {
+    /// The audited device.
+    storage_device: S,
+
+    /// Where the records are stored.
+    log: L,
+
+    /// The latest record, if any.
+    last: Option<AuditRecord>,
+}
+
+impl<S: StorageDevice, L: AuditLog> core::fmt::Debug for AuditedDevice<S, L> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AuditedDevice")
+            .field("storage_device", &self.storage_device)
+            .field("last", &self.last)
+            .finish()
+    }
+}
+
+impl<S: StorageDevice, L: AuditLog> AuditedDevice<S, L> {
+    /// Record the operations modifying ``storage_device`` in ``log``, starting a new chain.
+    pub fn new(storage_device: S, log: L) -> Self {
+        AuditedDevice {
+            storage_device,
+            log,
+            last: None,
+        }
+    }
+
+    /// Record the operations modifying ``storage_device`` in ``log``, continuing the chain after
+    /// ``last``, e.g. the last record of a [StorageAuditLog] which was reopened.
+    pub fn resume(storage_device: S, log: L, last: Option<AuditRecord>) -> Self {
+        AuditedDevice {
+            storage_device,
+            log,
+            last,
+        }
+    }
+
+    /// Return the latest record, whose chain hash vouches for the whole log.
+    pub fn last(&self) -> Option<&AuditRecord> {
+        self.last.as_ref()
+    }
+
+    /// Return a reference to the audited device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a reference to the log.
+    pub fn log(&self) -> &L {
+        &self.log
+    }
+
+    /// Consume the wrapper, returning the audited device and the log.
+    pub fn into_inner(self) -> (S, L) {
+        (self.storage_device, self.log)
+    }
+
+    /// Store the record of an operation, before performing it.
+    fn record(
+        &mut self,
+        operation: AuditOperation,
+        offset: u64,
+        len: u64,
+        data_hash: [u8; DIGEST_LEN],
+    ) -> StorageDeviceResult<()> {
+        let record = AuditRecord::new(self.last.as_ref(), operation, offset, len, data_hash);
+        self.log.append(&record)?;
+        self.last = Some(record);
+        Ok(())
+    }
+}
+
+impl<S: StorageDevice, L: AuditLog> StorageDevice for AuditedDevice<S, L> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.storage_device.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.record(AuditOperation::Write, offset, buf.len() as u64, sha256(buf))?;
+        self.storage_device.write(offset, buf)
+    }
+
+    fn read_partial(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        self.storage_device.read_partial(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.storage_device.len()
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.record(AuditOperation::WriteZeroes, offset, len, [0; DIGEST_LEN])?;
+        self.storage_device.write_zeroes(offset, len)
+    }
+
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        self.record(AuditOperation::Fill(byte), offset, len, [0; DIGEST_LEN])?;
+        self.storage_device.fill(offset, len, byte)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.record(AuditOperation::Discard, offset, len, [0; DIGEST_LEN])?;
+        self.storage_device.discard(offset, len)
+    }
+
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        self.storage_device.next_data(offset)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.record(AuditOperation::Write, offset, buf.len() as u64, sha256(buf))?;
+        self.storage_device.write_fua(offset, buf)
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.storage_device.capabilities()
+    }
+
+    fn sanitize(&mut self) -> StorageDeviceResult<()> {
+        let len = self.storage_device.len()?;
+        self.record(AuditOperation::Sanitize, 0, len, [0; DIGEST_LEN])?;
+        self.storage_device.sanitize()
+    }
+}
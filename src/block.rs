@@ -1,5 +1,10 @@
+use crate::DeviceIdentity;
+
 /// Represent a block error.
-#[derive(Debug)]
+///
+/// New errors may be added in minor releases, so matching on it needs a wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum BlockError {
     /// Read error.
     ReadError,
@@ -7,10 +12,45 @@ pub enum BlockError {
     /// Write error.
     WriteError,
 
+    /// The device is temporarily unable to serve the request, which may succeed if retried.
+    Busy,
+
+    /// The medium failed to read or write the requested blocks.
+    MediaError,
+
+    /// There is no space left on the device.
+    OutOfSpace,
+
+    /// The device doesn't support the operation.
+    Unsupported,
+
     /// Unknown error.
     Unknown,
 }
 
+impl BlockError {
+    /// Check whether retrying the failed operation may succeed.
+    pub fn is_transient(&self) -> bool {
+        *self == BlockError::Busy
+    }
+
+    /// Check whether the error comes from a failure of the medium itself, meaning the data may be
+    /// available on another copy.
+    pub fn is_media_error(&self) -> bool {
+        *self == BlockError::MediaError
+    }
+
+    /// Check whether the device ran out of space.
+    pub fn is_out_of_space(&self) -> bool {
+        *self == BlockError::OutOfSpace
+    }
+
+    /// Check whether the device doesn't support the operation.
+    pub fn is_unsupported(&self) -> bool {
+        *self == BlockError::Unsupported
+    }
+}
+
 /// Represent a block result.
 pub type BlockResult<T> = core::result::Result<T, BlockError>;
 
@@ -22,12 +62,16 @@ pub struct Block {
     pub contents: [u8; Block::LEN],
 }
 
+// The conversions between blocks and bytes rely on blocks being laid out one after the other
+// without padding.
+const _: () = assert!(core::mem::size_of::<Block>() == Block::LEN);
+
 /// Represent the position of a block on a block device.
 #[derive(Debug, Copy, Clone, Hash, PartialOrd, PartialEq, Ord, Eq)]
 pub struct BlockIndex(pub u64);
 
 /// Represent the count of blocks that a block device hold.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Hash, PartialOrd, PartialEq, Ord, Eq)]
 pub struct BlockCount(pub u64);
 
 impl BlockCount {
@@ -53,6 +97,58 @@ impl Block {
     pub fn as_contents(&self) -> [u8; Block::LEN] {
         self.contents
     }
+
+    /// View ``blocks`` as a slice of bytes, without copying.
+    pub(crate) fn slice_as_bytes(blocks: &[Block]) -> &[u8] {
+        // SAFETY: blocks are plain arrays of bytes, laid out one after the other without padding,
+        // as checked at compile time, so the bytes of the slice are initialized and exactly
+        // cover it, and borrowing them shares the lifetime of the slice.
+        unsafe {
+            core::slice::from_raw_parts(
+                blocks.as_ptr().cast::<u8>(),
+                core::mem::size_of_val(blocks),
+            )
+        }
+    }
+
+    /// View ``blocks`` as a mutable slice of bytes, without copying.
+    pub(crate) fn slice_as_bytes_mut(blocks: &mut [Block]) -> &mut [u8] {
+        let len = core::mem::size_of_val(blocks);
+        // SAFETY: as in slice_as_bytes, and any byte written is a valid content of a block.
+        unsafe { core::slice::from_raw_parts_mut(blocks.as_mut_ptr().cast::<u8>(), len) }
+    }
+
+    /// View ``bytes`` as a slice of blocks, without copying.
+    ///
+    /// Return None if ``bytes`` isn't aligned on ``align_of::<Block>()`` or its length isn't a
+    /// multiple of [Block::LEN].
+    pub fn from_bytes(bytes: &[u8]) -> Option<&[Block]> {
+        // SAFETY: a block is a plain array of bytes, so any aligned group of Block::LEN bytes is a
+        // valid block, and align_to only puts aligned groups in the middle slice.
+        let (prefix, blocks, suffix) = unsafe { bytes.align_to::<Block>() };
+        if prefix.is_empty() && suffix.is_empty() {
+            debug_assert_eq!(core::mem::size_of_val(blocks), bytes.len());
+            Some(blocks)
+        } else {
+            None
+        }
+    }
+
+    /// View ``bytes`` as a mutable slice of blocks, without copying.
+    ///
+    /// Return None if ``bytes`` isn't aligned on ``align_of::<Block>()`` or its length isn't a
+    /// multiple of [Block::LEN].
+    pub fn from_bytes_mut(bytes: &mut [u8]) -> Option<&mut [Block]> {
+        let len = bytes.len();
+        // SAFETY: as in from_bytes, and any content of a block is a valid group of bytes.
+        let (prefix, blocks, suffix) = unsafe { bytes.align_to_mut::<Block>() };
+        if prefix.is_empty() && suffix.is_empty() {
+            debug_assert_eq!(core::mem::size_of_val(blocks), len);
+            Some(blocks)
+        } else {
+            None
+        }
+    }
 }
 
 impl Default for Block {
@@ -81,6 +177,42 @@ impl BlockIndex {
     pub fn into_offset(self) -> u64 {
         self.0 * Block::LEN_U64
     }
+
+    /// Convert the block index into an offset in bytes, returning None if it overflows.
+    pub fn checked_into_offset(self) -> Option<u64> {
+        self.0.checked_mul(Block::LEN_U64)
+    }
+
+    /// Return the index of the block holding the byte at ``offset``.
+    pub fn from_offset(offset: u64) -> BlockIndex {
+        BlockIndex(offset / Block::LEN_U64)
+    }
+
+    /// Return the index ``count`` blocks after this one, or None if it overflows.
+    pub fn checked_add(self, count: BlockCount) -> Option<BlockIndex> {
+        self.0.checked_add(count.0).map(BlockIndex)
+    }
+
+    /// Return the index ``count`` blocks before this one, or None if it underflows.
+    pub fn checked_sub(self, count: BlockCount) -> Option<BlockIndex> {
+        self.0.checked_sub(count.0).map(BlockIndex)
+    }
+
+    /// Return the index ``count`` blocks after this one, saturating at the last index.
+    pub fn saturating_add(self, count: BlockCount) -> BlockIndex {
+        BlockIndex(self.0.saturating_add(count.0))
+    }
+
+    /// Return the index ``count`` blocks before this one, saturating at the first index.
+    pub fn saturating_sub(self, count: BlockCount) -> BlockIndex {
+        BlockIndex(self.0.saturating_sub(count.0))
+    }
+
+    /// Return the number of blocks from ``start`` to this index, or None if ``start`` is after
+    /// it.
+    pub fn checked_count_from(self, start: BlockIndex) -> Option<BlockCount> {
+        self.0.checked_sub(start.0).map(BlockCount)
+    }
 }
 
 impl BlockCount {
@@ -88,6 +220,331 @@ impl BlockCount {
     pub fn into_size(self) -> u64 {
         self.0 * Block::LEN_U64
     }
+
+    /// Convert the block count into a size in bytes, returning None if it overflows.
+    pub fn checked_into_size(self) -> Option<u64> {
+        self.0.checked_mul(Block::LEN_U64)
+    }
+
+    /// Return the number of whole blocks in ``size`` bytes.
+    pub fn from_size(size: u64) -> BlockCount {
+        BlockCount(size / Block::LEN_U64)
+    }
+
+    /// Return the number of blocks needed to hold ``size`` bytes, the last one possibly partly.
+    pub fn from_size_rounded_up(size: u64) -> BlockCount {
+        BlockCount(size.div_ceil(Block::LEN_U64))
+    }
+
+    /// Check whether there are no blocks.
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Return the sum of both counts, or None if it overflows.
+    pub fn checked_add(self, count: BlockCount) -> Option<BlockCount> {
+        self.0.checked_add(count.0).map(BlockCount)
+    }
+
+    /// Return the difference of both counts, or None if ``count`` is bigger.
+    pub fn checked_sub(self, count: BlockCount) -> Option<BlockCount> {
+        self.0.checked_sub(count.0).map(BlockCount)
+    }
+
+    /// Return the sum of both counts, saturating at the biggest count.
+    pub fn saturating_add(self, count: BlockCount) -> BlockCount {
+        BlockCount(self.0.saturating_add(count.0))
+    }
+
+    /// Return the difference of both counts, saturating at zero.
+    pub fn saturating_sub(self, count: BlockCount) -> BlockCount {
+        BlockCount(self.0.saturating_sub(count.0))
+    }
+}
+
+impl From<u64> for BlockIndex {
+    fn from(index: u64) -> BlockIndex {
+        BlockIndex(index)
+    }
+}
+
+impl From<BlockIndex> for u64 {
+    fn from(index: BlockIndex) -> u64 {
+        index.0
+    }
+}
+
+impl From<u64> for BlockCount {
+    fn from(count: u64) -> BlockCount {
+        BlockCount(count)
+    }
+}
+
+impl From<BlockCount> for u64 {
+    fn from(count: BlockCount) -> u64 {
+        count.0
+    }
+}
+
+impl core::ops::Add<BlockCount> for BlockIndex {
+    type Output = BlockIndex;
+
+    fn add(self, count: BlockCount) -> BlockIndex {
+        BlockIndex(self.0 + count.0)
+    }
+}
+
+impl core::ops::AddAssign<BlockCount> for BlockIndex {
+    fn add_assign(&mut self, count: BlockCount) {
+        self.0 += count.0;
+    }
+}
+
+impl core::ops::Sub<BlockCount> for BlockIndex {
+    type Output = BlockIndex;
+
+    fn sub(self, count: BlockCount) -> BlockIndex {
+        BlockIndex(self.0 - count.0)
+    }
+}
+
+impl core::ops::SubAssign<BlockCount> for BlockIndex {
+    fn sub_assign(&mut self, count: BlockCount) {
+        self.0 -= count.0;
+    }
+}
+
+/// The number of blocks between two indices.
+impl core::ops::Sub<BlockIndex> for BlockIndex {
+    type Output = BlockCount;
+
+    fn sub(self, start: BlockIndex) -> BlockCount {
+        BlockCount(self.0 - start.0)
+    }
+}
+
+impl core::ops::Add<BlockCount> for BlockCount {
+    type Output = BlockCount;
+
+    fn add(self, count: BlockCount) -> BlockCount {
+        BlockCount(self.0 + count.0)
+    }
+}
+
+impl core::ops::AddAssign<BlockCount> for BlockCount {
+    fn add_assign(&mut self, count: BlockCount) {
+        self.0 += count.0;
+    }
+}
+
+impl core::ops::Sub<BlockCount> for BlockCount {
+    type Output = BlockCount;
+
+    fn sub(self, count: BlockCount) -> BlockCount {
+        BlockCount(self.0 - count.0)
+    }
+}
+
+impl core::ops::SubAssign<BlockCount> for BlockCount {
+    fn sub_assign(&mut self, count: BlockCount) {
+        self.0 -= count.0;
+    }
+}
+
+/// A single operation submitted to a [BlockDevice] as part of a batch.
+pub enum IoRequest<'a> {
+    /// Read blocks starting at the given ``index``.
+    Read {
+        /// The index of the first block to read.
+        index: BlockIndex,
+        /// The blocks to read into.
+        blocks: &'a mut [Block],
+    },
+
+    /// Write blocks starting at the given ``index``.
+    Write {
+        /// The index of the first block to write.
+        index: BlockIndex,
+        /// The blocks to write.
+        blocks: &'a [Block],
+    },
+}
+
+/// The geometry of a block device, describing the requests it performs best.
+///
+/// Sizes are in bytes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BlockGeometry {
+    /// The size of the blocks requests address.
+    pub logical_block_size: usize,
+
+    /// The size of the blocks the medium reads and writes at once. Writing less, or at an
+    /// unaligned position, makes the device read, modify and write a whole physical block.
+    pub physical_block_size: usize,
+
+    /// The size of the requests the device performs most efficiently, such as its stripe or
+    /// erase block size. Larger requests should be multiples of it.
+    pub optimal_transfer_size: usize,
+
+    /// The size of the largest request the device performs at once. Larger requests are split.
+    pub max_transfer_size: usize,
+}
+
+impl BlockGeometry {
+    /// Return the optimal transfer size in blocks, at least one.
+    pub fn optimal_transfer_blocks(&self) -> usize {
+        core::cmp::max(self.optimal_transfer_size / Block::LEN, 1)
+    }
+
+    /// Return the maximum transfer size in blocks, at least one.
+    pub fn max_transfer_blocks(&self) -> usize {
+        core::cmp::max(self.max_transfer_size / Block::LEN, 1)
+    }
+}
+
+impl Default for BlockGeometry {
+    /// Blocks of [Block::LEN] bytes, no preferred transfer size, and no limit on transfers.
+    fn default() -> Self {
+        BlockGeometry {
+            logical_block_size: Block::LEN,
+            physical_block_size: Block::LEN,
+            optimal_transfer_size: Block::LEN,
+            max_transfer_size: usize::MAX,
+        }
+    }
+}
+
+/// The capabilities of a device, as a set of flags, so wrappers can adapt to the device instead
+/// of guessing.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Flushing the device makes the data written durable, rather than doing nothing.
+    pub const FLUSH: Capabilities = Capabilities(1 << 0);
+
+    /// The device reclaims the storage of discarded ranges.
+    pub const TRIM: Capabilities = Capabilities(1 << 1);
+
+    /// The device writes zeroes without transferring them, e.g. by deallocating the range.
+    pub const WRITE_ZEROES: Capabilities = Capabilities(1 << 2);
+
+    /// The device can make a single write durable without flushing everything (forced unit
+    /// access).
+    pub const FUA: Capabilities = Capabilities(1 << 3);
+
+    /// The device serves requests from several users at once, e.g. through
+    /// [SharedStorageDevice](crate::SharedStorageDevice).
+    pub const CONCURRENT: Capabilities = Capabilities(1 << 4);
+
+    /// The medium is rotational, making seeks expensive.
+    pub const ROTATIONAL: Capabilities = Capabilities(1 << 5);
+
+    /// The device refuses writes.
+    pub const READ_ONLY: Capabilities = Capabilities(1 << 6);
+
+    /// Discarded ranges read as zeros, as they are left as holes.
+    pub const DISCARD_ZEROES: Capabilities = Capabilities(1 << 7);
+
+    /// The flags and their names, for [core::fmt::Debug].
+    const NAMES: [(Capabilities, &'static str); 8] = [
+        (Capabilities::FLUSH, "FLUSH"),
+        (Capabilities::TRIM, "TRIM"),
+        (Capabilities::WRITE_ZEROES, "WRITE_ZEROES"),
+        (Capabilities::FUA, "FUA"),
+        (Capabilities::CONCURRENT, "CONCURRENT"),
+        (Capabilities::ROTATIONAL, "ROTATIONAL"),
+        (Capabilities::READ_ONLY, "READ_ONLY"),
+        (Capabilities::DISCARD_ZEROES, "DISCARD_ZEROES"),
+    ];
+
+    /// Return the empty set of capabilities.
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    /// Create a set of capabilities from its raw ``bits``, dropping the unknown ones.
+    pub const fn from_bits_truncate(bits: u32) -> Self {
+        Capabilities(bits & 0xFF)
+    }
+
+    /// Return the raw bits of the set.
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Check whether the set holds every capability of ``other``.
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Check whether the set is empty.
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Add the capabilities of ``other``.
+    pub fn insert(&mut self, other: Capabilities) {
+        self.0 |= other.0;
+    }
+
+    /// Remove the capabilities of ``other``.
+    pub fn remove(&mut self, other: Capabilities) {
+        self.0 &= !other.0;
+    }
+
+    /// Add or remove the capabilities of ``other`` depending on ``value``.
+    pub fn set(&mut self, other: Capabilities, value: bool) {
+        if value {
+            self.insert(other);
+        } else {
+            self.remove(other);
+        }
+    }
+}
+
+impl core::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl core::ops::BitOrAssign for Capabilities {
+    fn bitor_assign(&mut self, other: Capabilities) {
+        self.insert(other);
+    }
+}
+
+impl core::ops::BitAnd for Capabilities {
+    type Output = Capabilities;
+    fn bitand(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl core::ops::Sub for Capabilities {
+    type Output = Capabilities;
+    fn sub(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+}
+
+impl core::fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("Capabilities(")?;
+        let mut first = true;
+        for (flag, name) in Capabilities::NAMES.iter() {
+            if self.contains(*flag) {
+                if !first {
+                    f.write_str(" | ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        f.write_str(")")
+    }
 }
 
 /// Represent a device holding blocks.
@@ -100,6 +557,165 @@ pub trait BlockDevice: core::fmt::Debug {
 
     /// Return the amount of blocks hold by the block device.
     fn count(&mut self) -> BlockResult<BlockCount>;
+
+    /// Perform a batch of requests.
+    ///
+    /// Devices with command queues should override this to have all the requests in flight at once.
+    /// As requests may then complete in any order, callers must not submit a write overlapping
+    /// another request of the same batch. If a request fails, an error is returned, and other
+    /// requests of the batch may or may not have been performed.
+    ///
+    /// The default implementation performs the requests one after the other.
+    fn submit(&mut self, requests: &mut [IoRequest<'_>]) -> BlockResult<()> {
+        for request in requests.iter_mut() {
+            match request {
+                IoRequest::Read { index, blocks } => self.read(blocks, *index)?,
+                IoRequest::Write { index, blocks } => self.write(blocks, *index)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Ensure every block written so far has reached stable storage.
+    ///
+    /// The default implementation does nothing.
+    fn flush(&mut self) -> BlockResult<()> {
+        Ok(())
+    }
+
+    /// Write blocks to the block device starting at the given ``index``, returning once they
+    /// reached stable storage (forced unit access).
+    ///
+    /// Unlike [BlockDevice::flush], only these blocks are guaranteed to be durable, which devices
+    /// reporting [Capabilities::FUA] do without flushing everything.
+    ///
+    /// The default implementation writes the blocks, then flushes the device.
+    fn write_fua(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.write(blocks, index)?;
+        self.flush()
+    }
+
+    /// Ensure every block written before the barrier reaches stable storage before any block
+    /// written after it, e.g. so a journal commit record never lands before the journal.
+    ///
+    /// Unlike [BlockDevice::flush], the barrier doesn't need to wait for the writes to be
+    /// durable, only to order them.
+    ///
+    /// The default implementation flushes the device, which orders the writes as well.
+    fn barrier(&mut self) -> BlockResult<()> {
+        self.flush()
+    }
+
+    /// Discard ``count`` blocks starting at the given ``index``, letting the device reclaim the
+    /// storage backing them.
+    ///
+    /// This is only a hint: the content of the discarded blocks is unspecified afterwards.
+    ///
+    /// The default implementation does nothing.
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        let _ = (index, count);
+        Ok(())
+    }
+
+    /// Hint that the given ranges of blocks, each made of a start index and a block count, will
+    /// be read soon.
+    ///
+    /// Caching devices should load them in their cache without evicting the blocks already there,
+    /// so a filesystem can warm its metadata right after mounting instead of issuing a burst of
+    /// small reads later.
+    ///
+    /// The default implementation does nothing.
+    fn prefetch(&mut self, ranges: &[(BlockIndex, BlockCount)]) -> BlockResult<()> {
+        let _ = ranges;
+        Ok(())
+    }
+
+    /// Return the geometry of the device, so callers can size and align their requests.
+    ///
+    /// The default implementation returns [BlockGeometry::default].
+    fn geometry(&mut self) -> BlockResult<BlockGeometry> {
+        Ok(BlockGeometry::default())
+    }
+
+    /// Return the identity of the device, such as its model and serial number.
+    ///
+    /// The default implementation fails with [BlockError::Unsupported].
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        Err(BlockError::Unsupported)
+    }
+
+    /// Return the capabilities of the device.
+    ///
+    /// The default implementation returns no capabilities, matching the default implementations
+    /// of the other methods.
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        Ok(Capabilities::empty())
+    }
+}
+
+/// The minimal interface of a block device, made of the operations every device must support.
+///
+/// Its required methods are those of [BlockDevice], but unlike it, this trait is frozen: it never
+/// gains a method, even a provided one. Each provided method added to [BlockDevice] can break a
+/// driver implementing it, as calling a method of the same name from another trait in scope,
+/// such as ``std::io::Write::flush``, becomes ambiguous on the driver. Drivers implementing this
+/// trait instead are turned into a [BlockDevice] with [LegacyBlockDeviceAdapter], which keeps the
+/// default of everything else, so they keep compiling whatever is added to [BlockDevice].
+pub trait LegacyBlockDevice: core::fmt::Debug {
+    /// Read blocks from the block device starting at the given ``index``.
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()>;
+
+    /// Write blocks to the block device starting at the given ``index``.
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()>;
+
+    /// Return the amount of blocks hold by the block device.
+    fn count(&mut self) -> BlockResult<BlockCount>;
+}
+
+/// Expose a [LegacyBlockDevice] as a [BlockDevice].
+///
+/// Capabilities unknown to [LegacyBlockDevice] keep the default implementation of [BlockDevice],
+/// so flushes and discards do nothing, and no capability is reported.
+#[derive(Debug)]
+pub struct LegacyBlockDeviceAdapter<B: LegacyBlockDevice> {
+    /// The inner legacy block device.
+    block_device: B,
+}
+
+impl<B: LegacyBlockDevice> LegacyBlockDeviceAdapter<B> {
+    /// Wrap a legacy block device.
+    pub fn new(block_device: B) -> Self {
+        LegacyBlockDeviceAdapter { block_device }
+    }
+
+    /// Return a reference to the inner legacy block device.
+    pub fn get_ref(&self) -> &B {
+        &self.block_device
+    }
+
+    /// Return a mutable reference to the inner legacy block device.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.block_device
+    }
+
+    /// Consume the adapter, returning the inner legacy block device.
+    pub fn into_inner(self) -> B {
+        self.block_device
+    }
+}
+
+impl<B: LegacyBlockDevice> BlockDevice for LegacyBlockDeviceAdapter<B> {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        self.block_device.read(blocks, index)
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.block_device.write(blocks, index)
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        self.block_device.count()
+    }
 }
 
 /// A BlockDevice that reduces device accesses by keeping the most recently used blocks in a cache.
@@ -118,8 +734,65 @@ pub struct CachedBlockDevice<B: BlockDevice> {
 
     /// The LRU cache.
     lru_cache: lru::LruCache<BlockIndex, CachedBlock>,
+
+    /// The number of dirty blocks in the cache.
+    dirty_blocks: usize,
+
+    /// Whether blocks were written to the device since it was last flushed.
+    unflushed: bool,
+
+    /// The limits on the number of dirty blocks, if any.
+    watermarks: Option<DirtyWatermarks>,
+
+    /// Whether the device is read-only, once its capabilities were queried.
+    read_only: Option<bool>,
 }
 
+/// What a [CachedBlockDevice] does when a write would take its dirty blocks above the high
+/// watermark.
+#[derive(Debug, Copy, Clone)]
+#[cfg(any(
+    feature = "cached-block-device",
+    feature = "cached-block-device-nightly"
+))]
+pub enum Backpressure {
+    /// Write dirty blocks back to the device until they are at the low watermark, then perform
+    /// the write. The writer is slowed down to the pace of the device.
+    WriteBack,
+
+    /// Fail the write with [BlockError::Busy], leaving the caller to flush before trying again.
+    Reject,
+
+    /// Call the hook with the number of dirty blocks, e.g. to wake up a flusher, then perform the
+    /// write.
+    Hook(fn(usize)),
+}
+
+/// Limits on the number of dirty blocks a [CachedBlockDevice] holds, so a slow device can't let
+/// unflushed data pile up in memory.
+#[derive(Debug, Copy, Clone)]
+#[cfg(any(
+    feature = "cached-block-device",
+    feature = "cached-block-device-nightly"
+))]
+pub struct DirtyWatermarks {
+    /// The number of dirty blocks above which writes trigger the backpressure.
+    pub high: usize,
+
+    /// The number of dirty blocks [Backpressure::WriteBack] writes back down to.
+    pub low: usize,
+
+    /// What to do when above the high watermark.
+    pub backpressure: Backpressure,
+}
+
+/// How many blocks a [CachedBlockDevice] reads from the device at once when prefetching.
+#[cfg(any(
+    feature = "cached-block-device",
+    feature = "cached-block-device-nightly"
+))]
+const PREFETCH_BATCH_BLOCKS: usize = 8;
+
 /// Represent a cached block in the LRU cache.
 #[cfg(any(
     feature = "cached-block-device",
@@ -157,23 +830,148 @@ impl<B: BlockDevice> CachedBlockDevice<B> {
         CachedBlockDevice {
             block_device: device,
             lru_cache: lru::LruCache::new(cap),
+            dirty_blocks: 0,
+            unflushed: false,
+            watermarks: None,
+            read_only: None,
+        }
+    }
+
+    /// Limit the number of dirty blocks held in the cache.
+    pub fn with_dirty_watermarks(mut self, watermarks: DirtyWatermarks) -> Self {
+        self.watermarks = Some(watermarks);
+        self
+    }
+
+    /// Return the number of dirty blocks in the cache, that haven't been written to the device yet.
+    pub fn dirty_blocks(&self) -> usize {
+        self.dirty_blocks
+    }
+
+    /// Write dirty blocks to the device until at most ``target`` remain, without flushing it.
+    ///
+    /// This function has no effect on lru order.
+    pub fn write_back(&mut self, target: usize) -> BlockResult<()> {
+        for (index, block) in self.lru_cache.iter_mut() {
+            if self.dirty_blocks <= target {
+                break;
+            }
+            if block.dirty {
+                self.block_device
+                    .write(core::slice::from_ref(&block.data), *index)?;
+                self.unflushed = true;
+                block.dirty = false;
+                self.dirty_blocks -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check whether the device is read-only, querying its capabilities the first time.
+    fn is_read_only(&mut self) -> BlockResult<bool> {
+        if let Some(read_only) = self.read_only {
+            return Ok(read_only);
+        }
+        let read_only = self
+            .block_device
+            .capabilities()?
+            .contains(Capabilities::READ_ONLY);
+        self.read_only = Some(read_only);
+        Ok(read_only)
+    }
+
+    /// Apply the backpressure if writing ``count`` blocks would take the dirty blocks above the
+    /// high watermark.
+    fn check_watermarks(&mut self, count: usize) -> BlockResult<()> {
+        let watermarks = match self.watermarks {
+            Some(watermarks) if self.dirty_blocks + count > watermarks.high => watermarks,
+            _ => return Ok(()),
+        };
+
+        match watermarks.backpressure {
+            Backpressure::WriteBack => self.write_back(watermarks.low),
+            Backpressure::Reject => Err(BlockError::Busy),
+            Backpressure::Hook(hook) => {
+                hook(self.dirty_blocks);
+                Ok(())
+            }
+        }
+    }
+
+    /// Read ``count`` blocks starting at ``index`` that are not in the cache yet into it, as long
+    /// as the cache has free room.
+    ///
+    /// Return whether the cache still has free room afterwards.
+    fn prefetch_range(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<bool> {
+        let mut blocks: [Block; PREFETCH_BATCH_BLOCKS] = core::array::from_fn(|_| Block::new());
+        let max_run = core::cmp::min(
+            blocks.len(),
+            self.block_device.geometry()?.max_transfer_blocks(),
+        );
+        let end = index.0.saturating_add(count.0);
+        let mut current = index.0;
+
+        while current < end {
+            let free = self.lru_cache.cap() - self.lru_cache.len();
+            if free == 0 {
+                return Ok(false);
+            }
+
+            // Skip the blocks we already have, without touching their access time.
+            if self.lru_cache.contains(&BlockIndex(current)) {
+                current += 1;
+                continue;
+            }
+
+            // Read the run of missing blocks that starts here, in one go.
+            let mut run = 1;
+            while run < core::cmp::min(free, max_run)
+                && current + (run as u64) < end
+                && !self.lru_cache.contains(&BlockIndex(current + run as u64))
+            {
+                run += 1;
+            }
+
+            self.block_device
+                .read(&mut blocks[..run], BlockIndex(current))?;
+            for (i, block) in blocks[..run].iter().enumerate() {
+                self.lru_cache.put(
+                    BlockIndex(current + i as u64),
+                    CachedBlock {
+                        dirty: false,
+                        data: block.clone(),
+                    },
+                );
+            }
+            current += run as u64;
         }
+
+        Ok(self.lru_cache.len() < self.lru_cache.cap())
     }
 
-    /// Writes every dirty cached block to device.
+    /// Writes every dirty cached block to device, and flushes the device.
     ///
     /// Note that this will not empty the cache, just perform device writes
     /// and update dirty blocks as now non-dirty.
     ///
+    /// Nothing is done when no block is dirty and nothing was written to the device since it was
+    /// last flushed, which is always the case of read-only devices.
+    ///
     /// This function has no effect on lru order.
     pub fn flush(&mut self) -> BlockResult<()> {
+        if self.dirty_blocks == 0 && !self.unflushed {
+            return Ok(());
+        }
         for (index, block) in self.lru_cache.iter_mut() {
             if block.dirty {
                 self.block_device
                     .write(core::slice::from_ref(&block.data), *index)?;
                 block.dirty = false;
+                self.dirty_blocks -= 1;
             }
         }
+        self.block_device.flush()?;
+        self.unflushed = false;
         Ok(())
     }
 }
@@ -208,7 +1006,7 @@ impl<B: BlockDevice> BlockDevice for CachedBlockDevice<B> {
         } else {
             // check each block is found in the cache
             for i in 0..blocks.len() {
-                if !self.lru_cache.contains(&BlockIndex(index.0 + i as u64)) {
+                if !self.lru_cache.contains(&(index + BlockCount(i as u64))) {
                     fully_cached = false;
                     break;
                 }
@@ -222,7 +1020,7 @@ impl<B: BlockDevice> BlockDevice for CachedBlockDevice<B> {
 
         // update from/to cache
         for (i, block) in blocks.iter_mut().enumerate() {
-            if let Some(cached_block) = self.lru_cache.get(&BlockIndex(index.0 + i as u64)) {
+            if let Some(cached_block) = self.lru_cache.get(&(index + BlockCount(i as u64))) {
                 // block was found in cache, its access time was updated.
                 if fully_cached || cached_block.dirty {
                     // fully_cached: block[i] is uninitialized, copy it from cache.
@@ -238,6 +1036,8 @@ impl<B: BlockDevice> BlockDevice for CachedBlockDevice<B> {
                     if evicted_block.dirty {
                         self.block_device
                             .write(core::slice::from_ref(&evicted_block.data), evicted_index)?;
+                        self.unflushed = true;
+                        self.dirty_blocks -= 1;
                     }
                 }
                 let new_cached_block = CachedBlock {
@@ -245,7 +1045,7 @@ impl<B: BlockDevice> BlockDevice for CachedBlockDevice<B> {
                     data: block.clone(),
                 };
                 self.lru_cache
-                    .put(BlockIndex(index.0 + i as u64), new_cached_block);
+                    .put(index + BlockCount(i as u64), new_cached_block);
             }
         }
         Ok(())
@@ -257,8 +1057,18 @@ impl<B: BlockDevice> BlockDevice for CachedBlockDevice<B> {
     ///
     /// When the cache is full, least recently used blocks will be evicted and written to device.
     /// This operation may fail, and this function will return an error when it happens.
+    ///
+    /// With [DirtyWatermarks], a write that would take the dirty blocks above the high watermark
+    /// first applies the [Backpressure].
+    ///
+    /// Writes to a device reporting [Capabilities::READ_ONLY] fail with [BlockError::WriteError]
+    /// right away, instead of being cached and failing on write-back.
     fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        if self.is_read_only()? {
+            return Err(BlockError::WriteError);
+        }
         if blocks.len() < self.lru_cache.cap() {
+            self.check_watermarks(blocks.len())?;
             for (i, block) in blocks.iter().enumerate() {
                 let new_block = CachedBlock {
                     dirty: true,
@@ -271,14 +1081,24 @@ impl<B: BlockDevice> BlockDevice for CachedBlockDevice<B> {
                     if evicted_block.dirty {
                         self.block_device
                             .write(core::slice::from_ref(&evicted_block.data), evicted_index)?;
+                        self.unflushed = true;
+                        self.dirty_blocks -= 1;
                     }
                 }
-                self.lru_cache
-                    .put(BlockIndex(index.0 + i as u64), new_block);
+                let block_index = index + BlockCount(i as u64);
+                if !self
+                    .lru_cache
+                    .peek(&block_index)
+                    .is_some_and(|cached_block| cached_block.dirty)
+                {
+                    self.dirty_blocks += 1;
+                }
+                self.lru_cache.put(block_index, new_block);
             }
         } else {
             // we're performing a big write, that will evict all cache blocks.
             // evict it in one go, and repopulate with the first `cap` blocks from `blocks`.
+            self.unflushed = true;
             for (evicted_index, evicted_block) in self.lru_cache.iter() {
                 if evicted_block.dirty
                     // if evicted block is `blocks`, don't bother writing it as we're about to re-write it anyway.
@@ -290,10 +1110,11 @@ impl<B: BlockDevice> BlockDevice for CachedBlockDevice<B> {
             }
             // write in one go
             self.block_device.write(blocks, index)?;
-            // add first `cap` blocks to cache
+            // add first `cap` blocks to cache, which evicts every other block.
+            self.dirty_blocks = 0;
             for (i, block) in blocks.iter().take(self.lru_cache.cap()).enumerate() {
                 self.lru_cache.put(
-                    BlockIndex(index.0 + i as u64),
+                    index + BlockCount(i as u64),
                     CachedBlock {
                         dirty: false,
                         data: block.clone(),
@@ -307,6 +1128,95 @@ impl<B: BlockDevice> BlockDevice for CachedBlockDevice<B> {
     fn count(&mut self) -> BlockResult<BlockCount> {
         self.block_device.count()
     }
+
+    /// Writes every dirty cached block to device, and flushes the device.
+    fn flush(&mut self) -> BlockResult<()> {
+        CachedBlockDevice::flush(self)
+    }
+
+    /// Writes the blocks through to the device with forced unit access, and updates the blocks
+    /// found in the cache, which are clean afterwards.
+    ///
+    /// This function has no effect on lru order.
+    fn write_fua(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        if self.is_read_only()? {
+            return Err(BlockError::WriteError);
+        }
+        self.block_device.write_fua(blocks, index)?;
+        for (cached_index, cached_block) in self.lru_cache.iter_mut() {
+            if *cached_index >= index && cached_index.0 - index.0 < blocks.len() as u64 {
+                if cached_block.dirty {
+                    self.dirty_blocks -= 1;
+                }
+                cached_block.dirty = false;
+                cached_block.data = blocks[(cached_index.0 - index.0) as usize].clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty cached block to device before the barrier, so they land before the
+    /// blocks written afterwards.
+    fn barrier(&mut self) -> BlockResult<()> {
+        if self.is_read_only()? {
+            return Ok(());
+        }
+        self.write_back(0)?;
+        self.block_device.barrier()
+    }
+
+    /// Zeroes the discarded blocks found in the cache so they are never written back, and forwards
+    /// the discard to the device.
+    ///
+    /// This function has no effect on lru order.
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        let end = BlockIndex(index.0.saturating_add(count.0));
+        for (cached_index, cached_block) in self.lru_cache.iter_mut() {
+            if *cached_index >= index && *cached_index < end {
+                if cached_block.dirty {
+                    self.dirty_blocks -= 1;
+                }
+                cached_block.dirty = false;
+                cached_block.data = Block::new();
+            }
+        }
+        self.block_device.discard(index, count)
+    }
+
+    /// Reads the blocks of ``ranges`` missing from the cache into it, in order, until the cache is
+    /// full.
+    ///
+    /// Runs of missing blocks are read in requests no larger than the maximum transfer size of
+    /// the [BlockGeometry] of the device.
+    ///
+    /// Prefetching never evicts blocks from the cache, so it doesn't get in the way of the blocks
+    /// actually in use.
+    fn prefetch(&mut self, ranges: &[(BlockIndex, BlockCount)]) -> BlockResult<()> {
+        for (index, count) in ranges.iter() {
+            if !self.prefetch_range(*index, *count)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn geometry(&mut self) -> BlockResult<BlockGeometry> {
+        self.block_device.geometry()
+    }
+
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        self.block_device.identity()
+    }
+
+    /// Flushing writes the dirty cached blocks back, whatever the device does with flushes, unless
+    /// the device is read-only.
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        let mut capabilities = self.block_device.capabilities()?;
+        if !capabilities.contains(Capabilities::READ_ONLY) {
+            capabilities.insert(Capabilities::FLUSH);
+        }
+        Ok(capabilities)
+    }
 }
 
 #[cfg(feature = "std")]
@@ -316,10 +1226,10 @@ impl BlockDevice for std::fs::File {
         use std::io::{Read, Seek};
 
         self.seek(std::io::SeekFrom::Start(index.into_offset()))
-            .map_err(|_| BlockError::ReadError)?;
+            .map_err(|err| crate::sys::block_error(&err, BlockError::ReadError))?;
         for block in blocks.iter_mut() {
             self.read_exact(&mut block.contents)
-                .map_err(|_| BlockError::ReadError)?;
+                .map_err(|err| crate::sys::block_error(&err, BlockError::ReadError))?;
         }
         Ok(())
     }
@@ -329,22 +1239,108 @@ impl BlockDevice for std::fs::File {
         use std::io::{Seek, Write};
 
         self.seek(std::io::SeekFrom::Start(index.into_offset()))
-            .map_err(|_| BlockError::ReadError)?;
+            .map_err(|err| crate::sys::block_error(&err, BlockError::ReadError))?;
         for block in blocks.iter() {
             self.write_all(&block.contents)
-                .map_err(|_| BlockError::WriteError)?;
+                .map_err(|err| crate::sys::block_error(&err, BlockError::WriteError))?;
+        }
+        Ok(())
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        let num_blocks = crate::sys::file_len(self)
+            .map_err(|err| crate::sys::block_error(&err, BlockError::Unknown))?
+            / (Block::LEN_U64);
+        Ok(BlockCount(num_blocks))
+    }
+
+    /// Syncs the content of the file to disk.
+    fn flush(&mut self) -> BlockResult<()> {
+        self.sync_all()
+            .map_err(|err| crate::sys::block_error(&err, BlockError::WriteError))
+    }
+
+    /// Punches a hole in the file if the filesystem supports it, does nothing otherwise.
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        match crate::sys::punch_hole(self, index.into_offset(), count.into_size()) {
+            Err(err) if !crate::sys::is_unsupported(&err) => {
+                Err(crate::sys::block_error(&err, BlockError::WriteError))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Query the identity of the disk or file from the operating system.
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        crate::sys::identity(self).map_err(|err| crate::sys::block_error(&err, BlockError::Unknown))
+    }
+
+    /// Query the capabilities of the disk or file from the operating system.
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        Ok(crate::sys::capabilities(self))
+    }
+}
+
+/// Positional reads and writes, which leave the cursor of the file alone so several users can
+/// share the file.
+#[cfg(all(feature = "std", any(unix, windows)))]
+impl BlockDevice for &std::fs::File {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        let mut offset = index.into_offset();
+        for block in blocks.iter_mut() {
+            crate::sys::read_exact_at(self, &mut block.contents, offset)
+                .map_err(|err| crate::sys::block_error(&err, BlockError::ReadError))?;
+            offset += Block::LEN_U64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        let mut offset = index.into_offset();
+        for block in blocks.iter() {
+            crate::sys::write_all_at(self, &block.contents, offset)
+                .map_err(|err| crate::sys::block_error(&err, BlockError::WriteError))?;
+            offset += Block::LEN_U64;
         }
         Ok(())
     }
 
     fn count(&mut self) -> BlockResult<BlockCount> {
-        let num_blocks = self.metadata().map_err(|_| BlockError::Unknown)?.len() / (Block::LEN_U64);
+        let num_blocks = crate::sys::file_len(self)
+            .map_err(|err| crate::sys::block_error(&err, BlockError::Unknown))?
+            / (Block::LEN_U64);
         Ok(BlockCount(num_blocks))
     }
+
+    /// Syncs the content of the file to disk.
+    fn flush(&mut self) -> BlockResult<()> {
+        self.sync_all()
+            .map_err(|err| crate::sys::block_error(&err, BlockError::WriteError))
+    }
+
+    /// Punches a hole in the file if the filesystem supports it, does nothing otherwise.
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        match crate::sys::punch_hole(self, index.into_offset(), count.into_size()) {
+            Err(err) if !crate::sys::is_unsupported(&err) => {
+                Err(crate::sys::block_error(&err, BlockError::WriteError))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Query the identity of the disk or file from the operating system.
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        crate::sys::identity(self).map_err(|err| crate::sys::block_error(&err, BlockError::Unknown))
+    }
+
+    /// Query the capabilities of the disk or file from the operating system.
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        Ok(crate::sys::capabilities(self))
+    }
 }
 
 #[cfg(feature = "std")]
-use crate::{StorageDevice, StorageDeviceError, StorageDeviceResult};
+use crate::{DeviceInfo, StorageDevice, StorageDeviceError, StorageDeviceResult};
 
 #[cfg(feature = "std")]
 impl StorageDevice for std::fs::File {
@@ -353,8 +1349,9 @@ impl StorageDevice for std::fs::File {
         use std::io::{Read, Seek};
 
         self.seek(std::io::SeekFrom::Start(offset))
-            .map_err(|_| BlockError::ReadError)?;
-        self.read_exact(buf).map_err(|_| BlockError::ReadError)?;
+            .map_err(|err| crate::sys::block_error(&err, BlockError::ReadError))?;
+        self.read_exact(buf)
+            .map_err(|err| crate::sys::block_error(&err, BlockError::ReadError))?;
         Ok(())
     }
 
@@ -363,17 +1360,207 @@ impl StorageDevice for std::fs::File {
         use std::io::{Seek, Write};
 
         self.seek(std::io::SeekFrom::Start(offset))
-            .map_err(|_| BlockError::WriteError)?;
-        self.write_all(buf).map_err(|_| BlockError::WriteError)?;
+            .map_err(|err| crate::sys::block_error(&err, BlockError::WriteError))?;
+        self.write_all(buf)
+            .map_err(|err| crate::sys::block_error(&err, BlockError::WriteError))?;
         Ok(())
     }
 
-    /// Return the total size of the storage device.
+    /// Read the data at the given ``offset`` into the rest of the buffer, without initializing it
+    /// first on Unix.
+    #[cfg(any(unix, windows))]
+    fn read_buf(&mut self, offset: u64, buf: &mut crate::ReadBuf<'_>) -> StorageDeviceResult<()> {
+        crate::sys::read_buf_exact_at(self, buf, offset)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::ReadError))
+    }
+
+    /// Seek to the given ``offset``, and read until the buffer is full, the end of the file is
+    /// reached, or an error occurs.
+    fn read_partial(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        use std::io::{Read, Seek};
+
+        self.seek(std::io::SeekFrom::Start(offset))
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::ReadError))?;
+
+        let mut read_size = 0;
+        while read_size < buf.len() {
+            match Read::read(self, &mut buf[read_size..]) {
+                Ok(0) => break,
+                Ok(size) => read_size += size,
+                Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) if read_size == 0 => return Err(StorageDeviceError::ReadError),
+                Err(_) => break,
+            }
+        }
+
+        if read_size == 0 && !buf.is_empty() {
+            return Err(StorageDeviceError::OutOfBounds);
+        }
+
+        Ok(read_size)
+    }
+
+    /// Seek to the given ``offset``, and write until the whole buffer is written or an error
+    /// occurs.
+    fn write_partial(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<usize> {
+        use std::io::{Seek, Write};
+
+        self.seek(std::io::SeekFrom::Start(offset))
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))?;
+
+        let mut write_size = 0;
+        while write_size < buf.len() {
+            match Write::write(self, &buf[write_size..]) {
+                Ok(0) => break,
+                Ok(size) => write_size += size,
+                Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) if write_size == 0 => return Err(StorageDeviceError::WriteError),
+                Err(_) => break,
+            }
+        }
+
+        if write_size == 0 && !buf.is_empty() {
+            return Err(StorageDeviceError::WriteError);
+        }
+
+        Ok(write_size)
+    }
+
+    /// Return the total size of the storage device, querying the operating system for raw block
+    /// devices.
     fn len(&mut self) -> StorageDeviceResult<u64> {
-        Ok(self
-            .metadata()
-            .map_err(|_| StorageDeviceError::Unknown)?
-            .len())
+        crate::sys::file_len(self)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+    }
+
+    /// Sync the content of the file to disk.
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.sync_all()
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+
+    /// Punch a hole in the file if the filesystem supports it, do nothing otherwise.
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        match crate::sys::punch_hole(self, offset, len) {
+            Err(err) if !crate::sys::is_unsupported(&err) => Err(crate::sys::storage_error(
+                &err,
+                StorageDeviceError::WriteError,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Return information about the file.
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo {
+            supports_holes: crate::sys::supports_holes(self),
+            ..DeviceInfo::default()
+        })
+    }
+
+    /// Punch a hole in the range if it is inside the file and the filesystem supports it, write
+    /// zeros otherwise.
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        let file_len = crate::sys::file_len(self)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))?;
+        if offset.saturating_add(len) <= file_len {
+            match crate::sys::punch_hole(self, offset, len) {
+                Ok(()) => return Ok(()),
+                Err(err) if !crate::sys::is_unsupported(&err) => {
+                    return Err(crate::sys::storage_error(
+                        &err,
+                        StorageDeviceError::WriteError,
+                    ))
+                }
+                Err(_) => (),
+            }
+        }
+        self.fill(offset, len, 0)
+    }
+
+    /// Report the holes of sparse files.
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        crate::sys::next_data(self, offset)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::ReadError))
+    }
+
+    /// Securely discard the whole device, if the file is a raw block device supporting it.
+    fn sanitize(&mut self) -> StorageDeviceResult<()> {
+        crate::sys::secure_discard(self)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+
+    /// Query the identity of the disk or file from the operating system.
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        crate::sys::identity(self)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+    }
+
+    /// Query the capabilities of the disk or file from the operating system.
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        Ok(crate::sys::capabilities(self))
+    }
+}
+
+#[cfg(feature = "std")]
+impl crate::Resizable for std::fs::File {
+    fn set_len(&mut self, len: u64) -> StorageDeviceResult<()> {
+        std::fs::File::set_len(self, len)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+}
+
+#[cfg(feature = "std")]
+impl crate::Resizable for &std::fs::File {
+    fn set_len(&mut self, len: u64) -> StorageDeviceResult<()> {
+        std::fs::File::set_len(self, len)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+}
+
+/// Positional reads and writes, which leave the cursor of the file alone so they can run
+/// concurrently.
+///
+/// On Windows, the cursor is moved, so the file must not be used through [std::io::Read] or
+/// [std::io::Write] at the same time.
+#[cfg(all(feature = "std", any(unix, windows)))]
+impl crate::SharedStorageDevice for std::fs::File {
+    fn read_shared(&self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        crate::sys::read_exact_at(self, buf, offset)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::ReadError))
+    }
+
+    fn write_shared(&self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        crate::sys::write_all_at(self, buf, offset)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+
+    fn len_shared(&self) -> StorageDeviceResult<u64> {
+        crate::sys::file_len(self)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+    }
+
+    /// Punch a hole in the file if the filesystem supports it, do nothing otherwise.
+    fn discard_shared(&self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        match crate::sys::punch_hole(self, offset, len) {
+            Err(err) if !crate::sys::is_unsupported(&err) => Err(crate::sys::storage_error(
+                &err,
+                StorageDeviceError::WriteError,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush_shared(&self) -> StorageDeviceResult<()> {
+        self.sync_all()
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+
+    fn info_shared(&self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo {
+            supports_holes: crate::sys::supports_holes(self),
+            ..DeviceInfo::default()
+        })
     }
 }
 
@@ -384,8 +1571,9 @@ impl StorageDevice for &std::fs::File {
         use std::io::{Read, Seek};
 
         self.seek(std::io::SeekFrom::Start(offset))
-            .map_err(|_| BlockError::ReadError)?;
-        self.read_exact(buf).map_err(|_| BlockError::ReadError)?;
+            .map_err(|err| crate::sys::block_error(&err, BlockError::ReadError))?;
+        self.read_exact(buf)
+            .map_err(|err| crate::sys::block_error(&err, BlockError::ReadError))?;
         Ok(())
     }
 
@@ -394,16 +1582,144 @@ impl StorageDevice for &std::fs::File {
         use std::io::{Seek, Write};
 
         self.seek(std::io::SeekFrom::Start(offset))
-            .map_err(|_| BlockError::WriteError)?;
-        self.write_all(buf).map_err(|_| BlockError::WriteError)?;
+            .map_err(|err| crate::sys::block_error(&err, BlockError::WriteError))?;
+        self.write_all(buf)
+            .map_err(|err| crate::sys::block_error(&err, BlockError::WriteError))?;
         Ok(())
     }
 
-    /// Return the total size of the storage device.
+    /// Read the data at the given ``offset`` into the rest of the buffer, without initializing it
+    /// first on Unix.
+    #[cfg(any(unix, windows))]
+    fn read_buf(&mut self, offset: u64, buf: &mut crate::ReadBuf<'_>) -> StorageDeviceResult<()> {
+        crate::sys::read_buf_exact_at(self, buf, offset)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::ReadError))
+    }
+
+    /// Seek to the given ``offset``, and read until the buffer is full, the end of the file is
+    /// reached, or an error occurs.
+    fn read_partial(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        use std::io::{Read, Seek};
+
+        self.seek(std::io::SeekFrom::Start(offset))
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::ReadError))?;
+
+        let mut read_size = 0;
+        while read_size < buf.len() {
+            match Read::read(self, &mut buf[read_size..]) {
+                Ok(0) => break,
+                Ok(size) => read_size += size,
+                Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) if read_size == 0 => return Err(StorageDeviceError::ReadError),
+                Err(_) => break,
+            }
+        }
+
+        if read_size == 0 && !buf.is_empty() {
+            return Err(StorageDeviceError::OutOfBounds);
+        }
+
+        Ok(read_size)
+    }
+
+    /// Seek to the given ``offset``, and write until the whole buffer is written or an error
+    /// occurs.
+    fn write_partial(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<usize> {
+        use std::io::{Seek, Write};
+
+        self.seek(std::io::SeekFrom::Start(offset))
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))?;
+
+        let mut write_size = 0;
+        while write_size < buf.len() {
+            match Write::write(self, &buf[write_size..]) {
+                Ok(0) => break,
+                Ok(size) => write_size += size,
+                Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) if write_size == 0 => return Err(StorageDeviceError::WriteError),
+                Err(_) => break,
+            }
+        }
+
+        if write_size == 0 && !buf.is_empty() {
+            return Err(StorageDeviceError::WriteError);
+        }
+
+        Ok(write_size)
+    }
+
+    /// Return the total size of the storage device, querying the operating system for raw block
+    /// devices.
     fn len(&mut self) -> StorageDeviceResult<u64> {
-        Ok(self
-            .metadata()
-            .map_err(|_| StorageDeviceError::Unknown)?
-            .len())
+        crate::sys::file_len(self)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+    }
+
+    /// Sync the content of the file to disk.
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.sync_all()
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+
+    /// Punch a hole in the file if the filesystem supports it, do nothing otherwise.
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        match crate::sys::punch_hole(self, offset, len) {
+            Err(err) if !crate::sys::is_unsupported(&err) => Err(crate::sys::storage_error(
+                &err,
+                StorageDeviceError::WriteError,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Return information about the file.
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo {
+            supports_holes: crate::sys::supports_holes(self),
+            ..DeviceInfo::default()
+        })
+    }
+
+    /// Punch a hole in the range if it is inside the file and the filesystem supports it, write
+    /// zeros otherwise.
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        let file_len = crate::sys::file_len(self)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))?;
+        if offset.saturating_add(len) <= file_len {
+            match crate::sys::punch_hole(self, offset, len) {
+                Ok(()) => return Ok(()),
+                Err(err) if !crate::sys::is_unsupported(&err) => {
+                    return Err(crate::sys::storage_error(
+                        &err,
+                        StorageDeviceError::WriteError,
+                    ))
+                }
+                Err(_) => (),
+            }
+        }
+        self.fill(offset, len, 0)
+    }
+
+    /// Report the holes of sparse files.
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        crate::sys::next_data(self, offset)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::ReadError))
+    }
+
+    /// Securely discard the whole device, if the file is a raw block device supporting it.
+    fn sanitize(&mut self) -> StorageDeviceResult<()> {
+        crate::sys::secure_discard(self)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+
+    /// Query the identity of the disk or file from the operating system.
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        crate::sys::identity(self)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+    }
+
+    /// Query the capabilities of the disk or file from the operating system.
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        Ok(crate::sys::capabilities(self))
     }
 }
@@ -0,0 +1,229 @@
+use crate::{
+    Block, BlockCount, BlockDevice, BlockError, BlockGeometry, BlockIndex, BlockResult,
+    Capabilities, DeviceIdentity,
+};
+use alloc::vec::Vec;
+
+/// A block device exposing the blocks of its inner device with another logical block size, such
+/// as a 4Kn drive as a 512e one, or the other way around.
+///
+/// The inner device reports the size of the blocks its requests must be aligned on as the
+/// [BlockGeometry::logical_block_size] of its geometry, and the adapter as the one it was created
+/// with. Requests must be aligned on the logical block size of the adapter, and fail otherwise,
+/// as they would on a drive of that size. Parts of a request not covering whole blocks of the
+/// inner device are read, modified and written back through a temporary buffer, so smaller
+/// blocks can be written to any device. The size of the blocks of the inner device is reported
+/// as the physical block size, so writers can avoid the read-modify-write cycles.
+///
+/// Block indices and counts are still in [Block]s of [Block::LEN] bytes, as for any
+/// [BlockDevice].
+pub struct BlockSizeAdapter<B: BlockDevice> {
+    /// The inner block device.
+    block_device: B,
+
+    /// The logical block size exposed, in [Block]s.
+    outer_blocks: u64,
+
+    /// The logical block size of the inner device, in [Block]s.
+    inner_blocks: u64,
+
+    /// The buffer holding a block of the inner device during read-modify-write cycles.
+    buffer: Vec<Block>,
+}
+
+impl<B: BlockDevice> core::fmt::Debug for BlockSizeAdapter<B> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("BlockSizeAdapter")
+            .field("block_device", &self.block_device)
+            .field("block_size", &self.block_size())
+            .field("inner_block_size", &self.inner_block_size())
+            .finish()
+    }
+}
+
+impl<B: BlockDevice> BlockSizeAdapter<B> {
+    /// Expose ``block_device`` with logical blocks of ``block_size`` bytes.
+    ///
+    /// ``block_size``, and the logical block size of ``block_device``, must be non-zero multiples
+    /// of [Block::LEN]. Return [BlockError::Unsupported] otherwise.
+    pub fn new(mut block_device: B, block_size: usize) -> BlockResult<Self> {
+        let inner_block_size = block_device.geometry()?.logical_block_size;
+        let blocks = |size: usize| match size {
+            0 => Err(BlockError::Unsupported),
+            size if size.is_multiple_of(Block::LEN) => Ok((size / Block::LEN) as u64),
+            _ => Err(BlockError::Unsupported),
+        };
+        let outer_blocks = blocks(block_size)?;
+        let inner_blocks = blocks(inner_block_size)?;
+
+        Ok(BlockSizeAdapter {
+            block_device,
+            outer_blocks,
+            inner_blocks,
+            buffer: alloc::vec![Block::new(); inner_blocks as usize],
+        })
+    }
+
+    /// Return the logical block size exposed, in bytes.
+    pub fn block_size(&self) -> usize {
+        self.outer_blocks as usize * Block::LEN
+    }
+
+    /// Return the logical block size of the inner device, in bytes.
+    pub fn inner_block_size(&self) -> usize {
+        self.inner_blocks as usize * Block::LEN
+    }
+
+    /// Return a reference to the inner block device.
+    pub fn get_ref(&self) -> &B {
+        &self.block_device
+    }
+
+    /// Return a mutable reference to the inner block device.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.block_device
+    }
+
+    /// Consume the adapter, returning the inner block device.
+    pub fn into_inner(self) -> B {
+        self.block_device
+    }
+
+    /// Check whether ``count`` blocks starting at ``index`` are aligned on the logical block size
+    /// exposed.
+    fn is_aligned(&self, index: BlockIndex, count: usize) -> bool {
+        index.0.is_multiple_of(self.outer_blocks)
+            && (count as u64).is_multiple_of(self.outer_blocks)
+    }
+
+    /// Return how many of the ``count`` blocks at ``index`` are in the same block of the inner
+    /// device, and whether they cover it whole.
+    fn run_len(&self, index: BlockIndex, count: usize) -> (usize, bool) {
+        let start = index.0 % self.inner_blocks;
+        if start == 0 && count as u64 >= self.inner_blocks {
+            // Whole blocks of the inner device can be transferred at once.
+            let whole = count as u64 / self.inner_blocks * self.inner_blocks;
+            return (whole as usize, true);
+        }
+        let run = core::cmp::min(self.inner_blocks - start, count as u64);
+        (run as usize, false)
+    }
+
+    /// Write ``blocks`` at ``index``, with ``write_fua`` if ``fua`` is set.
+    fn write_blocks(
+        &mut self,
+        mut blocks: &[Block],
+        mut index: BlockIndex,
+        fua: bool,
+    ) -> BlockResult<()> {
+        if !self.is_aligned(index, blocks.len()) {
+            return Err(BlockError::WriteError);
+        }
+        while !blocks.is_empty() {
+            let (run, whole) = self.run_len(index, blocks.len());
+            let (head, tail) = blocks.split_at(run);
+            let (data, data_index) = if whole {
+                (head, index)
+            } else {
+                let inner_index = BlockIndex(index.0 - index.0 % self.inner_blocks);
+                self.block_device.read(&mut self.buffer, inner_index)?;
+                let start = (index.0 - inner_index.0) as usize;
+                self.buffer[start..start + run].clone_from_slice(head);
+                (&self.buffer[..], inner_index)
+            };
+            if fua {
+                self.block_device.write_fua(data, data_index)?;
+            } else {
+                self.block_device.write(data, data_index)?;
+            }
+            blocks = tail;
+            index += BlockCount(run as u64);
+        }
+        Ok(())
+    }
+}
+
+impl<B: BlockDevice> BlockDevice for BlockSizeAdapter<B> {
+    fn read(&mut self, mut blocks: &mut [Block], mut index: BlockIndex) -> BlockResult<()> {
+        if !self.is_aligned(index, blocks.len()) {
+            return Err(BlockError::ReadError);
+        }
+        while !blocks.is_empty() {
+            let (run, whole) = self.run_len(index, blocks.len());
+            let (head, tail) = blocks.split_at_mut(run);
+            if whole {
+                self.block_device.read(head, index)?;
+            } else {
+                let inner_index = BlockIndex(index.0 - index.0 % self.inner_blocks);
+                self.block_device.read(&mut self.buffer, inner_index)?;
+                let start = (index.0 - inner_index.0) as usize;
+                head.clone_from_slice(&self.buffer[start..start + run]);
+            }
+            blocks = tail;
+            index += BlockCount(run as u64);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.write_blocks(blocks, index, false)
+    }
+
+    /// Return the number of blocks in whole logical blocks of both sizes.
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        let count = self.block_device.count()?.0;
+        let count = count - count % self.inner_blocks;
+        Ok(BlockCount(count - count % self.outer_blocks))
+    }
+
+    fn flush(&mut self) -> BlockResult<()> {
+        self.block_device.flush()
+    }
+
+    fn write_fua(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.write_blocks(blocks, index, true)
+    }
+
+    fn barrier(&mut self) -> BlockResult<()> {
+        self.block_device.barrier()
+    }
+
+    /// Discard the blocks of the inner device inside the range, keeping the ones it only covers
+    /// partly.
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        let start = index.0.div_ceil(self.inner_blocks) * self.inner_blocks;
+        let end = index.0.saturating_add(count.0);
+        let end = end - end % self.inner_blocks;
+        if start >= end {
+            return Ok(());
+        }
+        self.block_device
+            .discard(BlockIndex(start), BlockCount(end - start))
+    }
+
+    fn prefetch(&mut self, ranges: &[(BlockIndex, BlockCount)]) -> BlockResult<()> {
+        self.block_device.prefetch(ranges)
+    }
+
+    /// Report the logical block size exposed, and the biggest of both block sizes as the physical
+    /// one, unless the inner device reports a bigger one.
+    fn geometry(&mut self) -> BlockResult<BlockGeometry> {
+        let geometry = self.block_device.geometry()?;
+        Ok(BlockGeometry {
+            logical_block_size: self.block_size(),
+            physical_block_size: geometry
+                .physical_block_size
+                .max(self.inner_block_size())
+                .max(self.block_size()),
+            ..geometry
+        })
+    }
+
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        self.block_device.identity()
+    }
+
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        self.block_device.capabilities()
+    }
+}
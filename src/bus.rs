@@ -0,0 +1,258 @@
+use crate::{
+    Block, BlockCount, BlockDevice, BlockError, BlockGeometry, BlockIndex, BlockResult,
+    Capabilities, DeviceIdentity, IoRequest,
+};
+
+/// Another peripheral periodically holding the shared bus, such as a display being refreshed.
+///
+/// It holds the bus for ``duration`` nanoseconds every ``period`` nanoseconds, starting at
+/// ``phase``.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Peripheral {
+    /// The time between two transactions of the peripheral, in nanoseconds.
+    pub period: u64,
+
+    /// How long each transaction of the peripheral holds the bus, in nanoseconds.
+    pub duration: u64,
+
+    /// When the first transaction of the peripheral starts, in nanoseconds.
+    pub phase: u64,
+}
+
+impl Peripheral {
+    /// Return the end of the first transaction of the peripheral overlapping ``len`` nanoseconds
+    /// starting at ``start``, if any.
+    fn conflict(&self, start: u64, len: u64) -> Option<u64> {
+        if self.period == 0 || self.duration == 0 {
+            return None;
+        }
+
+        // The last transaction starting before ``start``, or the first one.
+        let mut window = if start < self.phase {
+            self.phase
+        } else {
+            self.phase + (start - self.phase) / self.period * self.period
+        };
+
+        // Only this transaction and the next one may overlap, anything after would start after
+        // the next one.
+        for _ in 0..2 {
+            let end = window.saturating_add(self.duration);
+            if end > start && window < start.saturating_add(len) {
+                return Some(end);
+            }
+            window = window.saturating_add(self.period);
+        }
+        None
+    }
+}
+
+/// How long the storage device holds the bus.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct BusTiming {
+    /// The fixed cost of a transaction (command, response, chip select), in nanoseconds.
+    pub per_transaction: u64,
+
+    /// The time to transfer a block, in nanoseconds.
+    pub per_block: u64,
+}
+
+/// Statistics collected by a [BusContentionDevice].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct BusStats {
+    /// The number of transactions performed.
+    pub transactions: u64,
+
+    /// The total time spent waiting for the bus, in nanoseconds.
+    pub wait_time: u64,
+
+    /// The total time spent holding the bus, in nanoseconds.
+    pub busy_time: u64,
+
+    /// The longest time a transaction took from request to completion, in nanoseconds.
+    pub max_latency: u64,
+}
+
+/// A block device simulating a bus shared with other peripherals, e.g. an SD card on the same
+/// SPI bus as a display.
+///
+/// Every request is a transaction that must wait for the bus to be free of the transactions of
+/// the [Peripheral]s, then holds it for a time given by the [BusTiming]. Time is simulated: it only
+/// advances with the transactions and [BusContentionDevice::advance], so firmware authors can
+/// measure the latency of their storage accesses deterministically, before the hardware exists.
+///
+/// Requests waiting for longer than the maximum wait fail with [BlockError::Busy].
+#[derive(Debug)]
+pub struct BusContentionDevice<B: BlockDevice, const N: usize> {
+    /// The inner block device.
+    block_device: B,
+
+    /// How long the storage device holds the bus.
+    timing: BusTiming,
+
+    /// The other peripherals sharing the bus.
+    peripherals: [Peripheral; N],
+
+    /// How long a transaction may wait for the bus, in nanoseconds.
+    max_wait: u64,
+
+    /// The simulated time, in nanoseconds.
+    now: u64,
+
+    /// The statistics collected so far.
+    stats: BusStats,
+}
+
+impl<B: BlockDevice, const N: usize> BusContentionDevice<B, N> {
+    /// Wrap ``block_device``, sharing the bus with ``peripherals``.
+    pub fn new(block_device: B, timing: BusTiming, peripherals: [Peripheral; N]) -> Self {
+        BusContentionDevice {
+            block_device,
+            timing,
+            peripherals,
+            max_wait: u64::MAX,
+            now: 0,
+            stats: BusStats::default(),
+        }
+    }
+
+    /// Make transactions waiting for the bus for longer than ``max_wait`` nanoseconds fail.
+    pub fn with_max_wait(mut self, max_wait: u64) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    /// Return the simulated time, in nanoseconds.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Advance the simulated time by ``nanos`` nanoseconds, e.g. to account for the processing
+    /// done between two requests.
+    pub fn advance(&mut self, nanos: u64) {
+        self.now = self.now.saturating_add(nanos);
+    }
+
+    /// Return the statistics collected so far.
+    pub fn stats(&self) -> BusStats {
+        self.stats
+    }
+
+    /// Reset the statistics.
+    pub fn reset_stats(&mut self) {
+        self.stats = BusStats::default();
+    }
+
+    /// Return a reference to the inner block device.
+    pub fn get_ref(&self) -> &B {
+        &self.block_device
+    }
+
+    /// Return a mutable reference to the inner block device.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.block_device
+    }
+
+    /// Consume the wrapper, returning the inner block device.
+    pub fn into_inner(self) -> B {
+        self.block_device
+    }
+
+    /// Wait for the bus to be free, and hold it for a transaction transferring ``blocks`` blocks.
+    fn transaction(&mut self, blocks: usize) -> BlockResult<()> {
+        let len = self
+            .timing
+            .per_transaction
+            .saturating_add(self.timing.per_block.saturating_mul(blocks as u64));
+
+        // Wait until no peripheral holds the bus during the whole transaction.
+        let mut start = self.now;
+        loop {
+            if start - self.now > self.max_wait {
+                self.now = start;
+                return Err(BlockError::Busy);
+            }
+
+            let conflict = self
+                .peripherals
+                .iter()
+                .filter_map(|peripheral| peripheral.conflict(start, len))
+                .max();
+            match conflict {
+                Some(end) => start = end,
+                None => break,
+            }
+        }
+
+        let wait = start - self.now;
+        self.now = start.saturating_add(len);
+        self.stats.transactions += 1;
+        self.stats.wait_time = self.stats.wait_time.saturating_add(wait);
+        self.stats.busy_time = self.stats.busy_time.saturating_add(len);
+        self.stats.max_latency = core::cmp::max(self.stats.max_latency, wait.saturating_add(len));
+        Ok(())
+    }
+}
+
+impl<B: BlockDevice, const N: usize> BlockDevice for BusContentionDevice<B, N> {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        self.transaction(blocks.len())?;
+        self.block_device.read(blocks, index)
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.transaction(blocks.len())?;
+        self.block_device.write(blocks, index)
+    }
+
+    /// The size of the device is assumed to be known, without a transaction.
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        self.block_device.count()
+    }
+
+    /// Perform the requests one transaction after the other.
+    fn submit(&mut self, requests: &mut [IoRequest<'_>]) -> BlockResult<()> {
+        for request in requests.iter_mut() {
+            match request {
+                IoRequest::Read { index, blocks } => self.read(blocks, *index)?,
+                IoRequest::Write { index, blocks } => self.write(blocks, *index)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> BlockResult<()> {
+        self.transaction(0)?;
+        self.block_device.flush()
+    }
+
+    fn write_fua(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.transaction(blocks.len())?;
+        self.block_device.write_fua(blocks, index)
+    }
+
+    fn barrier(&mut self) -> BlockResult<()> {
+        self.transaction(0)?;
+        self.block_device.barrier()
+    }
+
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        self.transaction(0)?;
+        self.block_device.discard(index, count)
+    }
+
+    /// The geometry of the device is assumed to be known, without a transaction.
+    fn geometry(&mut self) -> BlockResult<BlockGeometry> {
+        self.block_device.geometry()
+    }
+
+    /// The identity of the device is assumed to be known, without a transaction.
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        self.block_device.identity()
+    }
+
+    /// The capabilities of the device are assumed to be known, without a transaction.
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        self.block_device.capabilities()
+    }
+}
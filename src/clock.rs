@@ -0,0 +1,99 @@
+/// A monotonic source of time.
+///
+/// Implement it over a hardware timer on bare metal, or use [StdClock] when std is available.
+pub trait Clock: core::fmt::Debug {
+    /// Return the time elapsed since an arbitrary fixed point, in nanoseconds.
+    ///
+    /// The returned value must never decrease.
+    fn now(&self) -> u64;
+}
+
+/// A [Clock] based on [std::time::Instant], counting from its creation.
+#[cfg(feature = "std")]
+#[derive(Debug, Copy, Clone)]
+pub struct StdClock {
+    /// The point in time the clock counts from.
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl StdClock {
+    /// Create a new clock, starting at zero now.
+    pub fn new() -> Self {
+        StdClock {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> Self {
+        StdClock::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
+
+/// A way to wait for some time, used to back off before retrying an operation.
+///
+/// Implement it over a hardware timer or an idle loop on bare metal, or use [StdDelay] when std
+/// is available.
+pub trait Delay: core::fmt::Debug {
+    /// Wait for at least ``nanos`` nanoseconds.
+    fn delay(&mut self, nanos: u64);
+}
+
+/// A [Delay] returning immediately.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoDelay;
+
+impl Delay for NoDelay {
+    fn delay(&mut self, _nanos: u64) {}
+}
+
+/// A [Delay] putting the current thread to sleep.
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StdDelay;
+
+#[cfg(feature = "std")]
+impl Delay for StdDelay {
+    fn delay(&mut self, nanos: u64) {
+        std::thread::sleep(std::time::Duration::from_nanos(nanos))
+    }
+}
+
+/// A point in time after which an operation is abandoned.
+///
+/// Drivers polling hardware should check it in their busy loops, so a device that stopped
+/// responding makes the request fail instead of hanging the caller.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Deadline {
+    /// The time of the deadline, in nanoseconds on the clock it was created from.
+    at: u64,
+}
+
+impl Deadline {
+    /// Create a deadline ``timeout`` nanoseconds from now on ``clock``.
+    pub fn after<C: Clock + ?Sized>(clock: &C, timeout: u64) -> Self {
+        Deadline {
+            at: clock.now().saturating_add(timeout),
+        }
+    }
+
+    /// Check whether the deadline passed on ``clock``, which must be the one it was created from.
+    pub fn expired<C: Clock + ?Sized>(&self, clock: &C) -> bool {
+        clock.now() > self.at
+    }
+
+    /// Return the time left before the deadline on ``clock``, in nanoseconds.
+    pub fn remaining<C: Clock + ?Sized>(&self, clock: &C) -> u64 {
+        self.at.saturating_sub(clock.now())
+    }
+}
@@ -0,0 +1,247 @@
+use crate::{
+    Block, Capabilities, DeviceIdentity, DeviceInfo, StorageDevice, StorageDeviceError,
+    StorageDeviceResult,
+};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// The default number of bytes buffered before they are written back.
+pub const DEFAULT_COALESCE_THRESHOLD: usize = 64 * 1024;
+
+/// A storage device accumulating small writes, and writing them back as large block-aligned
+/// writes.
+///
+/// Metadata-heavy filesystems issue streams of tiny writes, often next to each other. The data
+/// written is kept in memory, merged with the buffered writes it is adjacent to or overlaps,
+/// until [WriteCoalescer::write_back] or [StorageDevice::flush] is called, or more than the
+/// threshold is buffered. Each merged range is then extended to whole blocks, reading the
+/// missing parts from the device, and written in a single request.
+///
+/// Reads see the buffered data. Errors of the buffered writes are only reported when they are
+/// written back.
+///
+/// Dropping a WriteCoalescer writes the buffered data back. If a device write fails, it is
+/// silently ignored.
+#[derive(Debug)]
+pub struct WriteCoalescer<S: StorageDevice> {
+    /// The inner storage device.
+    storage_device: S,
+
+    /// The buffered writes by offset, none of them overlapping or adjacent to another.
+    extents: BTreeMap<u64, Vec<u8>>,
+
+    /// The number of bytes buffered.
+    buffered: usize,
+
+    /// The number of bytes buffered above which they are written back.
+    threshold: usize,
+}
+
+impl<S: StorageDevice> WriteCoalescer<S> {
+    /// Wrap ``storage_device``, buffering up to [DEFAULT_COALESCE_THRESHOLD] bytes.
+    pub fn new(storage_device: S) -> Self {
+        WriteCoalescer {
+            storage_device,
+            extents: BTreeMap::new(),
+            buffered: 0,
+            threshold: DEFAULT_COALESCE_THRESHOLD,
+        }
+    }
+
+    /// Write the buffered data back once more than ``threshold`` bytes are buffered.
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Return the number of bytes buffered, that haven't been written to the device yet.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Write the buffered data to the device, without flushing it.
+    ///
+    /// On error, the data that couldn't be written stays buffered.
+    pub fn write_back(&mut self) -> StorageDeviceResult<()> {
+        if self.extents.is_empty() {
+            return Ok(());
+        }
+
+        let device_len = self.storage_device.len()?;
+        while let Some((&offset, data)) = self.extents.iter().next() {
+            let end = offset + data.len() as u64;
+
+            // Extend the range to whole blocks, without going past the end of the device.
+            let start = offset - offset % Block::LEN_U64;
+            let aligned_end = core::cmp::max(
+                end,
+                core::cmp::min(end.next_multiple_of(Block::LEN_U64), device_len),
+            );
+
+            if start == offset && aligned_end == end {
+                self.storage_device.write(offset, data)?;
+            } else {
+                let mut aligned = alloc::vec![0u8; (aligned_end - start) as usize];
+                let head = (offset - start) as usize;
+                let tail = head + data.len();
+                if head > 0 {
+                    self.storage_device.read(start, &mut aligned[..head])?;
+                }
+                if tail < aligned.len() {
+                    self.storage_device.read(end, &mut aligned[tail..])?;
+                }
+                aligned[head..tail].copy_from_slice(data);
+                self.storage_device.write(start, &aligned)?;
+            }
+
+            self.buffered -= data.len();
+            self.extents.remove(&offset);
+        }
+        Ok(())
+    }
+
+    /// Return a reference to the inner storage device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the inner storage device.
+    ///
+    /// The buffered data isn't written back, call [WriteCoalescer::write_back] first if needed.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Buffer ``buf`` at ``offset``, merging it with the buffered writes it touches.
+    fn buffer(&mut self, offset: u64, buf: &[u8]) {
+        let end = offset + buf.len() as u64;
+
+        // Find the extents overlapping or adjacent to the write, they are merged into one.
+        let touched: Vec<u64> = self
+            .extents
+            .range(..=end)
+            .rev()
+            .take_while(|(&start, data)| start + data.len() as u64 >= offset)
+            .map(|(&start, _)| start)
+            .collect();
+
+        let mut start = offset;
+        let mut merged_end = end;
+        for &extent in &touched {
+            start = core::cmp::min(start, extent);
+            merged_end = core::cmp::max(merged_end, extent + self.extents[&extent].len() as u64);
+        }
+
+        let mut merged = alloc::vec![0u8; (merged_end - start) as usize];
+        for extent in touched {
+            let data = self.extents.remove(&extent).unwrap();
+            let at = (extent - start) as usize;
+            merged[at..at + data.len()].copy_from_slice(&data);
+            self.buffered -= data.len();
+        }
+        let at = (offset - start) as usize;
+        merged[at..at + buf.len()].copy_from_slice(buf);
+
+        self.buffered += merged.len();
+        self.extents.insert(start, merged);
+    }
+}
+
+impl<S: StorageDevice> Drop for WriteCoalescer<S> {
+    fn drop(&mut self) {
+        let _ = self.write_back();
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for WriteCoalescer<S> {
+    /// Read from the device, then apply the buffered writes.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.storage_device.read(offset, buf)?;
+
+        let end = offset + buf.len() as u64;
+        for (&start, data) in self.extents.range(..end).rev() {
+            let extent_end = start + data.len() as u64;
+            if extent_end <= offset {
+                break;
+            }
+            let from = core::cmp::max(start, offset);
+            let to = core::cmp::min(extent_end, end);
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+        }
+        Ok(())
+    }
+
+    /// Buffer the write, writing everything back if the threshold is exceeded.
+    ///
+    /// Writes going past the end of the device aren't buffered, but performed after writing
+    /// everything back, so the device fails or grows as it would without the wrapper.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(StorageDeviceError::OutOfBounds)?;
+        if end > self.storage_device.len()? {
+            self.write_back()?;
+            return self.storage_device.write(offset, buf);
+        }
+
+        self.buffer(offset, buf);
+        if self.buffered > self.threshold {
+            self.write_back()?;
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.storage_device.len()
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.write_back()?;
+        self.storage_device.write_zeroes(offset, len)
+    }
+
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        self.write_back()?;
+        self.storage_device.fill(offset, len, byte)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.write_back()?;
+        self.storage_device.discard(offset, len)
+    }
+
+    /// Write the buffered data back, then flush the device.
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.write_back()?;
+        self.storage_device.flush()
+    }
+
+    /// Write the buffered writes back first, so they can't overwrite this one afterwards.
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.write_back()?;
+        self.storage_device.write_fua(offset, buf)
+    }
+
+    /// Write the buffered writes back before the barrier.
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.write_back()?;
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    /// Flushing writes the buffered writes back, whatever the device does with flushes.
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        Ok(self.storage_device.capabilities()? | Capabilities::FLUSH)
+    }
+}
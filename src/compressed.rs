@@ -0,0 +1,645 @@
+use crate::crc::crc32;
+use crate::{Block, StorageDevice, StorageDeviceError, StorageDeviceResult};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// The magic identifying the header of a compressed image.
+const COMPRESSED_MAGIC: u32 = u32::from_le_bytes(*b"CMPD");
+
+/// The version of the layout of compressed images.
+const COMPRESSED_VERSION: u32 = 1;
+
+/// The offset of the chunk index in the image, right after the header.
+const INDEX_OFFSET: u64 = Block::LEN_U64;
+
+/// The size of an entry of the chunk index, in bytes.
+const ENTRY_LEN: u64 = 8;
+
+/// The default size of the chunks compressed independently, in bytes.
+pub const DEFAULT_COMPRESSION_CHUNK: u32 = 64 * 1024;
+
+/// The parameters of a compressed image, stored in its first block.
+///
+/// It is stored in little endian as follow:
+///
+/// | Offset | Size | Field      |
+/// |--------|------|------------|
+/// | 0      | 4    | magic      |
+/// | 4      | 4    | version    |
+/// | 8      | 8    | len        |
+/// | 16     | 4    | chunk_size |
+/// | 20     | 4    | crc        |
+///
+/// The CRC-32 covers the first 20 bytes. The chunk index follows, with the 64-bit offset in the
+/// image of each chunk, then the offset of the end of the last one. The chunks come after the
+/// index, each compressed independently in the LZ4 block format, or stored as is when
+/// compression doesn't make it smaller.
+#[derive(Debug, Copy, Clone)]
+struct CompressedHeader {
+    /// The size of the uncompressed data, in bytes.
+    len: u64,
+
+    /// The size of the chunks compressed independently, in bytes.
+    chunk_size: u32,
+}
+
+impl CompressedHeader {
+    /// Serialize the header into a block.
+    fn to_block(self) -> Block {
+        let mut block = Block::new();
+        block[0..4].copy_from_slice(&COMPRESSED_MAGIC.to_le_bytes());
+        block[4..8].copy_from_slice(&COMPRESSED_VERSION.to_le_bytes());
+        block[8..16].copy_from_slice(&self.len.to_le_bytes());
+        block[16..20].copy_from_slice(&self.chunk_size.to_le_bytes());
+        let crc = crc32(&block[0..20]);
+        block[20..24].copy_from_slice(&crc.to_le_bytes());
+        block
+    }
+
+    /// Deserialize the header from a block, returning None if it isn't valid.
+    fn from_block(block: &Block) -> Option<Self> {
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        let mut len = [0u8; 8];
+        let mut chunk_size = [0u8; 4];
+        let mut crc = [0u8; 4];
+
+        magic.copy_from_slice(&block[0..4]);
+        version.copy_from_slice(&block[4..8]);
+        len.copy_from_slice(&block[8..16]);
+        chunk_size.copy_from_slice(&block[16..20]);
+        crc.copy_from_slice(&block[20..24]);
+
+        if u32::from_le_bytes(magic) != COMPRESSED_MAGIC
+            || u32::from_le_bytes(version) != COMPRESSED_VERSION
+            || u32::from_le_bytes(crc) != crc32(&block[0..20])
+            || u32::from_le_bytes(chunk_size) == 0
+        {
+            return None;
+        }
+
+        Some(CompressedHeader {
+            len: u64::from_le_bytes(len),
+            chunk_size: u32::from_le_bytes(chunk_size),
+        })
+    }
+
+    /// Return the number of chunks.
+    fn chunks(&self) -> u64 {
+        self.len.div_ceil(u64::from(self.chunk_size))
+    }
+
+    /// Return the size of the uncompressed ``chunk``, the last one being shorter.
+    fn chunk_len(&self, chunk: u64) -> usize {
+        let chunk_size = u64::from(self.chunk_size);
+        core::cmp::min(chunk_size, self.len - chunk * chunk_size) as usize
+    }
+
+    /// Return the offset of the first chunk, after the index.
+    fn data_offset(&self) -> u64 {
+        let index_end = INDEX_OFFSET + (self.chunks() + 1) * ENTRY_LEN;
+        index_end.next_multiple_of(Block::LEN_U64)
+    }
+}
+
+/// Builds compressed images out of existing storage devices, for [CompressedDevice].
+#[derive(Debug, Copy, Clone)]
+pub struct CompressedDeviceBuilder {
+    /// The size of the chunks compressed independently, in bytes.
+    chunk_size: u32,
+}
+
+impl Default for CompressedDeviceBuilder {
+    fn default() -> Self {
+        CompressedDeviceBuilder {
+            chunk_size: DEFAULT_COMPRESSION_CHUNK,
+        }
+    }
+}
+
+impl CompressedDeviceBuilder {
+    /// Create a builder compressing chunks of [DEFAULT_COMPRESSION_CHUNK] bytes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compress chunks of ``chunk_size`` bytes independently.
+    ///
+    /// Smaller chunks make random reads cheaper, bigger ones compress better. It must be a
+    /// non-zero multiple of [Block::LEN].
+    pub fn with_chunk_size(mut self, chunk_size: u32) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Compress the whole content of ``src`` into an image written at the start of ``dst``, and
+    /// return a device reading it back.
+    ///
+    /// Fail with [StorageDeviceError::OutOfSpace] if the image doesn't fit in ``dst``. Devices
+    /// growing on writes past their end, such as a ``Vec<u8>``, end up with the size of the
+    /// image.
+    pub fn build<S, D>(&self, src: &mut S, mut dst: D) -> StorageDeviceResult<CompressedDevice<D>>
+    where
+        S: StorageDevice + ?Sized,
+        D: StorageDevice,
+    {
+        if self.chunk_size == 0 || !(self.chunk_size as usize).is_multiple_of(Block::LEN) {
+            return Err(StorageDeviceError::Unsupported);
+        }
+
+        let header = CompressedHeader {
+            len: src.len()?,
+            chunk_size: self.chunk_size,
+        };
+        let entries =
+            usize::try_from(header.chunks() + 1).map_err(|_| StorageDeviceError::OutOfSpace)?;
+        let mut index = Vec::with_capacity(entries);
+
+        let mut chunk = alloc::vec![0u8; self.chunk_size as usize];
+        let mut compressed = Vec::new();
+        let mut offset = header.data_offset();
+        for number in 0..header.chunks() {
+            let chunk = &mut chunk[..header.chunk_len(number)];
+            src.read(number * u64::from(self.chunk_size), chunk)?;
+
+            compressed.clear();
+            lz4_compress(chunk, &mut compressed);
+            let stored = if compressed.len() < chunk.len() {
+                &compressed[..]
+            } else {
+                &chunk[..]
+            };
+
+            index.push(offset);
+            dst.write(offset, stored).map_err(out_of_space)?;
+            offset += stored.len() as u64;
+        }
+        index.push(offset);
+
+        // The header goes last, so an interrupted build doesn't leave a valid image behind.
+        let raw_index: Vec<u8> = index.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+        dst.write(INDEX_OFFSET, &raw_index).map_err(out_of_space)?;
+        dst.write(0, &header.to_block()[..])?;
+        dst.flush()?;
+
+        CompressedDevice::open(dst)
+    }
+}
+
+/// A read-only storage device over an image compressed by chunks, built with a
+/// [CompressedDeviceBuilder].
+///
+/// Each chunk is compressed independently, so a read only decompresses the chunks it covers.
+/// The last chunk decompressed is kept, so sequential reads decompress each chunk once.
+///
+/// Writes fail with [StorageDeviceError::Unsupported]. Put a [CowDevice] on top to modify the
+/// data without touching the image.
+///
+/// [CowDevice]: crate::CowDevice
+#[derive(Debug)]
+pub struct CompressedDevice<S: StorageDevice> {
+    /// The device holding the compressed image.
+    storage_device: S,
+
+    /// The parameters of the image.
+    header: CompressedHeader,
+
+    /// The offset of each chunk in the image, then the offset of the end of the last one.
+    index: Vec<u64>,
+
+    /// The compressed data of the chunk being decompressed.
+    compressed: Vec<u8>,
+
+    /// The last chunk decompressed.
+    chunk: Vec<u8>,
+
+    /// The number of the last chunk decompressed, if any.
+    cached_chunk: Option<u64>,
+}
+
+impl<S: StorageDevice> CompressedDevice<S> {
+    /// Open the compressed image at the start of ``storage_device``.
+    ///
+    /// Return [StorageDeviceError::Corrupted] if the header or the index aren't valid.
+    pub fn open(mut storage_device: S) -> StorageDeviceResult<Self> {
+        let mut block = Block::new();
+        storage_device.read(0, &mut block[..])?;
+        let header = CompressedHeader::from_block(&block).ok_or(StorageDeviceError::Corrupted)?;
+
+        let entries =
+            usize::try_from(header.chunks() + 1).map_err(|_| StorageDeviceError::OutOfSpace)?;
+        let mut raw_index = alloc::vec![0u8; entries * ENTRY_LEN as usize];
+        storage_device.read(INDEX_OFFSET, &mut raw_index)?;
+        let index: Vec<u64> = raw_index
+            .chunks_exact(ENTRY_LEN as usize)
+            .map(|entry| {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(entry);
+                u64::from_le_bytes(bytes)
+            })
+            .collect();
+
+        // The chunks follow each other inside the image, and none is bigger than uncompressed.
+        let image_len = storage_device.len()?;
+        let compressed_len = index[entries - 1].wrapping_sub(index[0]);
+        if index[0] < header.data_offset() || (compressed_len > 0 && index[entries - 1] > image_len)
+        {
+            return Err(StorageDeviceError::Corrupted);
+        }
+        for (number, bounds) in index.windows(2).enumerate() {
+            if bounds[1] < bounds[0]
+                || bounds[1] - bounds[0] > header.chunk_len(number as u64) as u64
+            {
+                return Err(StorageDeviceError::Corrupted);
+            }
+        }
+
+        Ok(CompressedDevice {
+            storage_device,
+            header,
+            index,
+            compressed: Vec::new(),
+            chunk: alloc::vec![0u8; header.chunk_size as usize],
+            cached_chunk: None,
+        })
+    }
+
+    /// Return the size of the chunks compressed independently, in bytes.
+    pub fn chunk_size(&self) -> u32 {
+        self.header.chunk_size
+    }
+
+    /// Return the size of the compressed chunks, without the header and index.
+    pub fn compressed_len(&self) -> u64 {
+        self.index[self.index.len() - 1] - self.index[0]
+    }
+
+    /// Return a reference to the device holding the image.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Consume the device, returning the device holding the image.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Decompress ``number`` into the cached chunk, unless it is already there.
+    fn load_chunk(&mut self, number: u64) -> StorageDeviceResult<()> {
+        if self.cached_chunk == Some(number) {
+            return Ok(());
+        }
+
+        let start = self.index[number as usize];
+        let stored_len = (self.index[number as usize + 1] - start) as usize;
+        let chunk_len = self.header.chunk_len(number);
+        self.cached_chunk = None;
+
+        if stored_len == chunk_len {
+            // Compression didn't help, the chunk is stored as is.
+            self.storage_device
+                .read(start, &mut self.chunk[..chunk_len])?;
+        } else {
+            self.compressed.resize(stored_len, 0);
+            self.storage_device.read(start, &mut self.compressed)?;
+            lz4_decompress(&self.compressed, &mut self.chunk[..chunk_len])
+                .ok_or(StorageDeviceError::Corrupted)?;
+        }
+
+        self.cached_chunk = Some(number);
+        Ok(())
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for CompressedDevice<S> {
+    fn read(&mut self, mut offset: u64, mut buf: &mut [u8]) -> StorageDeviceResult<()> {
+        match offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= self.header.len => (),
+            _ => return Err(StorageDeviceError::OutOfBounds),
+        }
+
+        let chunk_size = u64::from(self.header.chunk_size);
+        while !buf.is_empty() {
+            let in_chunk = (offset % chunk_size) as usize;
+            let run = core::cmp::min(chunk_size as usize - in_chunk, buf.len());
+            let (head, tail) = buf.split_at_mut(run);
+            self.load_chunk(offset / chunk_size)?;
+            head.copy_from_slice(&self.chunk[in_chunk..in_chunk + run]);
+            buf = tail;
+            offset += run as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, _offset: u64, _buf: &[u8]) -> StorageDeviceResult<()> {
+        Err(StorageDeviceError::Unsupported)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.header.len)
+    }
+}
+
+/// Report writes past the end of the destination as a lack of space.
+fn out_of_space(err: StorageDeviceError) -> StorageDeviceError {
+    match err {
+        StorageDeviceError::OutOfBounds => StorageDeviceError::OutOfSpace,
+        err => err,
+    }
+}
+
+/// The shortest match encoded by LZ4.
+const MIN_MATCH: usize = 4;
+
+/// The number of bits of the hashes indexing the match finder table.
+const HASH_LOG: u32 = 12;
+
+/// The number of bytes at the end of a block that are always literals.
+const LAST_LITERALS: usize = 5;
+
+/// The number of bytes at the end of a block in which no match starts.
+const MATCH_FIND_LIMIT: usize = 12;
+
+/// The farthest a match may be, limited by its 16-bit offset.
+const MAX_DISTANCE: usize = 0xFFFF;
+
+/// Read the 4 bytes at ``pos`` in ``data`` as an integer.
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+/// Hash 4 bytes into an index of the match finder table.
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Append the extra bytes of a length that didn't fit in the 4 bits of the token.
+fn push_len(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+/// Append a sequence made of ``literals`` then, except for the last sequence of the block, the
+/// ``matched`` distance and length of a match.
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let literal_token = core::cmp::min(literals.len(), 15) as u8;
+    let match_token = matched.map_or(0, |(_, len)| core::cmp::min(len - MIN_MATCH, 15) as u8);
+    out.push(literal_token << 4 | match_token);
+    if literals.len() >= 15 {
+        push_len(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+
+    if let Some((distance, len)) = matched {
+        out.extend_from_slice(&(distance as u16).to_le_bytes());
+        if len - MIN_MATCH >= 15 {
+            push_len(out, len - MIN_MATCH - 15);
+        }
+    }
+}
+
+/// Compress ``input`` in the LZ4 block format, appending it to ``out``.
+///
+/// This is a simple greedy compressor, favoring speed over ratio.
+fn lz4_compress(input: &[u8], out: &mut Vec<u8>) {
+    let mut table = [0u32; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    if input.len() > MATCH_FIND_LIMIT {
+        let limit = input.len() - MATCH_FIND_LIMIT;
+        let match_end_limit = input.len() - LAST_LITERALS;
+        while pos < limit {
+            let sequence = read_u32(input, pos);
+            let slot = &mut table[hash(sequence)];
+            let candidate = *slot as usize;
+            *slot = pos as u32;
+
+            if candidate >= pos
+                || pos - candidate > MAX_DISTANCE
+                || read_u32(input, candidate) != sequence
+            {
+                pos += 1;
+                continue;
+            }
+
+            let mut len = MIN_MATCH;
+            while pos + len < match_end_limit && input[candidate + len] == input[pos + len] {
+                len += 1;
+            }
+            push_sequence(out, &input[anchor..pos], Some((pos - candidate, len)));
+            pos += len;
+            anchor = pos;
+        }
+    }
+
+    push_sequence(out, &input[anchor..], None);
+}
+
+/// Read the extra bytes of a length at ``pos`` in ``input``, moving past them.
+fn read_len(input: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut len = 0usize;
+    loop {
+        let byte = *input.get(*pos)?;
+        *pos += 1;
+        len = len.checked_add(usize::from(byte))?;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+/// Decompress the LZ4 block ``input`` into ``out``, which it must fill exactly.
+///
+/// Return None if the block is malformed.
+fn lz4_decompress(input: &[u8], out: &mut [u8]) -> Option<()> {
+    let mut pos = 0;
+    let mut written = 0usize;
+    loop {
+        let token = *input.get(pos)?;
+        pos += 1;
+
+        let mut literals = usize::from(token >> 4);
+        if literals == 15 {
+            literals += read_len(input, &mut pos)?;
+        }
+        let literals_end = pos.checked_add(literals)?;
+        let written_end = written.checked_add(literals)?;
+        out.get_mut(written..written_end)?
+            .copy_from_slice(input.get(pos..literals_end)?);
+        pos = literals_end;
+        written = written_end;
+
+        if pos == input.len() {
+            // The last sequence has no match.
+            return if written == out.len() { Some(()) } else { None };
+        }
+
+        let distance = usize::from(u16::from_le_bytes([*input.get(pos)?, *input.get(pos + 1)?]));
+        pos += 2;
+        if distance == 0 || distance > written {
+            return None;
+        }
+
+        let mut len = usize::from(token & 15);
+        if len == 15 {
+            len += read_len(input, &mut pos)?;
+        }
+        len += MIN_MATCH;
+        if len > out.len() - written {
+            return None;
+        }
+
+        // The match may overlap the bytes it produces, so copy byte by byte.
+        for i in written..written + len {
+            out[i] = out[i - distance];
+        }
+        written += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return ``len`` bytes that don't compress.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// Compress ``input``, check it decompresses back to it, and return the compressed block.
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        lz4_compress(input, &mut compressed);
+        let mut output = alloc::vec![0xEE; input.len()];
+        assert_eq!(lz4_decompress(&compressed, &mut output), Some(()));
+        assert_eq!(output, input);
+        compressed
+    }
+
+    #[test]
+    fn round_trips_empty_input() {
+        assert_eq!(round_trip(&[]), [0]);
+    }
+
+    #[test]
+    fn round_trips_short_inputs_around_the_match_limits() {
+        let input: Vec<u8> = b"abcd".iter().copied().cycle().take(40).collect();
+        for len in 0..input.len() {
+            round_trip(&input[..len]);
+        }
+    }
+
+    #[test]
+    fn round_trips_incompressible_input() {
+        let input = noise(70_000);
+        let compressed = round_trip(&input);
+        assert!(compressed.len() > input.len());
+    }
+
+    #[test]
+    fn round_trips_highly_repetitive_input() {
+        let zeros = alloc::vec![0u8; 200_000];
+        assert!(round_trip(&zeros).len() < 1_000);
+
+        let pattern: Vec<u8> = b"storage".iter().copied().cycle().take(100_000).collect();
+        assert!(round_trip(&pattern).len() < 1_000);
+    }
+
+    #[test]
+    fn round_trips_long_literal_runs_between_matches() {
+        let mut input = noise(300);
+        input.extend_from_slice(&[7; 300]);
+        input.extend_from_slice(&noise(600)[300..]);
+        input.extend_from_slice(&input.clone()[..400]);
+        round_trip(&input);
+    }
+
+    #[test]
+    fn decompresses_overlapping_matches() {
+        // One literal, then a match one byte back covering 14 bytes, then the last 5 literals.
+        let compressed = [0x1A, b'a', 1, 0, 0x50, b'a', b'a', b'a', b'a', b'a'];
+        let mut output = [0; 20];
+        assert_eq!(lz4_decompress(&compressed, &mut output), Some(()));
+        assert_eq!(output, [b'a'; 20]);
+    }
+
+    #[test]
+    fn rejects_malformed_blocks() {
+        let mut output = [0; 20];
+        let malformed: [&[u8]; 8] = [
+            // Nothing at all.
+            &[],
+            // Literals past the end of the input.
+            &[0x50, b'a', b'a'],
+            // A match at distance zero.
+            &[0x1A, b'a', 0, 0, 0x50, b'a', b'a', b'a', b'a', b'a'],
+            // A match before the start of the output.
+            &[0x1A, b'a', 2, 0, 0x50, b'a', b'a', b'a', b'a', b'a'],
+            // A match past the end of the output.
+            &[0x1F, b'a', 1, 0, 10, 0x50, b'a', b'a', b'a', b'a', b'a'],
+            // A truncated match distance.
+            &[0x1A, b'a', 1],
+            // A literal length whose extra bytes never end.
+            &[0xF0, 255, 255, 255],
+            // Fewer bytes than the output.
+            &[0x50, b'a', b'a', b'a', b'a', b'a'],
+        ];
+        for input in malformed.iter() {
+            assert_eq!(lz4_decompress(input, &mut output), None, "{:x?}", input);
+        }
+
+        let input: Vec<u8> = b"abcd".iter().copied().cycle().take(1000).collect();
+        let compressed = round_trip(&input);
+        let mut output = alloc::vec![0; input.len()];
+        for len in 0..compressed.len() {
+            assert_eq!(lz4_decompress(&compressed[..len], &mut output), None);
+        }
+        let mut longer = alloc::vec![0; input.len() + 1];
+        assert_eq!(lz4_decompress(&compressed, &mut longer), None);
+    }
+
+    #[test]
+    fn reads_across_chunk_boundaries() {
+        let chunk_size = 4 * Block::LEN;
+        let mut src = noise(chunk_size);
+        src.extend_from_slice(&[3; 2 * 4 * Block::LEN]);
+        src.extend_from_slice(&noise(chunk_size + 100));
+
+        let mut device = CompressedDeviceBuilder::new()
+            .with_chunk_size(chunk_size as u32)
+            .build(&mut src.clone(), Vec::new())
+            .unwrap();
+        assert_eq!(device.len(), Ok(src.len() as u64));
+        assert!(device.compressed_len() < src.len() as u64);
+
+        for &(offset, len) in [
+            (0, src.len()),
+            (chunk_size - 1, 2),
+            (chunk_size - 300, chunk_size + 600),
+            (src.len() - 150, 150),
+            (3 * chunk_size, 0),
+        ]
+        .iter()
+        {
+            let mut buf = alloc::vec![0; len];
+            device.read(offset as u64, &mut buf).unwrap();
+            assert_eq!(buf, &src[offset..offset + len]);
+        }
+        assert_eq!(
+            device.read(src.len() as u64 - 1, &mut [0; 2]),
+            Err(StorageDeviceError::OutOfBounds)
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use crate::{Block, StorageDevice, StorageDeviceError, StorageDeviceResult};
+
+/// The number of bytes copied at once by [clone_device], a multiple of the block size.
+const CLONE_CHUNK: usize = 8 * Block::LEN;
+
+/// Copy ``len`` bytes at ``src_offset`` in ``src`` to ``dst_offset`` in ``dst``.
+///
+/// The data goes through a bounce buffer of a block, so this works without allocating.
+pub fn copy_range<S, D>(
+    src: &mut S,
+    src_offset: u64,
+    dst: &mut D,
+    dst_offset: u64,
+    len: u64,
+) -> StorageDeviceResult<()>
+where
+    S: StorageDevice + ?Sized,
+    D: StorageDevice + ?Sized,
+{
+    let mut bounce = Block::new();
+    let mut done = 0;
+    while done < len {
+        let chunk = &mut bounce[..core::cmp::min(Block::LEN_U64, len - done) as usize];
+        src.read(src_offset + done, chunk)?;
+        dst.write(dst_offset + done, chunk)?;
+        done += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+/// Copy ``len`` bytes at ``src_offset`` to ``dst_offset`` in ``device``.
+///
+/// The ranges may overlap: like [slice::copy_within], the destination ends up with the content
+/// the source had before the copy.
+pub fn copy_within<S: StorageDevice + ?Sized>(
+    device: &mut S,
+    src_offset: u64,
+    dst_offset: u64,
+    len: u64,
+) -> StorageDeviceResult<()> {
+    if dst_offset <= src_offset || dst_offset >= src_offset.saturating_add(len) {
+        // Copying forward never overwrites source data that wasn't copied yet.
+        let mut bounce = Block::new();
+        let mut done = 0;
+        while done < len {
+            let chunk = &mut bounce[..core::cmp::min(Block::LEN_U64, len - done) as usize];
+            device.read(src_offset + done, chunk)?;
+            device.write(dst_offset + done, chunk)?;
+            done += chunk.len() as u64;
+        }
+    } else {
+        // The destination overlaps the end of the source, copy backward.
+        let mut bounce = Block::new();
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk_len = core::cmp::min(Block::LEN_U64, remaining);
+            remaining -= chunk_len;
+            let chunk = &mut bounce[..chunk_len as usize];
+            device.read(src_offset + remaining, chunk)?;
+            device.write(dst_offset + remaining, chunk)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the whole content of ``src`` to the start of ``dst``.
+///
+/// The data is copied in block-aligned chunks. After each of them, ``progress`` is called with
+/// the number of bytes copied so far and the total number of bytes to copy. The holes of sparse
+/// devices aren't read, they are zeroed with [StorageDevice::write_zeroes] instead, so
+/// mostly-empty images are cloned quickly and stay sparse.
+///
+/// Fail with [StorageDeviceError::OutOfSpace] if ``dst`` is smaller than ``src``.
+pub fn clone_device<S, D, F>(src: &mut S, dst: &mut D, mut progress: F) -> StorageDeviceResult<()>
+where
+    S: StorageDevice + ?Sized,
+    D: StorageDevice + ?Sized,
+    F: FnMut(u64, u64),
+{
+    let total = src.len()?;
+    if dst.len()? < total {
+        return Err(StorageDeviceError::OutOfSpace);
+    }
+
+    let mut buf = [0u8; CLONE_CHUNK];
+    let mut done = 0;
+    while done < total {
+        let data_end = match src.next_data(done)? {
+            Some(data) if data.start > done => {
+                let hole_end = core::cmp::min(data.start, total);
+                dst.write_zeroes(done, hole_end - done)?;
+                done = hole_end;
+                progress(done, total);
+                continue;
+            }
+            Some(data) => core::cmp::min(data.end, total),
+            None => {
+                dst.write_zeroes(done, total - done)?;
+                done = total;
+                progress(done, total);
+                break;
+            }
+        };
+
+        while done < data_end {
+            let chunk = &mut buf[..core::cmp::min(CLONE_CHUNK as u64, data_end - done) as usize];
+            src.read(done, chunk)?;
+            dst.write(done, chunk)?;
+            done += chunk.len() as u64;
+            progress(done, total);
+        }
+    }
+    dst.flush()
+}
+
+/// Return the offset of the first byte differing between ``a`` and ``b``, or None if they have
+/// the same content.
+///
+/// If one device is a prefix of the other, the end of the shorter one is returned.
+pub fn compare_devices<A, B>(a: &mut A, b: &mut B) -> StorageDeviceResult<Option<u64>>
+where
+    A: StorageDevice + ?Sized,
+    B: StorageDevice + ?Sized,
+{
+    let a_len = a.len()?;
+    let b_len = b.len()?;
+    let len = core::cmp::min(a_len, b_len);
+
+    let mut a_block = Block::new();
+    let mut b_block = Block::new();
+    let mut offset = 0;
+    while offset < len {
+        let chunk = core::cmp::min(Block::LEN_U64, len - offset) as usize;
+        a.read(offset, &mut a_block[..chunk])?;
+        b.read(offset, &mut b_block[..chunk])?;
+        if let Some(position) = (0..chunk).find(|&i| a_block[i] != b_block[i]) {
+            return Ok(Some(offset + position as u64));
+        }
+        offset += chunk as u64;
+    }
+
+    if a_len != b_len {
+        return Ok(Some(len));
+    }
+    Ok(None)
+}
+
+/// Iterate over the ranges differing between ``a`` and ``b``.
+///
+/// See [DiffRanges].
+pub fn diff_ranges<'a, A, B>(a: &'a mut A, b: &'a mut B) -> DiffRanges<'a, A, B>
+where
+    A: StorageDevice + ?Sized,
+    B: StorageDevice + ?Sized,
+{
+    DiffRanges {
+        a,
+        b,
+        offset: 0,
+        lens: None,
+    }
+}
+
+/// An iterator over the ranges differing between two devices, returned by [diff_ranges].
+///
+/// The devices are compared block by block, so the ranges are made of whole blocks, except at
+/// the end of the devices. If the devices have different sizes, the part only present in the
+/// bigger one is the last range. The iteration stops after the first error.
+#[derive(Debug)]
+pub struct DiffRanges<'a, A: StorageDevice + ?Sized, B: StorageDevice + ?Sized> {
+    /// The first device.
+    a: &'a mut A,
+
+    /// The second device.
+    b: &'a mut B,
+
+    /// The offset to compare from.
+    offset: u64,
+
+    /// The sizes of the devices, once queried.
+    lens: Option<(u64, u64)>,
+}
+
+impl<A: StorageDevice + ?Sized, B: StorageDevice + ?Sized> DiffRanges<'_, A, B> {
+    /// Check whether the block at ``offset``, of ``len`` bytes, differs between the devices.
+    fn differs(&mut self, offset: u64, len: usize) -> StorageDeviceResult<bool> {
+        let mut a_block = Block::new();
+        let mut b_block = Block::new();
+        self.a.read(offset, &mut a_block[..len])?;
+        self.b.read(offset, &mut b_block[..len])?;
+        Ok(a_block[..len] != b_block[..len])
+    }
+
+    /// Return the next differing range, if any.
+    fn next_range(&mut self) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        let (a_len, b_len) = match self.lens {
+            Some(lens) => lens,
+            None => {
+                let lens = (self.a.len()?, self.b.len()?);
+                self.lens = Some(lens);
+                lens
+            }
+        };
+        let len = core::cmp::min(a_len, b_len);
+        let max_len = core::cmp::max(a_len, b_len);
+
+        // Skip the identical blocks.
+        while self.offset < len {
+            let chunk = core::cmp::min(Block::LEN_U64, len - self.offset);
+            if self.differs(self.offset, chunk as usize)? {
+                break;
+            }
+            self.offset += chunk;
+        }
+
+        if self.offset >= len {
+            // Only the part past the end of the smaller device remains.
+            if self.offset >= max_len {
+                return Ok(None);
+            }
+            let start = self.offset;
+            self.offset = max_len;
+            return Ok(Some(start..max_len));
+        }
+
+        // Extend the range over the following differing blocks.
+        let start = self.offset;
+        while self.offset < len {
+            let chunk = core::cmp::min(Block::LEN_U64, len - self.offset);
+            if !self.differs(self.offset, chunk as usize)? {
+                break;
+            }
+            self.offset += chunk;
+        }
+        Ok(Some(start..self.offset))
+    }
+}
+
+impl<A: StorageDevice + ?Sized, B: StorageDevice + ?Sized> Iterator for DiffRanges<'_, A, B> {
+    type Item = StorageDeviceResult<core::ops::Range<u64>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_range() {
+            Ok(range) => range.map(Ok),
+            Err(err) => {
+                // Don't keep failing on the same range.
+                self.offset = u64::MAX;
+                Some(Err(err))
+            }
+        }
+    }
+}
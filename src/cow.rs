@@ -0,0 +1,703 @@
+use crate::copy::copy_range;
+use crate::crc::crc32;
+use crate::{
+    Block, Capabilities, DeviceIdentity, DeviceInfo, StorageDevice, StorageDeviceError,
+    StorageDeviceResult,
+};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// The magic identifying the header of a [CowDevice] overlay.
+const COW_MAGIC: u32 = u32::from_le_bytes(*b"COWD");
+
+/// The version of the layout of the overlay.
+const COW_VERSION: u32 = 1;
+
+/// The value of the commit intent of the header while a [CowDevice::commit] is in progress.
+const COMMIT_INTENT: u32 = u32::from_le_bytes(*b"CMIT");
+
+/// The offset of the bitmap in the overlay, right after the header.
+const BITMAP_OFFSET: u64 = Block::LEN_U64;
+
+/// The parameters of the overlay, stored in its first block.
+///
+/// It is stored in little endian as follow:
+///
+/// | Offset | Size | Field         |
+/// |--------|------|---------------|
+/// | 0      | 4    | magic         |
+/// | 4      | 4    | version       |
+/// | 8      | 8    | base_len      |
+/// | 16     | 4    | cluster_size  |
+/// | 20     | 4    | crc           |
+/// | 24     | 4    | commit_intent |
+///
+/// The CRC-32 covers the first 20 bytes. The commit intent is ``CMIT`` while the clusters are being
+/// merged into the base device, and zero otherwise. The bitmap of the clusters stored in the
+/// overlay follows, one bit per cluster, then the clusters themselves, at the same position they
+/// have in the base device.
+#[derive(Debug, Copy, Clone)]
+struct CowHeader {
+    /// The size of the base device in bytes.
+    base_len: u64,
+
+    /// The granularity at which data is copied to the overlay, in bytes.
+    cluster_size: u32,
+
+    /// Whether a commit was started and not completed.
+    commit_pending: bool,
+}
+
+impl CowHeader {
+    /// Serialize the header into a block.
+    fn to_block(self) -> Block {
+        let mut block = Block::new();
+        block[0..4].copy_from_slice(&COW_MAGIC.to_le_bytes());
+        block[4..8].copy_from_slice(&COW_VERSION.to_le_bytes());
+        block[8..16].copy_from_slice(&self.base_len.to_le_bytes());
+        block[16..20].copy_from_slice(&self.cluster_size.to_le_bytes());
+        let crc = crc32(&block[0..20]);
+        block[20..24].copy_from_slice(&crc.to_le_bytes());
+        if self.commit_pending {
+            block[24..28].copy_from_slice(&COMMIT_INTENT.to_le_bytes());
+        }
+        block
+    }
+
+    /// Deserialize the header from a block, returning None if it isn't valid.
+    fn from_block(block: &Block) -> Option<Self> {
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        let mut base_len = [0u8; 8];
+        let mut cluster_size = [0u8; 4];
+        let mut crc = [0u8; 4];
+        let mut commit_intent = [0u8; 4];
+
+        magic.copy_from_slice(&block[0..4]);
+        version.copy_from_slice(&block[4..8]);
+        base_len.copy_from_slice(&block[8..16]);
+        cluster_size.copy_from_slice(&block[16..20]);
+        crc.copy_from_slice(&block[20..24]);
+        commit_intent.copy_from_slice(&block[24..28]);
+
+        if u32::from_le_bytes(magic) != COW_MAGIC
+            || u32::from_le_bytes(version) != COW_VERSION
+            || u32::from_le_bytes(crc) != crc32(&block[0..20])
+            || u32::from_le_bytes(cluster_size) == 0
+        {
+            return None;
+        }
+
+        Some(CowHeader {
+            base_len: u64::from_le_bytes(base_len),
+            cluster_size: u32::from_le_bytes(cluster_size),
+            commit_pending: u32::from_le_bytes(commit_intent) == COMMIT_INTENT,
+        })
+    }
+
+    /// Return the number of clusters covering the base device.
+    fn clusters(&self) -> u64 {
+        self.base_len.div_ceil(u64::from(self.cluster_size))
+    }
+
+    /// Return the offset of the first cluster in the overlay, after the bitmap.
+    fn data_offset(&self) -> u64 {
+        let bitmap_end = BITMAP_OFFSET + self.clusters().div_ceil(8);
+        bitmap_end.next_multiple_of(u64::from(self.cluster_size))
+    }
+}
+
+/// A storage device layering a copy-on-write overlay over a read-only base device.
+///
+/// Writes go to the overlay, and reads of the data never written fall back to the base, so a
+/// golden image can be experimented on without being modified, and several overlays can share
+/// the same base like VM snapshots. The overlay records which clusters it holds in a bitmap,
+/// stored after its header, so it can be reopened later over the same base.
+///
+/// The base is only ever written by [CowDevice::commit], which merges the modifications into it,
+/// while [CowDevice::discard_delta] throws them away.
+///
+/// A cluster written for the first time is copied from the base beforehand, unless the write
+/// covers it entirely. Its bit is written to the overlay after the data, with a
+/// [barrier](StorageDevice::barrier) in between, so a crash never leaves the bit set over a
+/// cluster whose data didn't reach the overlay, and flushing the overlay makes the write durable.
+///
+/// The overlay should be sparse, as it is as big as the base device plus the bitmap, even if
+/// only a few clusters are written.
+///
+/// The base may itself be a CowDevice, stacking overlays like a chain of snapshots, each layer
+/// only falling back to the one below for the clusters missing from its bitmap. See
+/// [OverlayChain] to inspect and flatten such chains.
+#[derive(Debug)]
+pub struct CowDevice<B: StorageDevice, O: StorageDevice> {
+    /// The read-only base device.
+    base: B,
+
+    /// The device holding the modified clusters.
+    overlay: O,
+
+    /// The parameters of the overlay.
+    header: CowHeader,
+
+    /// The bitmap of the clusters held by the overlay.
+    bitmap: Vec<u8>,
+}
+
+impl<B: StorageDevice, O: StorageDevice> CowDevice<B, O> {
+    /// Set up a new, empty overlay over ``base`` on ``overlay``, copying data by clusters of
+    /// ``cluster_size`` bytes.
+    ///
+    /// ``cluster_size`` must be a non-zero multiple of [Block::LEN]. The existing content of
+    /// ``overlay`` is meaningless afterwards.
+    pub fn create(mut base: B, mut overlay: O, cluster_size: u32) -> StorageDeviceResult<Self> {
+        if cluster_size == 0 || !(cluster_size as usize).is_multiple_of(Block::LEN) {
+            return Err(StorageDeviceError::Unsupported);
+        }
+
+        let header = CowHeader {
+            base_len: base.len()?,
+            cluster_size,
+            commit_pending: false,
+        };
+        let bitmap_len = usize::try_from(header.clusters().div_ceil(8))
+            .map_err(|_| StorageDeviceError::OutOfSpace)?;
+        let bitmap = alloc::vec![0u8; bitmap_len];
+
+        overlay.write(0, &header.to_block()[..])?;
+        overlay.write_zeroes(BITMAP_OFFSET, bitmap.len() as u64)?;
+        overlay.flush()?;
+
+        Ok(CowDevice {
+            base,
+            overlay,
+            header,
+            bitmap,
+        })
+    }
+
+    /// Open the overlay previously set up on ``overlay`` with [CowDevice::create], over ``base``.
+    ///
+    /// Return [StorageDeviceError::Corrupted] if the header of the overlay isn't valid, or if it
+    /// was created over a base device of a different size.
+    pub fn open(mut base: B, mut overlay: O) -> StorageDeviceResult<Self> {
+        let mut block = Block::new();
+        overlay.read(0, &mut block[..])?;
+        let header = CowHeader::from_block(&block).ok_or(StorageDeviceError::Corrupted)?;
+        if header.base_len != base.len()? {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        let bitmap_len = usize::try_from(header.clusters().div_ceil(8))
+            .map_err(|_| StorageDeviceError::OutOfSpace)?;
+        let mut bitmap = alloc::vec![0u8; bitmap_len];
+        overlay.read(BITMAP_OFFSET, &mut bitmap)?;
+
+        Ok(CowDevice {
+            base,
+            overlay,
+            header,
+            bitmap,
+        })
+    }
+
+    /// Merge every cluster held by the overlay into the base device, then empty the overlay.
+    ///
+    /// After each cluster, ``progress`` is called with the number of clusters merged so far and
+    /// the number of clusters to merge.
+    ///
+    /// An intent record is written to the header of the overlay before the base is modified, and
+    /// only cleared once the base is flushed and the bitmap emptied. If the commit is interrupted,
+    /// by an error or a crash, the base alone is left half-merged, which the reopened overlay
+    /// reports with [CowDevice::is_commit_pending]. Reads through the overlay still return the
+    /// modified data meanwhile, and calling commit again completes the merge.
+    pub fn commit<F: FnMut(u64, u64)>(&mut self, mut progress: F) -> StorageDeviceResult<()> {
+        let cluster_size = u64::from(self.header.cluster_size);
+        let data_offset = self.header.data_offset();
+        let total = self.modified_clusters();
+
+        self.set_commit_pending(true)?;
+        let mut done = 0;
+        for cluster in 0..self.header.clusters() {
+            if !self.has_cluster(cluster) {
+                continue;
+            }
+            let start = cluster * cluster_size;
+            let len = core::cmp::min(cluster_size, self.header.base_len - start);
+            copy_range(
+                &mut self.overlay,
+                data_offset + start,
+                &mut self.base,
+                start,
+                len,
+            )?;
+            done += 1;
+            progress(done, total);
+        }
+        self.base.flush()?;
+
+        self.clear_delta()?;
+        self.set_commit_pending(false)
+    }
+
+    /// Throw away every modification held by the overlay, so the device reads as the base again.
+    ///
+    /// The space used by the clusters is discarded from the overlay. Fail with
+    /// [StorageDeviceError::InvalidRequest] if a commit is pending, as the base is half-merged
+    /// until it completes.
+    pub fn discard_delta(&mut self) -> StorageDeviceResult<()> {
+        if self.header.commit_pending {
+            return Err(StorageDeviceError::InvalidRequest);
+        }
+        self.clear_delta()?;
+        self.overlay.flush()
+    }
+
+    /// Check whether a [CowDevice::commit] was started and didn't complete.
+    pub fn is_commit_pending(&self) -> bool {
+        self.header.commit_pending
+    }
+
+    /// Return the granularity at which data is copied to the overlay, in bytes.
+    pub fn cluster_size(&self) -> u32 {
+        self.header.cluster_size
+    }
+
+    /// Return the number of clusters held by the overlay.
+    pub fn modified_clusters(&self) -> u64 {
+        self.bitmap
+            .iter()
+            .map(|byte| u64::from(byte.count_ones()))
+            .sum()
+    }
+
+    /// Check whether the byte at ``offset`` was modified, i.e. is held by the overlay.
+    pub fn is_modified(&self, offset: u64) -> bool {
+        offset < self.header.base_len
+            && self.has_cluster(offset / u64::from(self.header.cluster_size))
+    }
+
+    /// Return a reference to the base device.
+    pub fn base(&self) -> &B {
+        &self.base
+    }
+
+    /// Return a reference to the overlay.
+    pub fn overlay(&self) -> &O {
+        &self.overlay
+    }
+
+    /// Consume the device, returning the base device and the overlay.
+    pub fn into_inner(self) -> (B, O) {
+        (self.base, self.overlay)
+    }
+
+    /// Record whether a commit is in progress in the header of the overlay, and flush it.
+    fn set_commit_pending(&mut self, commit_pending: bool) -> StorageDeviceResult<()> {
+        self.header.commit_pending = commit_pending;
+        self.overlay.write(0, &self.header.to_block()[..])?;
+        self.overlay.flush()
+    }
+
+    /// Empty the bitmap, and discard the clusters from the overlay.
+    fn clear_delta(&mut self) -> StorageDeviceResult<()> {
+        self.bitmap[..].fill(0);
+        self.overlay
+            .write_zeroes(BITMAP_OFFSET, self.bitmap.len() as u64)?;
+        self.overlay
+            .discard(self.header.data_offset(), self.header.base_len)
+    }
+
+    /// Check whether the overlay holds ``cluster``.
+    fn has_cluster(&self, cluster: u64) -> bool {
+        self.bitmap[(cluster / 8) as usize] & (1 << (cluster % 8)) != 0
+    }
+
+    /// Record that the overlay holds ``cluster``, and persist the bitmap.
+    fn set_cluster(&mut self, cluster: u64) -> StorageDeviceResult<()> {
+        let index = (cluster / 8) as usize;
+        self.bitmap[index] |= 1 << (cluster % 8);
+        self.overlay
+            .write(BITMAP_OFFSET + index as u64, &self.bitmap[index..index + 1])
+    }
+
+    /// Check whether the ``len`` bytes at ``offset`` are inside the device.
+    fn check_bounds(&self, offset: u64, len: usize) -> StorageDeviceResult<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.header.base_len => Ok(()),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+
+    /// Return how many of the ``len`` bytes at ``offset`` are in the same cluster.
+    fn run_len(&self, offset: u64, len: usize) -> usize {
+        let cluster_size = u64::from(self.header.cluster_size);
+        core::cmp::min(cluster_size - offset % cluster_size, len as u64) as usize
+    }
+}
+
+impl<B: StorageDevice, O: StorageDevice> StorageDevice for CowDevice<B, O> {
+    fn read(&mut self, mut offset: u64, mut buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        let data_offset = self.header.data_offset();
+        while !buf.is_empty() {
+            let run = self.run_len(offset, buf.len());
+            let (head, tail) = buf.split_at_mut(run);
+            if self.has_cluster(offset / u64::from(self.header.cluster_size)) {
+                self.overlay.read(data_offset + offset, head)?;
+            } else {
+                self.base.read(offset, head)?;
+            }
+            buf = tail;
+            offset += run as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, mut offset: u64, mut buf: &[u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        let cluster_size = u64::from(self.header.cluster_size);
+        let data_offset = self.header.data_offset();
+        while !buf.is_empty() {
+            let run = self.run_len(offset, buf.len());
+            let (head, tail) = buf.split_at(run);
+            let cluster = offset / cluster_size;
+            if self.has_cluster(cluster) {
+                self.overlay.write(data_offset + offset, head)?;
+            } else {
+                // Bring the rest of the cluster from the base first, unless it is overwritten.
+                let cluster_start = cluster * cluster_size;
+                let cluster_len =
+                    core::cmp::min(cluster_size, self.header.base_len - cluster_start);
+                if run as u64 != cluster_len {
+                    copy_range(
+                        &mut self.base,
+                        cluster_start,
+                        &mut self.overlay,
+                        data_offset + cluster_start,
+                        cluster_len,
+                    )?;
+                }
+                self.overlay.write(data_offset + offset, head)?;
+                self.overlay.barrier()?;
+                self.set_cluster(cluster)?;
+            }
+            buf = tail;
+            offset += run as u64;
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.header.base_len)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.overlay.flush()
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.overlay.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo::default())
+    }
+}
+
+/// A chain of copy-on-write overlays, such as base ← snap1 ← snap2 ← live, built by stacking
+/// [CowDevice]s over a [ChainBase].
+///
+/// The layers are numbered from the bottom device, layer 0, to the top overlay, layer
+/// [depth](OverlayChain::depth).
+pub trait OverlayChain: StorageDevice {
+    /// Return the number of overlays stacked over the bottom device.
+    fn depth(&self) -> usize;
+
+    /// Return the layer holding the byte at ``offset``: the topmost overlay which wrote it, or 0
+    /// if no overlay did.
+    fn layer_of(&self, offset: u64) -> usize;
+
+    /// Return the number of clusters held by the overlay of ``layer``, or None if there is no
+    /// such overlay.
+    fn layer_clusters(&self, layer: usize) -> Option<u64>;
+
+    /// Collapse the chain into its bottom device, merging every overlay into the layer below it
+    /// with [CowDevice::commit], from the top one down.
+    ///
+    /// After each cluster, ``progress`` is called with the layer being merged, the number of its
+    /// clusters merged so far and the number to merge. Afterwards, every overlay is empty and
+    /// the bottom device holds the content of the chain, so it can be used on its own. An
+    /// interrupted flatten is completed by calling it again.
+    fn flatten<F: FnMut(usize, u64, u64)>(&mut self, progress: F) -> StorageDeviceResult<()>;
+}
+
+impl<B: OverlayChain, O: StorageDevice> OverlayChain for CowDevice<B, O> {
+    fn depth(&self) -> usize {
+        self.base.depth() + 1
+    }
+
+    fn layer_of(&self, offset: u64) -> usize {
+        if self.is_modified(offset) {
+            self.depth()
+        } else {
+            self.base.layer_of(offset)
+        }
+    }
+
+    fn layer_clusters(&self, layer: usize) -> Option<u64> {
+        match layer.cmp(&self.depth()) {
+            core::cmp::Ordering::Equal => Some(self.modified_clusters()),
+            core::cmp::Ordering::Less => self.base.layer_clusters(layer),
+            core::cmp::Ordering::Greater => None,
+        }
+    }
+
+    fn flatten<F: FnMut(usize, u64, u64)>(&mut self, mut progress: F) -> StorageDeviceResult<()> {
+        let depth = self.depth();
+        self.commit(|done, total| progress(depth, done, total))?;
+        self.base.flatten(progress)
+    }
+}
+
+/// The bottom device of a chain of [CowDevice] overlays, letting the chain be inspected and
+/// flattened through [OverlayChain].
+///
+/// Every request is forwarded to the device.
+#[derive(Debug)]
+pub struct ChainBase<S: StorageDevice> {
+    /// The bottom device.
+    storage_device: S,
+}
+
+impl<S: StorageDevice> ChainBase<S> {
+    /// Use ``storage_device`` as the bottom of a chain.
+    pub fn new(storage_device: S) -> Self {
+        ChainBase { storage_device }
+    }
+
+    /// Return a reference to the bottom device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Consume the wrapper, returning the bottom device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+}
+
+impl<S: StorageDevice> OverlayChain for ChainBase<S> {
+    fn depth(&self) -> usize {
+        0
+    }
+
+    fn layer_of(&self, _offset: u64) -> usize {
+        0
+    }
+
+    fn layer_clusters(&self, _layer: usize) -> Option<u64> {
+        None
+    }
+
+    fn flatten<F: FnMut(usize, u64, u64)>(&mut self, _progress: F) -> StorageDeviceResult<()> {
+        Ok(())
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for ChainBase<S> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.storage_device.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.storage_device.write(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.storage_device.len()
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.write_zeroes(offset, len)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.discard(offset, len)
+    }
+
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        self.storage_device.next_data(offset)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.storage_device.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// The size of the base devices of the tests.
+    const BASE_LEN: usize = 8 * 1024;
+
+    /// A device failing its writes once ``writes_left`` reaches zero, to interrupt a commit.
+    #[derive(Debug)]
+    struct FailingDevice {
+        data: Vec<u8>,
+        writes_left: usize,
+    }
+
+    impl StorageDevice for FailingDevice {
+        fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+            self.data.read(offset, buf)
+        }
+
+        fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+            if self.writes_left == 0 {
+                return Err(StorageDeviceError::WriteError);
+            }
+            self.writes_left -= 1;
+            self.data.write(offset, buf)
+        }
+
+        fn len(&mut self) -> StorageDeviceResult<u64> {
+            StorageDevice::len(&mut self.data)
+        }
+    }
+
+    /// Create an overlay over a base filled with 0x11, with a few clusters modified.
+    fn modified_device<B: StorageDevice>(base: B) -> CowDevice<B, Vec<u8>> {
+        let mut device = CowDevice::create(base, vec![0u8; 2 * BASE_LEN], 1024).unwrap();
+        device.write(100, &[0x22; 50]).unwrap();
+        device.write(3000, &[0x33; 2000]).unwrap();
+        device
+    }
+
+    /// Return the content the base has once the modifications of [modified_device] are merged.
+    fn merged_content() -> Vec<u8> {
+        let mut expected = vec![0x11u8; BASE_LEN];
+        expected[100..150].fill(0x22);
+        expected[3000..5000].fill(0x33);
+        expected
+    }
+
+    #[test]
+    fn commit_merges_the_overlay_into_the_base() {
+        let mut device = modified_device(vec![0x11u8; BASE_LEN]);
+        assert_eq!(device.modified_clusters(), 4);
+
+        let mut reports = Vec::new();
+        device
+            .commit(|done, total| reports.push((done, total)))
+            .unwrap();
+        assert_eq!(reports, [(1, 4), (2, 4), (3, 4), (4, 4)]);
+        assert_eq!(device.modified_clusters(), 0);
+        assert!(!device.is_commit_pending());
+        assert_eq!(device.base(), &merged_content());
+
+        // The emptied overlay is persisted.
+        let (base, overlay) = device.into_inner();
+        let device = CowDevice::open(base, overlay).unwrap();
+        assert_eq!(device.modified_clusters(), 0);
+    }
+
+    #[test]
+    fn discard_delta_reverts_to_the_base() {
+        let mut device = modified_device(vec![0x11u8; BASE_LEN]);
+        device.discard_delta().unwrap();
+        assert_eq!(device.modified_clusters(), 0);
+
+        let mut content = vec![0u8; BASE_LEN];
+        device.read(0, &mut content).unwrap();
+        assert!(content.iter().all(|byte| *byte == 0x11));
+        assert!(device.base().iter().all(|byte| *byte == 0x11));
+    }
+
+    #[test]
+    fn chains_resolve_reads_and_flatten_into_the_bottom() {
+        let base = ChainBase::new(vec![0x11u8; BASE_LEN]);
+        let mut snap1 = modified_device(base);
+        snap1.flush().unwrap();
+        let mut snap2 = CowDevice::create(snap1, vec![0u8; 2 * BASE_LEN], 512).unwrap();
+        snap2.write(120, &[0x44; 10]).unwrap();
+        let mut live = CowDevice::create(snap2, vec![0u8; 2 * BASE_LEN], 2048).unwrap();
+        live.write(7000, &[0x55; 100]).unwrap();
+
+        let mut expected = merged_content();
+        expected[120..130].fill(0x44);
+        expected[7000..7100].fill(0x55);
+        let mut content = vec![0u8; BASE_LEN];
+        live.read(0, &mut content).unwrap();
+        assert_eq!(content, expected);
+
+        assert_eq!(live.depth(), 3);
+        // Layers hold whole clusters, of 1024, 512 and 2048 bytes from the bottom.
+        assert_eq!(live.layer_of(6000), 0);
+        assert_eq!(live.layer_of(600), 1);
+        assert_eq!(live.layer_of(0), 2);
+        assert_eq!(live.layer_of(7050), 3);
+        assert_eq!(live.layer_clusters(1), Some(4));
+        assert_eq!(live.layer_clusters(2), Some(1));
+        assert_eq!(live.layer_clusters(3), Some(1));
+        assert_eq!(live.layer_clusters(0), None);
+        assert_eq!(live.layer_clusters(4), None);
+
+        let mut layers = Vec::new();
+        live.flatten(|layer, _, _| layers.push(layer)).unwrap();
+        // Each merged cluster is split into the smaller clusters of the layer below.
+        assert_eq!(layers, [3, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1]);
+        assert!((1..=3).all(|layer| live.layer_clusters(layer) == Some(0)));
+        let (snap2, _) = live.into_inner();
+        let (snap1, _) = snap2.into_inner();
+        let (base, _) = snap1.into_inner();
+        assert_eq!(base.into_inner(), expected);
+    }
+
+    #[test]
+    fn interrupted_commit_is_pending_until_completed() {
+        let base = FailingDevice {
+            data: vec![0x11u8; BASE_LEN],
+            writes_left: 3,
+        };
+        let mut device = modified_device(base);
+        assert_eq!(
+            device.commit(|_, _| ()),
+            Err(StorageDeviceError::WriteError)
+        );
+
+        // The pending commit survives reopening, and the overlay still serves the modifications.
+        let (mut base, overlay) = device.into_inner();
+        base.writes_left = usize::MAX;
+        let mut device = CowDevice::open(base, overlay).unwrap();
+        assert!(device.is_commit_pending());
+        assert_eq!(
+            device.discard_delta(),
+            Err(StorageDeviceError::InvalidRequest)
+        );
+        let mut content = vec![0u8; BASE_LEN];
+        device.read(0, &mut content).unwrap();
+        assert_eq!(content, merged_content());
+
+        device.commit(|_, _| ()).unwrap();
+        assert!(!device.is_commit_pending());
+        assert_eq!(device.base().data, merged_content());
+    }
+}
@@ -0,0 +1,42 @@
+/// The reversed polynomial of CRC-32.
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+/// Lookup table computed at compile time.
+const TABLE: [u32; 256] = make_table();
+
+/// Build the lookup table for every possible byte value.
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Update the ``crc`` of the data seen so far with ``data``.
+///
+/// Start with a ``crc`` of 0 to compute the CRC-32 of a fresh buffer.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc = TABLE[((crc ^ u32::from(*byte)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Compute the CRC-32 of ``data``.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
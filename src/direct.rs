@@ -0,0 +1,130 @@
+use crate::aligned_io::AlignedFile;
+use crate::{Capabilities, DeviceIdentity, StorageDevice, StorageDeviceError, StorageDeviceResult};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// The default alignment of the I/O done by a [DirectFileDevice], suitable for every common device.
+pub const DEFAULT_DIRECT_ALIGNMENT: usize = 4096;
+
+/// The default size of the bounce buffer of a [DirectFileDevice].
+pub const DEFAULT_DIRECT_BUFFER_SIZE: usize = 128 * 1024;
+
+/// A storage device backed by a file or a block device opened with ``O_DIRECT``, bypassing the page
+/// cache.
+///
+/// Direct I/O must be aligned in offset, size and memory. Requests are transferred through an
+/// aligned bounce buffer, reading the surrounding data first when a write doesn't cover whole
+/// aligned units. This makes it possible to benchmark filesystems without the page cache
+/// getting in the way.
+pub struct DirectFileDevice {
+    /// The backing file, opened with ``O_DIRECT``, and its bounce buffer.
+    inner: AlignedFile,
+}
+
+impl core::fmt::Debug for DirectFileDevice {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+        fmt.debug_struct("DirectFileDevice")
+            .field("file", &self.inner.file)
+            .field("alignment", &self.inner.alignment)
+            .field("buffer_size", &self.inner.buffer.len())
+            .finish()
+    }
+}
+
+impl DirectFileDevice {
+    /// Open the file or block device at ``path`` for direct I/O, using the default alignment and
+    /// buffer size.
+    pub fn open<P: AsRef<Path>>(path: P, writable: bool) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+
+        Ok(Self::from_file(
+            file,
+            DEFAULT_DIRECT_ALIGNMENT,
+            DEFAULT_DIRECT_BUFFER_SIZE,
+        ))
+    }
+
+    /// Create a direct device from a ``file`` already opened with ``O_DIRECT``.
+    ///
+    /// ``alignment`` must be a power of two at least as big as the logical block size of the
+    /// underlying device, and ``buffer_size`` is rounded up to a multiple of it.
+    pub fn from_file(file: File, alignment: usize, buffer_size: usize) -> Self {
+        DirectFileDevice {
+            inner: AlignedFile::new(file, alignment, buffer_size),
+        }
+    }
+
+    /// Return the alignment of every transfer.
+    pub fn alignment(&self) -> usize {
+        self.inner.alignment
+    }
+
+    /// Return a reference to the backing file.
+    pub fn get_ref(&self) -> &File {
+        &self.inner.file
+    }
+
+    /// Consume the device, returning the backing file.
+    pub fn into_inner(self) -> File {
+        self.inner.file
+    }
+}
+
+impl StorageDevice for DirectFileDevice {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.inner.read(offset, buf)
+    }
+
+    /// Write through the bounce buffer, reading the partially covered aligned units first.
+    ///
+    /// As direct writes past the end of a file extend it to the alignment, the file is truncated
+    /// back to the end of the written range afterwards.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let original_len = self.len()?;
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(StorageDeviceError::OutOfBounds)?;
+        self.inner.write(offset, buf)?;
+
+        let expected_len = core::cmp::max(original_len, end);
+        if self.len()? > expected_len {
+            self.inner
+                .file
+                .set_len(expected_len)
+                .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))?;
+        }
+
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        crate::sys::file_len(&self.inner.file)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.inner
+            .file
+            .sync_all()
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+
+    /// Query the identity of the disk or file from the operating system.
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        crate::sys::identity(&self.inner.file)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+    }
+
+    /// Query the capabilities of the disk or file from the operating system, keeping those this
+    /// device makes use of.
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        let capabilities = crate::sys::capabilities(&self.inner.file);
+        Ok(capabilities
+            & (Capabilities::FLUSH | Capabilities::ROTATIONAL | Capabilities::READ_ONLY))
+    }
+}
@@ -0,0 +1,260 @@
+use crate::{
+    Capabilities, DeviceIdentity, DeviceInfo, StorageDevice, StorageDeviceError,
+    StorageDeviceResult,
+};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::ops::Range;
+
+/// The default granularity at which a [DirtyTrackingDevice] tracks writes, in bytes.
+pub const DEFAULT_DIRTY_GRANULARITY: u64 = 64 * 1024;
+
+/// A storage device recording which ranges were modified since the last time they were cleared.
+///
+/// The device is split in chunks of [DEFAULT_DIRTY_GRANULARITY] bytes unless configured
+/// otherwise, and a bitmap in memory records the chunks touched by writes, fills, discards and
+/// sanitizations. An incremental backup copies the ranges returned by
+/// [DirtyTrackingDevice::dirty_extents] then clears them, and a live migration repeats this
+/// until few enough ranges are left.
+///
+/// Chunks are marked before the request is forwarded, so a failed write, which may have modified
+/// part of the range, still marks it. The size of the device is read once, when it is wrapped.
+pub struct DirtyTrackingDevice<S: StorageDevice> {
+    /// The inner device.
+    storage_device: S,
+
+    /// The size of the device, in bytes.
+    len: u64,
+
+    /// The size of the chunks tracked, in bytes.
+    granularity: u64,
+
+    /// The bitmap of the chunks modified.
+    bitmap: Vec<u8>,
+}
+
+impl<S: StorageDevice> core::fmt::Debug for DirtyTrackingDevice<S> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DirtyTrackingDevice")
+            .field("storage_device", &self.storage_device)
+            .field("granularity", &self.granularity)
+            .field("dirty_chunks", &self.dirty_chunks())
+            .finish()
+    }
+}
+
+impl<S: StorageDevice> DirtyTrackingDevice<S> {
+    /// Wrap ``storage_device``, every chunk starting clean.
+    pub fn new(mut storage_device: S) -> StorageDeviceResult<Self> {
+        let len = storage_device.len()?;
+        let mut device = DirtyTrackingDevice {
+            storage_device,
+            len,
+            granularity: DEFAULT_DIRTY_GRANULARITY,
+            bitmap: Vec::new(),
+        };
+        device.bitmap = alloc::vec![0u8; device.bitmap_len()?];
+        Ok(device)
+    }
+
+    /// Track chunks of ``granularity`` bytes, instead of [DEFAULT_DIRTY_GRANULARITY], clearing
+    /// every chunk.
+    ///
+    /// Finer chunks report smaller ranges, but take more memory. Return
+    /// [StorageDeviceError::Unsupported] if ``granularity`` is zero.
+    pub fn with_granularity(mut self, granularity: u64) -> StorageDeviceResult<Self> {
+        if granularity == 0 {
+            return Err(StorageDeviceError::Unsupported);
+        }
+        self.granularity = granularity;
+        self.bitmap = alloc::vec![0u8; self.bitmap_len()?];
+        Ok(self)
+    }
+
+    /// Return the size of the chunks tracked, in bytes.
+    pub fn granularity(&self) -> u64 {
+        self.granularity
+    }
+
+    /// Return the number of chunks modified.
+    pub fn dirty_chunks(&self) -> u64 {
+        self.bitmap
+            .iter()
+            .map(|byte| u64::from(byte.count_ones()))
+            .sum()
+    }
+
+    /// Check whether the chunk holding the byte at ``offset`` was modified.
+    pub fn is_dirty(&self, offset: u64) -> bool {
+        offset < self.len && self.has_chunk(offset / self.granularity)
+    }
+
+    /// Iterate over the ranges modified, in order, merging adjacent chunks.
+    pub fn dirty_extents(&self) -> DirtyExtents<'_, S> {
+        DirtyExtents {
+            device: self,
+            chunk: 0,
+        }
+    }
+
+    /// Mark every chunk as clean, e.g. once a backup of the ranges modified is done.
+    pub fn clear(&mut self) {
+        self.bitmap[..].fill(0);
+    }
+
+    /// Mark the chunks overlapping ``range`` as clean.
+    ///
+    /// Clearing a range returned by [DirtyTrackingDevice::dirty_extents] after copying it keeps
+    /// the chunks modified in the meantime elsewhere marked.
+    pub fn clear_range(&mut self, range: Range<u64>) {
+        for chunk in self.chunks(range.start, range.end.saturating_sub(range.start)) {
+            self.bitmap[(chunk / 8) as usize] &= !(1 << (chunk % 8));
+        }
+    }
+
+    /// Return a reference to the inner device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the inner device.
+    ///
+    /// Writes made through it aren't tracked.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Consume the wrapper, returning the inner device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Return the size of the bitmap of the device, in bytes.
+    fn bitmap_len(&self) -> StorageDeviceResult<usize> {
+        usize::try_from(self.len.div_ceil(self.granularity).div_ceil(8))
+            .map_err(|_| StorageDeviceError::OutOfSpace)
+    }
+
+    /// Return the chunks overlapping the ``len`` bytes at ``offset``, up to the end of the
+    /// device.
+    fn chunks(&self, offset: u64, len: u64) -> Range<u64> {
+        let end = core::cmp::min(offset.saturating_add(len), self.len);
+        if offset >= end {
+            return 0..0;
+        }
+        offset / self.granularity..end.div_ceil(self.granularity)
+    }
+
+    /// Check whether ``chunk`` was modified.
+    fn has_chunk(&self, chunk: u64) -> bool {
+        self.bitmap[(chunk / 8) as usize] & (1 << (chunk % 8)) != 0
+    }
+
+    /// Mark the chunks overlapping the ``len`` bytes at ``offset`` as modified.
+    fn mark(&mut self, offset: u64, len: u64) {
+        for chunk in self.chunks(offset, len) {
+            self.bitmap[(chunk / 8) as usize] |= 1 << (chunk % 8);
+        }
+    }
+}
+
+/// An iterator over the ranges modified of a [DirtyTrackingDevice], returned by
+/// [DirtyTrackingDevice::dirty_extents].
+#[derive(Debug)]
+pub struct DirtyExtents<'a, S: StorageDevice> {
+    /// The device to report the ranges of.
+    device: &'a DirtyTrackingDevice<S>,
+
+    /// The chunk to look for the next range from.
+    chunk: u64,
+}
+
+impl<S: StorageDevice> Iterator for DirtyExtents<'_, S> {
+    type Item = Range<u64>;
+
+    fn next(&mut self) -> Option<Range<u64>> {
+        let device = self.device;
+        let chunks = device.len.div_ceil(device.granularity);
+        let start = (self.chunk..chunks).find(|&chunk| device.has_chunk(chunk))?;
+        let end = (start..chunks)
+            .find(|&chunk| !device.has_chunk(chunk))
+            .unwrap_or(chunks);
+        self.chunk = end;
+        Some(start * device.granularity..core::cmp::min(end * device.granularity, device.len))
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for DirtyTrackingDevice<S> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.storage_device.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.mark(offset, buf.len() as u64);
+        self.storage_device.write(offset, buf)
+    }
+
+    fn read_partial(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        self.storage_device.read_partial(offset, buf)
+    }
+
+    fn write_partial(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<usize> {
+        self.mark(offset, buf.len() as u64);
+        self.storage_device.write_partial(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.storage_device.len()
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.mark(offset, len);
+        self.storage_device.write_zeroes(offset, len)
+    }
+
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        self.mark(offset, len);
+        self.storage_device.fill(offset, len, byte)
+    }
+
+    /// Mark the range as modified, as discarded data may read differently afterwards.
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.mark(offset, len);
+        self.storage_device.discard(offset, len)
+    }
+
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<Range<u64>>> {
+        self.storage_device.next_data(offset)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.mark(offset, buf.len() as u64);
+        self.storage_device.write_fua(offset, buf)
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.storage_device.capabilities()
+    }
+
+    /// Mark the whole device as modified.
+    fn sanitize(&mut self) -> StorageDeviceResult<()> {
+        self.mark(0, self.len);
+        self.storage_device.sanitize()
+    }
+}
@@ -0,0 +1,327 @@
+use crate::{
+    Block, BlockCount, BlockDevice, BlockError, BlockGeometry, BlockIndex, BlockResult,
+    Capabilities, DeviceIdentity,
+};
+
+/// The default number of blocks of the bounce buffer of a [DmaBounceDevice].
+pub const DEFAULT_BOUNCE_BLOCKS: usize = 8;
+
+/// A buffer of blocks a device can reach by DMA.
+///
+/// Drivers call [DmaBuffer::clean] before the device reads the buffer, and
+/// [DmaBuffer::invalidate] after the device wrote it, so the caches of the CPU and the memory
+/// agree. On cache-coherent platforms, both do nothing.
+///
+/// # Safety
+///
+/// The blocks must be physically contiguous, starting at [DmaBuffer::physical_address], and
+/// reachable by the device, for as long as the buffer lives: devices write wherever they are
+/// told to.
+pub unsafe trait DmaBuffer {
+    /// Return the blocks of the buffer.
+    fn blocks(&self) -> &[Block];
+
+    /// Return the blocks of the buffer, mutably.
+    fn blocks_mut(&mut self) -> &mut [Block];
+
+    /// Return the address of the first block as seen by the device.
+    fn physical_address(&self) -> u64;
+
+    /// Write the content of the buffer held in the caches of the CPU back to memory, before the
+    /// device reads it.
+    ///
+    /// The default implementation does nothing, as needed on cache-coherent platforms.
+    fn clean(&self) {}
+
+    /// Drop the content of the buffer held in the caches of the CPU, after the device wrote it to
+    /// memory.
+    ///
+    /// The default implementation does nothing, as needed on cache-coherent platforms.
+    fn invalidate(&mut self) {}
+}
+
+/// An allocator of [DmaBuffer]s, such as a region of memory reserved for DMA.
+pub trait DmaPool: core::fmt::Debug {
+    /// The buffers allocated.
+    type Buffer: DmaBuffer;
+
+    /// Allocate a buffer of ``blocks`` blocks.
+    ///
+    /// Fail with [BlockError::OutOfSpace] if the pool is exhausted.
+    fn allocate(&mut self, blocks: usize) -> BlockResult<Self::Buffer>;
+}
+
+/// A [BlockDevice] able to transfer blocks directly between [DmaBuffer]s and the device.
+///
+/// Drivers of DMA-capable hardware implement this, and need only implement
+/// [BlockDevice::read] and [BlockDevice::write] through a bounce buffer, or return
+/// [BlockError::Unsupported] and let a [DmaBounceDevice] handle them.
+pub trait DmaBlockDevice: BlockDevice {
+    /// Read blocks starting at the given ``index`` into ``buffer``, filling it whole.
+    fn read_dma(&mut self, buffer: &mut dyn DmaBuffer, index: BlockIndex) -> BlockResult<()>;
+
+    /// Write ``buffer`` whole to blocks starting at the given ``index``.
+    fn write_dma(&mut self, buffer: &dyn DmaBuffer, index: BlockIndex) -> BlockResult<()>;
+}
+
+/// A [DmaBuffer] in memory identity-mapped for the device, allocated on the heap.
+///
+/// This suits platforms where devices see the same addresses as the CPU, such as most hobby
+/// kernels and virtual machines, with cache-coherent DMA.
+#[cfg(feature = "alloc")]
+#[derive(Clone)]
+pub struct IdentityDmaBuffer {
+    /// The blocks of the buffer.
+    blocks: alloc::vec::Vec<Block>,
+}
+
+#[cfg(feature = "alloc")]
+impl core::fmt::Debug for IdentityDmaBuffer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("IdentityDmaBuffer")
+            .field("blocks", &self.blocks.len())
+            .field("physical_address", &self.physical_address())
+            .finish()
+    }
+}
+
+// The blocks of a Vec are contiguous, and the device sees the addresses of the CPU.
+#[cfg(feature = "alloc")]
+unsafe impl DmaBuffer for IdentityDmaBuffer {
+    fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    fn blocks_mut(&mut self) -> &mut [Block] {
+        &mut self.blocks
+    }
+
+    fn physical_address(&self) -> u64 {
+        self.blocks.as_ptr() as usize as u64
+    }
+}
+
+/// A [DmaPool] allocating [IdentityDmaBuffer]s on the heap.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct IdentityDmaPool;
+
+#[cfg(feature = "alloc")]
+impl DmaPool for IdentityDmaPool {
+    type Buffer = IdentityDmaBuffer;
+
+    fn allocate(&mut self, blocks: usize) -> BlockResult<IdentityDmaBuffer> {
+        Ok(IdentityDmaBuffer {
+            blocks: alloc::vec![Block::new(); blocks],
+        })
+    }
+}
+
+/// A block device performing the reads and writes of a [DmaBlockDevice] through a bounce buffer
+/// allocated from a [DmaPool].
+///
+/// Wrapped in a [StorageBlockDevice](crate::StorageBlockDevice), the temporary blocks of the
+/// storage device are copied to and from DMA-capable memory, whatever the buffers of the
+/// requests are. The bounce buffer holds [DEFAULT_BOUNCE_BLOCKS] blocks unless configured
+/// otherwise, and is allocated on the first request.
+pub struct DmaBounceDevice<B: DmaBlockDevice, P: DmaPool> {
+    /// The inner block device.
+    block_device: B,
+
+    /// The pool the bounce buffer is allocated from.
+    pool: P,
+
+    /// The bounce buffer, once allocated.
+    buffer: Option<P::Buffer>,
+
+    /// The number of blocks of the bounce buffer.
+    bounce_blocks: usize,
+}
+
+impl<B: DmaBlockDevice, P: DmaPool> core::fmt::Debug for DmaBounceDevice<B, P> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DmaBounceDevice")
+            .field("block_device", &self.block_device)
+            .field("pool", &self.pool)
+            .field("bounce_blocks", &self.bounce_blocks)
+            .finish()
+    }
+}
+
+impl<B: DmaBlockDevice, P: DmaPool> DmaBounceDevice<B, P> {
+    /// Wrap ``block_device``, allocating the bounce buffer from ``pool``.
+    pub fn new(block_device: B, pool: P) -> Self {
+        DmaBounceDevice {
+            block_device,
+            pool,
+            buffer: None,
+            bounce_blocks: DEFAULT_BOUNCE_BLOCKS,
+        }
+    }
+
+    /// Use a bounce buffer of ``blocks`` blocks, instead of [DEFAULT_BOUNCE_BLOCKS].
+    ///
+    /// Larger buffers make fewer, larger requests.
+    pub fn with_bounce_blocks(mut self, blocks: usize) -> Self {
+        self.bounce_blocks = core::cmp::max(blocks, 1);
+        self.buffer = None;
+        self
+    }
+
+    /// Return a reference to the inner block device.
+    pub fn get_ref(&self) -> &B {
+        &self.block_device
+    }
+
+    /// Return a mutable reference to the inner block device.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.block_device
+    }
+
+    /// Consume the wrapper, returning the inner block device and the pool.
+    pub fn into_inner(self) -> (B, P) {
+        (self.block_device, self.pool)
+    }
+
+    /// Return the bounce buffer, allocating it if needed.
+    fn buffer(&mut self) -> BlockResult<P::Buffer> {
+        match self.buffer.take() {
+            Some(buffer) => Ok(buffer),
+            None => {
+                let buffer = self.pool.allocate(self.bounce_blocks)?;
+                if buffer.blocks().len() != self.bounce_blocks {
+                    return Err(BlockError::OutOfSpace);
+                }
+                Ok(buffer)
+            }
+        }
+    }
+
+    /// Read ``blocks`` at ``index`` through ``buffer``.
+    fn read_through(
+        &mut self,
+        buffer: &mut P::Buffer,
+        blocks: &mut [Block],
+        index: BlockIndex,
+    ) -> BlockResult<()> {
+        for (i, chunk) in blocks.chunks_mut(self.bounce_blocks).enumerate() {
+            let chunk_index = BlockIndex(index.0 + (i * self.bounce_blocks) as u64);
+            let mut prefix = DmaPrefix {
+                buffer: &mut *buffer,
+                len: chunk.len(),
+            };
+            self.block_device.read_dma(&mut prefix, chunk_index)?;
+            chunk.clone_from_slice(prefix.blocks());
+        }
+        Ok(())
+    }
+
+    /// Write ``blocks`` at ``index`` through ``buffer``.
+    fn write_through(
+        &mut self,
+        buffer: &mut P::Buffer,
+        blocks: &[Block],
+        index: BlockIndex,
+    ) -> BlockResult<()> {
+        for (i, chunk) in blocks.chunks(self.bounce_blocks).enumerate() {
+            let chunk_index = BlockIndex(index.0 + (i * self.bounce_blocks) as u64);
+            let mut prefix = DmaPrefix {
+                buffer: &mut *buffer,
+                len: chunk.len(),
+            };
+            prefix.blocks_mut().clone_from_slice(chunk);
+            self.block_device.write_dma(&prefix, chunk_index)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first ``len`` blocks of a [DmaBuffer].
+struct DmaPrefix<'a, D: DmaBuffer> {
+    /// The whole buffer.
+    buffer: &'a mut D,
+
+    /// The number of blocks of the prefix.
+    len: usize,
+}
+
+// A prefix of physically contiguous blocks starts at the same address, and is contiguous too.
+unsafe impl<D: DmaBuffer> DmaBuffer for DmaPrefix<'_, D> {
+    fn blocks(&self) -> &[Block] {
+        &self.buffer.blocks()[..self.len]
+    }
+
+    fn blocks_mut(&mut self) -> &mut [Block] {
+        &mut self.buffer.blocks_mut()[..self.len]
+    }
+
+    fn physical_address(&self) -> u64 {
+        self.buffer.physical_address()
+    }
+
+    fn clean(&self) {
+        self.buffer.clean()
+    }
+
+    fn invalidate(&mut self) {
+        self.buffer.invalidate()
+    }
+}
+
+impl<B: DmaBlockDevice, P: DmaPool> BlockDevice for DmaBounceDevice<B, P> {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        let mut buffer = self.buffer()?;
+        let result = self.read_through(&mut buffer, blocks, index);
+        self.buffer = Some(buffer);
+        result
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        let mut buffer = self.buffer()?;
+        let result = self.write_through(&mut buffer, blocks, index);
+        self.buffer = Some(buffer);
+        result
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        self.block_device.count()
+    }
+
+    fn flush(&mut self) -> BlockResult<()> {
+        self.block_device.flush()
+    }
+
+    fn barrier(&mut self) -> BlockResult<()> {
+        self.block_device.barrier()
+    }
+
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        self.block_device.discard(index, count)
+    }
+
+    fn prefetch(&mut self, ranges: &[(BlockIndex, BlockCount)]) -> BlockResult<()> {
+        self.block_device.prefetch(ranges)
+    }
+
+    fn geometry(&mut self) -> BlockResult<BlockGeometry> {
+        self.block_device.geometry()
+    }
+
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        self.block_device.identity()
+    }
+
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        self.block_device.capabilities()
+    }
+}
+
+impl<B: DmaBlockDevice, P: DmaPool> DmaBlockDevice for DmaBounceDevice<B, P> {
+    fn read_dma(&mut self, buffer: &mut dyn DmaBuffer, index: BlockIndex) -> BlockResult<()> {
+        self.block_device.read_dma(buffer, index)
+    }
+
+    fn write_dma(&mut self, buffer: &dyn DmaBuffer, index: BlockIndex) -> BlockResult<()> {
+        self.block_device.write_dma(buffer, index)
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{Block, BlockCount, BlockDevice, BlockError, BlockIndex, BlockResult};
+
+/// A block device accessed through byte slices, with a block size known at runtime.
+///
+/// Unlike [BlockDevice], the buffers don't need to be made of [Block]s, and devices with
+/// different block sizes look the same, so drivers can be stored side by side as
+/// ``Box<dyn DynBlockDevice>``, e.g. in the device table of a kernel. Every [BlockDevice]
+/// implements it.
+pub trait DynBlockDevice: core::fmt::Debug {
+    /// Return the size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Read blocks starting at the given ``index`` into ``buf``.
+    ///
+    /// Fail with [BlockError::Unsupported] if the length of ``buf`` isn't a multiple of the block
+    /// size.
+    fn read_blocks(&mut self, index: BlockIndex, buf: &mut [u8]) -> BlockResult<()>;
+
+    /// Write the blocks in ``buf`` starting at the given ``index``.
+    ///
+    /// Fail with [BlockError::Unsupported] if the length of ``buf`` isn't a multiple of the block
+    /// size.
+    fn write_blocks(&mut self, index: BlockIndex, buf: &[u8]) -> BlockResult<()>;
+
+    /// Return the amount of blocks hold by the block device.
+    fn block_count(&mut self) -> BlockResult<BlockCount>;
+
+    /// Ensure every block written so far has reached stable storage.
+    fn flush_blocks(&mut self) -> BlockResult<()>;
+
+    /// Discard ``count`` blocks starting at the given ``index``.
+    fn discard_blocks(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()>;
+}
+
+impl<B: BlockDevice + ?Sized> DynBlockDevice for B {
+    fn block_size(&self) -> usize {
+        Block::LEN
+    }
+
+    fn read_blocks(&mut self, index: BlockIndex, buf: &mut [u8]) -> BlockResult<()> {
+        if !buf.len().is_multiple_of(Block::LEN) {
+            return Err(BlockError::Unsupported);
+        }
+
+        if let Some(blocks) = Block::from_bytes_mut(buf) {
+            return self.read(blocks, index);
+        }
+
+        // Misaligned buffer, go through a temporary block.
+        let mut block = [Block::new()];
+        for (i, chunk) in buf.chunks_exact_mut(Block::LEN).enumerate() {
+            self.read(&mut block, index + BlockCount(i as u64))?;
+            chunk.copy_from_slice(&block[0].contents);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, index: BlockIndex, buf: &[u8]) -> BlockResult<()> {
+        if !buf.len().is_multiple_of(Block::LEN) {
+            return Err(BlockError::Unsupported);
+        }
+
+        if let Some(blocks) = Block::from_bytes(buf) {
+            return self.write(blocks, index);
+        }
+
+        // Misaligned buffer, go through a temporary block.
+        let mut block = [Block::new()];
+        for (i, chunk) in buf.chunks_exact(Block::LEN).enumerate() {
+            block[0].contents.copy_from_slice(chunk);
+            self.write(&block, index + BlockCount(i as u64))?;
+        }
+        Ok(())
+    }
+
+    fn block_count(&mut self) -> BlockResult<BlockCount> {
+        self.count()
+    }
+
+    fn flush_blocks(&mut self) -> BlockResult<()> {
+        self.flush()
+    }
+
+    fn discard_blocks(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        self.discard(index, count)
+    }
+}
@@ -0,0 +1,232 @@
+use crate::nand::{NandDevice, NandGeometry};
+use crate::{Block, BlockError, BlockResult};
+use alloc::vec::Vec;
+
+/// The number of data bytes covered by each error-correcting code.
+pub const ECC_CHUNK_SIZE: usize = 256;
+
+/// The size of the error-correcting code of each chunk, in bytes.
+pub const ECC_CODE_LEN: usize = 3;
+
+/// The outcome of checking a chunk against its code.
+enum Check {
+    /// The chunk and its code match.
+    Clean,
+
+    /// A bit of the chunk was flipped, and is at this bit offset.
+    DataBit(usize),
+
+    /// A bit of the code was flipped, the chunk is fine.
+    CodeBit,
+
+    /// Too many bits were flipped to correct them.
+    Uncorrectable,
+}
+
+/// Compute the Hamming code of a chunk of [ECC_CHUNK_SIZE] bytes.
+///
+/// For each of the 11 bits of the offset of a bit in the chunk, the code holds the parity of the
+/// bits whose offset has it set, and the parity of those whose offset has it clear. It is stored
+/// inverted, so the code of an erased chunk is erased as well.
+fn hamming(chunk: &[u8]) -> [u8; ECC_CODE_LEN] {
+    let mut columns = 0u8;
+    let mut odd_lines = 0u32;
+    for (index, &byte) in chunk.iter().enumerate() {
+        columns ^= byte;
+        if byte.count_ones() % 2 == 1 {
+            odd_lines ^= index as u32;
+        }
+    }
+
+    // The offset of a bit is its byte index, shifted, with its position in the byte.
+    let mut odd_columns = 0u32;
+    for bit in 0..8 {
+        if columns & (1 << bit) != 0 {
+            odd_columns ^= bit;
+        }
+    }
+    let ones = (odd_lines << 3) | odd_columns;
+    let total = columns.count_ones() % 2;
+
+    let mut code = 0u32;
+    for k in 0..11 {
+        let set = (ones >> k) & 1;
+        code |= set << (2 * k);
+        code |= (set ^ total) << (2 * k + 1);
+    }
+    let code = !code;
+    [code as u8, (code >> 8) as u8, (code >> 16) as u8]
+}
+
+/// Compare the ``stored`` code of a chunk with the ``computed`` one.
+fn check(stored: &[u8], computed: &[u8; ECC_CODE_LEN]) -> Check {
+    let word = |code: &[u8]| {
+        (u32::from(code[0]) | (u32::from(code[1]) << 8) | (u32::from(code[2]) << 16)) & 0x3F_FFFF
+    };
+    let syndrome = word(stored) ^ word(computed);
+    if syndrome == 0 {
+        return Check::Clean;
+    }
+    if syndrome.count_ones() == 1 {
+        return Check::CodeBit;
+    }
+
+    // A single flipped data bit changes exactly one parity of each pair.
+    let mut offset = 0;
+    for k in 0..11 {
+        match (syndrome >> (2 * k)) & 0b11 {
+            0b01 => offset |= 1 << k,
+            0b10 => (),
+            _ => return Check::Uncorrectable,
+        }
+    }
+    Check::DataBit(offset)
+}
+
+/// A raw flash device protecting the data of the pages of the flash it wraps with
+/// error-correcting codes, stored at the end of their spare area.
+///
+/// Each 256-byte chunk of a page gets a 3-byte Hamming code, which corrects a flipped bit in the
+/// chunk, and detects two. Reads correct what they can, counted by [EccDevice::corrected_bits],
+/// and fail with [BlockError::MediaError] if a chunk has more flipped bits, counted by
+/// [EccDevice::uncorrectable_chunks]. Flash wears by flipping bits, which an
+/// [Ftl](crate::Ftl) should not see.
+///
+/// The spare area exposed is what remains of the spare area of the flash, and isn't protected.
+/// Erased pages have valid codes, so they read as erased, even with a flipped bit.
+#[derive(Debug)]
+pub struct EccDevice<N: NandDevice> {
+    /// The raw flash.
+    nand: N,
+
+    /// The number of spare bytes left to the layer above.
+    spare_size: usize,
+
+    /// The number of bits corrected so far.
+    corrected_bits: u64,
+
+    /// The number of chunks found uncorrectable so far.
+    uncorrectable_chunks: u64,
+}
+
+impl<N: NandDevice> EccDevice<N> {
+    /// Wrap ``nand``.
+    ///
+    /// Fail with [BlockError::Unsupported] if its spare area is too small for the codes of a page.
+    pub fn new(nand: N) -> BlockResult<Self> {
+        let geometry = nand.geometry();
+        let codes = geometry.page_size.div_ceil(ECC_CHUNK_SIZE) * ECC_CODE_LEN;
+        let spare_size = geometry
+            .spare_size
+            .checked_sub(codes)
+            .ok_or(BlockError::Unsupported)?;
+        Ok(EccDevice {
+            nand,
+            spare_size,
+            corrected_bits: 0,
+            uncorrectable_chunks: 0,
+        })
+    }
+
+    /// Return the number of bits corrected so far.
+    pub fn corrected_bits(&self) -> u64 {
+        self.corrected_bits
+    }
+
+    /// Return the number of chunks found with too many flipped bits to correct them so far.
+    pub fn uncorrectable_chunks(&self) -> u64 {
+        self.uncorrectable_chunks
+    }
+
+    /// Return a reference to the raw flash.
+    pub fn get_ref(&self) -> &N {
+        &self.nand
+    }
+
+    /// Return a mutable reference to the raw flash.
+    pub fn get_mut(&mut self) -> &mut N {
+        &mut self.nand
+    }
+
+    /// Consume the wrapper, returning the raw flash.
+    pub fn into_inner(self) -> N {
+        self.nand
+    }
+}
+
+impl<N: NandDevice> NandDevice for EccDevice<N> {
+    fn geometry(&self) -> NandGeometry {
+        NandGeometry {
+            spare_size: self.spare_size,
+            ..self.nand.geometry()
+        }
+    }
+
+    fn read_page(&mut self, page: u64, data: &mut [Block], spare: &mut [u8]) -> BlockResult<()> {
+        if spare.len() > self.spare_size {
+            return Err(BlockError::ReadError);
+        }
+        if data.is_empty() {
+            return self.nand.read_page(page, data, spare);
+        }
+
+        let mut full_spare = alloc::vec![0; self.nand.geometry().spare_size];
+        self.nand.read_page(page, data, &mut full_spare)?;
+        spare.copy_from_slice(&full_spare[..spare.len()]);
+
+        let codes = &full_spare[self.spare_size..];
+        let chunks = data
+            .iter_mut()
+            .flat_map(|block| block.chunks_mut(ECC_CHUNK_SIZE));
+        let mut uncorrectable = false;
+        for (chunk, stored) in chunks.zip(codes.chunks(ECC_CODE_LEN)) {
+            match check(stored, &hamming(chunk)) {
+                Check::Clean => (),
+                Check::CodeBit => self.corrected_bits += 1,
+                Check::DataBit(offset) => {
+                    chunk[offset / 8] ^= 1 << (offset % 8);
+                    self.corrected_bits += 1;
+                }
+                Check::Uncorrectable => {
+                    self.uncorrectable_chunks += 1;
+                    uncorrectable = true;
+                }
+            }
+        }
+
+        if uncorrectable {
+            return Err(BlockError::MediaError);
+        }
+        Ok(())
+    }
+
+    fn program_page(&mut self, page: u64, data: &[Block], spare: &[u8]) -> BlockResult<()> {
+        if spare.len() > self.spare_size {
+            return Err(BlockError::WriteError);
+        }
+        if data.is_empty() {
+            return self.nand.program_page(page, data, spare);
+        }
+
+        let mut full_spare: Vec<u8> = alloc::vec![0xFF; self.nand.geometry().spare_size];
+        full_spare[..spare.len()].copy_from_slice(spare);
+        let codes = &mut full_spare[self.spare_size..];
+        let chunks = data.iter().flat_map(|block| block.chunks(ECC_CHUNK_SIZE));
+        for (chunk, code) in chunks.zip(codes.chunks_mut(ECC_CODE_LEN)) {
+            code.copy_from_slice(&hamming(chunk));
+        }
+        self.nand.program_page(page, data, &full_spare)
+    }
+
+    fn erase(&mut self, erase_block: u64) -> BlockResult<()> {
+        self.nand.erase(erase_block)
+    }
+
+    fn is_bad(&mut self, erase_block: u64) -> BlockResult<bool> {
+        self.nand.is_bad(erase_block)
+    }
+
+    fn mark_bad(&mut self, erase_block: u64) -> BlockResult<()> {
+        self.nand.mark_bad(erase_block)
+    }
+}
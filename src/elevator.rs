@@ -0,0 +1,225 @@
+use crate::{
+    Capabilities, DeviceIdentity, DeviceInfo, StorageDevice, StorageDeviceResult, StorageRequest,
+};
+use alloc::vec::Vec;
+
+/// A storage device reordering and merging the requests of a batch before performing them.
+///
+/// The requests given to [StorageDevice::submit] are sorted by offset, in the order an elevator
+/// sweeps the disk: starting from where the previous request ended, up to the end of the device,
+/// then from the start. Requests of the same kind following each other on the device are merged
+/// into a single vectored request, as long as it stays within the maximum transfer size of the
+/// [BlockGeometry](crate::BlockGeometry) of the device. Rotational media and many flash
+/// controllers perform sorted, merged requests much faster.
+///
+/// The batch still behaves as if its requests were performed in order: a request is never moved
+/// past an earlier one it overlaps, if either of them is a write. Such requests split the batch
+/// in groups, each sorted and merged on its own.
+#[derive(Debug)]
+pub struct ElevatorScheduler<S: StorageDevice> {
+    /// The inner storage device.
+    storage_device: S,
+
+    /// Where the last request ended, where the next sweep starts.
+    head: u64,
+
+    /// The number of requests merged into another one so far.
+    merged: u64,
+}
+
+impl<S: StorageDevice> ElevatorScheduler<S> {
+    /// Wrap ``storage_device``, scheduling the batches submitted to it.
+    pub fn new(storage_device: S) -> Self {
+        ElevatorScheduler {
+            storage_device,
+            head: 0,
+            merged: 0,
+        }
+    }
+
+    /// Return the number of requests merged into another one so far.
+    pub fn merged(&self) -> u64 {
+        self.merged
+    }
+
+    /// Return a reference to the inner storage device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the inner storage device.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Consume the wrapper, returning the inner storage device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Sort and merge ``group``, whose requests can be performed in any order, into requests of
+    /// at most ``max_len`` bytes.
+    fn dispatch(
+        &mut self,
+        group: &mut [StorageRequest<'_>],
+        max_len: u64,
+    ) -> StorageDeviceResult<()> {
+        let mut sorted: Vec<&mut StorageRequest<'_>> = group.iter_mut().collect();
+        sorted.sort_by_key(|request| request.offset());
+
+        // Sweep up from the head, then come back to the start of the device.
+        let wrap = sorted.partition_point(|request| request.offset() < self.head);
+        sorted.rotate_left(wrap);
+
+        let mut start = 0;
+        while start < sorted.len() {
+            let mut end = start + 1;
+            let mut run_end = sorted[start].offset() + sorted[start].len() as u64;
+            while end < sorted.len()
+                && sorted[end].is_write() == sorted[start].is_write()
+                && sorted[end].offset() == run_end
+                && run_end + sorted[end].len() as u64 - sorted[start].offset() <= max_len
+            {
+                run_end += sorted[end].len() as u64;
+                end += 1;
+            }
+
+            let offset = sorted[start].offset();
+            let run = &mut sorted[start..end];
+            if run.len() == 1 {
+                match &mut *run[0] {
+                    StorageRequest::Read { offset, buf } => {
+                        self.storage_device.read(*offset, buf)?
+                    }
+                    StorageRequest::Write { offset, buf } => {
+                        self.storage_device.write(*offset, buf)?
+                    }
+                }
+            } else if run[0].is_write() {
+                let bufs: Vec<&[u8]> = run
+                    .iter()
+                    .map(|request| match &**request {
+                        StorageRequest::Write { buf, .. } => *buf,
+                        StorageRequest::Read { .. } => unreachable!(),
+                    })
+                    .collect();
+                self.storage_device.write_vectored(offset, &bufs)?;
+            } else {
+                let mut bufs: Vec<&mut [u8]> = run
+                    .iter_mut()
+                    .map(|request| match &mut **request {
+                        StorageRequest::Read { buf, .. } => &mut **buf,
+                        StorageRequest::Write { .. } => unreachable!(),
+                    })
+                    .collect();
+                self.storage_device.read_vectored(offset, &mut bufs)?;
+            }
+
+            self.merged += (end - start - 1) as u64;
+            self.head = run_end;
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+/// Check whether ``request`` must be performed after the overlapping requests of ``group``,
+/// because either is a write.
+fn conflicts(group: &[StorageRequest<'_>], request: &StorageRequest<'_>) -> bool {
+    let start = request.offset();
+    let end = start + request.len() as u64;
+    group.iter().any(|other| {
+        (request.is_write() || other.is_write())
+            && other.offset() < end
+            && start < other.offset() + other.len() as u64
+    })
+}
+
+impl<S: StorageDevice> StorageDevice for ElevatorScheduler<S> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.storage_device.read(offset, buf)?;
+        self.head = offset + buf.len() as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.storage_device.write(offset, buf)?;
+        self.head = offset + buf.len() as u64;
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.storage_device.len()
+    }
+
+    fn read_vectored(&mut self, offset: u64, bufs: &mut [&mut [u8]]) -> StorageDeviceResult<()> {
+        self.storage_device.read_vectored(offset, bufs)
+    }
+
+    fn write_vectored(&mut self, offset: u64, bufs: &[&[u8]]) -> StorageDeviceResult<()> {
+        self.storage_device.write_vectored(offset, bufs)
+    }
+
+    /// Sort and merge the requests, then perform them.
+    ///
+    /// If a request fails, an error is returned. The requests sorted before it were performed,
+    /// and the ones sorted after it weren't, which doesn't match the order of the batch.
+    fn submit(&mut self, requests: &mut [StorageRequest<'_>]) -> StorageDeviceResult<()> {
+        if requests.is_empty() {
+            return Ok(());
+        }
+        let max_len = self.storage_device.info()?.geometry.max_transfer_size as u64;
+        let mut start = 0;
+        while start < requests.len() {
+            let mut end = start + 1;
+            while end < requests.len() && !conflicts(&requests[start..end], &requests[end]) {
+                end += 1;
+            }
+            self.dispatch(&mut requests[start..end], max_len)?;
+            start = end;
+        }
+        Ok(())
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.write_zeroes(offset, len)
+    }
+
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        self.storage_device.fill(offset, len, byte)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.discard(offset, len)
+    }
+
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        self.storage_device.next_data(offset)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.storage_device.write_fua(offset, buf)?;
+        self.head = offset + buf.len() as u64;
+        Ok(())
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.storage_device.capabilities()
+    }
+}
@@ -0,0 +1,186 @@
+use crate::remap::mix;
+use crate::sparse::Ranges;
+use crate::{StorageDevice, StorageDeviceResult};
+#[cfg(feature = "plain")]
+use plain::Plain;
+
+/// The size of the buffer the erase patterns are generated in, in bytes.
+const ERASE_CHUNK: usize = 512;
+
+/// What to overwrite erased data with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErasePattern {
+    /// Fill the range with the given byte.
+    Byte(u8),
+
+    /// Fill the range with pseudo-random bytes generated from the given seed.
+    ///
+    /// The bytes depend on their offset in the device, so a range erased in several requests
+    /// gets the same content as if it was erased at once.
+    Random(u64),
+}
+
+impl ErasePattern {
+    /// Fill ``buf``, which is at ``offset`` in the device, with the pattern.
+    fn fill(self, offset: u64, buf: &mut [u8]) {
+        match self {
+            ErasePattern::Byte(byte) => buf.fill(byte),
+            ErasePattern::Random(seed) => {
+                for (position, byte) in (offset..).zip(buf.iter_mut()) {
+                    *byte = (mix(seed ^ (position / 8)) >> ((position % 8) * 8)) as u8;
+                }
+            }
+        }
+    }
+}
+
+/// Types without padding, whose every byte is initialized, so they can be written as bytes.
+///
+/// # Safety
+///
+/// The type must not contain any padding, including at its end or between the variants of a
+/// union, as padding bytes are uninitialized and reading them is undefined behavior.
+pub unsafe trait NoPadding {}
+
+macro_rules! impl_no_padding {
+    ($($ty:ty),*) => {
+        $(
+            // SAFETY: primitive numbers don't contain padding.
+            unsafe impl NoPadding for $ty {}
+        )*
+    };
+}
+
+impl_no_padding!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+// SAFETY: the elements of an array or slice are laid out one after the other, without padding.
+unsafe impl<T: NoPadding, const N: usize> NoPadding for [T; N] {}
+
+// SAFETY: as above.
+unsafe impl<T: NoPadding> NoPadding for [T] {}
+
+/// Return the bytes of ``value``.
+pub(crate) fn as_bytes<T: NoPadding + ?Sized>(value: &T) -> &[u8] {
+    // SAFETY: T doesn't contain padding, so its size_of_val bytes are all initialized, and a u8
+    // has no alignment requirement.
+    unsafe {
+        core::slice::from_raw_parts(
+            value as *const T as *const u8,
+            core::mem::size_of_val(value),
+        )
+    }
+}
+
+/// Typed reads and writes, and erasure helpers, available on every [StorageDevice].
+///
+/// Values are transferred as their in-memory representation, so on-disk structures should be
+/// ``#[repr(C)]``, without padding, and use fields of explicit endianness where it matters.
+/// Reading requires ``plain::Plain``, with the ``plain`` feature, and writing requires
+/// [NoPadding].
+///
+/// With the ``bytemuck`` feature, types implementing ``bytemuck::Pod`` can be used as well,
+/// without also implementing ``plain::Plain``.
+pub trait StorageDeviceExt: StorageDevice {
+    /// Read a ``T`` stored at ``offset``.
+    #[cfg(feature = "plain")]
+    fn read_val<T: Plain>(&mut self, offset: u64) -> StorageDeviceResult<T> {
+        // Any bit pattern, including all zeros, is a valid Plain value.
+        let mut value: T = unsafe { core::mem::zeroed() };
+        self.read_into(offset, &mut value)?;
+        Ok(value)
+    }
+
+    /// Read the ``T`` stored at ``offset`` into ``value``.
+    #[cfg(feature = "plain")]
+    fn read_into<T: Plain + ?Sized>(
+        &mut self,
+        offset: u64,
+        value: &mut T,
+    ) -> StorageDeviceResult<()> {
+        // Writing arbitrary bytes to a Plain type is always fine.
+        let bytes = unsafe { plain::as_mut_bytes(value) };
+        self.read(offset, bytes)
+    }
+
+    /// Write ``value`` at ``offset``.
+    ///
+    /// ``T`` must implement [NoPadding], as padding bytes are uninitialized.
+    fn write_val<T: NoPadding + ?Sized>(
+        &mut self,
+        offset: u64,
+        value: &T,
+    ) -> StorageDeviceResult<()> {
+        self.write(offset, as_bytes(value))
+    }
+
+    /// Read a ``T`` stored at ``offset``.
+    #[cfg(feature = "bytemuck")]
+    fn read_pod<T: bytemuck::Pod>(&mut self, offset: u64) -> StorageDeviceResult<T> {
+        let mut value = T::zeroed();
+        self.read_pod_into(offset, core::slice::from_mut(&mut value))?;
+        Ok(value)
+    }
+
+    /// Read the ``T``s stored at ``offset`` into ``values``.
+    #[cfg(feature = "bytemuck")]
+    fn read_pod_into<T: bytemuck::Pod>(
+        &mut self,
+        offset: u64,
+        values: &mut [T],
+    ) -> StorageDeviceResult<()> {
+        self.read(offset, bytemuck::cast_slice_mut(values))
+    }
+
+    /// Write ``values`` at ``offset``.
+    ///
+    /// Use [core::slice::from_ref] to write a single value.
+    #[cfg(feature = "bytemuck")]
+    fn write_pod<T: bytemuck::NoUninit>(
+        &mut self,
+        offset: u64,
+        values: &[T],
+    ) -> StorageDeviceResult<()> {
+        self.write(offset, bytemuck::cast_slice(values))
+    }
+
+    /// Overwrite the ``len`` bytes at ``offset`` with ``pattern``.
+    fn erase(&mut self, offset: u64, len: u64, pattern: ErasePattern) -> StorageDeviceResult<()> {
+        let mut buf = [0u8; ERASE_CHUNK];
+        let mut position = offset;
+        let end = offset.saturating_add(len);
+        while position < end {
+            let chunk = &mut buf[..core::cmp::min(ERASE_CHUNK as u64, end - position) as usize];
+            pattern.fill(position, chunk);
+            self.write(position, chunk)?;
+            position += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Erase the whole device, so that its previous content can't be recovered.
+    ///
+    /// A device-level erase is used if the device supports it, see [StorageDevice::sanitize].
+    /// Otherwise the device is overwritten with each of the ``passes`` in turn, flushing after
+    /// each of them. Overwriting doesn't reach the copies of the data a flash device may keep
+    /// internally, so prefer encrypting sensitive data in the first place.
+    fn secure_erase(&mut self, passes: &[ErasePattern]) -> StorageDeviceResult<()> {
+        match self.sanitize() {
+            Err(err) if err.is_unsupported() => (),
+            result => return result,
+        }
+
+        let len = self.len()?;
+        for pattern in passes {
+            self.erase(0, len, *pattern)?;
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Iterate over the data and holes of the device, see [StorageDevice::next_data].
+    fn ranges(&mut self) -> Ranges<'_, Self> {
+        Ranges::new(self)
+    }
+}
+
+impl<S: StorageDevice + ?Sized> StorageDeviceExt for S {}
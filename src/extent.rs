@@ -0,0 +1,179 @@
+use crate::{StorageDevice, StorageDeviceResult, StorageRequest};
+
+/// Maximum amount of extents handled at once by [read_extents] and [write_extents].
+const EXTENT_BATCH: usize = 16;
+
+/// A contiguous range of a storage device, mapped to a range of a buffer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Extent {
+    /// The offset of the range in the storage device.
+    pub device_offset: u64,
+
+    /// The offset of the range in the buffer.
+    pub buf_offset: usize,
+
+    /// The length of the range in bytes.
+    pub len: usize,
+}
+
+impl Extent {
+    /// Create a new extent mapping ``len`` bytes at ``device_offset`` to ``buf_offset``.
+    pub fn new(device_offset: u64, buf_offset: usize, len: usize) -> Self {
+        Extent {
+            device_offset,
+            buf_offset,
+            len,
+        }
+    }
+
+    /// Return the offset in the storage device right after the extent.
+    fn device_end(&self) -> u64 {
+        self.device_offset + self.len as u64
+    }
+}
+
+/// Split ``buf`` into the parts covered by ``extents``, keeping the order of ``extents``.
+///
+/// Panics if the extents overlap in the buffer, or go past its end.
+fn split_extents<'a>(
+    buf: &'a mut [u8],
+    extents: &[Extent],
+    slices: &mut [&'a mut [u8]; EXTENT_BATCH],
+) {
+    let mut order = [0usize; EXTENT_BATCH];
+    for (i, entry) in order.iter_mut().enumerate().take(extents.len()) {
+        *entry = i;
+    }
+    order[..extents.len()].sort_unstable_by_key(|&i| extents[i].buf_offset);
+
+    // Walk the buffer in order, handing out the parts covered by the extents.
+    let mut remaining = buf;
+    let mut consumed = 0;
+    for &i in order[..extents.len()].iter() {
+        let extent = &extents[i];
+        assert!(
+            extent.buf_offset >= consumed,
+            "extents must not overlap in the buffer"
+        );
+
+        let (_, tail) = core::mem::take(&mut remaining).split_at_mut(extent.buf_offset - consumed);
+        let (slice, tail) = tail.split_at_mut(extent.len);
+        slices[i] = slice;
+        remaining = tail;
+        consumed = extent.buf_offset + extent.len;
+    }
+}
+
+/// Return the length of the run of extents contiguous on the device starting at the beginning of
+/// ``extents``.
+fn contiguous_run_len(extents: &[Extent]) -> usize {
+    let mut run_len = 1;
+    while run_len < extents.len()
+        && extents[run_len - 1].device_end() == extents[run_len].device_offset
+    {
+        run_len += 1;
+    }
+    run_len
+}
+
+/// Read every extent of ``extents`` from ``device`` into ``buf``.
+///
+/// The extents are sorted by device offset. Extents contiguous on the device are read with a
+/// single vectored read, and the others are submitted together as a batch, so devices with
+/// scatter-gather or command queue support can serve them efficiently.
+///
+/// Panics if the extents overlap in the buffer, or go past its end.
+pub fn read_extents<S: StorageDevice + ?Sized>(
+    device: &mut S,
+    buf: &mut [u8],
+    extents: &mut [Extent],
+) -> StorageDeviceResult<()> {
+    extents.sort_unstable_by_key(|extent| extent.device_offset);
+
+    for chunk in extents.chunks(EXTENT_BATCH) {
+        let mut slices: [&mut [u8]; EXTENT_BATCH] = Default::default();
+        split_extents(buf, chunk, &mut slices);
+
+        let mut requests: [StorageRequest<'_>; EXTENT_BATCH] =
+            core::array::from_fn(|_| StorageRequest::Read {
+                offset: 0,
+                buf: &mut [],
+            });
+        let mut request_count = 0;
+
+        let mut i = 0;
+        while i < chunk.len() {
+            let run_len = contiguous_run_len(&chunk[i..]);
+            if run_len == 1 {
+                requests[request_count] = StorageRequest::Read {
+                    offset: chunk[i].device_offset,
+                    buf: core::mem::take(&mut slices[i]),
+                };
+                request_count += 1;
+            } else {
+                device.read_vectored(chunk[i].device_offset, &mut slices[i..i + run_len])?;
+            }
+            i += run_len;
+        }
+
+        device.submit(&mut requests[..request_count])?;
+    }
+
+    Ok(())
+}
+
+/// Write every extent of ``extents`` from ``buf`` to ``device``.
+///
+/// The extents are sorted by device offset. Extents contiguous on the device are written with a
+/// single vectored write, and the others are submitted together as a batch, so devices with
+/// scatter-gather or command queue support can serve them efficiently.
+///
+/// Panics if the extents overlap on the device, or go past the end of the buffer.
+pub fn write_extents<S: StorageDevice + ?Sized>(
+    device: &mut S,
+    buf: &[u8],
+    extents: &mut [Extent],
+) -> StorageDeviceResult<()> {
+    extents.sort_unstable_by_key(|extent| extent.device_offset);
+
+    // The order of the extents was lost, so overlapping writes would be ambiguous.
+    for pair in extents.windows(2) {
+        assert!(
+            pair[0].device_end() <= pair[1].device_offset,
+            "extents must not overlap on the device"
+        );
+    }
+
+    for chunk in extents.chunks(EXTENT_BATCH) {
+        let mut slices: [&[u8]; EXTENT_BATCH] = Default::default();
+        for (slice, extent) in slices.iter_mut().zip(chunk.iter()) {
+            *slice = &buf[extent.buf_offset..extent.buf_offset + extent.len];
+        }
+
+        let mut requests: [StorageRequest<'_>; EXTENT_BATCH] =
+            core::array::from_fn(|_| StorageRequest::Write {
+                offset: 0,
+                buf: &[],
+            });
+        let mut request_count = 0;
+
+        let mut i = 0;
+        while i < chunk.len() {
+            let run_len = contiguous_run_len(&chunk[i..]);
+            if run_len == 1 {
+                requests[request_count] = StorageRequest::Write {
+                    offset: chunk[i].device_offset,
+                    buf: slices[i],
+                };
+                request_count += 1;
+            } else {
+                device.write_vectored(chunk[i].device_offset, &slices[i..i + run_len])?;
+            }
+            i += run_len;
+        }
+
+        device.submit(&mut requests[..request_count])?;
+    }
+
+    Ok(())
+}
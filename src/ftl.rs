@@ -0,0 +1,851 @@
+use crate::crc::crc32;
+use crate::nand::{NandDevice, NandGeometry};
+use crate::{Block, BlockCount, BlockDevice, BlockError, BlockIndex, BlockResult, Capabilities};
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+
+/// The minimum number of erase blocks an [Ftl] keeps out of its logical capacity.
+///
+/// Two are kept free for the garbage collector, and the others guarantee that some erase block
+/// holds invalid pages to collect.
+pub const MIN_RESERVED_ERASE_BLOCKS: u64 = 4;
+
+/// The number of free erase blocks at which the garbage collector runs, which it may use to move
+/// valid pages, even if the erase block it collects fails to erase.
+const GC_FREE_ERASE_BLOCKS: u64 = 2;
+
+/// The number of bytes of the spare area of each page used by an [Ftl].
+///
+/// The first two are left erased, for the bad block marker.
+pub const FTL_SPARE_LEN: usize = 22;
+
+/// Where the [PageTag] starts in the spare area, after the bad block marker.
+const TAG_OFFSET: usize = 2;
+
+/// The number of times programming a logical page is attempted, on a new page each time.
+const PROGRAM_ATTEMPTS: usize = 3;
+
+/// The marker of an unmapped logical page, or of a physical page without valid data.
+const UNMAPPED: u32 = u32::MAX;
+
+/// The bit set in the logical page of the tag of a tombstone, a page recording that the logical
+/// page was discarded.
+const TOMBSTONE: u32 = 1 << 31;
+
+/// Return the default number of erase blocks reserved by an [Ftl] on a device of ``geometry``:
+/// about 3% of the device, and at least [MIN_RESERVED_ERASE_BLOCKS].
+pub fn default_reserved_erase_blocks(geometry: &NandGeometry) -> u64 {
+    core::cmp::max(MIN_RESERVED_ERASE_BLOCKS, geometry.erase_blocks / 32)
+}
+
+/// The metadata stored in the spare area of each page programmed by an [Ftl].
+#[derive(Debug, Copy, Clone)]
+struct PageTag {
+    /// The logical page the page holds, with [TOMBSTONE] set if it records its discard instead.
+    logical: u32,
+
+    /// The number of pages programmed before this one, telling the newest copy of a logical page.
+    sequence: u64,
+
+    /// The number of times the erase block of the page was erased.
+    erase_count: u32,
+}
+
+/// The state of a page, according to its spare area.
+enum SpareState {
+    /// The page is erased.
+    Erased,
+
+    /// The page was programmed, but its metadata is unreadable, e.g. after a power loss.
+    Garbage,
+
+    /// The page holds a logical page.
+    Tagged(PageTag),
+}
+
+impl PageTag {
+    /// Encode the tag as stored in the spare area.
+    fn to_bytes(self) -> [u8; FTL_SPARE_LEN] {
+        let mut spare = [0xFF; FTL_SPARE_LEN];
+        let bytes = &mut spare[TAG_OFFSET..];
+        bytes[0..4].copy_from_slice(&self.logical.to_le_bytes());
+        bytes[4..12].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.erase_count.to_le_bytes());
+        let crc = crc32(&bytes[..16]);
+        bytes[16..20].copy_from_slice(&crc.to_le_bytes());
+        spare
+    }
+
+    /// Decode the spare area of a page.
+    fn parse(spare: &[u8; FTL_SPARE_LEN]) -> SpareState {
+        let bytes = &spare[TAG_OFFSET..];
+        if bytes.iter().all(|&byte| byte == 0xFF) {
+            return SpareState::Erased;
+        }
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        if word(16) != crc32(&bytes[..16]) {
+            return SpareState::Garbage;
+        }
+        SpareState::Tagged(PageTag {
+            logical: word(0),
+            sequence: u64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+            erase_count: word(12),
+        })
+    }
+}
+
+/// A flash translation layer, presenting a [NandDevice] as an ordinary [BlockDevice].
+///
+/// Logical pages are written out of place: each write programs the next free page, and the
+/// previous copy of the logical page becomes invalid. When free erase blocks run low, the garbage
+/// collector picks the erase block with the fewest valid pages, moves them elsewhere and erases
+/// it. Free erase blocks are used least erased first, spreading the wear over the device.
+///
+/// Each page records in its spare area which logical page it holds, the order it was programmed
+/// in, and the erase count of its erase block, protected by a CRC, so the mapping is rebuilt by
+/// [Ftl::mount] after a restart or a power loss. The erase count of erase blocks that are free
+/// when mounting isn't known, and is estimated as the average of the others.
+///
+/// Logical blocks never written read as zeros. Writes smaller than a page read the rest of the
+/// page first. Discarding whole pages frees them for the garbage collector, which makes it much
+/// more efficient on a device with free space. A discarded page is recorded by a tombstone, a page
+/// with only its spare area programmed, which hides the older copies of the logical page when
+/// mounting, until they are all erased.
+///
+/// Erase blocks marked bad are never used. Erase blocks failing to erase are marked bad and
+/// retired, and those failing to program are closed and retired once garbage collected. They are
+/// taken out of the reserved erase blocks: once too many went bad, writes fail with
+/// [BlockError::OutOfSpace].
+///
+/// The data of the pages isn't checked, use an error-correcting layer on the device for this.
+pub struct Ftl<N: NandDevice> {
+    /// The raw flash.
+    nand: N,
+
+    /// The layout of the flash.
+    geometry: NandGeometry,
+
+    /// The number of logical pages exposed.
+    logical_pages: u32,
+
+    /// The physical page holding each logical page, or [UNMAPPED].
+    l2p: Vec<u32>,
+
+    /// The physical page holding the tombstone of each logical page, or [UNMAPPED] if it has
+    /// none, or doesn't need one as no older copy of it is left.
+    tombstones: Vec<u32>,
+
+    /// The number of older copies of each logical page, left on erase blocks not erased yet.
+    stale: Vec<u32>,
+
+    /// The logical page held by each physical page, with [TOMBSTONE] set if it holds the
+    /// tombstone of the logical page, or [UNMAPPED] if it holds no valid data.
+    p2l: Vec<u32>,
+
+    /// The number of pages holding valid data in each erase block.
+    valid: Vec<u32>,
+
+    /// The number of times each erase block was erased.
+    erase_counts: Vec<u32>,
+
+    /// Whether each erase block is erased and unused.
+    free: Vec<bool>,
+
+    /// Whether each erase block is bad.
+    bad: Vec<bool>,
+
+    /// Whether each erase block failed to program, and must be retired once garbage collected.
+    failing: Vec<bool>,
+
+    /// The number of free erase blocks.
+    free_count: u64,
+
+    /// The erase block being written, and the index of its next free page.
+    active: Option<(u64, u32)>,
+
+    /// The sequence number of the next page programmed.
+    sequence: u64,
+
+    /// Whether the garbage collector is running, and may use the last free erase blocks.
+    collecting: bool,
+
+    /// The number of erase blocks garbage collected so far.
+    garbage_collections: u64,
+}
+
+impl<N: NandDevice> Ftl<N> {
+    /// Erase the whole of ``nand`` and create an empty translation layer on it, keeping
+    /// ``reserved_erase_blocks`` erase blocks out of its capacity.
+    ///
+    /// [default_reserved_erase_blocks] gives a sensible value. The same value must be given to
+    /// [Ftl::mount] later.
+    pub fn format(nand: N, reserved_erase_blocks: u64) -> BlockResult<Self> {
+        let mut ftl = Self::empty(nand, reserved_erase_blocks)?;
+        for erase_block in 0..ftl.geometry.erase_blocks {
+            if ftl.nand.is_bad(erase_block)? {
+                ftl.bad[erase_block as usize] = true;
+                continue;
+            }
+            match ftl.nand.erase(erase_block) {
+                Ok(()) => {
+                    ftl.free[erase_block as usize] = true;
+                    ftl.free_count += 1;
+                }
+                Err(BlockError::MediaError) => ftl.retire(erase_block),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(ftl)
+    }
+
+    /// Rebuild the translation layer stored on ``nand`` by [Ftl::format] and subsequent writes,
+    /// scanning the spare area of every page.
+    pub fn mount(nand: N, reserved_erase_blocks: u64) -> BlockResult<Self> {
+        let mut ftl = Self::empty(nand, reserved_erase_blocks)?;
+        let pages_per_erase_block = ftl.geometry.pages_per_erase_block;
+        let mut sequences = alloc::vec![0u64; ftl.logical_pages as usize];
+        let mut spare = [0; FTL_SPARE_LEN];
+        let (mut used, mut total_erase_count) = (0u64, 0u64);
+
+        for erase_block in 0..ftl.geometry.erase_blocks {
+            if ftl.nand.is_bad(erase_block)? {
+                ftl.bad[erase_block as usize] = true;
+                continue;
+            }
+
+            let first_page = ftl.geometry.first_page(erase_block);
+            let mut programmed = false;
+            for page in first_page..first_page + u64::from(pages_per_erase_block) {
+                ftl.nand.read_page(page, &mut [], &mut spare)?;
+                let tag = match PageTag::parse(&spare) {
+                    SpareState::Erased => continue,
+                    SpareState::Garbage => {
+                        programmed = true;
+                        continue;
+                    }
+                    SpareState::Tagged(tag) => tag,
+                };
+                programmed = true;
+                ftl.sequence = core::cmp::max(ftl.sequence, tag.sequence + 1);
+                let erase_count = &mut ftl.erase_counts[erase_block as usize];
+                *erase_count = core::cmp::max(*erase_count, tag.erase_count);
+
+                // Until the scan is done, l2p holds the newest page of each logical page, with
+                // TOMBSTONE set if it is a tombstone, and stale counts the copies of its data.
+                let logical = (tag.logical & !TOMBSTONE) as usize;
+                if logical >= ftl.logical_pages as usize {
+                    continue;
+                }
+                if tag.logical & TOMBSTONE == 0 {
+                    ftl.stale[logical] += 1;
+                }
+                if ftl.l2p[logical] == UNMAPPED || tag.sequence > sequences[logical] {
+                    ftl.l2p[logical] = page as u32 | (tag.logical & TOMBSTONE);
+                    sequences[logical] = tag.sequence;
+                }
+            }
+
+            if programmed {
+                used += 1;
+                total_erase_count += u64::from(ftl.erase_counts[erase_block as usize]);
+            } else {
+                ftl.free[erase_block as usize] = true;
+                ftl.free_count += 1;
+            }
+        }
+
+        let estimate = total_erase_count.checked_div(used).unwrap_or(0) as u32;
+        for erase_block in 0..ftl.geometry.erase_blocks as usize {
+            if ftl.free[erase_block] || ftl.bad[erase_block] {
+                ftl.erase_counts[erase_block] = estimate;
+            }
+        }
+
+        for logical in 0..ftl.logical_pages {
+            let index = logical as usize;
+            let newest = ftl.l2p[index];
+            if newest == UNMAPPED {
+                continue;
+            }
+            let page = newest & !TOMBSTONE;
+            if newest & TOMBSTONE == 0 {
+                ftl.stale[index] -= 1;
+                ftl.p2l[page as usize] = logical;
+            } else {
+                ftl.l2p[index] = UNMAPPED;
+                if ftl.stale[index] == 0 {
+                    continue;
+                }
+                ftl.tombstones[index] = page;
+                ftl.p2l[page as usize] = logical | TOMBSTONE;
+            }
+            ftl.valid[ftl.geometry.erase_block_of(u64::from(page)) as usize] += 1;
+        }
+        Ok(ftl)
+    }
+
+    /// Create the state of a translation layer with no data and no free erase block.
+    fn empty(nand: N, reserved_erase_blocks: u64) -> BlockResult<Self> {
+        let geometry = nand.geometry();
+        let pages = geometry.pages();
+        if geometry.page_size == 0
+            || !geometry.page_size.is_multiple_of(Block::LEN)
+            || geometry.spare_size < FTL_SPARE_LEN
+            || geometry.pages_per_erase_block == 0
+            || reserved_erase_blocks < MIN_RESERVED_ERASE_BLOCKS
+            || geometry.erase_blocks <= reserved_erase_blocks
+            || pages > u64::from(TOMBSTONE)
+        {
+            return Err(BlockError::Unsupported);
+        }
+
+        let erase_blocks =
+            usize::try_from(geometry.erase_blocks).map_err(|_| BlockError::Unsupported)?;
+        let logical_pages = ((geometry.erase_blocks - reserved_erase_blocks)
+            * u64::from(geometry.pages_per_erase_block)) as u32;
+
+        Ok(Ftl {
+            nand,
+            geometry,
+            logical_pages,
+            l2p: alloc::vec![UNMAPPED; logical_pages as usize],
+            tombstones: alloc::vec![UNMAPPED; logical_pages as usize],
+            stale: alloc::vec![0; logical_pages as usize],
+            p2l: alloc::vec![UNMAPPED; pages as usize],
+            valid: alloc::vec![0; erase_blocks],
+            erase_counts: alloc::vec![0; erase_blocks],
+            free: alloc::vec![false; erase_blocks],
+            bad: alloc::vec![false; erase_blocks],
+            failing: alloc::vec![false; erase_blocks],
+            free_count: 0,
+            active: None,
+            sequence: 0,
+            collecting: false,
+            garbage_collections: 0,
+        })
+    }
+
+    /// Return the layout of the flash.
+    pub fn geometry(&self) -> NandGeometry {
+        self.geometry
+    }
+
+    /// Return the number of times each erase block was erased.
+    pub fn erase_counts(&self) -> &[u32] {
+        &self.erase_counts
+    }
+
+    /// Return the number of erase blocks that are erased and unused.
+    pub fn free_erase_blocks(&self) -> u64 {
+        self.free_count
+    }
+
+    /// Return the number of bad erase blocks.
+    pub fn bad_erase_blocks(&self) -> u64 {
+        self.bad.iter().filter(|&&bad| bad).count() as u64
+    }
+
+    /// Return the number of erase blocks garbage collected so far.
+    pub fn garbage_collections(&self) -> u64 {
+        self.garbage_collections
+    }
+
+    /// Return a reference to the raw flash.
+    pub fn get_ref(&self) -> &N {
+        &self.nand
+    }
+
+    /// Consume the translation layer, returning the raw flash.
+    pub fn into_inner(self) -> N {
+        self.nand
+    }
+
+    /// Mark ``logical`` as not holding any data, its current copy becoming an older one.
+    fn unmap(&mut self, logical: u32) {
+        let page = core::mem::replace(&mut self.l2p[logical as usize], UNMAPPED);
+        if page != UNMAPPED {
+            self.invalidate(page);
+            self.stale[logical as usize] += 1;
+        }
+    }
+
+    /// Drop the tombstone of ``logical``, once a newer copy of it is written, or no older copy
+    /// of it is left to hide.
+    fn drop_tombstone(&mut self, logical: u32) {
+        let page = core::mem::replace(&mut self.tombstones[logical as usize], UNMAPPED);
+        if page != UNMAPPED {
+            self.invalidate(page);
+        }
+    }
+
+    /// Mark ``page`` as not holding valid data.
+    fn invalidate(&mut self, page: u32) {
+        self.p2l[page as usize] = UNMAPPED;
+        self.valid[self.geometry.erase_block_of(u64::from(page)) as usize] -= 1;
+    }
+
+    /// Forget the older copies of logical pages held by ``erase_block``, which holds no valid
+    /// data and is about to be erased or retired, dropping the tombstones no longer needed.
+    fn forget_stale(&mut self, erase_block: u64) -> BlockResult<()> {
+        let mut spare = [0; FTL_SPARE_LEN];
+        let first_page = self.geometry.first_page(erase_block);
+        for page in first_page..first_page + u64::from(self.geometry.pages_per_erase_block) {
+            self.nand.read_page(page, &mut [], &mut spare)?;
+            if let SpareState::Tagged(tag) = PageTag::parse(&spare) {
+                let logical = tag.logical as usize;
+                if logical >= self.logical_pages as usize {
+                    continue;
+                }
+                // Pages whose program failed may hold a tag never counted.
+                self.stale[logical] = self.stale[logical].saturating_sub(1);
+                if self.stale[logical] == 0 {
+                    self.drop_tombstone(tag.logical);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the next free page, opening a new erase block if needed, after garbage collecting
+    /// until more than [GC_FREE_ERASE_BLOCKS] erase blocks are free.
+    fn next_page(&mut self) -> BlockResult<u64> {
+        if !self.collecting {
+            while self.free_count <= GC_FREE_ERASE_BLOCKS {
+                self.collect()?;
+            }
+        }
+
+        loop {
+            if let Some((erase_block, next)) = &mut self.active {
+                if *next < self.geometry.pages_per_erase_block {
+                    let page = self.geometry.first_page(*erase_block) + u64::from(*next);
+                    *next += 1;
+                    return Ok(page);
+                }
+            }
+
+            // Dynamic wear leveling: use the least erased free block.
+            let erase_block = (0..self.geometry.erase_blocks)
+                .filter(|&erase_block| self.free[erase_block as usize])
+                .min_by_key(|&erase_block| self.erase_counts[erase_block as usize])
+                .ok_or(BlockError::OutOfSpace)?;
+            self.free[erase_block as usize] = false;
+            self.free_count -= 1;
+            self.active = Some((erase_block, 0));
+        }
+    }
+
+    /// Mark ``erase_block``, which holds no valid data, as bad.
+    fn retire(&mut self, erase_block: u64) {
+        // The erase block is never used again anyway, even if the marker can't be written.
+        let _ = self.nand.mark_bad(erase_block);
+        self.bad[erase_block as usize] = true;
+    }
+
+    /// Program ``data`` as the new copy of ``logical``.
+    fn program(&mut self, logical: u32, data: &[Block]) -> BlockResult<()> {
+        let page = self.program_tagged(logical, data)?;
+        self.unmap(logical);
+        self.drop_tombstone(logical);
+        self.l2p[logical as usize] = page;
+        self.p2l[page as usize] = logical;
+        self.valid[self.geometry.erase_block_of(u64::from(page)) as usize] += 1;
+        Ok(())
+    }
+
+    /// Program a tombstone for ``logical``, which is unmapped, replacing its current one.
+    ///
+    /// Only the spare area of the page is programmed, unless the device doesn't support it.
+    fn program_tombstone(&mut self, logical: u32) -> BlockResult<()> {
+        let page = match self.program_tagged(logical | TOMBSTONE, &[]) {
+            Err(BlockError::Unsupported) => {
+                let page_blocks = self.geometry.page_blocks();
+                self.program_tagged(logical | TOMBSTONE, &alloc::vec![Block::new(); page_blocks])?
+            }
+            result => result?,
+        };
+        self.drop_tombstone(logical);
+        self.tombstones[logical as usize] = page;
+        self.p2l[page as usize] = logical | TOMBSTONE;
+        self.valid[self.geometry.erase_block_of(u64::from(page)) as usize] += 1;
+        Ok(())
+    }
+
+    /// Program ``data`` on the next free page with a tag holding ``logical``, and return the
+    /// page.
+    ///
+    /// If the medium fails to program it, the erase block is closed, to be retired when garbage
+    /// collected, and the next free page is tried.
+    fn program_tagged(&mut self, logical: u32, data: &[Block]) -> BlockResult<u32> {
+        let mut attempts = 0;
+        loop {
+            let page = self.next_page()?;
+            let erase_block = self.geometry.erase_block_of(page);
+            let tag = PageTag {
+                logical,
+                sequence: self.sequence,
+                erase_count: self.erase_counts[erase_block as usize],
+            };
+            self.sequence += 1;
+            match self.nand.program_page(page, data, &tag.to_bytes()) {
+                Ok(()) => return Ok(page as u32),
+                Err(BlockError::MediaError) if attempts + 1 < PROGRAM_ATTEMPTS => {
+                    attempts += 1;
+                    self.failing[erase_block as usize] = true;
+                    self.active = None;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Free the used erase block with the fewest valid pages, moving them elsewhere.
+    fn collect(&mut self) -> BlockResult<()> {
+        let active = self.active.map(|(erase_block, _)| erase_block);
+        let victim = (0..self.geometry.erase_blocks)
+            .filter(|&erase_block| {
+                let index = erase_block as usize;
+                !self.free[index] && !self.bad[index] && Some(erase_block) != active
+            })
+            .min_by_key(|&erase_block| {
+                (
+                    self.valid[erase_block as usize],
+                    self.erase_counts[erase_block as usize],
+                )
+            })
+            .ok_or(BlockError::OutOfSpace)?;
+        if self.valid[victim as usize] == self.geometry.pages_per_erase_block {
+            return Err(BlockError::OutOfSpace);
+        }
+
+        self.collecting = true;
+        let result = self.relocate(victim);
+        self.collecting = false;
+        result?;
+
+        self.forget_stale(victim)?;
+        self.garbage_collections += 1;
+        if core::mem::replace(&mut self.failing[victim as usize], false) {
+            self.retire(victim);
+            return Ok(());
+        }
+        match self.nand.erase(victim) {
+            Ok(()) => {
+                self.erase_counts[victim as usize] += 1;
+                self.free[victim as usize] = true;
+                self.free_count += 1;
+                Ok(())
+            }
+            Err(BlockError::MediaError) => {
+                self.retire(victim);
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Move the valid pages of ``erase_block`` to other erase blocks.
+    fn relocate(&mut self, erase_block: u64) -> BlockResult<()> {
+        let mut buffer = alloc::vec![Block::new(); self.geometry.page_blocks()];
+        let first_page = self.geometry.first_page(erase_block);
+        for page in first_page..first_page + u64::from(self.geometry.pages_per_erase_block) {
+            let logical = self.p2l[page as usize];
+            if logical == UNMAPPED {
+                continue;
+            }
+            if logical & TOMBSTONE != 0 {
+                self.program_tombstone(logical & !TOMBSTONE)?;
+            } else {
+                self.nand.read_page(page, &mut buffer, &mut [])?;
+                self.program(logical, &buffer)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that ``count`` blocks at ``index`` are inside the device.
+    fn check_bounds(&self, index: BlockIndex, count: usize, error: BlockError) -> BlockResult<()> {
+        let blocks = u64::from(self.logical_pages) * self.geometry.page_blocks() as u64;
+        match index.0.checked_add(count as u64) {
+            Some(end) if end <= blocks => Ok(()),
+            _ => Err(error),
+        }
+    }
+}
+
+impl<N: NandDevice> core::fmt::Debug for Ftl<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Ftl")
+            .field("nand", &self.nand)
+            .field("logical_pages", &self.logical_pages)
+            .field("free_count", &self.free_count)
+            .field("active", &self.active)
+            .field("sequence", &self.sequence)
+            .field("garbage_collections", &self.garbage_collections)
+            .finish()
+    }
+}
+
+impl<N: NandDevice> BlockDevice for Ftl<N> {
+    fn read(&mut self, mut blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        self.check_bounds(index, blocks.len(), BlockError::ReadError)?;
+        let page_blocks = self.geometry.page_blocks();
+        let mut buffer = Vec::new();
+        let mut index = index.0;
+        while !blocks.is_empty() {
+            let logical = (index / page_blocks as u64) as usize;
+            let in_page = (index % page_blocks as u64) as usize;
+            let run = core::cmp::min(page_blocks - in_page, blocks.len());
+            let (head, tail) = blocks.split_at_mut(run);
+
+            match self.l2p[logical] {
+                UNMAPPED => head.fill(Block::new()),
+                page if run == page_blocks => {
+                    self.nand.read_page(u64::from(page), head, &mut [])?
+                }
+                page => {
+                    buffer.resize(page_blocks, Block::new());
+                    self.nand.read_page(u64::from(page), &mut buffer, &mut [])?;
+                    head.clone_from_slice(&buffer[in_page..in_page + run]);
+                }
+            }
+            blocks = tail;
+            index += run as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, mut blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.check_bounds(index, blocks.len(), BlockError::WriteError)?;
+        let page_blocks = self.geometry.page_blocks();
+        let mut buffer = Vec::new();
+        let mut index = index.0;
+        while !blocks.is_empty() {
+            let logical = (index / page_blocks as u64) as u32;
+            let in_page = (index % page_blocks as u64) as usize;
+            let run = core::cmp::min(page_blocks - in_page, blocks.len());
+            let (head, tail) = blocks.split_at(run);
+
+            if run == page_blocks {
+                self.program(logical, head)?;
+            } else {
+                buffer.clear();
+                buffer.resize(page_blocks, Block::new());
+                let page = self.l2p[logical as usize];
+                if page != UNMAPPED {
+                    self.nand.read_page(u64::from(page), &mut buffer, &mut [])?;
+                }
+                buffer[in_page..in_page + run].clone_from_slice(head);
+                self.program(logical, &buffer)?;
+            }
+            blocks = tail;
+            index += run as u64;
+        }
+        Ok(())
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        Ok(BlockCount(
+            u64::from(self.logical_pages) * self.geometry.page_blocks() as u64,
+        ))
+    }
+
+    /// Unmap the pages fully covered by the range, which read as zeros afterwards, recording it
+    /// with a tombstone for each page written before.
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        let page_blocks = self.geometry.page_blocks() as u64;
+        let end = index.0.saturating_add(count.0);
+        let first = index.0.div_ceil(page_blocks);
+        let last = core::cmp::min(end / page_blocks, u64::from(self.logical_pages));
+        for logical in first..last {
+            let logical = logical as u32;
+            if self.l2p[logical as usize] != UNMAPPED {
+                self.unmap(logical);
+                self.program_tombstone(logical)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards unmap pages, which garbage collection reclaims.
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        Ok(Capabilities::TRIM)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nand::MemoryNand;
+
+    /// The layout of the flash of the tests: 16 erase blocks of 4 pages of 2 blocks.
+    const GEOMETRY: NandGeometry = NandGeometry {
+        page_size: 2 * Block::LEN,
+        spare_size: 32,
+        pages_per_erase_block: 4,
+        erase_blocks: 16,
+    };
+
+    /// The number of logical blocks of the translation layer of the tests.
+    const BLOCKS: usize = 12 * 4 * 2;
+
+    /// A flash failing to program the data of a given page, once.
+    #[derive(Debug)]
+    struct FailingNand {
+        nand: MemoryNand,
+        failing_page: Option<u64>,
+    }
+
+    impl NandDevice for FailingNand {
+        fn geometry(&self) -> NandGeometry {
+            self.nand.geometry()
+        }
+
+        fn read_page(
+            &mut self,
+            page: u64,
+            data: &mut [Block],
+            spare: &mut [u8],
+        ) -> BlockResult<()> {
+            self.nand.read_page(page, data, spare)
+        }
+
+        fn program_page(&mut self, page: u64, data: &[Block], spare: &[u8]) -> BlockResult<()> {
+            if !data.is_empty() && self.failing_page == Some(page) {
+                self.failing_page = None;
+                return Err(BlockError::MediaError);
+            }
+            self.nand.program_page(page, data, spare)
+        }
+
+        fn erase(&mut self, erase_block: u64) -> BlockResult<()> {
+            self.nand.erase(erase_block)
+        }
+    }
+
+    /// Return a block filled with ``byte``.
+    fn block(byte: u8) -> Block {
+        Block {
+            contents: [byte; Block::LEN],
+        }
+    }
+
+    /// Return the byte filling each block of ``ftl``.
+    fn content<N: NandDevice>(ftl: &mut Ftl<N>) -> Vec<u8> {
+        let mut blocks = alloc::vec![Block::new(); BLOCKS];
+        ftl.read(&mut blocks, BlockIndex(0)).unwrap();
+        blocks
+            .iter()
+            .map(|block| {
+                assert!(block.iter().all(|byte| *byte == block[0]));
+                block[0]
+            })
+            .collect()
+    }
+
+    /// Format a translation layer on an erased flash.
+    fn formatted() -> Ftl<MemoryNand> {
+        Ftl::format(MemoryNand::new(GEOMETRY), MIN_RESERVED_ERASE_BLOCKS).unwrap()
+    }
+
+    /// Mount the translation layer stored on ``nand``.
+    fn remount<N: NandDevice>(nand: N) -> Ftl<N> {
+        Ftl::mount(nand, MIN_RESERVED_ERASE_BLOCKS).unwrap()
+    }
+
+    #[test]
+    fn writes_survive_remounting() {
+        let mut ftl = formatted();
+        assert_eq!(ftl.count(), Ok(BlockCount(BLOCKS as u64)));
+        ftl.write(&[block(1), block(2), block(3)], BlockIndex(4))
+            .unwrap();
+        // Writing half a page keeps the other half.
+        ftl.write(&[block(4)], BlockIndex(5)).unwrap();
+
+        let mut expected = alloc::vec![0u8; BLOCKS];
+        expected[4..7].copy_from_slice(&[1, 4, 3]);
+        assert_eq!(content(&mut ftl), expected);
+        let mut ftl = remount(ftl.into_inner());
+        assert_eq!(content(&mut ftl), expected);
+        assert_eq!(
+            ftl.write(&[block(5)], BlockIndex(BLOCKS as u64)),
+            Err(BlockError::WriteError)
+        );
+    }
+
+    #[test]
+    fn tombstones_hide_discarded_pages_when_remounting() {
+        let mut ftl = formatted();
+        ftl.write(&[block(1), block(1)], BlockIndex(0)).unwrap();
+        ftl.write(&[block(2), block(2)], BlockIndex(0)).unwrap();
+        ftl.write(&[block(3), block(3)], BlockIndex(2)).unwrap();
+        // Only whole pages are discarded.
+        ftl.discard(BlockIndex(0), BlockCount(3)).unwrap();
+
+        let mut expected = alloc::vec![0u8; BLOCKS];
+        expected[2..4].fill(3);
+        assert_eq!(content(&mut ftl), expected);
+
+        // Both older copies of the discarded page are still on the flash.
+        assert_eq!(ftl.garbage_collections(), 0);
+        let mut ftl = remount(ftl.into_inner());
+        assert_eq!(content(&mut ftl), expected);
+    }
+
+    #[test]
+    fn garbage_collection_frees_erase_blocks_of_a_full_device() {
+        let mut ftl = formatted();
+        let mut expected = alloc::vec![0u8; BLOCKS];
+        for round in 1..=6u8 {
+            for (i, byte) in expected.iter_mut().enumerate() {
+                // Rewrite a varying part of the device, leaving erase blocks partly valid.
+                if round == 1 || i % usize::from(round) == 0 {
+                    *byte = round;
+                    ftl.write(&[block(round)], BlockIndex(i as u64)).unwrap();
+                }
+            }
+        }
+
+        assert!(ftl.garbage_collections() > 0);
+        assert!(ftl.free_erase_blocks() >= GC_FREE_ERASE_BLOCKS);
+        assert_eq!(ftl.bad_erase_blocks(), 0);
+        assert_eq!(content(&mut ftl), expected);
+        let mut ftl = remount(ftl.into_inner());
+        assert_eq!(content(&mut ftl), expected);
+    }
+
+    #[test]
+    fn erase_blocks_failing_to_program_are_retired() {
+        // The least erased erase block is used first, so page 1 is the second one programmed.
+        let nand = FailingNand {
+            nand: MemoryNand::new(GEOMETRY),
+            failing_page: Some(1),
+        };
+        let mut ftl = Ftl::format(nand, MIN_RESERVED_ERASE_BLOCKS).unwrap();
+        ftl.write(&[block(1), block(1)], BlockIndex(0)).unwrap();
+        ftl.write(&[block(2), block(2)], BlockIndex(2)).unwrap();
+        assert!(ftl.failing[0]);
+
+        // Rewrite the rest of the device until the closed erase block, holding the only copy of
+        // the first page, is collected.
+        let mut expected = alloc::vec![0u8; BLOCKS];
+        expected[0..2].fill(1);
+        for round in 2..20 {
+            if ftl.bad_erase_blocks() > 0 {
+                break;
+            }
+            for (index, byte) in expected.iter_mut().enumerate().skip(2) {
+                ftl.write(&[block(round)], BlockIndex(index as u64))
+                    .unwrap();
+                *byte = round;
+            }
+        }
+        assert!(ftl.garbage_collections() > 0);
+        assert!(ftl.bad[0]);
+        assert_eq!(content(&mut ftl), expected);
+
+        let mut ftl = remount(ftl.into_inner());
+        assert_eq!(ftl.bad_erase_blocks(), 1);
+        assert_eq!(content(&mut ftl), expected);
+    }
+}
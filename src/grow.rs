@@ -0,0 +1,232 @@
+use crate::{
+    Capabilities, DeviceIdentity, DeviceInfo, Resizable, StorageDevice, StorageDeviceError,
+    StorageDeviceResult,
+};
+
+/// How an [AutoGrowDevice] grows its backing storage when a write goes past its end.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum GrowthPolicy {
+    /// Grow to the end of the write.
+    Exact,
+
+    /// Grow to the end of the write rounded up to a multiple of the given number of bytes.
+    Chunked(u64),
+
+    /// At least double the size of the backing storage, so building an image write after write
+    /// only resizes it a logarithmic number of times.
+    #[default]
+    Exponential,
+}
+
+impl GrowthPolicy {
+    /// Return the new size of backing storage of ``current`` bytes, that must hold at least
+    /// ``needed`` bytes.
+    fn grow(self, current: u64, needed: u64) -> u64 {
+        match self {
+            GrowthPolicy::Exact => needed,
+            GrowthPolicy::Chunked(0) => needed,
+            GrowthPolicy::Chunked(chunk) => {
+                needed.checked_next_multiple_of(chunk).unwrap_or(u64::MAX)
+            }
+            GrowthPolicy::Exponential => core::cmp::max(needed, current.saturating_mul(2)),
+        }
+    }
+}
+
+/// A storage device that transparently grows its backing storage when written past its end.
+///
+/// This lets tools build images incrementally without knowing their final size. The size of the
+/// device is the end of the furthest write, while the backing storage may be bigger depending on
+/// the [GrowthPolicy]: call [AutoGrowDevice::shrink_to_fit] once done to trim it.
+#[derive(Debug)]
+pub struct AutoGrowDevice<S: Resizable> {
+    /// The inner storage device.
+    storage_device: S,
+
+    /// How to grow the backing storage.
+    policy: GrowthPolicy,
+
+    /// The maximum size of the device, if any.
+    max_len: Option<u64>,
+
+    /// The size of the device, as seen by its users.
+    len: u64,
+
+    /// The size of the inner storage device.
+    backing_len: u64,
+}
+
+impl<S: Resizable> AutoGrowDevice<S> {
+    /// Wrap a resizable storage device, starting at its current size.
+    pub fn new(mut storage_device: S) -> StorageDeviceResult<Self> {
+        let len = storage_device.len()?;
+        Ok(AutoGrowDevice {
+            storage_device,
+            policy: GrowthPolicy::default(),
+            max_len: None,
+            len,
+            backing_len: len,
+        })
+    }
+
+    /// Set how the backing storage grows.
+    pub fn with_growth_policy(mut self, policy: GrowthPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Make writes ending past ``max_len`` fail with [StorageDeviceError::OutOfSpace].
+    pub fn with_max_len(mut self, max_len: u64) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Return a reference to the inner storage device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the inner storage device.
+    ///
+    /// Resizing it behind the back of the wrapper leads to unexpected sizes.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Consume the wrapper, returning the inner storage device, which may be bigger than the device
+    /// was.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Trim the backing storage down to the size of the device.
+    pub fn shrink_to_fit(&mut self) -> StorageDeviceResult<()> {
+        if self.backing_len != self.len {
+            self.storage_device.set_len(self.len)?;
+            self.backing_len = self.len;
+        }
+        Ok(())
+    }
+
+    /// Check that ``len`` bytes at ``offset`` are inside the device.
+    fn check_bounds(&self, offset: u64, len: usize) -> StorageDeviceResult<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+
+    /// Make the device at least ``end`` bytes big, growing the backing storage if needed.
+    fn grow_to(&mut self, end: u64) -> StorageDeviceResult<()> {
+        if end <= self.len {
+            return Ok(());
+        }
+
+        if let Some(max_len) = self.max_len {
+            if end > max_len {
+                return Err(StorageDeviceError::OutOfSpace);
+            }
+        }
+
+        if end > self.backing_len {
+            let mut backing_len = self.policy.grow(self.backing_len, end);
+            if let Some(max_len) = self.max_len {
+                backing_len = core::cmp::min(backing_len, max_len);
+            }
+            self.storage_device.set_len(backing_len)?;
+            self.backing_len = backing_len;
+        }
+
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl<S: Resizable> StorageDevice for AutoGrowDevice<S> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        self.storage_device.read(offset, buf)
+    }
+
+    /// Grow the device to the end of the write if needed, then write.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(StorageDeviceError::OutOfBounds)?;
+        self.grow_to(end)?;
+        self.storage_device.write(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.len)
+    }
+
+    /// Grow the device to the end of the range if needed, then fill it.
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        let end = offset
+            .checked_add(len)
+            .ok_or(StorageDeviceError::OutOfBounds)?;
+        self.grow_to(end)?;
+        crate::fill_with_writes(self, offset, len, byte)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.discard(offset, len)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(StorageDeviceError::OutOfBounds)?;
+        self.grow_to(end)?;
+        self.storage_device.write_fua(offset, buf)
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.storage_device.capabilities()
+    }
+}
+
+impl<S: Resizable> Resizable for AutoGrowDevice<S> {
+    /// Resize the device, and its backing storage to the same size.
+    fn set_len(&mut self, len: u64) -> StorageDeviceResult<()> {
+        if let Some(max_len) = self.max_len {
+            if len > max_len {
+                return Err(StorageDeviceError::OutOfSpace);
+            }
+        }
+
+        self.storage_device.set_len(len)?;
+        self.len = len;
+        self.backing_len = len;
+        Ok(())
+    }
+}
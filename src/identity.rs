@@ -0,0 +1,135 @@
+/// The capacity of an [IdentityString], in bytes.
+pub const IDENTITY_STRING_LEN: usize = 64;
+
+/// A string of a [DeviceIdentity], stored inline so identities can be built without a heap.
+///
+/// Strings longer than [IDENTITY_STRING_LEN] bytes are truncated.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct IdentityString {
+    /// The UTF-8 bytes of the string, followed by zeroes.
+    bytes: [u8; IDENTITY_STRING_LEN],
+
+    /// The length of the string, in bytes.
+    len: u8,
+}
+
+impl IdentityString {
+    /// Create an empty string.
+    pub const fn new() -> Self {
+        IdentityString {
+            bytes: [0; IDENTITY_STRING_LEN],
+            len: 0,
+        }
+    }
+
+    /// Create a string from the ASCII field ``bytes`` reported by a device, which is usually
+    /// padded with spaces or NUL bytes, replacing anything but printable ASCII with ``?``.
+    pub fn from_ascii(bytes: &[u8]) -> Self {
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        let mut string = IdentityString::new();
+        for &byte in bytes[..end].trim_ascii() {
+            if string.len() == IDENTITY_STRING_LEN {
+                break;
+            }
+            let byte = if byte.is_ascii_graphic() || byte == b' ' {
+                byte
+            } else {
+                b'?'
+            };
+            let end = string.len();
+            string.bytes[end] = byte;
+            string.len += 1;
+        }
+        string
+    }
+
+    /// Append ``s``, truncating it on a character boundary if the string is full.
+    pub fn push_str(&mut self, s: &str) {
+        let start = self.len();
+        let mut len = core::cmp::min(s.len(), IDENTITY_STRING_LEN - start);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.bytes[start..start + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len as u8;
+    }
+
+    /// Return the string.
+    pub fn as_str(&self) -> &str {
+        // Only whole UTF-8 characters are ever stored.
+        core::str::from_utf8(&self.bytes[..self.len()]).unwrap_or_default()
+    }
+
+    /// Return the length of the string, in bytes.
+    pub fn len(&self) -> usize {
+        usize::from(self.len)
+    }
+
+    /// Check whether the string is empty, usually because the device didn't report it.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for IdentityString {
+    fn default() -> Self {
+        IdentityString::new()
+    }
+}
+
+/// Create a string from ``s``, without its leading and trailing whitespace.
+impl From<&str> for IdentityString {
+    fn from(s: &str) -> Self {
+        let mut string = IdentityString::new();
+        string.push_str(s.trim());
+        string
+    }
+}
+
+impl core::ops::Deref for IdentityString {
+    type Target = str;
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl core::fmt::Debug for IdentityString {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl core::fmt::Display for IdentityString {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Writing to an identity string appends to it, silently truncating what doesn't fit.
+impl core::fmt::Write for IdentityString {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+/// The identity of a device, telling it apart from other devices.
+///
+/// Every field may be empty, if the device doesn't report it. Only [DeviceIdentity::unique_id]
+/// is meant to identify a device reliably: models are shared by many devices, and cheap devices
+/// often report the same serial number.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceIdentity {
+    /// The model of the device.
+    pub model: IdentityString,
+
+    /// The serial number of the device.
+    pub serial: IdentityString,
+
+    /// The revision of the firmware of the device.
+    pub firmware_revision: IdentityString,
+
+    /// An identifier unique to the device, and stable across reboots and connections, such as
+    /// its World Wide Name, prefixed with its kind, e.g. ``naa.5000c500a1b2c3d4``.
+    pub unique_id: IdentityString,
+}
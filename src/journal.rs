@@ -0,0 +1,1170 @@
+use crate::crc::{crc32, crc32_update};
+use crate::{Block, StorageDevice, StorageDeviceError, StorageDeviceResult};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// The magic identifying the header of a [JournaledDevice].
+const JOURNAL_MAGIC: u32 = u32::from_le_bytes(*b"JRNL");
+
+/// The magic identifying the checkpoint of a [JournaledDevice].
+const CHECKPOINT_MAGIC: u32 = u32::from_le_bytes(*b"JCKP");
+
+/// The magic identifying a commit record of a [JournaledDevice].
+const COMMIT_MAGIC: u32 = u32::from_le_bytes(*b"JCMT");
+
+/// The version of the layout of the journal.
+const JOURNAL_VERSION: u32 = 2;
+
+/// The offset of the checkpoint in the journal, right after the header.
+const CHECKPOINT_OFFSET: u64 = Block::LEN_U64;
+
+/// The offset of the ring of transactions in the journal, right after the checkpoint.
+const RING_OFFSET: u64 = 2 * Block::LEN_U64;
+
+/// The size of a commit record, preceding the payload of its transaction.
+const RECORD_LEN: u64 = Block::LEN_U64;
+
+/// The size of the header of every write of the payload: its offset and its length.
+const ENTRY_HEADER_LEN: usize = 12;
+
+/// The smallest journal, holding the header, the checkpoint, and a commit record followed by a
+/// block of payload.
+pub const MIN_JOURNAL_LEN: u64 = 4 * Block::LEN_U64;
+
+/// The header of the journal, stored in its first block, and only written when the journal is
+/// created.
+///
+/// It is stored in little endian as follow:
+///
+/// | Offset | Size | Field       |
+/// |--------|------|-------------|
+/// | 0      | 4    | magic       |
+/// | 4      | 4    | version     |
+/// | 8      | 8    | journal_len |
+/// | 16     | 4    | crc         |
+///
+/// The CRC-32 covers the first 16 bytes.
+#[derive(Debug, Copy, Clone)]
+struct JournalHeader {
+    /// The size of the journal at the start of the device, in bytes.
+    journal_len: u64,
+}
+
+impl JournalHeader {
+    /// Serialize the header into a block.
+    fn to_block(self) -> Block {
+        let mut block = Block::new();
+        block[0..4].copy_from_slice(&JOURNAL_MAGIC.to_le_bytes());
+        block[4..8].copy_from_slice(&JOURNAL_VERSION.to_le_bytes());
+        block[8..16].copy_from_slice(&self.journal_len.to_le_bytes());
+        let crc = crc32(&block[0..16]);
+        block[16..20].copy_from_slice(&crc.to_le_bytes());
+        block
+    }
+
+    /// Deserialize the header from a block.
+    ///
+    /// Return [StorageDeviceError::Corrupted] if it isn't valid, and
+    /// [StorageDeviceError::Unsupported] if it has another version.
+    fn from_block(block: &Block) -> StorageDeviceResult<Self> {
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        let mut journal_len = [0u8; 8];
+        let mut crc = [0u8; 4];
+
+        magic.copy_from_slice(&block[0..4]);
+        version.copy_from_slice(&block[4..8]);
+        journal_len.copy_from_slice(&block[8..16]);
+        crc.copy_from_slice(&block[16..20]);
+
+        let journal_len = u64::from_le_bytes(journal_len);
+        if u32::from_le_bytes(magic) != JOURNAL_MAGIC
+            || u32::from_le_bytes(crc) != crc32(&block[0..16])
+        {
+            return Err(StorageDeviceError::Corrupted);
+        }
+        if u32::from_le_bytes(version) != JOURNAL_VERSION {
+            return Err(StorageDeviceError::Unsupported);
+        }
+        if journal_len < MIN_JOURNAL_LEN || !journal_len.is_multiple_of(Block::LEN_U64) {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        Ok(JournalHeader { journal_len })
+    }
+}
+
+/// The checkpoint of the journal, stored in its second block, holding the sequence number of the
+/// last transaction whose writes were performed.
+///
+/// It is stored in little endian as follow:
+///
+/// | Offset | Size | Field    |
+/// |--------|------|----------|
+/// | 0      | 4    | magic    |
+/// | 4      | 8    | sequence |
+/// | 12     | 4    | crc      |
+///
+/// The CRC-32 covers the first 12 bytes.
+#[derive(Debug, Copy, Clone)]
+struct Checkpoint {
+    /// The sequence number of the last performed transaction.
+    sequence: u64,
+}
+
+impl Checkpoint {
+    /// Serialize the checkpoint into a block.
+    fn to_block(self) -> Block {
+        let mut block = Block::new();
+        block[0..4].copy_from_slice(&CHECKPOINT_MAGIC.to_le_bytes());
+        block[4..12].copy_from_slice(&self.sequence.to_le_bytes());
+        let crc = crc32(&block[0..12]);
+        block[12..16].copy_from_slice(&crc.to_le_bytes());
+        block
+    }
+
+    /// Deserialize the checkpoint from a block, returning None if it isn't valid.
+    fn from_block(block: &Block) -> Option<Self> {
+        let mut magic = [0u8; 4];
+        let mut sequence = [0u8; 8];
+        let mut crc = [0u8; 4];
+
+        magic.copy_from_slice(&block[0..4]);
+        sequence.copy_from_slice(&block[4..12]);
+        crc.copy_from_slice(&block[12..16]);
+
+        if u32::from_le_bytes(magic) != CHECKPOINT_MAGIC
+            || u32::from_le_bytes(crc) != crc32(&block[0..12])
+        {
+            return None;
+        }
+
+        Some(Checkpoint {
+            sequence: u64::from_le_bytes(sequence),
+        })
+    }
+}
+
+/// The commit record of a transaction, stored in the block preceding its payload in the ring of
+/// transactions.
+///
+/// It is stored in little endian as follow:
+///
+/// | Offset | Size | Field       |
+/// |--------|------|-------------|
+/// | 0      | 4    | magic       |
+/// | 4      | 4    | entries     |
+/// | 8      | 8    | sequence    |
+/// | 16     | 8    | payload_len |
+/// | 24     | 4    | crc         |
+///
+/// The CRC-32 covers the first 24 bytes followed by the payload, so a record whose payload was
+/// only partly written, or overwritten by a later transaction, is never replayed.
+///
+/// The payload follows the commit record. It is made of the writes of the transaction one after
+/// the other, each being its offset on 8 bytes and its length on 4 bytes, followed by the content
+/// it overwrites, then by its data.
+#[derive(Debug, Copy, Clone)]
+struct CommitRecord {
+    /// The number of writes of the transaction.
+    entries: u32,
+
+    /// The sequence number of the transaction.
+    sequence: u64,
+
+    /// The size of the payload, in bytes.
+    payload_len: u64,
+
+    /// The CRC-32 of the record and the payload.
+    crc: u32,
+}
+
+impl CommitRecord {
+    /// Create the record of the transaction ``sequence``, made of ``entries`` writes serialized in
+    /// ``payload``.
+    fn new(sequence: u64, entries: u32, payload: &[u8]) -> Self {
+        let mut record = CommitRecord {
+            entries,
+            sequence,
+            payload_len: payload.len() as u64,
+            crc: 0,
+        };
+        record.crc = record.compute_crc(payload);
+        record
+    }
+
+    /// Serialize the record into a block.
+    fn to_block(self) -> Block {
+        let mut block = Block::new();
+        block[0..24].copy_from_slice(&self.fields());
+        block[24..28].copy_from_slice(&self.crc.to_le_bytes());
+        block
+    }
+
+    /// Deserialize the record from the start of ``bytes``, returning None if it isn't a commit
+    /// record.
+    ///
+    /// The CRC-32 can only be checked once the payload is read.
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut magic = [0u8; 4];
+        let mut entries = [0u8; 4];
+        let mut sequence = [0u8; 8];
+        let mut payload_len = [0u8; 8];
+        let mut crc = [0u8; 4];
+
+        magic.copy_from_slice(&bytes[0..4]);
+        entries.copy_from_slice(&bytes[4..8]);
+        sequence.copy_from_slice(&bytes[8..16]);
+        payload_len.copy_from_slice(&bytes[16..24]);
+        crc.copy_from_slice(&bytes[24..28]);
+
+        if u32::from_le_bytes(magic) != COMMIT_MAGIC {
+            return None;
+        }
+
+        Some(CommitRecord {
+            entries: u32::from_le_bytes(entries),
+            sequence: u64::from_le_bytes(sequence),
+            payload_len: u64::from_le_bytes(payload_len),
+            crc: u32::from_le_bytes(crc),
+        })
+    }
+
+    /// Return the first 24 bytes of the record, covered by the CRC-32.
+    fn fields(self) -> [u8; 24] {
+        let mut fields = [0u8; 24];
+        fields[0..4].copy_from_slice(&COMMIT_MAGIC.to_le_bytes());
+        fields[4..8].copy_from_slice(&self.entries.to_le_bytes());
+        fields[8..16].copy_from_slice(&self.sequence.to_le_bytes());
+        fields[16..24].copy_from_slice(&self.payload_len.to_le_bytes());
+        fields
+    }
+
+    /// Compute the CRC-32 of this record and the given payload.
+    fn compute_crc(self, payload: &[u8]) -> u32 {
+        crc32_update(crc32_update(0, &self.fields()), payload)
+    }
+}
+
+/// Where a transaction is in the ring of transactions of the journal.
+#[derive(Debug, Copy, Clone)]
+struct RecordLocation {
+    /// The sequence number of the transaction.
+    sequence: u64,
+
+    /// The offset of its commit record in the journal.
+    offset: u64,
+
+    /// The size of its payload, following the commit record.
+    payload_len: u64,
+}
+
+impl RecordLocation {
+    /// Return the offset following the transaction in the journal, its payload being padded to a
+    /// block.
+    fn end(&self) -> u64 {
+        self.offset + RECORD_LEN + self.payload_len.next_multiple_of(Block::LEN_U64)
+    }
+}
+
+/// A storage device logging writes to a journal before performing them, so every write is
+/// atomic, even across a crash.
+///
+/// The journal takes the start of the inner device, and the data follows it. A write is first
+/// logged to a ring of transactions in the journal, as a payload followed by a commit record
+/// checksumming it, and only then is the write performed on the data. Once it is, the sequence
+/// number of the transaction is stored in a checkpoint. Barriers order the three steps, and the
+/// next transaction, so the data is only ever modified once the transaction is committed, and
+/// opening the device only replays the transactions after the checkpoint, completing those
+/// interrupted by a crash, and never overwrites what was written to the data since, e.g. through
+/// [JournaledDevice::into_inner].
+///
+/// Every write is logged along with the content it overwrites, which is read first. The journal
+/// keeps the transactions it has room for, overwriting the oldest ones as new ones are added, so
+/// the device can be seen as it was once any of them was performed, with
+/// [JournaledDevice::view_at]. Every byte is written three times, so the journal is best kept to
+/// metadata rather than bulk data. As with any device, writes are durable once
+/// [StorageDevice::flush] returns.
+///
+/// Writes bigger than the payload of the journal are split in several transactions, each of them
+/// atomic. Several writes can be made atomic together in a [Transaction], started with
+/// [JournaledDevice::begin].
+pub struct JournaledDevice<S: StorageDevice> {
+    /// The device holding the journal, followed by the data.
+    storage_device: S,
+
+    /// The size of the journal at the start of the device, in bytes.
+    journal_len: u64,
+
+    /// The sequence number of the last performed transaction, zero if there is none.
+    sequence: u64,
+
+    /// The transactions still in the journal, the oldest first, ending with the last one.
+    history: VecDeque<RecordLocation>,
+
+    /// The offset in the journal where the next transaction is logged, unless it doesn't fit
+    /// before the end of the journal.
+    head: u64,
+
+    /// The payload of the transaction being built.
+    payload: Vec<u8>,
+
+    /// The number of writes in the payload.
+    entries: u32,
+}
+
+impl<S: StorageDevice> core::fmt::Debug for JournaledDevice<S> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("JournaledDevice")
+            .field("storage_device", &self.storage_device)
+            .field("journal_len", &self.journal_len)
+            .field("sequence", &self.sequence)
+            .finish()
+    }
+}
+
+impl<S: StorageDevice> JournaledDevice<S> {
+    /// Set up a new, empty journal of ``journal_len`` bytes at the start of ``storage_device``.
+    ///
+    /// ``journal_len`` must be a multiple of [Block::LEN], and at least [MIN_JOURNAL_LEN]. The
+    /// journal is zeroed, but the data after it is kept.
+    pub fn create(mut storage_device: S, journal_len: u64) -> StorageDeviceResult<Self> {
+        if journal_len < MIN_JOURNAL_LEN || !journal_len.is_multiple_of(Block::LEN_U64) {
+            return Err(StorageDeviceError::Unsupported);
+        }
+        if journal_len > storage_device.len()? {
+            return Err(StorageDeviceError::OutOfSpace);
+        }
+
+        // Transactions left by a previous journal must not be replayed.
+        storage_device.write_zeroes(RING_OFFSET, journal_len - RING_OFFSET)?;
+        let checkpoint = Checkpoint { sequence: 0 };
+        storage_device.write(CHECKPOINT_OFFSET, &checkpoint.to_block()[..])?;
+        storage_device.barrier()?;
+        let header = JournalHeader { journal_len };
+        storage_device.write(0, &header.to_block()[..])?;
+        storage_device.flush()?;
+
+        Ok(JournaledDevice {
+            storage_device,
+            journal_len,
+            sequence: 0,
+            history: VecDeque::new(),
+            head: RING_OFFSET,
+            payload: Vec::new(),
+            entries: 0,
+        })
+    }
+
+    /// Open the journal previously set up on ``storage_device`` with [JournaledDevice::create],
+    /// replaying the transactions committed but not performed.
+    ///
+    /// Return [StorageDeviceError::Corrupted] if the header of the journal isn't valid.
+    pub fn open(mut storage_device: S) -> StorageDeviceResult<Self> {
+        let mut block = Block::new();
+        storage_device.read(0, &mut block[..])?;
+        let header = JournalHeader::from_block(&block)?;
+        if header.journal_len > storage_device.len()? {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        let mut device = JournaledDevice {
+            storage_device,
+            journal_len: header.journal_len,
+            sequence: 0,
+            history: VecDeque::new(),
+            head: RING_OFFSET,
+            payload: Vec::new(),
+            entries: 0,
+        };
+        device.replay()?;
+        Ok(device)
+    }
+
+    /// Return the size of the journal at the start of the inner device, in bytes.
+    pub fn journal_len(&self) -> u64 {
+        self.journal_len
+    }
+
+    /// Return the sequence number of the last transaction performed, zero if there was none.
+    ///
+    /// Transactions are numbered from one, in the order they are committed.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Return the sequence number of the oldest transaction still in the journal, or the one the
+    /// next transaction will get if the journal holds none.
+    pub fn oldest_sequence(&self) -> u64 {
+        self.history
+            .front()
+            .map_or(self.sequence + 1, |location| location.sequence)
+    }
+
+    /// Start a transaction, whose writes are all performed atomically once it is committed, or
+    /// not at all.
+    pub fn begin(&mut self) -> Transaction<'_, S> {
+        self.clear();
+        Transaction { device: self }
+    }
+
+    /// Return a read-only view of the device as it was once the transaction ``sequence`` was
+    /// performed, zero being the device before the first transaction.
+    ///
+    /// The view undoes the transactions after ``sequence``, which must all still be in the
+    /// journal: ``sequence`` goes from one less than [JournaledDevice::oldest_sequence] to
+    /// [JournaledDevice::sequence]. Return [StorageDeviceError::OutOfBounds] if the journal no
+    /// longer holds the transactions needed, and [StorageDeviceError::InvalidRequest] if
+    /// ``sequence`` wasn't performed yet.
+    pub fn view_at(&mut self, sequence: u64) -> StorageDeviceResult<JournalView<'_, S>> {
+        if sequence > self.sequence {
+            return Err(StorageDeviceError::InvalidRequest);
+        }
+        if sequence + 1 < self.oldest_sequence() {
+            return Err(StorageDeviceError::OutOfBounds);
+        }
+
+        let mut undo = Vec::new();
+        for location in self.history.iter().rev() {
+            if location.sequence <= sequence {
+                break;
+            }
+            let mut payload = alloc::vec![0u8; location.payload_len as usize];
+            self.storage_device
+                .read(location.offset + RECORD_LEN, &mut payload)?;
+            undo.push(payload);
+        }
+
+        Ok(JournalView {
+            device: self,
+            sequence,
+            undo,
+        })
+    }
+
+    /// Return a reference to the inner device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Consume the device, returning the inner device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Return the size of the payload of the journal, bounding the size of a transaction.
+    fn capacity(&self) -> usize {
+        usize::try_from(self.journal_len - RING_OFFSET - RECORD_LEN).unwrap_or(usize::MAX)
+    }
+
+    /// Check whether the ``len`` bytes at ``offset`` are inside the data.
+    fn check_bounds(&mut self, offset: u64, len: usize) -> StorageDeviceResult<()> {
+        let data_len = self.len()?;
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= data_len => Ok(()),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+
+    /// Read the data at ``offset``, with the writes of the transaction being built applied over
+    /// it.
+    fn read_pending(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.read(offset, buf)?;
+        for entry in Entries(&self.payload) {
+            let entry = entry.ok_or(StorageDeviceError::Corrupted)?;
+            overlay(offset, buf, entry.offset, entry.data);
+        }
+        Ok(())
+    }
+
+    /// Append a write of ``data`` at ``offset`` to the transaction being built, along with the
+    /// content it overwrites.
+    ///
+    /// Return [StorageDeviceError::OutOfSpace] if it doesn't fit in the journal, or if it is
+    /// bigger than the 4 GiB an entry can hold.
+    fn push_entry(&mut self, offset: u64, data: &[u8]) -> StorageDeviceResult<()> {
+        let data_len = u32::try_from(data.len()).map_err(|_| StorageDeviceError::OutOfSpace)?;
+        let entry_len = data
+            .len()
+            .checked_mul(2)
+            .and_then(|len| len.checked_add(ENTRY_HEADER_LEN));
+        match entry_len {
+            Some(len) if len <= self.capacity() - self.payload.len() => (),
+            _ => return Err(StorageDeviceError::OutOfSpace),
+        }
+
+        let mut previous = alloc::vec![0u8; data.len()];
+        self.read_pending(offset, &mut previous)?;
+        self.payload.extend_from_slice(&offset.to_le_bytes());
+        self.payload.extend_from_slice(&data_len.to_le_bytes());
+        self.payload.extend_from_slice(&previous);
+        self.payload.extend_from_slice(data);
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Drop the transaction being built.
+    fn clear(&mut self) {
+        self.payload.clear();
+        self.entries = 0;
+    }
+
+    /// Log the transaction being built to the journal, commit it, then perform its writes.
+    fn commit(&mut self) -> StorageDeviceResult<()> {
+        if self.entries == 0 {
+            return Ok(());
+        }
+
+        let record = CommitRecord::new(self.sequence + 1, self.entries, &self.payload);
+        let mut location = RecordLocation {
+            sequence: record.sequence,
+            offset: self.head,
+            payload_len: record.payload_len,
+        };
+        if location.end() > self.journal_len {
+            location.offset = RING_OFFSET;
+        }
+        // The oldest transactions are overwritten, and can no longer be undone.
+        let overwritten = self.history.iter().rposition(|previous| {
+            previous.offset < location.end() && location.offset < previous.end()
+        });
+        if let Some(last) = overwritten {
+            self.history.drain(..=last);
+        }
+
+        let result = self
+            .storage_device
+            .write(location.offset + RECORD_LEN, &self.payload)
+            .and_then(|()| {
+                self.storage_device
+                    .write(location.offset, &record.to_block()[..])
+            })
+            .and_then(|()| self.storage_device.barrier())
+            .and_then(|()| {
+                self.sequence = location.sequence;
+                self.history.push_back(location);
+                self.head = location.end();
+                apply(&mut self.storage_device, self.journal_len, &self.payload)
+            })
+            .and_then(|()| self.storage_device.barrier())
+            .and_then(|()| self.checkpoint());
+        self.clear();
+        result
+    }
+
+    /// Find the transactions in the journal, and perform again those committed after the
+    /// checkpoint.
+    ///
+    /// The transactions kept are the ones with consecutive sequence numbers up to the last one,
+    /// so those partly overwritten, and any older, are ignored.
+    fn replay(&mut self) -> StorageDeviceResult<()> {
+        let ring_len = usize::try_from(self.journal_len - RING_OFFSET)
+            .map_err(|_| StorageDeviceError::OutOfSpace)?;
+        let mut ring = alloc::vec![0u8; ring_len];
+        self.storage_device.read(RING_OFFSET, &mut ring)?;
+
+        let mut found = Vec::new();
+        for start in (0..ring_len).step_by(Block::LEN) {
+            let payload_start = start + RECORD_LEN as usize;
+            let record = match CommitRecord::from_bytes(&ring[start..]) {
+                Some(record) if record.payload_len <= (ring_len - payload_start) as u64 => record,
+                _ => continue,
+            };
+            let payload = &ring[payload_start..payload_start + record.payload_len as usize];
+            if record.compute_crc(payload) == record.crc {
+                found.push(RecordLocation {
+                    sequence: record.sequence,
+                    offset: RING_OFFSET + start as u64,
+                    payload_len: record.payload_len,
+                });
+            }
+        }
+        found.sort_by_key(|location| location.sequence);
+        let mut history: VecDeque<RecordLocation> = VecDeque::new();
+        for location in found.into_iter().rev() {
+            match history.front() {
+                Some(next) if location.sequence + 1 != next.sequence => break,
+                _ => history.push_front(location),
+            }
+        }
+
+        let mut block = Block::new();
+        self.storage_device
+            .read(CHECKPOINT_OFFSET, &mut block[..])?;
+        let last = history.back().map_or(0, |location| location.sequence);
+        // A torn checkpoint can only be the one of the last transaction, as the next is only
+        // performed once it is written.
+        let performed = match Checkpoint::from_block(&block) {
+            Some(checkpoint) => checkpoint.sequence,
+            None => last.saturating_sub(1),
+        };
+
+        let mut replayed = false;
+        for location in history
+            .iter()
+            .filter(|location| location.sequence > performed)
+        {
+            let start = (location.offset - RING_OFFSET + RECORD_LEN) as usize;
+            let payload = &ring[start..start + location.payload_len as usize];
+            apply(&mut self.storage_device, self.journal_len, payload)?;
+            replayed = true;
+        }
+
+        self.sequence = core::cmp::max(performed, last);
+        if last != self.sequence {
+            // The transactions after the last one found were lost, so none can be undone.
+            history.clear();
+        }
+        self.head = history.back().map_or(RING_OFFSET, RecordLocation::end);
+        self.history = history;
+
+        if replayed {
+            self.storage_device.flush()?;
+            self.checkpoint()?;
+            self.storage_device.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Record the last transaction as performed, so it is never replayed over data written
+    /// afterwards.
+    fn checkpoint(&mut self) -> StorageDeviceResult<()> {
+        let checkpoint = Checkpoint {
+            sequence: self.sequence,
+        };
+        self.storage_device
+            .write(CHECKPOINT_OFFSET, &checkpoint.to_block()[..])
+    }
+}
+
+/// Perform the writes serialized in ``payload`` on the data of ``storage_device``, which follows
+/// a journal of ``journal_len`` bytes.
+fn apply<S: StorageDevice>(
+    storage_device: &mut S,
+    journal_len: u64,
+    payload: &[u8],
+) -> StorageDeviceResult<()> {
+    for entry in Entries(payload) {
+        let entry = entry.ok_or(StorageDeviceError::Corrupted)?;
+        let offset = entry
+            .offset
+            .checked_add(journal_len)
+            .ok_or(StorageDeviceError::Corrupted)?;
+        storage_device.write(offset, entry.data)?;
+    }
+    Ok(())
+}
+
+/// Copy the part of ``data``, at ``data_offset``, overlapping ``buf``, at ``offset``.
+fn overlay(offset: u64, buf: &mut [u8], data_offset: u64, data: &[u8]) {
+    let start = core::cmp::max(offset, data_offset);
+    let stop = core::cmp::min(offset + buf.len() as u64, data_offset + data.len() as u64);
+    if start < stop {
+        let len = (stop - start) as usize;
+        let from = (start - data_offset) as usize;
+        let to = (start - offset) as usize;
+        buf[to..to + len].copy_from_slice(&data[from..from + len]);
+    }
+}
+
+/// A write serialized in a payload.
+#[derive(Debug, Copy, Clone)]
+struct Entry<'a> {
+    /// The offset of the write in the data.
+    offset: u64,
+
+    /// The content the write overwrites.
+    previous: &'a [u8],
+
+    /// The content written.
+    data: &'a [u8],
+}
+
+/// An iterator over the writes serialized in a payload, yielding None once if the payload is
+/// malformed.
+struct Entries<'a>(&'a [u8]);
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Option<Entry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let payload = self.0;
+        if payload.is_empty() {
+            return None;
+        }
+        self.0 = &[];
+        if payload.len() < ENTRY_HEADER_LEN {
+            return Some(None);
+        }
+        let mut offset = [0u8; 8];
+        let mut len = [0u8; 4];
+        offset.copy_from_slice(&payload[0..8]);
+        len.copy_from_slice(&payload[8..12]);
+        let len = u32::from_le_bytes(len) as usize;
+
+        let contents = match payload[ENTRY_HEADER_LEN..].get(..2 * len) {
+            Some(contents) => contents,
+            None => return Some(None),
+        };
+        self.0 = &payload[ENTRY_HEADER_LEN + 2 * len..];
+        let (previous, data) = contents.split_at(len);
+        Some(Some(Entry {
+            offset: u64::from_le_bytes(offset),
+            previous,
+            data,
+        }))
+    }
+}
+
+/// A transaction on a [JournaledDevice], started with [JournaledDevice::begin].
+///
+/// Writes are buffered in memory, and only reach the device, all at once, when the transaction is
+/// committed with [Transaction::commit]. Until then, reads through the transaction see its own
+/// writes, and the device is left untouched. Dropping the transaction without committing it
+/// discards its writes, as [Transaction::rollback] does.
+///
+/// The whole transaction must fit in the payload of the journal, every write taking twice its size
+/// and 12 bytes, as the content it overwrites is logged along with it: writes which don't fit fail
+/// with [StorageDeviceError::OutOfSpace], and leave the rest of the transaction as it was.
+#[derive(Debug)]
+pub struct Transaction<'a, S: StorageDevice> {
+    /// The device the transaction applies to, holding the payload being built.
+    device: &'a mut JournaledDevice<S>,
+}
+
+impl<S: StorageDevice> Transaction<'_, S> {
+    /// Log the writes of the transaction to the journal, then perform them atomically.
+    ///
+    /// The transaction is discarded even if this fails: the device then holds either none or all
+    /// of its writes, the latter being replayed when opening the device if needed.
+    pub fn commit(self) -> StorageDeviceResult<()> {
+        self.device.commit()
+    }
+
+    /// Discard the writes of the transaction.
+    pub fn rollback(self) {
+        self.device.clear();
+    }
+}
+
+impl<S: StorageDevice> Drop for Transaction<'_, S> {
+    fn drop(&mut self) {
+        self.device.clear();
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for Transaction<'_, S> {
+    /// Read from the device, then apply the writes of the transaction over what was read.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.device.read_pending(offset, buf)
+    }
+
+    /// Add the write to the transaction.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.device.check_bounds(offset, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.device.push_entry(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.device.len()
+    }
+
+    /// Do nothing, as the writes of the transaction only reach the device once it is committed.
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        Ok(())
+    }
+}
+
+/// A read-only view of a [JournaledDevice] as it was once a past transaction was performed,
+/// returned by [JournaledDevice::view_at].
+///
+/// Reads see the content of the device with the writes of the later transactions undone, from
+/// the content they overwrote, logged in the journal. Writes fail with
+/// [StorageDeviceError::Unsupported].
+pub struct JournalView<'a, S: StorageDevice> {
+    /// The device the view is taken on.
+    device: &'a mut JournaledDevice<S>,
+
+    /// The sequence number of the transaction the view is at.
+    sequence: u64,
+
+    /// The payloads of the transactions to undo, the last one first.
+    undo: Vec<Vec<u8>>,
+}
+
+impl<S: StorageDevice> core::fmt::Debug for JournalView<'_, S> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("JournalView")
+            .field("device", &self.device)
+            .field("sequence", &self.sequence)
+            .finish()
+    }
+}
+
+impl<S: StorageDevice> JournalView<'_, S> {
+    /// Return the sequence number of the transaction the view is at.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for JournalView<'_, S> {
+    /// Read from the device, then restore what the later transactions overwrote, from the last
+    /// write to the first.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.device.read(offset, buf)?;
+        for payload in &self.undo {
+            let entries = Entries(payload)
+                .collect::<Option<Vec<_>>>()
+                .ok_or(StorageDeviceError::Corrupted)?;
+            for entry in entries.iter().rev() {
+                overlay(offset, buf, entry.offset, entry.previous);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, _offset: u64, _buf: &[u8]) -> StorageDeviceResult<()> {
+        Err(StorageDeviceError::Unsupported)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.device.len()
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for JournaledDevice<S> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        self.storage_device.read(self.journal_len + offset, buf)
+    }
+
+    /// Log the write to the journal, then perform it, in transactions no bigger than the payload
+    /// of the journal.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        let chunk_len = core::cmp::min((self.capacity() - ENTRY_HEADER_LEN) / 2, u32::MAX as usize);
+        for (i, chunk) in buf.chunks(chunk_len).enumerate() {
+            self.push_entry(offset + (i * chunk_len) as u64, chunk)?;
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.storage_device.len()?.saturating_sub(self.journal_len))
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// The size of the journal of the tests, whose ring holds three single block transactions.
+    const JOURNAL_LEN: u64 = 8 * Block::LEN_U64;
+
+    /// The size of the data following the journal in the tests.
+    const DATA_LEN: usize = 8 * 1024;
+
+    /// An operation done on a [RecordingDevice].
+    #[derive(Debug, Clone)]
+    enum Operation {
+        Write(u64, Vec<u8>),
+        Barrier,
+    }
+
+    /// A device recording its writes and barriers, so crashes can be simulated by replaying only
+    /// the operations before a given point over the initial content.
+    #[derive(Debug)]
+    struct RecordingDevice {
+        data: Vec<u8>,
+        operations: Vec<Operation>,
+    }
+
+    impl StorageDevice for RecordingDevice {
+        fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+            self.data.read(offset, buf)
+        }
+
+        fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+            self.operations.push(Operation::Write(offset, buf.to_vec()));
+            self.data.write(offset, buf)
+        }
+
+        fn len(&mut self) -> StorageDeviceResult<u64> {
+            StorageDevice::len(&mut self.data)
+        }
+
+        fn flush(&mut self) -> StorageDeviceResult<()> {
+            self.operations.push(Operation::Barrier);
+            Ok(())
+        }
+
+        fn barrier(&mut self) -> StorageDeviceResult<()> {
+            self.operations.push(Operation::Barrier);
+            Ok(())
+        }
+    }
+
+    /// Return the device holding a new journal followed by data filled with 0x11.
+    fn journaled_image() -> Vec<u8> {
+        let image = vec![0x11u8; JOURNAL_LEN as usize + DATA_LEN];
+        JournaledDevice::create(image, JOURNAL_LEN)
+            .unwrap()
+            .into_inner()
+    }
+
+    /// Return the content of ``device``.
+    fn content<D: StorageDevice>(device: &mut D) -> Vec<u8> {
+        let mut data = vec![0u8; DATA_LEN];
+        device.read(0, &mut data).unwrap();
+        data
+    }
+
+    /// Return the content of the data of ``image``, once opened.
+    fn data_after_open(image: Vec<u8>) -> Vec<u8> {
+        content(&mut JournaledDevice::open(image).unwrap())
+    }
+
+    /// Write the transaction of the crash tests, two writes at both ends of the data.
+    fn write_transaction<S: StorageDevice>(device: &mut JournaledDevice<S>) {
+        let mut transaction = device.begin();
+        transaction.write(100, &[0x22; 50]).unwrap();
+        transaction.write(6000, &[0x33; 1000]).unwrap();
+        transaction.commit().unwrap();
+    }
+
+    /// Return the data once the transaction of the crash tests is performed.
+    fn committed_data() -> Vec<u8> {
+        let mut data = vec![0x11u8; DATA_LEN];
+        data[100..150].fill(0x22);
+        data[6000..7000].fill(0x33);
+        data
+    }
+
+    /// Log the transaction of the crash tests as the first one of ``image``, without performing
+    /// it, and return its payload.
+    fn log_transaction(image: &mut Vec<u8>) -> Vec<u8> {
+        let mut device = JournaledDevice::open(core::mem::take(image)).unwrap();
+        device.push_entry(100, &[0x22; 50]).unwrap();
+        device.push_entry(6000, &[0x33; 200]).unwrap();
+        let payload = device.payload.clone();
+        let record = CommitRecord::new(1, device.entries, &payload);
+        *image = device.into_inner();
+        image.write(RING_OFFSET + RECORD_LEN, &payload).unwrap();
+        image.write(RING_OFFSET, &record.to_block()[..]).unwrap();
+        payload
+    }
+
+    #[test]
+    fn committed_writes_survive_reopening() {
+        let mut device = JournaledDevice::create(journaled_image(), JOURNAL_LEN).unwrap();
+        assert_eq!(StorageDevice::len(&mut device), Ok(DATA_LEN as u64));
+        write_transaction(&mut device);
+        // Plain writes bigger than the payload of the journal are split in transactions.
+        device.write(1000, &[0x44; 4000]).unwrap();
+        assert_eq!(device.sequence(), 5);
+
+        let mut expected = committed_data();
+        expected[1000..5000].fill(0x44);
+        let mut device = JournaledDevice::open(device.into_inner()).unwrap();
+        assert_eq!(device.sequence(), 5);
+        assert_eq!(content(&mut device), expected);
+    }
+
+    #[test]
+    fn crashes_at_any_point_leave_all_or_nothing() {
+        let initial = journaled_image();
+        let mut device = JournaledDevice::open(RecordingDevice {
+            data: initial.clone(),
+            operations: Vec::new(),
+        })
+        .unwrap();
+        write_transaction(&mut device);
+        let operations = device.into_inner().operations;
+        assert_eq!(
+            operations
+                .iter()
+                .filter(|operation| matches!(operation, Operation::Barrier))
+                .count(),
+            2
+        );
+
+        // Stop at every operation, including each barrier, keeping the writes before it.
+        let mut committed_cuts = 0;
+        for cut in 0..=operations.len() {
+            let mut image = initial.clone();
+            for operation in &operations[..cut] {
+                if let Operation::Write(offset, data) = operation {
+                    image.write(*offset, data).unwrap();
+                }
+            }
+            let data_before_open = image[JOURNAL_LEN as usize..].to_vec();
+
+            let mut device = JournaledDevice::open(image).unwrap();
+            let data = content(&mut device);
+            if data == committed_data() {
+                assert_eq!(device.sequence(), 1);
+                committed_cuts += 1;
+            } else {
+                assert_eq!(data, vec![0x11u8; DATA_LEN], "crash at operation {}", cut);
+                assert_eq!(data_before_open, data);
+                assert_eq!(device.sequence(), 0);
+            }
+        }
+        // The transaction is committed once its commit record is written after its payload, the
+        // first two writes, and is completed by the replay.
+        assert_eq!(committed_cuts, operations.len() - 1);
+    }
+
+    #[test]
+    fn committed_transactions_are_replayed_on_open() {
+        let mut image = journaled_image();
+        log_transaction(&mut image);
+        assert!(image[JOURNAL_LEN as usize..]
+            .iter()
+            .all(|byte| *byte == 0x11));
+
+        let mut device = JournaledDevice::open(image).unwrap();
+        let mut expected = vec![0x11u8; DATA_LEN];
+        expected[100..150].fill(0x22);
+        expected[6000..6200].fill(0x33);
+        assert_eq!(content(&mut device), expected);
+        assert_eq!(device.sequence(), 1);
+
+        // The checkpoint keeps the transaction from being replayed over later writes.
+        let mut image = device.into_inner();
+        image.write(JOURNAL_LEN + 100, &[0x55; 10]).unwrap();
+        expected[100..110].fill(0x55);
+        assert_eq!(data_after_open(image), expected);
+    }
+
+    #[test]
+    fn torn_commit_records_are_not_replayed() {
+        let mut image = journaled_image();
+        let payload = log_transaction(&mut image);
+
+        // The record reached the journal, but the end of the payload didn't.
+        let mut torn = image.clone();
+        let end = (RING_OFFSET + RECORD_LEN) as usize + payload.len();
+        torn[end - 10..end].fill(0);
+        assert_eq!(data_after_open(torn), vec![0x11u8; DATA_LEN]);
+
+        // The same goes for a record whose own fields are damaged.
+        let mut torn = image.clone();
+        torn[RING_OFFSET as usize + 20] ^= 1;
+        assert_eq!(data_after_open(torn), vec![0x11u8; DATA_LEN]);
+
+        // Once intact, the record is replayed.
+        assert_ne!(data_after_open(image), vec![0x11u8; DATA_LEN]);
+    }
+
+    #[test]
+    fn transactions_read_their_own_writes() {
+        let mut device = JournaledDevice::create(journaled_image(), JOURNAL_LEN).unwrap();
+        let mut transaction = device.begin();
+        transaction.write(100, &[0x22; 50]).unwrap();
+        transaction.write(120, &[0x33; 50]).unwrap();
+
+        let mut buf = [0u8; 100];
+        transaction.read(90, &mut buf).unwrap();
+        let mut expected = [0x11u8; 100];
+        expected[10..30].fill(0x22);
+        expected[30..80].fill(0x33);
+        assert_eq!(buf, expected);
+        assert_eq!(
+            transaction.write(DATA_LEN as u64 - 1, &[0; 2]),
+            Err(StorageDeviceError::OutOfBounds)
+        );
+        transaction.commit().unwrap();
+
+        device.read(90, &mut buf).unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn dropped_transactions_are_rolled_back() {
+        let mut device = JournaledDevice::create(journaled_image(), JOURNAL_LEN).unwrap();
+        {
+            let mut transaction = device.begin();
+            transaction.write(100, &[0x22; 50]).unwrap();
+        }
+        let mut transaction = device.begin();
+        transaction.write(6000, &[0x33; 10]).unwrap();
+        transaction.rollback();
+        assert_eq!(device.sequence(), 0);
+
+        // Neither transaction reached the device, nor leaks into the next one.
+        let mut transaction = device.begin();
+        transaction.write(0, &[0x44; 10]).unwrap();
+        transaction.commit().unwrap();
+        let mut expected = vec![0x11u8; DATA_LEN];
+        expected[0..10].fill(0x44);
+        assert_eq!(data_after_open(device.into_inner()), expected);
+    }
+
+    #[test]
+    fn views_undo_later_transactions() {
+        let mut device = JournaledDevice::create(journaled_image(), JOURNAL_LEN).unwrap();
+        let mut states = vec![content(&mut device)];
+        for (i, byte) in [0x22u8, 0x33, 0x44].iter().enumerate() {
+            let mut transaction = device.begin();
+            transaction.write(100 * i as u64, &[*byte; 150]).unwrap();
+            transaction.write(120, &[*byte; 10]).unwrap();
+            transaction.commit().unwrap();
+            states.push(content(&mut device));
+        }
+
+        for _ in 0..2 {
+            assert_eq!(device.sequence(), 3);
+            assert_eq!(device.oldest_sequence(), 1);
+            for (sequence, state) in states.iter().enumerate() {
+                let mut view = device.view_at(sequence as u64).unwrap();
+                assert_eq!(view.sequence(), sequence as u64);
+                assert_eq!(&content(&mut view), state);
+                assert_eq!(view.write(0, &[0]), Err(StorageDeviceError::Unsupported));
+            }
+            assert_eq!(
+                device.view_at(4).err(),
+                Some(StorageDeviceError::InvalidRequest)
+            );
+
+            // The history is found again in the journal.
+            device = JournaledDevice::open(device.into_inner()).unwrap();
+        }
+    }
+
+    #[test]
+    fn views_need_the_transactions_to_undo() {
+        let mut device = JournaledDevice::create(journaled_image(), JOURNAL_LEN).unwrap();
+        let mut states = vec![content(&mut device)];
+        for i in 0..5u8 {
+            device.write(u64::from(i) * 10, &[i + 1; 20]).unwrap();
+            states.push(content(&mut device));
+        }
+
+        // The ring only holds the last three transactions, so the device can be seen after the
+        // second one at the earliest.
+        for _ in 0..2 {
+            assert_eq!(device.sequence(), 5);
+            assert_eq!(device.oldest_sequence(), 3);
+            for sequence in 2..=5 {
+                let mut view = device.view_at(sequence).unwrap();
+                assert_eq!(content(&mut view), states[sequence as usize]);
+            }
+            assert_eq!(
+                device.view_at(1).err(),
+                Some(StorageDeviceError::OutOfBounds)
+            );
+            device = JournaledDevice::open(device.into_inner()).unwrap();
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use crate::clock::Delay;
+use crate::{Capabilities, DeviceIdentity, DeviceInfo, StorageDevice, StorageDeviceResult};
+
+/// How long the requests take on a simulated device.
+///
+/// The latency of a request is the sum of the fixed cost, the transfer time of its bytes, and a
+/// seek time if it doesn't start where the previous request ended.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct LatencyModel {
+    /// The fixed cost of every request, in nanoseconds.
+    pub per_request: u64,
+
+    /// The time to transfer a KiB, in nanoseconds.
+    pub per_kib: u64,
+
+    /// The cost of a request not starting where the previous one ended, in nanoseconds.
+    pub seek: u64,
+
+    /// The additional cost of such a request per GiB between the end of the previous request
+    /// and its start, in nanoseconds.
+    pub seek_per_gib: u64,
+}
+
+impl LatencyModel {
+    /// A 7200 RPM hard drive: 4 ms average rotational latency plus a seek time growing with the
+    /// distance, and 150 MB/s transfers.
+    pub const HARD_DRIVE: LatencyModel = LatencyModel {
+        per_request: 50_000,
+        per_kib: 6_500,
+        seek: 4_000_000,
+        seek_per_gib: 10_000,
+    };
+
+    /// A SATA solid state drive: 80 µs per request, 500 MB/s transfers, no seeks.
+    pub const SSD: LatencyModel = LatencyModel {
+        per_request: 80_000,
+        per_kib: 2_000,
+        seek: 0,
+        seek_per_gib: 0,
+    };
+
+    /// An SD card in SPI mode: 1 ms per request, 2 MB/s transfers, no seeks.
+    pub const SD_CARD: LatencyModel = LatencyModel {
+        per_request: 1_000_000,
+        per_kib: 500_000,
+        seek: 0,
+        seek_per_gib: 0,
+    };
+
+    /// Return the latency of a request of ``len`` bytes at ``offset``, the previous one having
+    /// ended at ``head``, in nanoseconds.
+    pub fn latency(&self, head: u64, offset: u64, len: u64) -> u64 {
+        let transfer = (u128::from(len) * u128::from(self.per_kib) / 1024) as u64;
+        let seek = if offset == head {
+            0
+        } else {
+            let distance = u128::from(offset.abs_diff(head));
+            let per_distance = (distance * u128::from(self.seek_per_gib)) >> 30;
+            self.seek.saturating_add(per_distance as u64)
+        };
+        self.per_request
+            .saturating_add(transfer)
+            .saturating_add(seek)
+    }
+}
+
+/// A storage device making the requests to the device it wraps take as long as on a simulated
+/// device, so higher layers can be benchmarked against realistic devices without the hardware.
+///
+/// The latency of each request is computed from a [LatencyModel], one for reads and one for
+/// writes, and waited for on a [Delay] before performing it. With [NoDelay], nothing is waited
+/// for, and [LatencyDevice::elapsed] gives the time the requests would have taken.
+///
+/// [NoDelay]: crate::NoDelay
+#[derive(Debug)]
+pub struct LatencyDevice<S: StorageDevice, D: Delay> {
+    /// The inner storage device.
+    storage_device: S,
+
+    /// How to wait for the latency of the requests.
+    delay: D,
+
+    /// The model for reads.
+    read_model: LatencyModel,
+
+    /// The model for writes.
+    write_model: LatencyModel,
+
+    /// The cost of a flush, in nanoseconds.
+    flush_latency: u64,
+
+    /// Where the last request ended.
+    head: u64,
+
+    /// The latency injected so far, in nanoseconds.
+    elapsed: u64,
+}
+
+impl<S: StorageDevice, D: Delay> LatencyDevice<S, D> {
+    /// Wrap ``storage_device``, delaying its reads and writes according to ``model`` with
+    /// ``delay``.
+    pub fn new(storage_device: S, delay: D, model: LatencyModel) -> Self {
+        LatencyDevice {
+            storage_device,
+            delay,
+            read_model: model,
+            write_model: model,
+            flush_latency: 0,
+            head: 0,
+            elapsed: 0,
+        }
+    }
+
+    /// Use a different model for writes, for media writing slower than they read.
+    pub fn with_write_model(mut self, model: LatencyModel) -> Self {
+        self.write_model = model;
+        self
+    }
+
+    /// Make each flush take ``latency`` nanoseconds.
+    pub fn with_flush_latency(mut self, latency: u64) -> Self {
+        self.flush_latency = latency;
+        self
+    }
+
+    /// Return the latency injected so far, in nanoseconds.
+    pub fn elapsed(&self) -> u64 {
+        self.elapsed
+    }
+
+    /// Return a reference to the inner storage device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the inner storage device.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Consume the wrapper, returning the inner storage device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Wait for ``latency`` nanoseconds.
+    fn wait(&mut self, latency: u64) {
+        if latency > 0 {
+            self.delay.delay(latency);
+            self.elapsed = self.elapsed.saturating_add(latency);
+        }
+    }
+
+    /// Wait for the latency of a request of ``len`` bytes at ``offset``.
+    fn simulate(&mut self, offset: u64, len: u64, is_write: bool) {
+        let model = if is_write {
+            self.write_model
+        } else {
+            self.read_model
+        };
+        let latency = model.latency(self.head, offset, len);
+        self.head = offset.saturating_add(len);
+        self.wait(latency);
+    }
+}
+
+impl<S: StorageDevice, D: Delay> StorageDevice for LatencyDevice<S, D> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.simulate(offset, buf.len() as u64, false);
+        self.storage_device.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.simulate(offset, buf.len() as u64, true);
+        self.storage_device.write(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.storage_device.len()
+    }
+
+    /// Delay the request as a single read of the whole range.
+    fn read_vectored(&mut self, offset: u64, bufs: &mut [&mut [u8]]) -> StorageDeviceResult<()> {
+        let len = bufs.iter().map(|buf| buf.len() as u64).sum();
+        self.simulate(offset, len, false);
+        self.storage_device.read_vectored(offset, bufs)
+    }
+
+    /// Delay the request as a single write of the whole range.
+    fn write_vectored(&mut self, offset: u64, bufs: &[&[u8]]) -> StorageDeviceResult<()> {
+        let len = bufs.iter().map(|buf| buf.len() as u64).sum();
+        self.simulate(offset, len, true);
+        self.storage_device.write_vectored(offset, bufs)
+    }
+
+    /// Delay the request as a write of the whole range.
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.simulate(offset, len, true);
+        self.storage_device.write_zeroes(offset, len)
+    }
+
+    /// Delay the request as a write of the whole range.
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        self.simulate(offset, len, true);
+        self.storage_device.fill(offset, len, byte)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.discard(offset, len)
+    }
+
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        self.storage_device.next_data(offset)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.wait(self.flush_latency);
+        self.storage_device.flush()
+    }
+
+    /// Simulate the write, followed by the latency of a flush.
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.simulate(offset, buf.len() as u64, true);
+        self.wait(self.flush_latency);
+        self.storage_device.write_fua(offset, buf)
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.storage_device.capabilities()
+    }
+}
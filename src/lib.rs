@@ -3,6 +3,9 @@
 
 #![no_std]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 #[cfg(feature = "std")]
 extern crate std;
 
@@ -11,8 +14,379 @@ pub mod block;
 
 pub use block::*;
 
+/// Buffers filled by reads, which may start uninitialized.
+pub mod read_buf;
+
+pub use read_buf::ReadBuf;
+
+/// Platform specific helpers for std backends.
+#[cfg(feature = "std")]
+mod sys;
+
+/// Storage device performing its I/O through io_uring.
+#[cfg(all(feature = "uring-storage-device", target_os = "linux"))]
+pub mod uring;
+
+#[cfg(all(feature = "uring-storage-device", target_os = "linux"))]
+pub use uring::UringStorageDevice;
+
+/// Asynchronous storage device backed by a tokio file.
+#[cfg(feature = "tokio")]
+pub mod tokio_file;
+
+#[cfg(feature = "tokio")]
+pub use tokio_file::TokioFileStorageDevice;
+
+/// Storage device backed by a memory-mapped file.
+#[cfg(feature = "mmap-storage-device")]
+pub mod mmap;
+
+#[cfg(feature = "mmap-storage-device")]
+pub use mmap::MmapStorageDevice;
+
+/// Block device and raw flash driving a SPI NOR flash chip through embedded-hal.
+#[cfg(feature = "spi-nor")]
+pub mod spi_nor;
+
+#[cfg(feature = "spi-nor")]
+pub use spi_nor::{SpiNorFlash, SpiNorInfo};
+
+/// Block device speaking the SPI protocol of SD cards through embedded-hal.
+#[cfg(feature = "sd-spi")]
+pub mod sd_spi;
+
+#[cfg(feature = "sd-spi")]
+pub use sd_spi::{SdCardType, SdSpiBlockDevice};
+
+/// Block device over the virtqueue of a virtio-blk device.
+pub mod virtio;
+
+pub use virtio::{VirtQueue, VirtioBlkDevice};
+
+#[cfg(all(feature = "std", any(target_os = "linux", windows)))]
+mod aligned_io;
+
+/// Storage device bypassing the page cache with ``O_DIRECT``.
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod direct;
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub use direct::DirectFileDevice;
+
+/// Raw Windows physical drives.
+#[cfg(all(feature = "std", windows))]
+pub mod physical_drive;
+
+#[cfg(all(feature = "std", windows))]
+pub use physical_drive::PhysicalDrive;
+
+/// Typed reads and writes, and erasure helpers, on storage devices.
+pub mod ext;
+
+pub use ext::{ErasePattern, NoPadding, StorageDeviceExt};
+
+/// Reporting the allocated and unallocated ranges of sparse devices.
+pub mod sparse;
+
+pub use sparse::{Ranges, SparseRange};
+
+/// Storage device growing its backing storage on writes past the end.
+pub mod grow;
+
+pub use grow::{AutoGrowDevice, GrowthPolicy};
+
+/// Heap buffers with a guaranteed alignment.
+#[cfg(feature = "alloc")]
+pub mod aligned;
+
+#[cfg(feature = "alloc")]
+pub use aligned::AlignedBox;
+
+/// Buffers reachable by DMA, and block devices transferring to them.
+pub mod dma;
+
+pub use dma::{DmaBlockDevice, DmaBounceDevice, DmaBuffer, DmaPool};
+#[cfg(feature = "alloc")]
+pub use dma::{IdentityDmaBuffer, IdentityDmaPool};
+
+/// Model, serial number and unique identifier of devices.
+pub mod identity;
+
+pub use identity::{DeviceIdentity, IdentityString};
+
+/// In-memory storage devices.
+pub mod memory;
+
+pub use memory::RomDevice;
+
+/// Extent-based reads and writes.
+pub mod extent;
+
+pub use extent::{read_extents, write_extents, Extent};
+
+/// Storage device associating metadata with every block.
+pub mod sidecar;
+
+pub use sidecar::{SidecarLayout, SidecarStorageDevice};
+
+/// Monotonic time sources and delays.
+pub mod clock;
+
+pub use clock::{Clock, Deadline, Delay, NoDelay};
+#[cfg(feature = "std")]
+pub use clock::{StdClock, StdDelay};
+
+/// Block device request tracing.
+pub mod trace;
+
+pub use trace::TracingBlockDevice;
+
+/// CRC-32 checksum helpers.
+pub mod crc;
+
+/// SHA-256 hash helpers.
+pub mod sha256;
+
+/// Redundant and checksummed superblock storage.
+#[cfg(feature = "plain")]
+pub mod superblock;
+
+#[cfg(feature = "plain")]
+pub use superblock::Superblock;
+
+/// Remote storage device access protocol.
+pub mod rpc;
+
+#[cfg(feature = "std")]
+pub use rpc::IoTransport;
+
+/// Reliable transport over lossy serial links.
+pub mod serial;
+
+pub use rpc::{RpcClient, RpcServer, Transport};
+#[cfg(feature = "std")]
+pub use serial::IoSerialLink;
+
+/// Network Block Device client.
+pub mod nbd;
+
+pub use nbd::NbdClientDevice;
+
+/// Network Block Device server.
+#[cfg(feature = "alloc")]
+pub mod nbd_server;
+
+#[cfg(feature = "alloc")]
+pub use nbd_server::NbdServer;
+
+/// Process-wide registry of opened devices.
+#[cfg(feature = "std")]
+pub mod registry;
+
+#[cfg(feature = "std")]
+pub use registry::{DeviceKey, DeviceRegistry, SharedDevice};
+
+/// Object-safe block device interface over byte slices.
+pub mod dynamic;
+
+pub use dynamic::DynBlockDevice;
+
+/// Sharing storage devices behind locks.
+pub mod lock;
+
+pub use lock::SharedRef;
+
+/// Block devices remapping block addresses, through a scrambling permutation or a translation
+/// table.
+pub mod remap;
+
+pub use remap::{RemappedDevice, ScrambledBlockDevice};
+
+/// Block device exposing another logical block size than its inner device.
+#[cfg(feature = "alloc")]
+pub mod block_size;
+
+#[cfg(feature = "alloc")]
+pub use block_size::BlockSizeAdapter;
+
+/// Raw flash, programmed by pages and erased by erase blocks.
+pub mod nand;
+
+pub use nand::{NandDevice, NandGeometry};
+
+#[cfg(feature = "alloc")]
+pub use nand::MemoryNand;
+
+/// Flash translation layer presenting raw flash as a block device.
+#[cfg(feature = "alloc")]
+pub mod ftl;
+
+#[cfg(feature = "alloc")]
+pub use ftl::Ftl;
+
+/// Raw flash protected by error-correcting codes.
+#[cfg(feature = "alloc")]
+pub mod ecc;
+
+#[cfg(feature = "alloc")]
+pub use ecc::EccDevice;
+
+/// Storage device retrying failed operations.
+pub mod retry;
+
+pub use retry::{RetryPolicy, RetryingDevice};
+pub use serial::{SerialLink, SerialTransport};
+
+/// Block device simulating a bus shared with other peripherals.
+pub mod bus;
+
+pub use bus::{BusContentionDevice, BusStats, BusTiming, Peripheral};
+
+/// Configurable block device recording and checking the operations performed on it, for tests.
+#[cfg(feature = "alloc")]
+pub mod mock;
+
+#[cfg(feature = "alloc")]
+pub use mock::{MockBlockDevice, MockBlockDeviceBuilder, MockOperation};
+
+/// Storage device checking another one against an in-memory model.
+#[cfg(feature = "alloc")]
+pub mod shadow;
+
+#[cfg(feature = "alloc")]
+pub use shadow::ShadowCheckedDevice;
+
+/// Block devices, buffers and operation sequences for testing code built on this crate.
+#[cfg(any(feature = "test-util", all(test, feature = "alloc")))]
+pub mod testing;
+
+/// Storage device simulating the latency of real devices.
+pub mod latency;
+
+pub use latency::{LatencyDevice, LatencyModel};
+
+/// Storage device abandoning requests that take too long.
+pub mod timeout;
+
+pub use timeout::TimeoutDevice;
+
+/// Storage device limiting its bandwidth and operations per second.
+pub mod throttle;
+
+pub use throttle::ThrottledDevice;
+
+/// Storage device reading back and checking every write.
+pub mod verify;
+
+pub use verify::VerifiedWrites;
+
+/// Copying and comparing data between storage devices.
+pub mod copy;
+
+pub use copy::{clone_device, compare_devices, copy_range, copy_within, diff_ranges, DiffRanges};
+
+/// Storage device prefetching the data following sequential reads.
+#[cfg(feature = "alloc")]
+pub mod readahead;
+
+#[cfg(feature = "alloc")]
+pub use readahead::ReadAheadDevice;
+
+/// Storage device merging small writes into large block-aligned ones.
+#[cfg(feature = "alloc")]
+pub mod coalesce;
+
+#[cfg(feature = "alloc")]
+pub use coalesce::WriteCoalescer;
+
+/// Storage device sorting and merging batched requests.
+#[cfg(feature = "alloc")]
+pub mod elevator;
+
+#[cfg(feature = "alloc")]
+pub use elevator::ElevatorScheduler;
+
+/// Copy-on-write overlay over a read-only storage device.
+#[cfg(feature = "alloc")]
+pub mod cow;
+
+#[cfg(feature = "alloc")]
+pub use cow::{ChainBase, CowDevice, OverlayChain};
+
+/// Write-ahead journal making every write atomic across crashes.
+#[cfg(feature = "alloc")]
+pub mod journal;
+
+#[cfg(feature = "alloc")]
+pub use journal::{JournalView, JournaledDevice, Transaction};
+
+/// Shadow-paged storage device, making every write atomic without writing it twice.
+#[cfg(feature = "alloc")]
+pub mod shadow_paging;
+
+#[cfg(feature = "alloc")]
+pub use shadow_paging::ShadowPagedDevice;
+
+/// Storage device tracking the ranges modified, for incremental backups.
+#[cfg(feature = "alloc")]
+pub mod dirty;
+
+#[cfg(feature = "alloc")]
+pub use dirty::{DirtyExtents, DirtyTrackingDevice};
+
+/// Storage device recording every write in a tamper-evident audit log.
+pub mod audit;
+
+pub use audit::AuditedDevice;
+
+/// Storage device over a qcow2 image.
+#[cfg(feature = "qcow2-device")]
+pub mod qcow2;
+
+#[cfg(feature = "qcow2-device")]
+pub use qcow2::Qcow2Device;
+
+/// Storage device over a VHD image.
+#[cfg(feature = "vhd-device")]
+pub mod vhd;
+
+#[cfg(feature = "vhd-device")]
+pub use vhd::{VhdDevice, VhdDiskType};
+
+/// Thinly provisioned storage device, allocating backing space on first write.
+#[cfg(feature = "alloc")]
+pub mod thin;
+
+#[cfg(feature = "alloc")]
+pub use thin::ThinDevice;
+
+/// Read-only storage device over an image compressed by chunks.
+#[cfg(feature = "compressed-device")]
+pub mod compressed;
+
+#[cfg(feature = "compressed-device")]
+pub use compressed::{CompressedDevice, CompressedDeviceBuilder};
+
+/// MBR and GPT partition tables.
+#[cfg(feature = "alloc")]
+pub mod partition;
+
+#[cfg(feature = "alloc")]
+pub use partition::{GptBuilder, GptPartition, Guid, MbrBuilder, MbrPartition};
+
+/// Identification of filesystems and containers from their signature.
+pub mod probe;
+
+pub use probe::{probe, probe_at, Filesystem};
+
+/// The commonly needed traits and types, to import them all at once.
+pub mod prelude;
+
 /// Represent a storage device error.
-#[derive(Debug)]
+///
+/// New errors may be added in minor releases, so matching on it needs a wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum StorageDeviceError {
     /// Read error.
     ReadError,
@@ -20,25 +394,499 @@ pub enum StorageDeviceError {
     /// Write error.
     WriteError,
 
+    /// The requested range is not inside the storage device.
+    OutOfBounds,
+
+    /// The data read from the storage device is invalid.
+    Corrupted,
+
+    /// The device is temporarily unable to serve the request, which may succeed if retried.
+    Busy,
+
+    /// The medium failed to read or write the requested range.
+    MediaError,
+
+    /// There is no space left on the device.
+    OutOfSpace,
+
+    /// The device doesn't support the operation.
+    Unsupported,
+
+    /// The data read back after a write differs from the data written.
+    VerificationFailed,
+
+    /// The request makes no sense, such as an empty transfer sent to a device validating requests
+    /// strictly.
+    InvalidRequest,
+
     /// Unknown error.
     Unknown,
 }
 
+impl StorageDeviceError {
+    /// Check whether retrying the failed operation may succeed.
+    pub fn is_transient(&self) -> bool {
+        *self == StorageDeviceError::Busy
+    }
+
+    /// Check whether the error comes from a failure of the medium itself, meaning the data may be
+    /// available on another copy.
+    pub fn is_media_error(&self) -> bool {
+        *self == StorageDeviceError::MediaError
+    }
+
+    /// Check whether the device ran out of space.
+    pub fn is_out_of_space(&self) -> bool {
+        *self == StorageDeviceError::OutOfSpace
+    }
+
+    /// Check whether the device doesn't support the operation.
+    pub fn is_unsupported(&self) -> bool {
+        *self == StorageDeviceError::Unsupported
+    }
+
+    /// Convert the error to a [BlockError], falling back to ``default`` for errors that don't
+    /// have a block equivalent.
+    #[cfg(feature = "std")]
+    pub(crate) fn into_block_error(self, default: BlockError) -> BlockError {
+        match self {
+            StorageDeviceError::Busy => BlockError::Busy,
+            StorageDeviceError::MediaError => BlockError::MediaError,
+            StorageDeviceError::OutOfSpace => BlockError::OutOfSpace,
+            StorageDeviceError::Unsupported => BlockError::Unsupported,
+            _ => default,
+        }
+    }
+}
+
 /// Represent a storage device result.
 pub type StorageDeviceResult<T> = core::result::Result<T, StorageDeviceError>;
 
+/// Information about a storage device.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Whether [StorageDevice::discard] frees the storage backing the discarded range, leaving a
+    /// hole that reads as zeros.
+    pub supports_holes: bool,
+
+    /// The geometry of the device, describing the requests it performs best.
+    pub geometry: BlockGeometry,
+}
+
+/// A single operation submitted to a [StorageDevice] as part of a batch.
+#[derive(Debug)]
+pub enum StorageRequest<'a> {
+    /// Read the data at the given ``offset`` into ``buf``.
+    Read {
+        /// The offset of the data in the storage device.
+        offset: u64,
+        /// The buffer to read into.
+        buf: &'a mut [u8],
+    },
+
+    /// Write the data from ``buf`` at the given ``offset``.
+    Write {
+        /// The offset of the data in the storage device.
+        offset: u64,
+        /// The buffer to write.
+        buf: &'a [u8],
+    },
+}
+
+impl<'a> StorageRequest<'a> {
+    /// Return the offset of the request in the storage device.
+    pub fn offset(&self) -> u64 {
+        match self {
+            StorageRequest::Read { offset, .. } | StorageRequest::Write { offset, .. } => *offset,
+        }
+    }
+
+    /// Return the length of the buffer of the request.
+    pub fn len(&self) -> usize {
+        match self {
+            StorageRequest::Read { buf, .. } => buf.len(),
+            StorageRequest::Write { buf, .. } => buf.len(),
+        }
+    }
+
+    /// Return whether the buffer of the request is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return whether this is a write request.
+    pub fn is_write(&self) -> bool {
+        match self {
+            StorageRequest::Read { .. } => false,
+            StorageRequest::Write { .. } => true,
+        }
+    }
+}
+
 /// Represent a device managing storage.
 // we don't need is_empty, this would be stupid.
 #[allow(clippy::len_without_is_empty)]
 pub trait StorageDevice: core::fmt::Debug {
     /// Read the data at the given ``offset`` in the storage device into a given buffer.
+    ///
+    /// The whole buffer is filled, or an error is returned.
     fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()>;
 
     /// Write the data from the given buffer at the given ``offset`` in the storage device.
+    ///
+    /// The whole buffer is written, or an error is returned.
     fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()>;
 
+    /// Read the data at the given ``offset`` in the storage device into a given buffer, returning
+    /// how many bytes were read.
+    ///
+    /// Fewer bytes than the size of the buffer may be read, for instance when reaching the end of
+    /// the device or a range that can't be read. An error is only returned if nothing could be
+    /// read.
+    ///
+    /// The default implementation calls [StorageDevice::read] with the whole buffer.
+    fn read_partial(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        self.read(offset, buf)?;
+        Ok(buf.len())
+    }
+
+    /// Read the data at the given ``offset`` in the storage device into the bytes left to fill
+    /// of ``buf``, marking them as filled.
+    ///
+    /// The whole rest of the buffer is filled, or an error is returned. Devices copying their data
+    /// into the buffer should override it to skip initializing it.
+    ///
+    /// The default implementation initializes the rest of the buffer, then calls
+    /// [StorageDevice::read].
+    fn read_buf(&mut self, offset: u64, buf: &mut ReadBuf<'_>) -> StorageDeviceResult<()> {
+        let unfilled = buf.initialize_unfilled();
+        let len = unfilled.len();
+        self.read(offset, unfilled)?;
+        buf.advance(len);
+        Ok(())
+    }
+
+    /// Read the data at the given ``offset`` in the storage device into a buffer which may be
+    /// uninitialized, returning it initialized.
+    ///
+    /// The whole buffer is filled, or an error is returned. Large reads avoid zeroing the buffer
+    /// first on devices overriding [StorageDevice::read_buf].
+    ///
+    /// The default implementation calls [StorageDevice::read_buf].
+    fn read_uninit<'a>(
+        &mut self,
+        offset: u64,
+        buf: &'a mut [core::mem::MaybeUninit<u8>],
+    ) -> StorageDeviceResult<&'a mut [u8]> {
+        let mut buf = ReadBuf::uninit(buf);
+        self.read_buf(offset, &mut buf)?;
+        Ok(buf.into_filled())
+    }
+
+    /// Write the data from the given buffer at the given ``offset`` in the storage device,
+    /// returning how many bytes were written.
+    ///
+    /// Fewer bytes than the size of the buffer may be written, for instance when reaching the end
+    /// of the device or a range that can't be written. An error is only returned if nothing could
+    /// be written.
+    ///
+    /// The default implementation calls [StorageDevice::write] with the whole buffer.
+    fn write_partial(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<usize> {
+        self.write(offset, buf)?;
+        Ok(buf.len())
+    }
+
     /// Return the total size of the storage device in bytes.
     fn len(&mut self) -> StorageDeviceResult<u64>;
+
+    /// Read the data at the given ``offset`` in the storage device into a sequence of buffers.
+    ///
+    /// The buffers are filled in order, covering a contiguous range of the device.
+    ///
+    /// The default implementation calls [StorageDevice::read] for every buffer.
+    fn read_vectored(&mut self, offset: u64, bufs: &mut [&mut [u8]]) -> StorageDeviceResult<()> {
+        let mut current_offset = offset;
+        for buf in bufs.iter_mut() {
+            self.read(current_offset, buf)?;
+            current_offset += buf.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Write the data from a sequence of buffers at the given ``offset`` in the storage device.
+    ///
+    /// The buffers are written in order, covering a contiguous range of the device.
+    ///
+    /// The default implementation calls [StorageDevice::write] for every buffer.
+    fn write_vectored(&mut self, offset: u64, bufs: &[&[u8]]) -> StorageDeviceResult<()> {
+        let mut current_offset = offset;
+        for buf in bufs.iter() {
+            self.write(current_offset, buf)?;
+            current_offset += buf.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Perform a batch of requests, as if they were performed one after the other.
+    ///
+    /// If a request fails, an error is returned and the following requests are not performed.
+    ///
+    /// The default implementation performs the requests one after the other.
+    fn submit(&mut self, requests: &mut [StorageRequest<'_>]) -> StorageDeviceResult<()> {
+        for request in requests.iter_mut() {
+            match request {
+                StorageRequest::Read { offset, buf } => self.read(*offset, buf)?,
+                StorageRequest::Write { offset, buf } => self.write(*offset, buf)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the ``len`` bytes at the given ``offset`` to zero.
+    ///
+    /// Backends should override it with a command zeroing the range without transferring the
+    /// zeros, such as WRITE SAME or punching a hole in a file.
+    ///
+    /// The default implementation calls [StorageDevice::fill].
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.fill(offset, len, 0)
+    }
+
+    /// Set the ``len`` bytes at the given ``offset`` to ``byte``.
+    ///
+    /// The default implementation checks that the range is inside the device, so a failed fill
+    /// leaves it untouched, then calls [StorageDevice::write] repeatedly with a small buffer
+    /// filled with ``byte``.
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        fill_with_writes(self, offset, len, byte)
+    }
+
+    /// Discard the ``len`` bytes at the given ``offset``, letting the device reclaim the storage
+    /// backing them.
+    ///
+    /// This is only a hint: the content of the range is unspecified afterwards, unless
+    /// [DeviceInfo::supports_holes] is set, in which case it reads as zeros.
+    ///
+    /// The default implementation does nothing.
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        let _ = (offset, len);
+        Ok(())
+    }
+
+    /// Return the first range of allocated data at or after the given ``offset``, or None if
+    /// the rest of the device is a hole.
+    ///
+    /// Holes read as zeros, so copying a device can skip them.
+    ///
+    /// The default implementation reports the whole device as allocated.
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        let len = self.len()?;
+        Ok(if offset < len {
+            Some(offset..len)
+        } else {
+            None
+        })
+    }
+
+    /// Ensure every write done so far has reached stable storage.
+    ///
+    /// The default implementation does nothing.
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        Ok(())
+    }
+
+    /// Write ``buf`` at ``offset``, returning once it reached stable storage (forced unit access).
+    ///
+    /// Unlike [StorageDevice::flush], only this write is guaranteed to be durable, which devices
+    /// reporting [Capabilities::FUA] do without flushing everything.
+    ///
+    /// The default implementation writes ``buf``, then flushes the device.
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.write(offset, buf)?;
+        self.flush()
+    }
+
+    /// Ensure every write done before the barrier reaches stable storage before any write done
+    /// after it, e.g. so a journal commit record never lands before the journal.
+    ///
+    /// Unlike [StorageDevice::flush], the barrier doesn't need to wait for the writes to be
+    /// durable, only to order them.
+    ///
+    /// The default implementation flushes the device, which orders the writes as well.
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.flush()
+    }
+
+    /// Return information about the storage device.
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo::default())
+    }
+
+    /// Return the identity of the device, such as its model and serial number.
+    ///
+    /// The default implementation fails with [StorageDeviceError::Unsupported].
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        Err(StorageDeviceError::Unsupported)
+    }
+
+    /// Return the capabilities of the device.
+    ///
+    /// The default implementation returns no capabilities, matching the default implementations
+    /// of the other methods.
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        Ok(Capabilities::empty())
+    }
+
+    /// Erase the whole device with a device-level command (secure discard, ATA SECURITY ERASE,
+    /// NVMe sanitize, ...), which also erases the copies of the data that overwriting can't
+    /// reach, such as remapped sectors or the spare area of flash.
+    ///
+    /// The content of the device is unspecified afterwards.
+    ///
+    /// The default implementation fails with [StorageDeviceError::Unsupported].
+    fn sanitize(&mut self) -> StorageDeviceResult<()> {
+        Err(StorageDeviceError::Unsupported)
+    }
+}
+
+/// A storage device whose size can be changed.
+pub trait Resizable: StorageDevice {
+    /// Set the size of the device to ``len`` bytes, truncating it or extending it with zeros.
+    fn set_len(&mut self, len: u64) -> StorageDeviceResult<()>;
+
+    /// Extend the device with zeros to ``len`` bytes, leaving it alone if it is already at least
+    /// as big.
+    fn grow(&mut self, len: u64) -> StorageDeviceResult<()> {
+        if self.len()? < len {
+            self.set_len(len)?;
+        }
+        Ok(())
+    }
+}
+
+/// A storage device able to serve requests through a shared reference, e.g. from several
+/// threads at once, without a lock around it.
+///
+/// Devices are used through ``Arc<S>``, ``Rc<S>`` or a [SharedRef], which implement
+/// [StorageDevice], so every user gets its own handle on the same device. Devices that only support
+/// exclusive access can be shared by putting them behind a lock, such as a ``std::sync::Mutex`` or
+/// a ``RefCell``. Requests may run concurrently, in which case the result of overlapping writes is
+/// unspecified.
+pub trait SharedStorageDevice: core::fmt::Debug {
+    /// Read the data at the given ``offset`` in the storage device into a given buffer.
+    ///
+    /// The whole buffer is filled, or an error is returned.
+    fn read_shared(&self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()>;
+
+    /// Write the data from the given buffer at the given ``offset`` in the storage device.
+    ///
+    /// The whole buffer is written, or an error is returned.
+    fn write_shared(&self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()>;
+
+    /// Return the total size of the storage device in bytes.
+    fn len_shared(&self) -> StorageDeviceResult<u64>;
+
+    /// Discard the ``len`` bytes at the given ``offset``, like [StorageDevice::discard].
+    ///
+    /// The default implementation does nothing.
+    fn discard_shared(&self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        let _ = (offset, len);
+        Ok(())
+    }
+
+    /// Ensure every write done so far has reached stable storage.
+    ///
+    /// The default implementation does nothing.
+    fn flush_shared(&self) -> StorageDeviceResult<()> {
+        Ok(())
+    }
+
+    /// Return information about the storage device.
+    fn info_shared(&self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo::default())
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<S: SharedStorageDevice + ?Sized> StorageDevice for alloc::sync::Arc<S> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        (**self).read_shared(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        (**self).write_shared(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        (**self).len_shared()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        (**self).discard_shared(offset, len)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        (**self).flush_shared()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        (**self).info_shared()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<S: SharedStorageDevice + ?Sized> StorageDevice for alloc::rc::Rc<S> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        (**self).read_shared(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        (**self).write_shared(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        (**self).len_shared()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        (**self).discard_shared(offset, len)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        (**self).flush_shared()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        (**self).info_shared()
+    }
+}
+
+/// Represent a device managing storage, accessed asynchronously.
+///
+/// The futures are not required to be [Send], as no_std executors are usually single threaded.
+#[allow(async_fn_in_trait)]
+// we don't need is_empty, this would be stupid.
+#[allow(clippy::len_without_is_empty)]
+pub trait AsyncStorageDevice: core::fmt::Debug {
+    /// Read the data at the given ``offset`` in the storage device into a given buffer.
+    ///
+    /// The whole buffer is filled, or an error is returned.
+    async fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()>;
+
+    /// Write the data from the given buffer at the given ``offset`` in the storage device.
+    ///
+    /// The whole buffer is written, or an error is returned.
+    async fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()>;
+
+    /// Return the total size of the storage device in bytes.
+    async fn len(&mut self) -> StorageDeviceResult<u64>;
+
+    /// Ensure every write done so far has reached stable storage.
+    ///
+    /// The default implementation does nothing.
+    async fn flush(&mut self) -> StorageDeviceResult<()> {
+        Ok(())
+    }
 }
 
 impl From<BlockError> for StorageDeviceError {
@@ -46,35 +894,512 @@ impl From<BlockError> for StorageDeviceError {
         match error {
             BlockError::ReadError => StorageDeviceError::ReadError,
             BlockError::WriteError => StorageDeviceError::WriteError,
+            BlockError::Busy => StorageDeviceError::Busy,
+            BlockError::MediaError => StorageDeviceError::MediaError,
+            BlockError::OutOfSpace => StorageDeviceError::OutOfSpace,
+            BlockError::Unsupported => StorageDeviceError::Unsupported,
             BlockError::Unknown => StorageDeviceError::Unknown,
         }
     }
-}
+}
+
+/// Behavior of a [StorageBlockDevice] when a request goes past the end of the device.
+///
+/// This typically happens when the device is backed by a file whose size is not a multiple of
+/// [Block::LEN]: the trailing partial block is not part of the device, but callers may still
+/// want to access the range ending inside it.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum TrailingBlockPolicy {
+    /// Reject the whole request with [StorageDeviceError::OutOfBounds].
+    #[default]
+    Error,
+
+    /// Fill the part of a read past the end of the device with zeros.
+    ///
+    /// Writes past the end of the device are rejected with [StorageDeviceError::OutOfBounds].
+    ZeroPad,
+
+    /// Only transfer the part of the request inside the device, ignoring the rest.
+    ///
+    /// The part of a read buffer past the end of the device is left untouched.
+    Truncate,
+}
+
+/// Maximum amount of blocks transferred by a single batch of block requests in
+/// [StorageBlockDevice::submit].
+const BATCH_BLOCKS: usize = 8;
+
+/// The default amount of blocks of the bounce buffer the whole blocks of misaligned buffers are
+/// transferred through by [StorageBlockDevice].
+#[cfg(feature = "alloc")]
+pub const DEFAULT_BUFFER_BLOCKS: usize = 128;
+
+/// The part of a [StorageRequest] contained in a single block.
+#[derive(Debug, Default, Copy, Clone)]
+struct BatchPiece {
+    /// The index of the request in the batch.
+    request: usize,
+
+    /// The offset of the piece in the buffer of the request.
+    buf_offset: usize,
+
+    /// The slot holding the block in the temporary blocks.
+    slot: usize,
+
+    /// The offset of the piece in the block.
+    block_offset: usize,
+
+    /// The length of the piece.
+    len: usize,
+}
+
+/// The position in a batch of [StorageRequest] being translated into block requests.
+#[derive(Debug, Default)]
+struct BatchCursor {
+    /// The index of the current request.
+    request: usize,
+
+    /// How many bytes of the current request have been translated.
+    progress: usize,
+
+    /// How many bytes of the current request are to be transferred, once checked against the
+    /// bounds of the device.
+    transfer_len: Option<usize>,
+}
+
+/// Implementation of storage device for block device.
+/// NOTE: The whole blocks of buffers aligned on ``align_of::<Block>()``, such as an
+/// ``AlignedBox``, are transferred directly in one request. With the ``alloc`` feature, the whole
+/// blocks of other buffers go through a bounce buffer allocated by the request, of up to
+/// [DEFAULT_BUFFER_BLOCKS] blocks unless configured otherwise with
+/// [StorageBlockDevice::with_buffer_blocks], and without it, they are transferred block by
+/// block, so the heap is never used. The blocks only partly covered by a request are always
+/// transferred one at a time.
+/// NOTE: The temporary blocks live on the stack of each request, so when a shared reference to
+/// the block device is a [BlockDevice] too, requests can be made through a shared reference with
+/// [SharedStorageDevice], concurrently.
+/// NOTE: Empty requests succeed without any request to the block device, wherever they are, and
+/// requests going past the end of the device follow its [TrailingBlockPolicy]. With
+/// [StorageBlockDevice::with_strict_validation], both are rejected instead.
+/// NOTE: The size of the block device is queried once, when the storage block device is created,
+/// and kept up to date by [Resizable::set_len]. When it changes behind its back, such as when the
+/// media of a removable drive is swapped, [StorageBlockDevice::refresh_len] must be called.
+#[derive(Debug)]
+pub struct StorageBlockDevice<B: BlockDevice> {
+    /// The inner block device.
+    block_device: B,
+
+    /// What to do with requests going past the end of the device.
+    trailing_block_policy: TrailingBlockPolicy,
+
+    /// Whether empty requests, and requests starting at or past the end of the device, are
+    /// rejected.
+    strict_validation: bool,
+
+    /// The size of the device, in bytes, once queried.
+    len: Option<u64>,
+
+    /// The maximum amount of blocks of the bounce buffer.
+    #[cfg(feature = "alloc")]
+    buffer_blocks: usize,
+}
+
+impl<B: BlockDevice> StorageBlockDevice<B> {
+    /// Create a new storage block device.
+    ///
+    /// The size of the block device is queried right away. If that fails, it is queried again by
+    /// the first request needing it.
+    pub fn new(mut block_device: B) -> Self {
+        let len = block_device.count().ok().map(BlockCount::into_bytes_count);
+        StorageBlockDevice {
+            block_device,
+            trailing_block_policy: TrailingBlockPolicy::default(),
+            strict_validation: false,
+            len,
+            #[cfg(feature = "alloc")]
+            buffer_blocks: DEFAULT_BUFFER_BLOCKS,
+        }
+    }
+
+    /// Set the behavior on requests going past the end of the device.
+    pub fn with_trailing_block_policy(mut self, policy: TrailingBlockPolicy) -> Self {
+        self.trailing_block_policy = policy;
+        self
+    }
+
+    /// Return the behavior on requests going past the end of the device.
+    pub fn trailing_block_policy(&self) -> TrailingBlockPolicy {
+        self.trailing_block_policy
+    }
+
+    /// Set whether requests that make no sense are rejected, instead of being served as well as
+    /// possible.
+    ///
+    /// When set, empty requests fail with [StorageDeviceError::InvalidRequest], and requests
+    /// starting at or past the end of the device, which no [TrailingBlockPolicy] can serve, fail
+    /// with [StorageDeviceError::OutOfBounds], as do discards going past the end of the device.
+    /// This catches callers computing bogus ranges early, rather than letting them turn into
+    /// useless requests to the block device.
+    pub fn with_strict_validation(mut self, strict: bool) -> Self {
+        self.strict_validation = strict;
+        self
+    }
+
+    /// Return whether requests that make no sense are rejected.
+    pub fn strict_validation(&self) -> bool {
+        self.strict_validation
+    }
+
+    /// Query the size of the block device again, returning it in bytes.
+    ///
+    /// This must be called when the size changes other than through [Resizable::set_len], such
+    /// as when the media of a removable drive is swapped, as requests are checked against the
+    /// size queried last.
+    pub fn refresh_len(&mut self) -> StorageDeviceResult<u64> {
+        self.len = None;
+        let len = self.block_device.count()?.into_bytes_count();
+        self.len = Some(len);
+        Ok(len)
+    }
+
+    /// Check an empty request, returning whether it is accepted.
+    fn check_empty(&self) -> StorageDeviceResult<()> {
+        if self.strict_validation {
+            Err(StorageDeviceError::InvalidRequest)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Transfer the whole blocks of misaligned buffers by requests of up to ``blocks`` blocks,
+    /// instead of [DEFAULT_BUFFER_BLOCKS].
+    ///
+    /// The bounce buffer is allocated by each request needing it, as big as the part of the
+    /// request it transfers, so smaller values bound the memory used by requests, and a single
+    /// block transfers them block by block.
+    #[cfg(feature = "alloc")]
+    pub fn with_buffer_blocks(mut self, blocks: usize) -> Self {
+        self.buffer_blocks = core::cmp::max(blocks, 1);
+        self
+    }
+
+    /// Return the maximum amount of blocks of the bounce buffer.
+    #[cfg(feature = "alloc")]
+    pub fn buffer_blocks(&self) -> usize {
+        self.buffer_blocks
+    }
+
+    /// Compute how many of the ``len`` bytes starting at ``offset`` should be transferred to or
+    /// from the device.
+    fn transfer_len(
+        &mut self,
+        offset: u64,
+        len: usize,
+        is_write: bool,
+    ) -> StorageDeviceResult<usize> {
+        if len == 0 {
+            self.check_empty()?;
+            return Ok(0);
+        }
+
+        let end = offset
+            .checked_add(len as u64)
+            .ok_or(StorageDeviceError::OutOfBounds)?;
+        let device_len = self.len()?;
+
+        if self.strict_validation && offset >= device_len {
+            return Err(StorageDeviceError::OutOfBounds);
+        }
+
+        if end <= device_len {
+            return Ok(len);
+        }
+
+        match self.trailing_block_policy {
+            TrailingBlockPolicy::ZeroPad if !is_write => {}
+            TrailingBlockPolicy::Truncate => {}
+            _ => return Err(StorageDeviceError::OutOfBounds),
+        }
+
+        Ok(device_len.saturating_sub(offset) as usize)
+    }
+
+    /// Translate the next part of a batch, up to the end of a block, into a piece.
+    ///
+    /// Return the index of the request, the offset in its buffer, the offset in the device and the
+    /// length of the piece. Stop at the end of the batch, or at the first request not matching
+    /// ``is_write``.
+    fn next_batch_piece(
+        &mut self,
+        requests: &mut [StorageRequest<'_>],
+        cursor: &mut BatchCursor,
+        is_write: bool,
+    ) -> StorageDeviceResult<Option<(usize, usize, u64, usize)>> {
+        while let Some(request) = requests.get_mut(cursor.request) {
+            if request.is_write() != is_write {
+                break;
+            }
+
+            let transfer_len = match cursor.transfer_len {
+                Some(transfer_len) => transfer_len,
+                None => {
+                    let transfer_len = match request {
+                        StorageRequest::Read { offset, buf } => {
+                            let transfer_len = self.transfer_len(*offset, buf.len(), false)?;
+                            if self.trailing_block_policy == TrailingBlockPolicy::ZeroPad {
+                                for byte in buf[transfer_len..].iter_mut() {
+                                    *byte = 0;
+                                }
+                            }
+                            transfer_len
+                        }
+                        StorageRequest::Write { offset, buf } => {
+                            self.transfer_len(*offset, buf.len(), true)?
+                        }
+                    };
+                    cursor.transfer_len = Some(transfer_len);
+                    transfer_len
+                }
+            };
+
+            if cursor.progress == transfer_len {
+                // Done with this request, move to the next one.
+                cursor.request += 1;
+                cursor.progress = 0;
+                cursor.transfer_len = None;
+                continue;
+            }
+
+            let offset = request.offset() + cursor.progress as u64;
+            let len = core::cmp::min(
+                Block::LEN - (offset % Block::LEN_U64) as usize,
+                transfer_len - cursor.progress,
+            );
+            let piece = (cursor.request, cursor.progress, offset, len);
+
+            cursor.progress += len;
+            return Ok(Some(piece));
+        }
+
+        Ok(None)
+    }
+
+    /// Submit the selected ``slots`` to the block device, merging the slots holding consecutive
+    /// blocks into a single request.
+    fn submit_slots(
+        &mut self,
+        slots: &[BlockIndex],
+        selected: &[bool],
+        blocks: &mut [Block],
+        is_write: bool,
+    ) -> StorageDeviceResult<()> {
+        let mut io_requests: [IoRequest<'_>; BATCH_BLOCKS] =
+            core::array::from_fn(|_| IoRequest::Read {
+                index: BlockIndex(0),
+                blocks: &mut [],
+            });
+        let mut request_count = 0;
+        let mut remaining_blocks = blocks;
+        let mut slot = 0;
+
+        while slot < slots.len() {
+            if !selected[slot] {
+                remaining_blocks = &mut core::mem::take(&mut remaining_blocks)[1..];
+                slot += 1;
+                continue;
+            }
+
+            // Extend the run as long as the next slot holds the next block.
+            let mut run_len = 1;
+            while slot + run_len < slots.len()
+                && selected[slot + run_len]
+                && slots[slot + run_len].0 == slots[slot].0 + run_len as u64
+            {
+                run_len += 1;
+            }
+
+            let (run_blocks, tail) = core::mem::take(&mut remaining_blocks).split_at_mut(run_len);
+            io_requests[request_count] = if is_write {
+                IoRequest::Write {
+                    index: slots[slot],
+                    blocks: run_blocks,
+                }
+            } else {
+                IoRequest::Read {
+                    index: slots[slot],
+                    blocks: run_blocks,
+                }
+            };
+
+            request_count += 1;
+            remaining_blocks = tail;
+            slot += run_len;
+        }
+
+        if request_count != 0 {
+            self.block_device
+                .submit(&mut io_requests[..request_count])?;
+        }
+
+        Ok(())
+    }
+
+    /// Perform the consecutive read requests found at ``cursor``, up to [BATCH_BLOCKS] blocks.
+    fn submit_batch_reads(
+        &mut self,
+        requests: &mut [StorageRequest<'_>],
+        cursor: &mut BatchCursor,
+        blocks: &mut [Block; BATCH_BLOCKS],
+    ) -> StorageDeviceResult<()> {
+        let mut pieces = [BatchPiece::default(); BATCH_BLOCKS];
+        let mut slots = [BlockIndex(0); BATCH_BLOCKS];
+        let mut count = 0;
+
+        while count < BATCH_BLOCKS {
+            let (request, buf_offset, offset, len) =
+                match self.next_batch_piece(requests, cursor, false)? {
+                    Some(piece) => piece,
+                    None => break,
+                };
+
+            slots[count] = BlockIndex(offset / Block::LEN_U64);
+            pieces[count] = BatchPiece {
+                request,
+                buf_offset,
+                slot: count,
+                block_offset: (offset % Block::LEN_U64) as usize,
+                len,
+            };
+            count += 1;
+        }
+
+        self.submit_slots(
+            &slots[..count],
+            &[true; BATCH_BLOCKS][..count],
+            &mut blocks[..count],
+            false,
+        )?;
+
+        // Copy the data into the buffers.
+        for piece in pieces[..count].iter() {
+            if let StorageRequest::Read { buf, .. } = &mut requests[piece.request] {
+                buf[piece.buf_offset..piece.buf_offset + piece.len].copy_from_slice(
+                    &blocks[piece.slot][piece.block_offset..piece.block_offset + piece.len],
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Perform the consecutive write requests found at ``cursor``, up to [BATCH_BLOCKS] blocks.
+    ///
+    /// Blocks partially covered by the requests are read first, in a single batch.
+    fn submit_batch_writes(
+        &mut self,
+        requests: &mut [StorageRequest<'_>],
+        cursor: &mut BatchCursor,
+        blocks: &mut [Block; BATCH_BLOCKS],
+    ) -> StorageDeviceResult<()> {
+        let mut pieces = [BatchPiece::default(); BATCH_BLOCKS];
+        let mut piece_count = 0;
+        let mut slots = [BlockIndex(0); BATCH_BLOCKS];
+        let mut needs_read = [true; BATCH_BLOCKS];
+        let mut slot_count = 0;
+
+        while piece_count < BATCH_BLOCKS && slot_count < BATCH_BLOCKS {
+            let (request, buf_offset, offset, len) =
+                match self.next_batch_piece(requests, cursor, true)? {
+                    Some(piece) => piece,
+                    None => break,
+                };
+
+            // Writes to the same block must end up in the same slot, so none of them is lost.
+            let index = BlockIndex(offset / Block::LEN_U64);
+            let slot = match slots[..slot_count].iter().position(|slot| *slot == index) {
+                Some(slot) => slot,
+                None => {
+                    slots[slot_count] = index;
+                    needs_read[slot_count] = true;
+                    slot_count += 1;
+                    slot_count - 1
+                }
+            };
+
+            if len == Block::LEN {
+                needs_read[slot] = false;
+            }
+
+            pieces[piece_count] = BatchPiece {
+                request,
+                buf_offset,
+                slot,
+                block_offset: (offset % Block::LEN_U64) as usize,
+                len,
+            };
+            piece_count += 1;
+        }
+
+        self.submit_slots(
+            &slots[..slot_count],
+            &needs_read[..slot_count],
+            &mut blocks[..slot_count],
+            false,
+        )?;
+
+        // Copy the data from the buffers, in the order of the requests.
+        for piece in pieces[..piece_count].iter() {
+            if let StorageRequest::Write { buf, .. } = &requests[piece.request] {
+                blocks[piece.slot][piece.block_offset..piece.block_offset + piece.len]
+                    .copy_from_slice(&buf[piece.buf_offset..piece.buf_offset + piece.len]);
+            }
+        }
+
+        self.submit_slots(
+            &slots[..slot_count],
+            &[true; BATCH_BLOCKS][..slot_count],
+            &mut blocks[..slot_count],
+            true,
+        )
+    }
 
-/// Implementation of storage device for block device.
-/// NOTE: This implementation doesn't use the heap.
-/// NOTE: As it doesn't use a heap, read/write operations are done block by block. If you wish better performances, please consider implementing your own wrapper.
-#[derive(Debug)]
-pub struct StorageBlockDevice<B: BlockDevice> {
-    /// The inner block device.
-    block_device: B,
-}
+    /// Compute how many of the ``len`` bytes starting at ``offset`` can be transferred by a partial
+    /// read or write.
+    fn partial_transfer_len(&mut self, offset: u64, len: usize) -> StorageDeviceResult<usize> {
+        if len == 0 {
+            self.check_empty()?;
+            return Ok(0);
+        }
 
-impl<B: BlockDevice> StorageBlockDevice<B> {
-    /// Create a new storage block device.
-    pub fn new(block_device: B) -> Self {
-        StorageBlockDevice { block_device }
+        let device_len = self.len()?;
+
+        if offset >= device_len {
+            return Err(StorageDeviceError::OutOfBounds);
+        }
+
+        Ok(core::cmp::min(len as u64, device_len - offset) as usize)
     }
-}
 
-impl<B: BlockDevice> StorageDevice for StorageBlockDevice<B> {
-    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
-        let mut read_size = 0u64;
+    /// Read the data at the given ``offset`` into ``buf``, keeping track of how many bytes were
+    /// read in ``read_size``.
+    ///
+    /// When ``partial`` is set, a failed multi-block read is retried block by block, to find out
+    /// how far the read can go; otherwise the error is returned.
+    fn read_range(
+        &mut self,
+        offset: u64,
+        buf: &mut [u8],
+        read_size: &mut u64,
+        partial: bool,
+    ) -> StorageDeviceResult<()> {
         let mut blocks = [Block::new()];
+        let mut direct = true;
+        #[cfg(feature = "alloc")]
+        let mut bounce = alloc::vec::Vec::new();
 
-        while read_size < buf.len() as u64 {
+        while *read_size < buf.len() as u64 {
             // Compute the next offset of the data to read.
-            let current_offset = offset + read_size;
+            let current_offset = offset + *read_size;
 
             // Extract the block index containing the data.
             let current_block_index = BlockIndex(current_offset / Block::LEN_U64);
@@ -82,12 +1407,45 @@ impl<B: BlockDevice> StorageDevice for StorageBlockDevice<B> {
             // Extract the offset inside the block containing the data.
             let current_block_offset = current_offset % Block::LEN_U64;
 
+            // Read the whole blocks directly into the buffer when it is aligned for them.
+            let whole_blocks = (buf.len() - *read_size as usize) / Block::LEN;
+            if direct && current_block_offset == 0 && whole_blocks != 0 {
+                let start = *read_size as usize;
+                let len = whole_blocks * Block::LEN;
+                let result = match Block::from_bytes_mut(&mut buf[start..start + len]) {
+                    Some(direct_blocks) => Some(
+                        self.block_device
+                            .read(direct_blocks, current_block_index)
+                            .map(|()| len),
+                    ),
+                    #[cfg(feature = "alloc")]
+                    None => Some(self.read_bounced(
+                        &mut bounce,
+                        &mut buf[start..start + len],
+                        current_block_index,
+                    )),
+                    #[cfg(not(feature = "alloc"))]
+                    None => None,
+                };
+                // On failure of a partial transfer, retry block by block to find out how far we
+                // can go.
+                match result {
+                    Some(Ok(len)) => {
+                        *read_size += len as u64;
+                        continue;
+                    }
+                    Some(Err(err)) if !partial => return Err(err.into()),
+                    Some(Err(_)) => direct = false,
+                    None => {}
+                }
+            }
+
             // Read the block.
             self.block_device
                 .read(&mut blocks, BlockIndex(current_block_index.0))?;
 
             // Slice on the part of the buffer we need.
-            let buf_slice = &mut buf[read_size as usize..];
+            let buf_slice = &mut buf[*read_size as usize..];
 
             // Limit copy to the size of a block or lower.
             let buf_limit = if buf_slice.len() + current_block_offset as usize >= Block::LEN {
@@ -102,19 +1460,32 @@ impl<B: BlockDevice> StorageDevice for StorageBlockDevice<B> {
             }
 
             // Increment with what we read.
-            read_size += buf_limit as u64;
+            *read_size += buf_limit as u64;
         }
 
         Ok(())
     }
 
-    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
-        let mut write_size = 0u64;
+    /// Write the data from ``buf`` at the given ``offset``, keeping track of how many bytes were
+    /// written in ``write_size``.
+    ///
+    /// When ``partial`` is set, a failed multi-block write is retried block by block, to find out
+    /// how far the write can go; otherwise the error is returned.
+    fn write_range(
+        &mut self,
+        offset: u64,
+        buf: &[u8],
+        write_size: &mut u64,
+        partial: bool,
+    ) -> StorageDeviceResult<()> {
         let mut blocks = [Block::new()];
+        let mut direct = true;
+        #[cfg(feature = "alloc")]
+        let mut bounce = alloc::vec::Vec::new();
 
-        while write_size < buf.len() as u64 {
+        while *write_size < buf.len() as u64 {
             // Compute the next offset of the data to write.
-            let current_offset = offset + write_size;
+            let current_offset = offset + *write_size;
 
             // Extract the block index containing the data.
             let current_block_index = BlockIndex(current_offset / Block::LEN_U64);
@@ -122,12 +1493,45 @@ impl<B: BlockDevice> StorageDevice for StorageBlockDevice<B> {
             // Extract the offset inside the block containing the data.
             let current_block_offset = current_offset % Block::LEN_U64;
 
+            // Write the whole blocks directly from the buffer when it is aligned for them.
+            let whole_blocks = (buf.len() - *write_size as usize) / Block::LEN;
+            if direct && current_block_offset == 0 && whole_blocks != 0 {
+                let start = *write_size as usize;
+                let len = whole_blocks * Block::LEN;
+                let result = match Block::from_bytes(&buf[start..start + len]) {
+                    Some(direct_blocks) => Some(
+                        self.block_device
+                            .write(direct_blocks, current_block_index)
+                            .map(|()| len),
+                    ),
+                    #[cfg(feature = "alloc")]
+                    None => Some(self.write_bounced(
+                        &mut bounce,
+                        &buf[start..start + len],
+                        current_block_index,
+                    )),
+                    #[cfg(not(feature = "alloc"))]
+                    None => None,
+                };
+                // On failure of a partial transfer, retry block by block to find out how far we
+                // can go.
+                match result {
+                    Some(Ok(len)) => {
+                        *write_size += len as u64;
+                        continue;
+                    }
+                    Some(Err(err)) if !partial => return Err(err.into()),
+                    Some(Err(_)) => direct = false,
+                    None => {}
+                }
+            }
+
             // Read the block.
             self.block_device
                 .read(&mut blocks, BlockIndex(current_block_index.0))?;
 
             // Slice on the part of the buffer we need.
-            let buf_slice = &buf[write_size as usize..];
+            let buf_slice = &buf[*write_size as usize..];
 
             // Limit copy to the size of a block or lower.
             let buf_limit = if buf_slice.len() + current_block_offset as usize >= Block::LEN {
@@ -147,13 +1551,822 @@ impl<B: BlockDevice> StorageDevice for StorageBlockDevice<B> {
                 .write(&blocks, BlockIndex(current_block_index.0))?;
 
             // Increment with what we wrote.
-            write_size += buf_limit as u64;
+            *write_size += buf_limit as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Read the first whole blocks of ``buf`` from ``index`` through ``bounce``, allocating it on
+    /// first use, and return how many bytes were read.
+    #[cfg(feature = "alloc")]
+    fn read_bounced(
+        &mut self,
+        bounce: &mut alloc::vec::Vec<Block>,
+        buf: &mut [u8],
+        index: BlockIndex,
+    ) -> BlockResult<usize> {
+        let count = core::cmp::min(buf.len() / Block::LEN, self.buffer_blocks);
+        if bounce.len() < count {
+            bounce.resize(count, Block::new());
+        }
+        let blocks = &mut bounce[..count];
+        self.block_device.read(blocks, index)?;
+        let len = count * Block::LEN;
+        buf[..len].copy_from_slice(Block::slice_as_bytes(blocks));
+        Ok(len)
+    }
+
+    /// Write the first whole blocks of ``buf`` at ``index`` through ``bounce``, allocating it on
+    /// first use, and return how many bytes were written.
+    #[cfg(feature = "alloc")]
+    fn write_bounced(
+        &mut self,
+        bounce: &mut alloc::vec::Vec<Block>,
+        buf: &[u8],
+        index: BlockIndex,
+    ) -> BlockResult<usize> {
+        let count = core::cmp::min(buf.len() / Block::LEN, self.buffer_blocks);
+        if bounce.len() < count {
+            bounce.resize(count, Block::new());
+        }
+        let blocks = &mut bounce[..count];
+        let len = count * Block::LEN;
+        Block::slice_as_bytes_mut(blocks).copy_from_slice(&buf[..len]);
+        self.block_device.write(blocks, index)?;
+        Ok(len)
+    }
+}
+
+impl<B: BlockDevice> StorageBlockDevice<B> {
+    /// Return a storage block device over a shared reference to the block device.
+    fn borrowed<'a>(&'a self) -> StorageBlockDevice<&'a B>
+    where
+        &'a B: BlockDevice,
+    {
+        StorageBlockDevice {
+            block_device: &self.block_device,
+            trailing_block_policy: self.trailing_block_policy,
+            strict_validation: self.strict_validation,
+            len: self.len,
+            #[cfg(feature = "alloc")]
+            buffer_blocks: self.buffer_blocks,
         }
+    }
+}
+
+impl<B: BlockDevice> SharedStorageDevice for StorageBlockDevice<B>
+where
+    for<'a> &'a B: BlockDevice,
+{
+    fn read_shared(&self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.borrowed().read(offset, buf)
+    }
+
+    fn write_shared(&self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.borrowed().write(offset, buf)
+    }
+
+    fn len_shared(&self) -> StorageDeviceResult<u64> {
+        self.borrowed().len()
+    }
+
+    fn discard_shared(&self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.borrowed().discard(offset, len)
+    }
 
+    fn flush_shared(&self) -> StorageDeviceResult<()> {
+        self.borrowed().flush()
+    }
+
+    fn info_shared(&self) -> StorageDeviceResult<DeviceInfo> {
+        self.borrowed().info()
+    }
+}
+
+/// Resize the inner block device, when it is itself resizable.
+///
+/// The size must be a multiple of [Block::LEN], or [StorageDeviceError::Unsupported] is
+/// returned.
+impl<B: BlockDevice + Resizable> Resizable for StorageBlockDevice<B> {
+    fn set_len(&mut self, len: u64) -> StorageDeviceResult<()> {
+        if !len.is_multiple_of(Block::LEN_U64) {
+            return Err(StorageDeviceError::Unsupported);
+        }
+        // A failure may leave the block device resized or not, so it is queried again.
+        self.len = None;
+        self.block_device.set_len(len)?;
+        self.len = Some(len);
         Ok(())
     }
+}
+
+/// Set the ``len`` bytes at ``offset`` of ``device`` to ``byte``, with repeated writes of a small
+/// buffer filled with ``byte``.
+///
+/// Fail with [StorageDeviceError::OutOfBounds] without writing anything if the range isn't
+/// inside the device.
+pub(crate) fn fill_with_writes<S: StorageDevice + ?Sized>(
+    device: &mut S,
+    offset: u64,
+    len: u64,
+    byte: u8,
+) -> StorageDeviceResult<()> {
+    let buf = [byte; 512];
+    let mut position = offset;
+    let end = offset
+        .checked_add(len)
+        .ok_or(StorageDeviceError::OutOfBounds)?;
+    if len != 0 && end > device.len()? {
+        return Err(StorageDeviceError::OutOfBounds);
+    }
+    while position < end {
+        let chunk = core::cmp::min(buf.len() as u64, end - position) as usize;
+        device.write(position, &buf[..chunk])?;
+        position += chunk as u64;
+    }
+    Ok(())
+}
+
+impl<B: BlockDevice> StorageDevice for StorageBlockDevice<B> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        let transfer_len = self.transfer_len(offset, buf.len(), false)?;
+        let (buf, trailing) = buf.split_at_mut(transfer_len);
+
+        if self.trailing_block_policy == TrailingBlockPolicy::ZeroPad {
+            for byte in trailing.iter_mut() {
+                *byte = 0;
+            }
+        }
+
+        self.read_range(offset, buf, &mut 0, false)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let transfer_len = self.transfer_len(offset, buf.len(), true)?;
+
+        self.write_range(offset, &buf[..transfer_len], &mut 0, false)
+    }
+
+    /// Read block by block, stopping at the end of the device or at the first block that can't be
+    /// read.
+    fn read_partial(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        let transfer_len = self.partial_transfer_len(offset, buf.len())?;
+        let mut read_size = 0;
+
+        match self.read_range(offset, &mut buf[..transfer_len], &mut read_size, true) {
+            Err(err) if read_size == 0 => Err(err),
+            _ => Ok(read_size as usize),
+        }
+    }
+
+    /// Write block by block, stopping at the end of the device or at the first block that can't be
+    /// written.
+    fn write_partial(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<usize> {
+        let transfer_len = self.partial_transfer_len(offset, buf.len())?;
+        let mut write_size = 0;
+
+        match self.write_range(offset, &buf[..transfer_len], &mut write_size, true) {
+            Err(err) if write_size == 0 => Err(err),
+            _ => Ok(write_size as usize),
+        }
+    }
 
     fn len(&mut self) -> StorageDeviceResult<u64> {
-        Ok(self.block_device.count()?.into_bytes_count())
+        match self.len {
+            Some(len) => Ok(len),
+            None => self.refresh_len(),
+        }
+    }
+
+    /// Read every block of the range only once, even when it is shared by several buffers.
+    fn read_vectored(&mut self, offset: u64, bufs: &mut [&mut [u8]]) -> StorageDeviceResult<()> {
+        let total_len = bufs.iter().map(|buf| buf.len()).sum();
+        let transfer_len = self.transfer_len(offset, total_len, false)?;
+
+        let mut blocks = [Block::new()];
+        let mut bufs_iter = bufs.iter_mut();
+        let mut current_buf: &mut [u8] = &mut [];
+        let mut read_size = 0;
+
+        while read_size < transfer_len {
+            // Compute the next offset of the data to read.
+            let current_offset = offset + read_size as u64;
+
+            // Extract the block index and the offset inside the block containing the data.
+            let current_block_index = BlockIndex(current_offset / Block::LEN_U64);
+            let current_block_offset = (current_offset % Block::LEN_U64) as usize;
+
+            // Limit copy to the size of a block or lower.
+            let block_limit =
+                core::cmp::min(Block::LEN - current_block_offset, transfer_len - read_size);
+
+            self.block_device.read(&mut blocks, current_block_index)?;
+
+            // Spread the data of the block over as many buffers as needed.
+            let mut block_slice =
+                &blocks[0][current_block_offset..current_block_offset + block_limit];
+            while !block_slice.is_empty() {
+                while current_buf.is_empty() {
+                    // transfer_len is never bigger than the total length of the buffers.
+                    current_buf = bufs_iter.next().unwrap();
+                }
+
+                let copy_len = core::cmp::min(current_buf.len(), block_slice.len());
+                let (head, tail) = core::mem::take(&mut current_buf).split_at_mut(copy_len);
+                head.copy_from_slice(&block_slice[..copy_len]);
+                current_buf = tail;
+                block_slice = &block_slice[copy_len..];
+            }
+
+            // Increment with what we read.
+            read_size += block_limit;
+        }
+
+        if self.trailing_block_policy == TrailingBlockPolicy::ZeroPad {
+            for byte in current_buf.iter_mut() {
+                *byte = 0;
+            }
+            for buf in bufs_iter {
+                for byte in buf.iter_mut() {
+                    *byte = 0;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write every block of the range only once, even when it is shared by several buffers.
+    ///
+    /// Blocks fully covered by the buffers are written without being read first.
+    fn write_vectored(&mut self, offset: u64, bufs: &[&[u8]]) -> StorageDeviceResult<()> {
+        let total_len = bufs.iter().map(|buf| buf.len()).sum();
+        let transfer_len = self.transfer_len(offset, total_len, true)?;
+
+        let mut blocks = [Block::new()];
+        let mut bufs_iter = bufs.iter();
+        let mut current_buf: &[u8] = &[];
+        let mut write_size = 0;
+
+        while write_size < transfer_len {
+            // Compute the next offset of the data to write.
+            let current_offset = offset + write_size as u64;
+
+            // Extract the block index and the offset inside the block containing the data.
+            let current_block_index = BlockIndex(current_offset / Block::LEN_U64);
+            let current_block_offset = (current_offset % Block::LEN_U64) as usize;
+
+            // Limit copy to the size of a block or lower.
+            let block_limit =
+                core::cmp::min(Block::LEN - current_block_offset, transfer_len - write_size);
+
+            // Only read the block if we are not about to overwrite it completely.
+            if block_limit != Block::LEN {
+                self.block_device.read(&mut blocks, current_block_index)?;
+            }
+
+            // Gather the data of the block from as many buffers as needed.
+            let mut block_slice =
+                &mut blocks[0][current_block_offset..current_block_offset + block_limit];
+            while !block_slice.is_empty() {
+                while current_buf.is_empty() {
+                    // transfer_len is never bigger than the total length of the buffers.
+                    current_buf = bufs_iter.next().unwrap();
+                }
+
+                let copy_len = core::cmp::min(current_buf.len(), block_slice.len());
+                let (head, tail) = core::mem::take(&mut block_slice).split_at_mut(copy_len);
+                head.copy_from_slice(&current_buf[..copy_len]);
+                block_slice = tail;
+                current_buf = &current_buf[copy_len..];
+            }
+
+            self.block_device.write(&blocks, current_block_index)?;
+
+            // Increment with what we wrote.
+            write_size += block_limit;
+        }
+
+        Ok(())
+    }
+
+    /// Translate the requests into batches of block requests, each transferring up to 8 blocks.
+    ///
+    /// Consecutive blocks are merged into a single block request.
+    fn submit(&mut self, requests: &mut [StorageRequest<'_>]) -> StorageDeviceResult<()> {
+        let mut blocks: [Block; BATCH_BLOCKS] = core::array::from_fn(|_| Block::new());
+        let mut cursor = BatchCursor::default();
+
+        while let Some(request) = requests.get(cursor.request) {
+            if request.is_write() {
+                self.submit_batch_writes(requests, &mut cursor, &mut blocks)?;
+            } else {
+                self.submit_batch_reads(requests, &mut cursor, &mut blocks)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        if len == 0 {
+            return self.check_empty();
+        }
+        fill_with_writes(self, offset, len, byte)
+    }
+
+    /// Discard the blocks fully covered by the range, leaving partially covered ones untouched.
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        if len == 0 {
+            return self.check_empty();
+        }
+        let end = offset
+            .checked_add(len)
+            .ok_or(StorageDeviceError::OutOfBounds)?;
+        if self.strict_validation && end > self.len()? {
+            return Err(StorageDeviceError::OutOfBounds);
+        }
+
+        let first_block = offset.div_ceil(Block::LEN_U64);
+        let end_block = end / Block::LEN_U64;
+
+        if end_block > first_block {
+            self.block_device
+                .discard(BlockIndex(first_block), BlockCount(end_block - first_block))?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        Ok(self.block_device.flush()?)
+    }
+
+    /// Write whole aligned blocks with forced unit access, and anything else with a write
+    /// followed by a flush.
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let transfer_len = self.transfer_len(offset, buf.len(), true)?;
+        if transfer_len == 0 {
+            return Ok(());
+        }
+        let buf = &buf[..transfer_len];
+        match Block::from_bytes(buf) {
+            Some(blocks) if offset.is_multiple_of(Block::LEN_U64) => {
+                let index = BlockIndex(offset / Block::LEN_U64);
+                Ok(self.block_device.write_fua(blocks, index)?)
+            }
+            _ => {
+                self.write_range(offset, buf, &mut 0, false)?;
+                Ok(self.block_device.flush()?)
+            }
+        }
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        Ok(self.block_device.barrier()?)
+    }
+
+    /// Report the geometry of the block device, and whether it reports
+    /// [Capabilities::DISCARD_ZEROES].
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo {
+            supports_holes: self
+                .block_device
+                .capabilities()?
+                .contains(Capabilities::DISCARD_ZEROES),
+            geometry: self.block_device.geometry()?,
+        })
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        Ok(self.block_device.identity()?)
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        Ok(self.block_device.capabilities()?)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::testing::{fill_index_pattern, AlignedBuf, DbgBlockDevice};
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    /// The size of the odd-sized backend: two whole blocks, and a trailing partial one which
+    /// isn't part of the device.
+    const ODD_LEN: usize = 2 * Block::LEN + 276;
+
+    /// The offset of a request starting in the last block of the device.
+    const TRAILING_OFFSET: u64 = 900;
+
+    /// The length of that request, ending in the trailing partial block.
+    const TRAILING_LEN: usize = 200;
+
+    /// The part of that request inside the device.
+    const TRAILING_INSIDE: usize = 2 * Block::LEN - TRAILING_OFFSET as usize;
+
+    /// Return the byte at ``offset`` of the backend.
+    fn pattern(offset: usize) -> u8 {
+        (offset % 251) as u8
+    }
+
+    /// Create a file of ``ODD_LEN`` bytes filled with [pattern], removed once closed.
+    fn odd_file(name: &str) -> File {
+        let path = std::env::temp_dir().join(std::format!(
+            "storage_device-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let contents: std::vec::Vec<u8> = (0..ODD_LEN).map(pattern).collect();
+        file.write_all(&contents).unwrap();
+        // The file stays usable after being unlinked on unix, and is left behind elsewhere.
+        let _ = std::fs::remove_file(&path);
+        file
+    }
+
+    /// Return the whole content of the backend, trailing partial block included.
+    fn file_contents(device: &mut StorageBlockDevice<File>) -> std::vec::Vec<u8> {
+        let file = &mut device.block_device;
+        let mut contents = std::vec::Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    /// Create a device over a new odd-sized file, following ``policy``.
+    fn odd_device(name: &str, policy: TrailingBlockPolicy) -> StorageBlockDevice<File> {
+        StorageBlockDevice::new(odd_file(name)).with_trailing_block_policy(policy)
+    }
+
+    #[test]
+    fn odd_sized_backend_excludes_trailing_partial_block() {
+        for policy in [
+            TrailingBlockPolicy::Error,
+            TrailingBlockPolicy::ZeroPad,
+            TrailingBlockPolicy::Truncate,
+        ] {
+            let mut device = odd_device("len", policy);
+            assert_eq!(device.len().unwrap(), 2 * Block::LEN_U64);
+
+            // Requests ending exactly at the end of the device are served whatever the policy.
+            let mut buf = [0u8; 100];
+            device.read(2 * Block::LEN_U64 - 100, &mut buf).unwrap();
+            assert!(buf
+                .iter()
+                .enumerate()
+                .all(|(i, byte)| *byte == pattern(2 * Block::LEN - 100 + i)));
+            device
+                .write(2 * Block::LEN_U64 - 100, &[0xEE; 100])
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn error_policy_rejects_trailing_block() {
+        let mut device = odd_device("error", TrailingBlockPolicy::Error);
+        let before = file_contents(&mut device);
+
+        let mut buf = [0xAAu8; TRAILING_LEN];
+        assert_eq!(
+            device.read(TRAILING_OFFSET, &mut buf),
+            Err(StorageDeviceError::OutOfBounds)
+        );
+        assert!(buf.iter().all(|byte| *byte == 0xAA));
+
+        assert_eq!(
+            device.write(TRAILING_OFFSET, &[0xEE; TRAILING_LEN]),
+            Err(StorageDeviceError::OutOfBounds)
+        );
+        assert_eq!(file_contents(&mut device), before);
+    }
+
+    #[test]
+    fn zero_pad_policy_pads_reads_and_rejects_writes() {
+        let mut device = odd_device("zero-pad", TrailingBlockPolicy::ZeroPad);
+        let before = file_contents(&mut device);
+
+        let mut buf = [0xAAu8; TRAILING_LEN];
+        device.read(TRAILING_OFFSET, &mut buf).unwrap();
+        let (inside, outside) = buf.split_at(TRAILING_INSIDE);
+        assert!(inside
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| *byte == pattern(TRAILING_OFFSET as usize + i)));
+        // The trailing partial block is read as zeros, not as its content in the backend.
+        assert!(outside.iter().all(|byte| *byte == 0));
+
+        assert_eq!(
+            device.write(TRAILING_OFFSET, &[0xEE; TRAILING_LEN]),
+            Err(StorageDeviceError::OutOfBounds)
+        );
+        assert_eq!(file_contents(&mut device), before);
+    }
+
+    #[test]
+    fn truncate_policy_transfers_the_part_inside_the_device() {
+        let mut device = odd_device("truncate", TrailingBlockPolicy::Truncate);
+        let before = file_contents(&mut device);
+
+        let mut buf = [0xAAu8; TRAILING_LEN];
+        device.read(TRAILING_OFFSET, &mut buf).unwrap();
+        let (inside, outside) = buf.split_at(TRAILING_INSIDE);
+        assert!(inside
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| *byte == pattern(TRAILING_OFFSET as usize + i)));
+        assert!(outside.iter().all(|byte| *byte == 0xAA));
+
+        device
+            .write(TRAILING_OFFSET, &[0xEE; TRAILING_LEN])
+            .unwrap();
+        let after = file_contents(&mut device);
+        let mut expected = before;
+        expected[TRAILING_OFFSET as usize..2 * Block::LEN].fill(0xEE);
+        // The trailing partial block of the backend is left alone.
+        assert_eq!(after, expected);
+    }
+
+    /// The size of the devices of the request validation tests.
+    const DEVICE_LEN: usize = 4 * Block::LEN;
+
+    /// Create a device of ``DEVICE_LEN`` bytes filled with [fill_index_pattern].
+    fn index_device() -> StorageBlockDevice<DbgBlockDevice> {
+        let mut contents = [0u8; DEVICE_LEN];
+        fill_index_pattern(0, &mut contents);
+        StorageBlockDevice::new(DbgBlockDevice::with_contents(&contents))
+    }
+
+    /// Return the number of read and write requests made to the block device.
+    fn traffic(device: &StorageBlockDevice<DbgBlockDevice>) -> (u64, u64) {
+        let block_device = &device.block_device;
+        (block_device.read_requests(), block_device.write_requests())
+    }
+
+    /// Make every kind of empty request at ``offset``, returning their results.
+    fn empty_requests(
+        device: &mut StorageBlockDevice<DbgBlockDevice>,
+        offset: u64,
+    ) -> [StorageDeviceResult<()>; 8] {
+        [
+            device.read(offset, &mut []),
+            device.write(offset, &[]),
+            device.read_vectored(offset, &mut [&mut []]),
+            device.write_vectored(offset, &[&[], &[]]),
+            device.fill(offset, 0, 0xEE),
+            device.discard(offset, 0),
+            device.write_fua(offset, &[]),
+            device.submit(&mut [
+                StorageRequest::Read {
+                    offset,
+                    buf: &mut [],
+                },
+                StorageRequest::Write { offset, buf: &[] },
+            ]),
+        ]
+    }
+
+    #[test]
+    fn empty_requests_succeed_without_traffic() {
+        for offset in [0, 700, DEVICE_LEN as u64, 10 * DEVICE_LEN as u64, u64::MAX] {
+            let mut device = index_device();
+            for result in empty_requests(&mut device, offset).iter() {
+                assert_eq!(*result, Ok(()), "at {}", offset);
+            }
+            assert_eq!(device.read_partial(offset, &mut []), Ok(0));
+            assert_eq!(traffic(&device), (0, 0));
+        }
+    }
+
+    #[test]
+    fn strict_validation_rejects_empty_requests_without_traffic() {
+        for offset in [0, 700, DEVICE_LEN as u64, 10 * DEVICE_LEN as u64] {
+            let mut device = index_device().with_strict_validation(true);
+            for result in empty_requests(&mut device, offset).iter() {
+                assert_eq!(
+                    *result,
+                    Err(StorageDeviceError::InvalidRequest),
+                    "at {}",
+                    offset
+                );
+            }
+            assert_eq!(traffic(&device), (0, 0));
+        }
+    }
+
+    #[test]
+    fn requests_ending_at_device_end() {
+        for strict in [false, true] {
+            let mut device = index_device().with_strict_validation(strict);
+            let offset = DEVICE_LEN as u64 - 700;
+
+            let mut buf = [0u8; 700];
+            let mut expected = [0u8; 700];
+            fill_index_pattern(offset, &mut expected);
+            device.read(offset, &mut buf).unwrap();
+            assert_eq!(buf, expected);
+
+            device.write(offset, &[0xEE; 700]).unwrap();
+            device.read(offset, &mut buf).unwrap();
+            assert!(buf.iter().all(|byte| *byte == 0xEE));
+
+            // A byte more goes past the end.
+            assert_eq!(
+                device.read(offset, &mut [0u8; 701]),
+                Err(StorageDeviceError::OutOfBounds)
+            );
+            assert_eq!(
+                device.write(offset, &[0u8; 701]),
+                Err(StorageDeviceError::OutOfBounds)
+            );
+        }
+    }
+
+    #[test]
+    fn block_aligned_offsets_transfer_whole_blocks() {
+        // Misaligned buffers go through the bounce buffer, in a single request as well.
+        for (blocks, misalignment) in (1..=3).flat_map(|blocks| [(blocks, 0), (blocks, 1)]) {
+            let len = blocks * Block::LEN;
+            let mut device = index_device();
+            let mut buf = AlignedBuf::misaligned(len, misalignment);
+            let mut expected = std::vec![0u8; len];
+            fill_index_pattern(Block::LEN_U64, &mut expected);
+
+            device.read(Block::LEN_U64, &mut buf).unwrap();
+            assert_eq!(&buf[..], &expected[..]);
+            assert_eq!(device.block_device.read_requests(), 1);
+            assert_eq!(device.block_device.blocks_read(), blocks as u64);
+
+            // Whole blocks are written without being read first.
+            buf.fill(0xEE);
+            device.write(Block::LEN_U64, &buf).unwrap();
+            assert_eq!(device.block_device.read_requests(), 1);
+            assert_eq!(device.block_device.write_requests(), 1);
+            assert_eq!(device.block_device.blocks_written(), blocks as u64);
+            assert!(device.block_device.blocks()[1..=blocks]
+                .iter()
+                .all(|block| block.iter().all(|byte| *byte == 0xEE)));
+        }
+    }
+
+    #[test]
+    fn strict_validation_rejects_requests_past_the_end_without_traffic() {
+        for policy in [
+            TrailingBlockPolicy::Error,
+            TrailingBlockPolicy::ZeroPad,
+            TrailingBlockPolicy::Truncate,
+        ] {
+            let mut device = index_device()
+                .with_trailing_block_policy(policy)
+                .with_strict_validation(true);
+            for offset in [DEVICE_LEN as u64, DEVICE_LEN as u64 + 1, u64::MAX] {
+                assert_eq!(
+                    device.read(offset, &mut [0u8; 16]),
+                    Err(StorageDeviceError::OutOfBounds)
+                );
+                assert_eq!(
+                    device.write(offset, &[0u8; 16]),
+                    Err(StorageDeviceError::OutOfBounds)
+                );
+                assert_eq!(
+                    device.fill(offset, 16, 0xEE),
+                    Err(StorageDeviceError::OutOfBounds)
+                );
+            }
+            assert_eq!(
+                device.discard(DEVICE_LEN as u64 - 16, 32),
+                Err(StorageDeviceError::OutOfBounds)
+            );
+            assert_eq!(traffic(&device), (0, 0));
+        }
+    }
+
+    #[test]
+    fn truncated_requests_at_the_end_transfer_nothing() {
+        let mut device = index_device().with_trailing_block_policy(TrailingBlockPolicy::Truncate);
+        let mut buf = [0xAAu8; 16];
+        device.read(DEVICE_LEN as u64, &mut buf).unwrap();
+        assert_eq!(buf, [0xAA; 16]);
+        device.write(DEVICE_LEN as u64, &[0xEE; 16]).unwrap();
+        assert_eq!(traffic(&device), (0, 0));
+    }
+
+    /// A block device failing every request touching its last block.
+    #[derive(Debug)]
+    struct BadLastBlockDevice(DbgBlockDevice);
+
+    impl BadLastBlockDevice {
+        /// Return whether ``count`` blocks at ``index`` touch the last block.
+        fn touches_last_block(&mut self, index: BlockIndex, count: usize) -> bool {
+            index.0 + count as u64 >= self.0.count().unwrap().0
+        }
+    }
+
+    impl BlockDevice for BadLastBlockDevice {
+        fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+            if self.touches_last_block(index, blocks.len()) {
+                return Err(BlockError::ReadError);
+            }
+            self.0.read(blocks, index)
+        }
+
+        fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+            if self.touches_last_block(index, blocks.len()) {
+                return Err(BlockError::WriteError);
+            }
+            self.0.write(blocks, index)
+        }
+
+        fn count(&mut self) -> BlockResult<BlockCount> {
+            self.0.count()
+        }
+    }
+
+    #[test]
+    fn failed_transfers_are_only_retried_by_partial_requests() {
+        let mut device = StorageBlockDevice::new(BadLastBlockDevice(DbgBlockDevice::new(4)));
+        let mut buf = AlignedBuf::new(DEVICE_LEN);
+
+        assert_eq!(
+            device.write(0, &[0xEE; DEVICE_LEN]),
+            Err(StorageDeviceError::WriteError)
+        );
+        assert_eq!(device.block_device.0.write_requests(), 0);
+        assert_eq!(device.read(0, &mut buf), Err(StorageDeviceError::ReadError));
+        assert_eq!(device.block_device.0.read_requests(), 0);
+
+        let inside = DEVICE_LEN - Block::LEN;
+        assert_eq!(device.write_partial(0, &[0xEE; DEVICE_LEN]), Ok(inside));
+        assert_eq!(device.read_partial(0, &mut buf), Ok(inside));
+        assert!(buf[..inside].iter().all(|&byte| byte == 0xEE));
+    }
+
+    /// A block device of a settable number of blocks, counting how often it is queried, like a
+    /// removable drive whose media is swapped.
+    #[derive(Debug)]
+    struct RemovableBlockDevice {
+        /// The inner block device, big enough for every media.
+        inner: DbgBlockDevice,
+
+        /// The number of blocks of the current media.
+        blocks: u64,
+
+        /// The number of times the number of blocks was queried.
+        queries: u64,
+    }
+
+    impl BlockDevice for RemovableBlockDevice {
+        fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+            self.inner.read(blocks, index)
+        }
+
+        fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+            self.inner.write(blocks, index)
+        }
+
+        fn count(&mut self) -> BlockResult<BlockCount> {
+            self.queries += 1;
+            Ok(BlockCount(self.blocks))
+        }
+    }
+
+    #[test]
+    fn the_size_is_only_queried_again_when_refreshed() {
+        let mut device = StorageBlockDevice::new(RemovableBlockDevice {
+            inner: DbgBlockDevice::new(8),
+            blocks: 4,
+            queries: 0,
+        });
+        for offset in 0..8 {
+            device.write(offset * 100, &[0xEE; 100]).unwrap();
+            device.read(offset * 100, &mut [0u8; 100]).unwrap();
+        }
+        assert_eq!(device.len(), Ok(DEVICE_LEN as u64));
+        assert_eq!(device.block_device.queries, 1);
+
+        // Another media goes unnoticed until the size is refreshed.
+        device.block_device.blocks = 2;
+        device.read(3 * Block::LEN as u64, &mut [0u8; 16]).unwrap();
+        assert_eq!(device.refresh_len(), Ok(2 * Block::LEN as u64));
+        assert_eq!(
+            device.read(3 * Block::LEN as u64, &mut [0u8; 16]),
+            Err(StorageDeviceError::OutOfBounds)
+        );
+        assert_eq!(device.block_device.queries, 2);
+
+        // Resizing through the storage block device keeps the size up to date.
+        let mut device = StorageBlockDevice::new(odd_file("resized"));
+        device.set_len(4 * Block::LEN_U64).unwrap();
+        assert_eq!(device.len(), Ok(4 * Block::LEN_U64));
+        device.write(3 * Block::LEN_U64, &[0xEE; 16]).unwrap();
     }
 }
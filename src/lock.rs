@@ -0,0 +1,162 @@
+use crate::{
+    DeviceInfo, SharedStorageDevice, StorageDevice, StorageDeviceError, StorageDeviceResult,
+};
+use core::cell::RefCell;
+
+/// Share a storage device on a single thread, e.g. through an ``Rc<RefCell<S>>``.
+///
+/// Requests made while the device is already borrowed fail with [StorageDeviceError::Busy]
+/// instead of panicking.
+impl<S: StorageDevice + ?Sized> SharedStorageDevice for RefCell<S> {
+    fn read_shared(&self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        borrow(self)?.read(offset, buf)
+    }
+
+    fn write_shared(&self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        borrow(self)?.write(offset, buf)
+    }
+
+    fn len_shared(&self) -> StorageDeviceResult<u64> {
+        borrow(self)?.len()
+    }
+
+    fn discard_shared(&self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        borrow(self)?.discard(offset, len)
+    }
+
+    fn flush_shared(&self) -> StorageDeviceResult<()> {
+        borrow(self)?.flush()
+    }
+
+    fn info_shared(&self) -> StorageDeviceResult<DeviceInfo> {
+        borrow(self)?.info()
+    }
+}
+
+/// Borrow the device in ``cell``, failing if it is already borrowed.
+fn borrow<S: ?Sized>(cell: &RefCell<S>) -> StorageDeviceResult<core::cell::RefMut<'_, S>> {
+    cell.try_borrow_mut().map_err(|_| StorageDeviceError::Busy)
+}
+
+/// Share a storage device between threads, each request locking it for its duration.
+///
+/// A panic while the lock was held doesn't prevent further requests.
+#[cfg(feature = "std")]
+impl<S: StorageDevice + ?Sized> SharedStorageDevice for std::sync::Mutex<S> {
+    fn read_shared(&self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        lock(self).read(offset, buf)
+    }
+
+    fn write_shared(&self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        lock(self).write(offset, buf)
+    }
+
+    fn len_shared(&self) -> StorageDeviceResult<u64> {
+        lock(self).len()
+    }
+
+    fn discard_shared(&self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        lock(self).discard(offset, len)
+    }
+
+    fn flush_shared(&self) -> StorageDeviceResult<()> {
+        lock(self).flush()
+    }
+
+    fn info_shared(&self) -> StorageDeviceResult<DeviceInfo> {
+        lock(self).info()
+    }
+}
+
+/// Lock the device in ``mutex``, ignoring poisoning.
+#[cfg(feature = "std")]
+fn lock<S: ?Sized>(mutex: &std::sync::Mutex<S>) -> std::sync::MutexGuard<'_, S> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Share a storage device between cores without an operating system, each request locking it
+/// for its duration.
+#[cfg(feature = "spin")]
+impl<S: StorageDevice + ?Sized> SharedStorageDevice for spin::Mutex<S> {
+    fn read_shared(&self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.lock().read(offset, buf)
+    }
+
+    fn write_shared(&self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.lock().write(offset, buf)
+    }
+
+    fn len_shared(&self) -> StorageDeviceResult<u64> {
+        self.lock().len()
+    }
+
+    fn discard_shared(&self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.lock().discard(offset, len)
+    }
+
+    fn flush_shared(&self) -> StorageDeviceResult<()> {
+        self.lock().flush()
+    }
+
+    fn info_shared(&self) -> StorageDeviceResult<DeviceInfo> {
+        self.lock().info()
+    }
+}
+
+/// A [StorageDevice] borrowing a [SharedStorageDevice], e.g. a device in a ``static`` mutex.
+///
+/// Every user gets its own copy of the reference. ``Arc<S>`` and ``Rc<S>`` do the same for owned
+/// devices.
+#[derive(Debug)]
+pub struct SharedRef<'a, S: SharedStorageDevice + ?Sized> {
+    /// The shared device.
+    storage_device: &'a S,
+}
+
+impl<'a, S: SharedStorageDevice + ?Sized> SharedRef<'a, S> {
+    /// Use ``storage_device`` through a shared reference.
+    pub fn new(storage_device: &'a S) -> Self {
+        SharedRef { storage_device }
+    }
+
+    /// Return the shared device.
+    pub fn get_ref(&self) -> &'a S {
+        self.storage_device
+    }
+}
+
+impl<S: SharedStorageDevice + ?Sized> Clone for SharedRef<'_, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: SharedStorageDevice + ?Sized> Copy for SharedRef<'_, S> {}
+
+impl<S: SharedStorageDevice + ?Sized> StorageDevice for SharedRef<'_, S> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.storage_device.read_shared(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.storage_device.write_shared(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.storage_device.len_shared()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.discard_shared(offset, len)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush_shared()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info_shared()
+    }
+}
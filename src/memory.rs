@@ -0,0 +1,253 @@
+use crate::{Capabilities, ReadBuf, StorageDevice, StorageDeviceError, StorageDeviceResult};
+#[cfg(feature = "alloc")]
+use crate::{DeviceInfo, Resizable};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::ops::Range;
+
+/// Return the range covered by ``len`` bytes at ``offset`` in a buffer of ``size`` bytes.
+fn buffer_range(size: usize, offset: u64, len: usize) -> StorageDeviceResult<Range<usize>> {
+    let start = usize::try_from(offset).map_err(|_| StorageDeviceError::OutOfBounds)?;
+    match start.checked_add(len) {
+        Some(end) if end <= size => Ok(start..end),
+        _ => Err(StorageDeviceError::OutOfBounds),
+    }
+}
+
+/// Return the part of the range covered by ``len`` bytes at ``offset`` that lies in a buffer of
+/// ``size`` bytes.
+#[cfg(feature = "alloc")]
+fn clamped_range(size: usize, offset: u64, len: u64) -> Range<usize> {
+    let start = core::cmp::min(offset, size as u64) as usize;
+    let end = core::cmp::min(offset.saturating_add(len), size as u64) as usize;
+    start..end
+}
+
+/// A read-only storage device over a byte slice, such as a filesystem image embedded in the
+/// binary with ``include_bytes!``.
+///
+/// Writes fail with [StorageDeviceError::Unsupported].
+#[derive(Debug, Clone, Copy)]
+pub struct RomDevice<'a> {
+    /// The content of the device.
+    data: &'a [u8],
+}
+
+impl<'a> RomDevice<'a> {
+    /// Create a device exposing ``data``.
+    pub fn new(data: &'a [u8]) -> Self {
+        RomDevice { data }
+    }
+
+    /// Return the content of the device.
+    pub fn get_ref(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+impl StorageDevice for RomDevice<'_> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        let range = buffer_range(self.data.len(), offset, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, _offset: u64, _buf: &[u8]) -> StorageDeviceResult<()> {
+        Err(StorageDeviceError::Unsupported)
+    }
+
+    fn read_buf(&mut self, offset: u64, buf: &mut ReadBuf<'_>) -> StorageDeviceResult<()> {
+        let range = buffer_range(self.data.len(), offset, buf.remaining())?;
+        buf.append(&self.data[range]);
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        Ok(Capabilities::READ_ONLY | Capabilities::CONCURRENT)
+    }
+}
+
+impl crate::SharedStorageDevice for RomDevice<'_> {
+    fn read_shared(&self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        let range = buffer_range(self.data.len(), offset, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_shared(&self, _offset: u64, _buf: &[u8]) -> StorageDeviceResult<()> {
+        Err(StorageDeviceError::Unsupported)
+    }
+
+    fn len_shared(&self) -> StorageDeviceResult<u64> {
+        Ok(self.data.len() as u64)
+    }
+}
+
+/// A storage device backed by a growable buffer in memory.
+///
+/// Like a file, writing past the end of the buffer extends it, filling the gap with zeros.
+#[cfg(feature = "alloc")]
+impl StorageDevice for Vec<u8> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        let range = buffer_range(Vec::len(self), offset, buf.len())?;
+        buf.copy_from_slice(&self[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let range = buffer_range(usize::MAX, offset, buf.len())?;
+        if range.end > Vec::len(self) {
+            self.resize(range.end, 0);
+        }
+        self[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_buf(&mut self, offset: u64, buf: &mut ReadBuf<'_>) -> StorageDeviceResult<()> {
+        let range = buffer_range(Vec::len(self), offset, buf.remaining())?;
+        buf.append(&self[range]);
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(Vec::len(self) as u64)
+    }
+
+    /// Fill the range at once, extending the buffer like a write if it goes past its end.
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let len = usize::try_from(len).map_err(|_| StorageDeviceError::OutOfBounds)?;
+        let range = buffer_range(usize::MAX, offset, len)?;
+        if range.end > Vec::len(self) {
+            self.resize(range.end, 0);
+        }
+        self[range].fill(byte);
+        Ok(())
+    }
+
+    /// Zero the part of the range inside the buffer.
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        let range = clamped_range(Vec::len(self), offset, len);
+        for byte in self[range].iter_mut() {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo {
+            supports_holes: true,
+            ..DeviceInfo::default()
+        })
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Resizable for Vec<u8> {
+    fn set_len(&mut self, len: u64) -> StorageDeviceResult<()> {
+        let len = usize::try_from(len).map_err(|_| StorageDeviceError::OutOfSpace)?;
+        self.resize(len, 0);
+        Ok(())
+    }
+}
+
+/// A storage device backed by the growable buffer of a cursor, behaving like the buffer itself.
+///
+/// The position of the cursor is left untouched.
+#[cfg(feature = "std")]
+impl StorageDevice for std::io::Cursor<Vec<u8>> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.get_mut().read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.get_mut().write(offset, buf)
+    }
+
+    fn read_buf(&mut self, offset: u64, buf: &mut ReadBuf<'_>) -> StorageDeviceResult<()> {
+        self.get_mut().read_buf(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        StorageDevice::len(self.get_mut())
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.get_mut().discard(offset, len)
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.get_mut().info()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Resizable for std::io::Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> StorageDeviceResult<()> {
+        Resizable::set_len(self.get_mut(), len)
+    }
+}
+
+/// A fixed-size storage device backed by the buffer of a cursor.
+///
+/// The position of the cursor is left untouched.
+#[cfg(feature = "std")]
+impl StorageDevice for std::io::Cursor<&mut [u8]> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        let data = self.get_ref();
+        let range = buffer_range(data.len(), offset, buf.len())?;
+        buf.copy_from_slice(&data[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let data = self.get_mut();
+        let range = buffer_range(data.len(), offset, buf.len())?;
+        data[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_buf(&mut self, offset: u64, buf: &mut ReadBuf<'_>) -> StorageDeviceResult<()> {
+        let data = self.get_ref();
+        let range = buffer_range(data.len(), offset, buf.remaining())?;
+        buf.append(&data[range]);
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.get_ref().len() as u64)
+    }
+
+    /// Fill the range at once, leaving the buffer untouched if it isn't entirely inside.
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        let data = self.get_mut();
+        let len = usize::try_from(len).map_err(|_| StorageDeviceError::OutOfBounds)?;
+        let range = buffer_range(data.len(), offset, len)?;
+        data[range].fill(byte);
+        Ok(())
+    }
+
+    /// Zero the part of the range inside the buffer.
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        let data = self.get_mut();
+        let range = clamped_range(data.len(), offset, len);
+        for byte in data[range].iter_mut() {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo {
+            supports_holes: true,
+            ..DeviceInfo::default()
+        })
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{
+    Capabilities, DeviceIdentity, DeviceInfo, StorageDevice, StorageDeviceError,
+    StorageDeviceResult,
+};
+use core::convert::TryFrom;
+use memmap2::{Mmap, MmapMut};
+use std::fs::File;
+
+/// The memory mapping of a [MmapStorageDevice].
+#[derive(Debug)]
+enum Mapping {
+    /// The file is mapped read-only, and writes are rejected.
+    ReadOnly(Mmap),
+
+    /// The file is mapped read-write, and shared with the file.
+    ReadWrite(MmapMut),
+}
+
+impl Mapping {
+    /// Return the content of the mapping.
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Mapping::ReadOnly(map) => map,
+            Mapping::ReadWrite(map) => map,
+        }
+    }
+}
+
+/// A storage device backed by a file mapped in memory.
+///
+/// Reads and writes are plain copies from and to the mapping, which avoids a system call per
+/// request and makes it much faster than seeking and reading for large disk images. Writes reach
+/// the file in the background, and [StorageDevice::flush] syncs them with ``msync``.
+///
+/// The device has the size of the file at the time it was mapped, and never grows.
+#[derive(Debug)]
+pub struct MmapStorageDevice {
+    /// The mapped file.
+    file: File,
+
+    /// The mapping of the whole file.
+    map: Mapping,
+}
+
+impl MmapStorageDevice {
+    /// Map the whole ``file`` read-write. The file must have been opened for reading and writing.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated while it is mapped, whether by this process or another one,
+    /// as accessing the missing part of the mapping raises ``SIGBUS``. Modifications of the file
+    /// by another process are visible through the device.
+    pub unsafe fn new(file: File) -> std::io::Result<Self> {
+        let map = MmapMut::map_mut(&file)?;
+        Ok(MmapStorageDevice {
+            file,
+            map: Mapping::ReadWrite(map),
+        })
+    }
+
+    /// Map the whole ``file`` read-only. Writes to the device fail with
+    /// [StorageDeviceError::Unsupported].
+    ///
+    /// # Safety
+    ///
+    /// See [MmapStorageDevice::new].
+    pub unsafe fn new_read_only(file: File) -> std::io::Result<Self> {
+        let map = Mmap::map(&file)?;
+        Ok(MmapStorageDevice {
+            file,
+            map: Mapping::ReadOnly(map),
+        })
+    }
+
+    /// Return a reference to the mapped file.
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    /// Unmap the file and return it.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    /// Return the range of the mapping covered by ``len`` bytes at ``offset``.
+    fn range(&self, offset: u64, len: usize) -> StorageDeviceResult<core::ops::Range<usize>> {
+        let start = usize::try_from(offset).map_err(|_| StorageDeviceError::OutOfBounds)?;
+        match start.checked_add(len) {
+            Some(end) if end <= self.map.as_slice().len() => Ok(start..end),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+}
+
+impl StorageDevice for MmapStorageDevice {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        let range = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.map.as_slice()[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let range = self.range(offset, buf.len())?;
+        match &mut self.map {
+            Mapping::ReadOnly(_) => Err(StorageDeviceError::Unsupported),
+            Mapping::ReadWrite(map) => {
+                map[range].copy_from_slice(buf);
+                Ok(())
+            }
+        }
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.map.as_slice().len() as u64)
+    }
+
+    /// Fill the mapping directly.
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        let len = usize::try_from(len).map_err(|_| StorageDeviceError::OutOfBounds)?;
+        let range = self.range(offset, len)?;
+        match &mut self.map {
+            Mapping::ReadOnly(_) => Err(StorageDeviceError::Unsupported),
+            Mapping::ReadWrite(map) => {
+                map[range].fill(byte);
+                Ok(())
+            }
+        }
+    }
+
+    /// Report the holes of the file, limited to the mapping.
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        let len = self.map.as_slice().len() as u64;
+        match crate::sys::next_data(&self.file, offset) {
+            Ok(Some(range)) if range.start < len => Ok(Some(range.start..range.end.min(len))),
+            Ok(_) => Ok(None),
+            Err(err) => Err(crate::sys::storage_error(
+                &err,
+                StorageDeviceError::ReadError,
+            )),
+        }
+    }
+
+    /// Synchronously write the modified pages of the mapping back to the file.
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        match &self.map {
+            Mapping::ReadOnly(_) => Ok(()),
+            Mapping::ReadWrite(map) => map
+                .flush()
+                .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError)),
+        }
+    }
+
+    /// Punch a hole in the file if the filesystem supports it, do nothing otherwise.
+    ///
+    /// The hole is visible through the mapping, which reads as zeros afterwards.
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        if let Mapping::ReadOnly(_) = self.map {
+            return Ok(());
+        }
+
+        match crate::sys::punch_hole(&self.file, offset, len) {
+            Err(err) if !crate::sys::is_unsupported(&err) => Err(crate::sys::storage_error(
+                &err,
+                StorageDeviceError::WriteError,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo {
+            supports_holes: matches!(self.map, Mapping::ReadWrite(_))
+                && crate::sys::supports_holes(&self.file),
+            ..DeviceInfo::default()
+        })
+    }
+
+    /// Query the identity of the disk or file from the operating system.
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        crate::sys::identity(&self.file)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+    }
+
+    /// Query the capabilities of the disk or file from the operating system, the device being
+    /// read-only if the file is mapped read-only.
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        let mut capabilities = crate::sys::capabilities(&self.file);
+        capabilities.remove(Capabilities::WRITE_ZEROES | Capabilities::CONCURRENT);
+        if !matches!(self.map, Mapping::ReadWrite(_)) {
+            capabilities.remove(Capabilities::TRIM);
+            capabilities.insert(Capabilities::READ_ONLY);
+        }
+        Ok(capabilities)
+    }
+}
@@ -0,0 +1,246 @@
+use crate::{Block, BlockCount, BlockDevice, BlockError, BlockIndex, BlockResult};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// An operation performed on a [MockBlockDevice].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MockOperation {
+    /// Read ``count`` blocks starting at ``index``.
+    Read {
+        /// The first block read.
+        index: u64,
+        /// The number of blocks read.
+        count: u64,
+    },
+
+    /// Write ``count`` blocks starting at ``index``.
+    Write {
+        /// The first block written.
+        index: u64,
+        /// The number of blocks written.
+        count: u64,
+    },
+
+    /// Query the number of blocks of the device.
+    Count,
+
+    /// Flush the device.
+    Flush,
+
+    /// Discard ``count`` blocks starting at ``index``.
+    Discard {
+        /// The first block discarded.
+        index: u64,
+        /// The number of blocks discarded.
+        count: u64,
+    },
+
+    /// Hint that blocks will be read soon.
+    Prefetch,
+}
+
+/// Builds a [MockBlockDevice].
+#[derive(Debug, Clone, Default)]
+pub struct MockBlockDeviceBuilder {
+    /// The number of blocks of the device.
+    block_count: u64,
+
+    /// The initial content of the device.
+    contents: Vec<u8>,
+
+    /// The operations the device expects, in order, if any is.
+    expected: Option<Vec<MockOperation>>,
+
+    /// The errors to return instead of performing a call, by call number.
+    failures: BTreeMap<usize, BlockError>,
+}
+
+impl MockBlockDeviceBuilder {
+    /// Create a builder for an empty device of no blocks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make the device ``block_count`` blocks big.
+    ///
+    /// If not called, the device is as big as its initial content.
+    pub fn with_block_count(mut self, block_count: u64) -> Self {
+        self.block_count = block_count;
+        self
+    }
+
+    /// Fill the start of the device with ``contents``, the rest being zeros.
+    pub fn with_contents(mut self, contents: &[u8]) -> Self {
+        self.contents = contents.to_vec();
+        self
+    }
+
+    /// Expect ``operation`` to be performed after the ones expected so far.
+    ///
+    /// Once an operation is expected, performing an operation that isn't the next expected one
+    /// panics.
+    pub fn with_expected(mut self, operation: MockOperation) -> Self {
+        self.expected.get_or_insert_with(Vec::new).push(operation);
+        self
+    }
+
+    /// Fail the ``call``-th call to the device with ``error``, counting from 0, instead of
+    /// performing it.
+    pub fn with_failure(mut self, call: usize, error: BlockError) -> Self {
+        self.failures.insert(call, error);
+        self
+    }
+
+    /// Build the device.
+    pub fn build(self) -> MockBlockDevice {
+        let content_blocks = self.contents.len().div_ceil(Block::LEN) as u64;
+        let block_count = core::cmp::max(self.block_count, content_blocks);
+
+        let mut blocks = alloc::vec![Block::new(); block_count as usize];
+        for (block, data) in blocks.iter_mut().zip(self.contents.chunks(Block::LEN)) {
+            block[..data.len()].copy_from_slice(data);
+        }
+
+        MockBlockDevice {
+            blocks,
+            expected: self.expected.map(VecDeque::from),
+            failures: self.failures,
+            operations: Vec::new(),
+        }
+    }
+}
+
+/// A block device for tests, recording the operations performed on it and checking them against
+/// the expected ones, and failing chosen calls.
+///
+/// The blocks are stored in memory, so the data written can be read back. Reads and writes out
+/// of the bounds of the device fail with [BlockError::ReadError] and [BlockError::WriteError].
+#[derive(Clone)]
+pub struct MockBlockDevice {
+    /// The content of the device.
+    blocks: Vec<Block>,
+
+    /// The operations still expected, in order, if any is.
+    expected: Option<VecDeque<MockOperation>>,
+
+    /// The errors to return instead of performing a call, by call number.
+    failures: BTreeMap<usize, BlockError>,
+
+    /// The operations performed so far, including the failed ones.
+    operations: Vec<MockOperation>,
+}
+
+impl MockBlockDevice {
+    /// Create a builder for a mock device.
+    pub fn builder() -> MockBlockDeviceBuilder {
+        MockBlockDeviceBuilder::new()
+    }
+
+    /// Return the operations performed so far, including the failed ones.
+    pub fn operations(&self) -> &[MockOperation] {
+        &self.operations
+    }
+
+    /// Return the content of the device.
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// Panic if some expected operations weren't performed.
+    pub fn assert_done(&self) {
+        if let Some(expected) = &self.expected {
+            assert!(
+                expected.is_empty(),
+                "expected operations were not performed: {:?}",
+                expected
+            );
+        }
+    }
+
+    /// Record ``operation``, check it was expected, and return the canned error of the call if
+    /// any.
+    fn call(&mut self, operation: MockOperation) -> BlockResult<()> {
+        let call = self.operations.len();
+        self.operations.push(operation);
+
+        if let Some(expected) = &mut self.expected {
+            match expected.pop_front() {
+                Some(next) if next == operation => (),
+                next => panic!("call {}: expected {:?}, got {:?}", call, next, operation),
+            }
+        }
+
+        match self.failures.get(&call) {
+            Some(error) => Err(*error),
+            None => Ok(()),
+        }
+    }
+
+    /// Return the range of the blocks of a request, if it is inside the device.
+    fn range(&self, index: BlockIndex, count: usize) -> Option<core::ops::Range<usize>> {
+        let start = usize::try_from(index.0).ok()?;
+        let end = start.checked_add(count)?;
+        if end > self.blocks.len() {
+            return None;
+        }
+        Some(start..end)
+    }
+}
+
+impl core::fmt::Debug for MockBlockDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MockBlockDevice")
+            .field("block_count", &self.blocks.len())
+            .field("expected", &self.expected)
+            .field("failures", &self.failures)
+            .field("operations", &self.operations)
+            .finish()
+    }
+}
+
+impl BlockDevice for MockBlockDevice {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        self.call(MockOperation::Read {
+            index: index.0,
+            count: blocks.len() as u64,
+        })?;
+        let range = self
+            .range(index, blocks.len())
+            .ok_or(BlockError::ReadError)?;
+        blocks.clone_from_slice(&self.blocks[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.call(MockOperation::Write {
+            index: index.0,
+            count: blocks.len() as u64,
+        })?;
+        let range = self
+            .range(index, blocks.len())
+            .ok_or(BlockError::WriteError)?;
+        self.blocks[range].clone_from_slice(blocks);
+        Ok(())
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        self.call(MockOperation::Count)?;
+        Ok(BlockCount(self.blocks.len() as u64))
+    }
+
+    fn flush(&mut self) -> BlockResult<()> {
+        self.call(MockOperation::Flush)
+    }
+
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        self.call(MockOperation::Discard {
+            index: index.0,
+            count: count.0,
+        })
+    }
+
+    fn prefetch(&mut self, _ranges: &[(BlockIndex, BlockCount)]) -> BlockResult<()> {
+        self.call(MockOperation::Prefetch)
+    }
+}
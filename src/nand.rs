@@ -0,0 +1,281 @@
+#[cfg(feature = "alloc")]
+use crate::BlockError;
+use crate::{Block, BlockResult};
+
+/// The layout of a [NandDevice].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NandGeometry {
+    /// The size of a page, the unit of reads and programs, in bytes.
+    ///
+    /// It must be a multiple of [Block::LEN].
+    pub page_size: usize,
+
+    /// The size of the spare area of each page, in bytes.
+    pub spare_size: usize,
+
+    /// The number of pages in an erase block.
+    pub pages_per_erase_block: u32,
+
+    /// The number of erase blocks of the device.
+    pub erase_blocks: u64,
+}
+
+impl NandGeometry {
+    /// Return the number of [Block]s in a page.
+    pub fn page_blocks(&self) -> usize {
+        self.page_size / Block::LEN
+    }
+
+    /// Return the number of pages of the device.
+    pub fn pages(&self) -> u64 {
+        self.erase_blocks * u64::from(self.pages_per_erase_block)
+    }
+
+    /// Return the erase block holding ``page``.
+    pub fn erase_block_of(&self, page: u64) -> u64 {
+        page / u64::from(self.pages_per_erase_block)
+    }
+
+    /// Return the first page of ``erase_block``.
+    pub fn first_page(&self, erase_block: u64) -> u64 {
+        erase_block * u64::from(self.pages_per_erase_block)
+    }
+}
+
+/// Represent raw flash, such as NAND, which can't be overwritten in place.
+///
+/// Pages are read and programmed whole, but only erased a whole erase block at a time. Erasing
+/// sets every bit of the erase block, so an erased page reads as ``0xFF`` bytes, data and spare
+/// area alike. Programming can only clear bits: a page must be programmed at most once between
+/// erases, and the pages of an erase block in increasing order. Breaking these rules is
+/// undefined at the device level, but never memory unsafe.
+///
+/// Each page comes with a spare area, also known as out-of-band area, holding the metadata of
+/// the page: bad block markers, error-correcting codes, and the bookkeeping of the layer above.
+///
+/// Most code wants a [BlockDevice](crate::BlockDevice) on top of this, provided by a flash
+/// translation layer such as [Ftl](crate::Ftl).
+pub trait NandDevice: core::fmt::Debug {
+    /// Return the layout of the device.
+    fn geometry(&self) -> NandGeometry;
+
+    /// Read ``page`` into ``data`` and its spare area into ``spare``.
+    ///
+    /// ``data`` is either empty, to only read the spare area, or a whole page. ``spare`` may be
+    /// shorter than the spare area, to only read its start.
+    fn read_page(&mut self, page: u64, data: &mut [Block], spare: &mut [u8]) -> BlockResult<()>;
+
+    /// Program ``page`` with ``data`` and the start of its spare area with ``spare``, the rest of
+    /// the spare area staying erased.
+    ///
+    /// ``data`` is either a whole page, or empty to only program the spare area, a partial
+    /// program most NAND supports a few times per page. Devices that don't return
+    /// [crate::BlockError::Unsupported] for it.
+    fn program_page(&mut self, page: u64, data: &[Block], spare: &[u8]) -> BlockResult<()>;
+
+    /// Erase ``erase_block``, making all its pages programmable again.
+    fn erase(&mut self, erase_block: u64) -> BlockResult<()>;
+
+    /// Read the start of the spare area of ``page`` into ``spare``.
+    fn read_spare(&mut self, page: u64, spare: &mut [u8]) -> BlockResult<()> {
+        self.read_page(page, &mut [], spare)
+    }
+
+    /// Program the start of the spare area of ``page`` with ``spare``, leaving its data erased.
+    fn program_spare(&mut self, page: u64, spare: &[u8]) -> BlockResult<()> {
+        self.program_page(page, &[], spare)
+    }
+
+    /// Check whether ``erase_block`` is bad, and must not be used.
+    ///
+    /// The default implementation follows the usual convention of NAND manufacturers: an erase
+    /// block is bad if the first byte of the spare area of its first page isn't ``0xFF``.
+    fn is_bad(&mut self, erase_block: u64) -> BlockResult<bool> {
+        let mut marker = [0];
+        let page = self.geometry().first_page(erase_block);
+        self.read_spare(page, &mut marker)?;
+        Ok(marker[0] != 0xFF)
+    }
+
+    /// Mark ``erase_block`` as bad, after it failed to erase or program.
+    ///
+    /// The default implementation tries to erase the erase block, then clears the first byte of
+    /// the spare area of its first page, as checked by [NandDevice::is_bad].
+    fn mark_bad(&mut self, erase_block: u64) -> BlockResult<()> {
+        let _ = self.erase(erase_block);
+        let page = self.geometry().first_page(erase_block);
+        self.program_spare(page, &[0])
+    }
+}
+
+/// A [NandDevice] in memory, simulating raw flash for tests.
+///
+/// It starts erased, enforces the rules of raw flash, and simulates wear: programming a page
+/// twice between erases, or out of order in its erase block, fails with
+/// [BlockError::WriteError], and programming only clears bits. Bad erase blocks fail to erase
+/// and to program with [BlockError::MediaError], and erase blocks worn out by as many erases as
+/// their endurance fail to erase. Programming only the spare area always succeeds, so they can
+/// be marked bad.
+#[cfg(feature = "alloc")]
+#[derive(Clone)]
+pub struct MemoryNand {
+    /// The layout of the device.
+    geometry: NandGeometry,
+
+    /// The data of every page.
+    data: alloc::vec::Vec<u8>,
+
+    /// The spare area of every page.
+    spare: alloc::vec::Vec<u8>,
+
+    /// The index of the next page programmable in each erase block.
+    next_page: alloc::vec::Vec<u32>,
+
+    /// The number of times each erase block was erased.
+    erase_counts: alloc::vec::Vec<u32>,
+
+    /// Whether each erase block is bad.
+    bad: alloc::vec::Vec<bool>,
+
+    /// The number of erases each erase block survives, if limited.
+    endurance: Option<u32>,
+}
+
+#[cfg(feature = "alloc")]
+impl MemoryNand {
+    /// Create an erased device of ``geometry``.
+    ///
+    /// ``geometry.page_size`` must be a multiple of [Block::LEN].
+    pub fn new(geometry: NandGeometry) -> Self {
+        let pages = geometry.pages() as usize;
+        let erase_blocks = geometry.erase_blocks as usize;
+        MemoryNand {
+            geometry,
+            data: alloc::vec![0xFF; pages * geometry.page_size],
+            spare: alloc::vec![0xFF; pages * geometry.spare_size],
+            next_page: alloc::vec![0; erase_blocks],
+            erase_counts: alloc::vec![0; erase_blocks],
+            bad: alloc::vec![false; erase_blocks],
+            endurance: None,
+        }
+    }
+
+    /// Make ``erase_block`` bad from the factory, with a bad block marker.
+    pub fn with_bad_block(mut self, erase_block: u64) -> Self {
+        self.bad[erase_block as usize] = true;
+        let page = self.geometry.first_page(erase_block) as usize;
+        if self.geometry.spare_size > 0 {
+            self.spare[page * self.geometry.spare_size] = 0;
+        }
+        self
+    }
+
+    /// Make erase blocks fail to erase once erased ``erases`` times.
+    pub fn with_endurance(mut self, erases: u32) -> Self {
+        self.endurance = Some(erases);
+        self
+    }
+
+    /// Return the number of times each erase block was erased.
+    pub fn erase_counts(&self) -> &[u32] {
+        &self.erase_counts
+    }
+
+    /// Check whether ``erase_block`` is worn out, and fails to erase.
+    fn is_worn_out(&self, erase_block: usize) -> bool {
+        self.endurance
+            .is_some_and(|endurance| self.erase_counts[erase_block] >= endurance)
+    }
+
+    /// Return the erase block of ``page``, if it is inside the device.
+    fn erase_block_of(&self, page: u64) -> Option<usize> {
+        if page >= self.geometry.pages() {
+            return None;
+        }
+        Some(self.geometry.erase_block_of(page) as usize)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl core::fmt::Debug for MemoryNand {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MemoryNand")
+            .field("geometry", &self.geometry)
+            .field("endurance", &self.endurance)
+            .finish()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl NandDevice for MemoryNand {
+    fn geometry(&self) -> NandGeometry {
+        self.geometry
+    }
+
+    fn read_page(&mut self, page: u64, data: &mut [Block], spare: &mut [u8]) -> BlockResult<()> {
+        self.erase_block_of(page).ok_or(BlockError::ReadError)?;
+        let (page_size, spare_size) = (self.geometry.page_size, self.geometry.spare_size);
+        if (!data.is_empty() && data.len() * Block::LEN != page_size) || spare.len() > spare_size {
+            return Err(BlockError::ReadError);
+        }
+
+        let page = page as usize;
+        for (i, block) in data.iter_mut().enumerate() {
+            let start = page * page_size + i * Block::LEN;
+            block.copy_from_slice(&self.data[start..start + Block::LEN]);
+        }
+        let start = page * spare_size;
+        spare.copy_from_slice(&self.spare[start..start + spare.len()]);
+        Ok(())
+    }
+
+    fn program_page(&mut self, page: u64, data: &[Block], spare: &[u8]) -> BlockResult<()> {
+        let erase_block = self.erase_block_of(page).ok_or(BlockError::WriteError)?;
+        let (page_size, spare_size) = (self.geometry.page_size, self.geometry.spare_size);
+        if (!data.is_empty() && data.len() * Block::LEN != page_size) || spare.len() > spare_size {
+            return Err(BlockError::WriteError);
+        }
+
+        if !data.is_empty() {
+            let index = (page - self.geometry.first_page(erase_block as u64)) as u32;
+            if index < self.next_page[erase_block] {
+                return Err(BlockError::WriteError);
+            }
+            if self.bad[erase_block] {
+                return Err(BlockError::MediaError);
+            }
+            self.next_page[erase_block] = index + 1;
+
+            let start = page as usize * page_size;
+            let bytes = data.iter().flat_map(|block| block.iter());
+            for (cell, byte) in self.data[start..start + page_size].iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+        }
+
+        let start = page as usize * spare_size;
+        for (cell, byte) in self.spare[start..start + spare.len()].iter_mut().zip(spare) {
+            *cell &= byte;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, erase_block: u64) -> BlockResult<()> {
+        if erase_block >= self.geometry.erase_blocks {
+            return Err(BlockError::WriteError);
+        }
+        let index = erase_block as usize;
+        if self.bad[index] || self.is_worn_out(index) {
+            return Err(BlockError::MediaError);
+        }
+
+        let first = self.geometry.first_page(erase_block) as usize;
+        let pages = self.geometry.pages_per_erase_block as usize;
+        let (page_size, spare_size) = (self.geometry.page_size, self.geometry.spare_size);
+        self.data[first * page_size..(first + pages) * page_size].fill(0xFF);
+        self.spare[first * spare_size..(first + pages) * spare_size].fill(0xFF);
+        self.next_page[index] = 0;
+        self.erase_counts[index] += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,511 @@
+use crate::rpc::Transport;
+use crate::{Capabilities, StorageDevice, StorageDeviceError, StorageDeviceResult};
+
+/// The maximum number of bytes transferred by a single request.
+///
+/// Bigger transfers are split by the client, and refused by the server.
+pub const NBD_MAX_REQUEST_LEN: usize = 1 << 20;
+
+/// The magic starting the handshake, "NBDMAGIC".
+pub(crate) const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+
+/// The magic of the newstyle negotiation and of the options, "IHAVEOPT".
+pub(crate) const NBD_IHAVEOPT: u64 = 0x4948_4156_454F_5054;
+
+/// The magic of the replies to options.
+pub(crate) const NBD_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+
+/// The magic of requests.
+pub(crate) const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+
+/// The magic of simple replies to requests.
+pub(crate) const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+/// The handshake flag of servers supporting the fixed newstyle negotiation.
+pub(crate) const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+
+/// The handshake flag of servers able to omit the zeroes ending the export name option.
+pub(crate) const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+
+/// The option selecting an export and ending the negotiation, without replies.
+pub(crate) const NBD_OPT_EXPORT_NAME: u32 = 1;
+
+/// The option ending the negotiation early.
+#[cfg(feature = "alloc")]
+pub(crate) const NBD_OPT_ABORT: u32 = 2;
+
+/// The option asking for information about an export.
+#[cfg(feature = "alloc")]
+pub(crate) const NBD_OPT_INFO: u32 = 6;
+
+/// The option selecting an export and ending the negotiation, with replies.
+pub(crate) const NBD_OPT_GO: u32 = 7;
+
+/// The reply acknowledging an option.
+pub(crate) const NBD_REP_ACK: u32 = 1;
+
+/// The reply carrying information about an export.
+pub(crate) const NBD_REP_INFO: u32 = 3;
+
+/// The bit set in the replies reporting an error.
+pub(crate) const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+
+/// The error reply of unknown options.
+pub(crate) const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+
+/// The error reply of malformed options.
+#[cfg(feature = "alloc")]
+pub(crate) const NBD_REP_ERR_INVALID: u32 = NBD_REP_FLAG_ERROR | 3;
+
+/// The error reply of unknown exports.
+#[cfg(feature = "alloc")]
+pub(crate) const NBD_REP_ERR_UNKNOWN: u32 = NBD_REP_FLAG_ERROR | 6;
+
+/// The information giving the size and transmission flags of an export.
+pub(crate) const NBD_INFO_EXPORT: u16 = 0;
+
+/// The transmission flag always set.
+pub const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+
+/// The transmission flag of read-only exports.
+pub const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+
+/// The transmission flag of exports supporting flushes.
+pub const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+
+/// The transmission flag of exports supporting forced unit access writes.
+pub const NBD_FLAG_SEND_FUA: u16 = 1 << 3;
+
+/// The transmission flag of exports on rotational media.
+pub const NBD_FLAG_ROTATIONAL: u16 = 1 << 4;
+
+/// The transmission flag of exports supporting trims.
+pub const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+
+/// The transmission flag of exports supporting write zeroes requests.
+pub const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+/// The flag of writes to be on stable storage before they are replied to.
+pub(crate) const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+
+/// The size of a request.
+pub(crate) const NBD_REQUEST_LEN: usize = 28;
+
+/// The size of a simple reply, without its data.
+pub(crate) const NBD_SIMPLE_REPLY_LEN: usize = 16;
+
+/// The type of a request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Command {
+    /// Read ``length`` bytes at ``offset``.
+    Read = 0,
+
+    /// Write the data following the request at ``offset``.
+    Write = 1,
+
+    /// End the connection.
+    Disconnect = 2,
+
+    /// Flush the export.
+    Flush = 3,
+
+    /// Discard ``length`` bytes at ``offset``.
+    Trim = 4,
+
+    /// Set ``length`` bytes at ``offset`` to zero.
+    WriteZeroes = 6,
+}
+
+#[cfg(feature = "alloc")]
+impl Command {
+    /// Decode the type of a request.
+    pub(crate) fn from_u16(value: u16) -> Option<Command> {
+        match value {
+            0 => Some(Command::Read),
+            1 => Some(Command::Write),
+            2 => Some(Command::Disconnect),
+            3 => Some(Command::Flush),
+            4 => Some(Command::Trim),
+            6 => Some(Command::WriteZeroes),
+            _ => None,
+        }
+    }
+}
+
+/// Encode the result of a request as the errno of its reply.
+#[cfg(feature = "alloc")]
+pub(crate) fn encode_error(result: &StorageDeviceResult<()>) -> u32 {
+    match result {
+        Ok(()) => 0,
+        Err(StorageDeviceError::Busy) => 12,
+        Err(StorageDeviceError::OutOfBounds) => 22,
+        Err(StorageDeviceError::OutOfSpace) => 28,
+        Err(StorageDeviceError::Unsupported) => 95,
+        Err(_) => 5,
+    }
+}
+
+/// Decode the errno of a reply to ``command``.
+fn decode_error(error: u32, command: Command) -> StorageDeviceResult<()> {
+    match error {
+        0 => Ok(()),
+        1 => Err(StorageDeviceError::WriteError),
+        12 => Err(StorageDeviceError::Busy),
+        22 | 75 => Err(StorageDeviceError::OutOfBounds),
+        28 => Err(StorageDeviceError::OutOfSpace),
+        95 => Err(StorageDeviceError::Unsupported),
+        _ if command == Command::Read => Err(StorageDeviceError::ReadError),
+        _ => Err(StorageDeviceError::WriteError),
+    }
+}
+
+/// Receive a big endian u16.
+pub(crate) fn recv_u16<T: Transport>(transport: &mut T) -> StorageDeviceResult<u16> {
+    let mut bytes = [0; 2];
+    transport.recv(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+/// Receive a big endian u32.
+pub(crate) fn recv_u32<T: Transport>(transport: &mut T) -> StorageDeviceResult<u32> {
+    let mut bytes = [0; 4];
+    transport.recv(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// Receive a big endian u64.
+pub(crate) fn recv_u64<T: Transport>(transport: &mut T) -> StorageDeviceResult<u64> {
+    let mut bytes = [0; 8];
+    transport.recv(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Receive and drop ``len`` bytes.
+pub(crate) fn skip<T: Transport>(transport: &mut T, mut len: usize) -> StorageDeviceResult<()> {
+    let mut buf = [0u8; 64];
+    while len != 0 {
+        let chunk = core::cmp::min(len, buf.len());
+        transport.recv(&mut buf[..chunk])?;
+        len -= chunk;
+    }
+    Ok(())
+}
+
+/// A storage device exported by a Network Block Device server, such as ``qemu-nbd``,
+/// ``nbdkit`` or an [NbdServer](crate::nbd_server::NbdServer), at the other end of a
+/// [Transport].
+///
+/// The client performs the fixed newstyle handshake, and falls back to the older export name
+/// option with servers not supporting ``NBD_OPT_GO``. Every request waits for its reply before
+/// returning, and transfers at most [NBD_MAX_REQUEST_LEN] bytes. Flushes, discards and write
+/// zeroes requests are only sent if the export supports them: flushes and discards do nothing
+/// otherwise, and write zeroes requests are replaced by writes.
+///
+/// With std, a [TcpStream](std::net::TcpStream) wrapped in an
+/// [IoTransport](crate::rpc::IoTransport) makes a transport.
+#[derive(Debug)]
+pub struct NbdClientDevice<T: Transport> {
+    /// The transport connected to the server.
+    transport: T,
+
+    /// The size of the export, in bytes.
+    size: u64,
+
+    /// The transmission flags of the export.
+    flags: u16,
+
+    /// The cookie of the next request.
+    cookie: u64,
+}
+
+impl<T: Transport> NbdClientDevice<T> {
+    /// Negotiate with the server at the other end of ``transport`` to use the export named
+    /// ``export_name``, the empty name selecting the default export.
+    ///
+    /// Fail with [StorageDeviceError::Unsupported] if the server doesn't speak the fixed
+    /// newstyle protocol, or doesn't know the export.
+    pub fn connect(mut transport: T, export_name: &str) -> StorageDeviceResult<Self> {
+        if recv_u64(&mut transport)? != NBD_MAGIC || recv_u64(&mut transport)? != NBD_IHAVEOPT {
+            return Err(StorageDeviceError::Unsupported);
+        }
+        let handshake_flags = recv_u16(&mut transport)?;
+        if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(StorageDeviceError::Unsupported);
+        }
+        let client_flags = handshake_flags & (NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES);
+        transport.send(&u32::from(client_flags).to_be_bytes())?;
+
+        let name = export_name.as_bytes();
+        let name_len = name.len() as u32;
+        send_option(&mut transport, NBD_OPT_GO, 4 + name_len + 2)?;
+        transport.send(&name_len.to_be_bytes())?;
+        transport.send(name)?;
+        // No information requested, the server sends the size and flags anyway.
+        transport.send(&0u16.to_be_bytes())?;
+        transport.flush()?;
+
+        let mut export = None;
+        loop {
+            if recv_u64(&mut transport)? != NBD_REPLY_MAGIC
+                || recv_u32(&mut transport)? != NBD_OPT_GO
+            {
+                return Err(StorageDeviceError::Corrupted);
+            }
+            let reply = recv_u32(&mut transport)?;
+            let len = recv_u32(&mut transport)? as usize;
+            match reply {
+                NBD_REP_ACK => {
+                    skip(&mut transport, len)?;
+                    break;
+                }
+                NBD_REP_INFO if len >= 2 => {
+                    let info = recv_u16(&mut transport)?;
+                    if info == NBD_INFO_EXPORT && len == 12 {
+                        export = Some((recv_u64(&mut transport)?, recv_u16(&mut transport)?));
+                    } else {
+                        skip(&mut transport, len - 2)?;
+                    }
+                }
+                NBD_REP_ERR_UNSUP => {
+                    skip(&mut transport, len)?;
+                    export = Some(Self::export_name(&mut transport, name, client_flags)?);
+                    break;
+                }
+                reply if reply & NBD_REP_FLAG_ERROR != 0 => {
+                    skip(&mut transport, len)?;
+                    return Err(StorageDeviceError::Unsupported);
+                }
+                _ => skip(&mut transport, len)?,
+            }
+        }
+
+        let (size, flags) = export.ok_or(StorageDeviceError::Corrupted)?;
+        Ok(NbdClientDevice {
+            transport,
+            size,
+            flags,
+            cookie: 0,
+        })
+    }
+
+    /// Select the export named ``name`` with the export name option, returning its size and
+    /// transmission flags.
+    fn export_name(
+        transport: &mut T,
+        name: &[u8],
+        client_flags: u16,
+    ) -> StorageDeviceResult<(u64, u16)> {
+        send_option(transport, NBD_OPT_EXPORT_NAME, name.len() as u32)?;
+        transport.send(name)?;
+        transport.flush()?;
+
+        let size = recv_u64(transport)?;
+        let flags = recv_u16(transport)?;
+        if client_flags & NBD_FLAG_NO_ZEROES == 0 {
+            skip(transport, 124)?;
+        }
+        Ok((size, flags))
+    }
+
+    /// Return the transmission flags of the export, such as [NBD_FLAG_READ_ONLY].
+    pub fn transmission_flags(&self) -> u16 {
+        self.flags
+    }
+
+    /// Return a reference to the transport.
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    /// Tell the server the client is going away, returning the transport.
+    pub fn disconnect(mut self) -> StorageDeviceResult<T> {
+        self.send_request(Command::Disconnect, 0, 0, 0, &[])?;
+        Ok(self.transport)
+    }
+
+    /// Consume the client without disconnecting, returning the transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Send a request with the command ``flags``, followed by ``data``, returning its cookie.
+    fn send_request(
+        &mut self,
+        command: Command,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+    ) -> StorageDeviceResult<u64> {
+        let cookie = self.cookie;
+        self.cookie = self.cookie.wrapping_add(1);
+
+        let mut request = [0u8; NBD_REQUEST_LEN];
+        request[..4].copy_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+        request[4..6].copy_from_slice(&flags.to_be_bytes());
+        request[6..8].copy_from_slice(&(command as u16).to_be_bytes());
+        request[8..16].copy_from_slice(&cookie.to_be_bytes());
+        request[16..24].copy_from_slice(&offset.to_be_bytes());
+        request[24..].copy_from_slice(&len.to_be_bytes());
+        self.transport.send(&request)?;
+        self.transport.send(data)?;
+        self.transport.flush()?;
+        Ok(cookie)
+    }
+
+    /// Perform a request with the command ``flags``, sending ``data`` and receiving ``response``,
+    /// which is only used by reads.
+    fn call(
+        &mut self,
+        command: Command,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+        response: &mut [u8],
+    ) -> StorageDeviceResult<()> {
+        let cookie = self.send_request(command, flags, offset, len, data)?;
+
+        let mut reply = [0u8; NBD_SIMPLE_REPLY_LEN];
+        self.transport.recv(&mut reply)?;
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(&reply[..4]);
+        let mut error = [0u8; 4];
+        error.copy_from_slice(&reply[4..8]);
+        if u32::from_be_bytes(magic) != NBD_SIMPLE_REPLY_MAGIC || reply[8..] != cookie.to_be_bytes()
+        {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        // The data of a read only follows successful replies.
+        decode_error(u32::from_be_bytes(error), command)?;
+        self.transport.recv(response)
+    }
+
+    /// Check that the ``len`` bytes at ``offset`` are inside the export, and that it can be
+    /// written to if ``write`` is set.
+    fn check(&self, offset: u64, len: u64, write: bool) -> StorageDeviceResult<()> {
+        if write && self.flags & NBD_FLAG_READ_ONLY != 0 {
+            return Err(StorageDeviceError::WriteError);
+        }
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+
+    /// Write ``buf`` at ``offset`` with the command ``flags``, split in requests of at most
+    /// [NBD_MAX_REQUEST_LEN] bytes.
+    fn write_with_flags(&mut self, offset: u64, buf: &[u8], flags: u16) -> StorageDeviceResult<()> {
+        self.check(offset, buf.len() as u64, true)?;
+        for (i, chunk) in buf.chunks(NBD_MAX_REQUEST_LEN).enumerate() {
+            let chunk_offset = offset + (i * NBD_MAX_REQUEST_LEN) as u64;
+            self.call(
+                Command::Write,
+                flags,
+                chunk_offset,
+                chunk.len() as u32,
+                chunk,
+                &mut [],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Perform ``command`` on the ``len`` bytes at ``offset``, split in requests of at most
+    /// [NBD_MAX_REQUEST_LEN] bytes.
+    fn call_range(&mut self, command: Command, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        let end = offset + len;
+        let mut current = offset;
+        while current < end {
+            let chunk = core::cmp::min(end - current, NBD_MAX_REQUEST_LEN as u64);
+            self.call(command, 0, current, chunk as u32, &[], &mut [])?;
+            current += chunk;
+        }
+        Ok(())
+    }
+}
+
+/// Send the header of the option ``option`` carrying ``len`` bytes of data.
+fn send_option<T: Transport>(transport: &mut T, option: u32, len: u32) -> StorageDeviceResult<()> {
+    transport.send(&NBD_IHAVEOPT.to_be_bytes())?;
+    transport.send(&option.to_be_bytes())?;
+    transport.send(&len.to_be_bytes())
+}
+
+impl<T: Transport> StorageDevice for NbdClientDevice<T> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.check(offset, buf.len() as u64, false)?;
+        for (i, chunk) in buf.chunks_mut(NBD_MAX_REQUEST_LEN).enumerate() {
+            let chunk_offset = offset + (i * NBD_MAX_REQUEST_LEN) as u64;
+            self.call(
+                Command::Read,
+                0,
+                chunk_offset,
+                chunk.len() as u32,
+                &[],
+                chunk,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.write_with_flags(offset, buf, 0)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.size)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.check(offset, len, true)?;
+        if self.flags & NBD_FLAG_SEND_WRITE_ZEROES == 0 {
+            return self.fill(offset, len, 0);
+        }
+        self.call_range(Command::WriteZeroes, offset, len)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.check(offset, len, true)?;
+        if self.flags & NBD_FLAG_SEND_TRIM == 0 {
+            return Ok(());
+        }
+        self.call_range(Command::Trim, offset, len)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        if self.flags & NBD_FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+        self.call(Command::Flush, 0, 0, 0, &[], &mut [])
+    }
+
+    /// Send the write with forced unit access if the server supports it, and follow it with a
+    /// flush otherwise.
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        if self.flags & NBD_FLAG_SEND_FUA == 0 {
+            self.write(offset, buf)?;
+            return self.flush();
+        }
+        self.write_with_flags(offset, buf, NBD_CMD_FLAG_FUA)
+    }
+
+    /// Report the capabilities advertised by the transmission flags of the export.
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        let mut capabilities = Capabilities::empty();
+        for (flag, capability) in [
+            (NBD_FLAG_SEND_FLUSH, Capabilities::FLUSH),
+            (NBD_FLAG_SEND_FUA, Capabilities::FUA),
+            (NBD_FLAG_SEND_TRIM, Capabilities::TRIM),
+            (NBD_FLAG_SEND_WRITE_ZEROES, Capabilities::WRITE_ZEROES),
+            (NBD_FLAG_ROTATIONAL, Capabilities::ROTATIONAL),
+            (NBD_FLAG_READ_ONLY, Capabilities::READ_ONLY),
+        ]
+        .iter()
+        {
+            capabilities.set(*capability, self.flags & flag != 0);
+        }
+        Ok(capabilities)
+    }
+}
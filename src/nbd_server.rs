@@ -0,0 +1,313 @@
+use crate::nbd::{
+    encode_error, recv_u32, recv_u64, skip, Command, NBD_CMD_FLAG_FUA, NBD_FLAG_FIXED_NEWSTYLE,
+    NBD_FLAG_HAS_FLAGS, NBD_FLAG_NO_ZEROES, NBD_FLAG_READ_ONLY, NBD_FLAG_ROTATIONAL,
+    NBD_FLAG_SEND_FLUSH, NBD_FLAG_SEND_FUA, NBD_FLAG_SEND_TRIM, NBD_FLAG_SEND_WRITE_ZEROES,
+    NBD_IHAVEOPT, NBD_INFO_EXPORT, NBD_MAGIC, NBD_MAX_REQUEST_LEN, NBD_OPT_ABORT,
+    NBD_OPT_EXPORT_NAME, NBD_OPT_GO, NBD_OPT_INFO, NBD_REPLY_MAGIC, NBD_REP_ACK,
+    NBD_REP_ERR_INVALID, NBD_REP_ERR_UNKNOWN, NBD_REP_ERR_UNSUP, NBD_REP_INFO, NBD_REQUEST_LEN,
+    NBD_REQUEST_MAGIC, NBD_SIMPLE_REPLY_LEN, NBD_SIMPLE_REPLY_MAGIC,
+};
+use crate::rpc::Transport;
+use crate::{Capabilities, StorageDevice, StorageDeviceError, StorageDeviceResult};
+use alloc::vec::Vec;
+
+/// The size of the longest export name accepted, in bytes.
+const MAX_EXPORT_NAME_LEN: usize = 4096;
+
+/// Serve a local storage device to a Network Block Device client, such as the ``nbd-client``
+/// of Linux, ``qemu-img`` or an [NbdClientDevice](crate::NbdClientDevice), at the other end of
+/// a [Transport].
+///
+/// The server performs the fixed newstyle handshake, offering a single export, then serves read,
+/// write, flush, trim and write zeroes requests until the client disconnects. Requests of more than
+/// [NBD_MAX_REQUEST_LEN] bytes are refused. Writes asking for forced unit access use
+/// [StorageDevice::write_fua], and other requests asking for it are followed by a flush. Devices
+/// reporting [Capabilities::READ_ONLY] are exported read-only, and [Capabilities::ROTATIONAL] is
+/// passed on to the client.
+///
+/// Each server handles a single connection: accept connections in a loop, giving the device of
+/// the previous server, returned by [NbdServer::into_inner], to the next one.
+#[derive(Debug)]
+pub struct NbdServer<S: StorageDevice, T: Transport> {
+    /// The device being served.
+    storage_device: S,
+
+    /// The transport connected to the client.
+    transport: T,
+
+    /// The name of the export, the empty name being accepted as well.
+    export_name: &'static str,
+
+    /// Whether the export is read-only.
+    read_only: bool,
+
+    /// Whether the device is on rotational media, as reported by its capabilities.
+    rotational: bool,
+
+    /// The buffer holding the data of the request being served.
+    buffer: Vec<u8>,
+}
+
+impl<S: StorageDevice, T: Transport> NbdServer<S, T> {
+    /// Serve ``storage_device`` over ``transport``.
+    pub fn new(storage_device: S, transport: T) -> Self {
+        NbdServer {
+            storage_device,
+            transport,
+            export_name: "",
+            read_only: false,
+            rotational: false,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Name the export ``export_name``, instead of the empty default name.
+    pub fn with_export_name(mut self, export_name: &'static str) -> Self {
+        self.export_name = export_name;
+        self
+    }
+
+    /// Refuse writes, trims and write zeroes requests if ``read_only`` is set.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Return a reference to the device being served.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the device being served.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Consume the server, returning the device and the transport.
+    pub fn into_inner(self) -> (S, T) {
+        (self.storage_device, self.transport)
+    }
+
+    /// Perform the handshake, then serve requests until the client disconnects.
+    ///
+    /// Errors of the device are sent to the client: an error is only returned if the transport
+    /// failed, or the client broke the protocol.
+    pub fn serve(&mut self) -> StorageDeviceResult<()> {
+        if !self.negotiate()? {
+            return Ok(());
+        }
+        while self.serve_one()? {}
+        Ok(())
+    }
+
+    /// Return the transmission flags of the export.
+    fn transmission_flags(&self) -> u16 {
+        let mut flags = NBD_FLAG_HAS_FLAGS
+            | NBD_FLAG_SEND_FLUSH
+            | NBD_FLAG_SEND_FUA
+            | NBD_FLAG_SEND_TRIM
+            | NBD_FLAG_SEND_WRITE_ZEROES;
+        if self.read_only {
+            flags |= NBD_FLAG_READ_ONLY;
+        }
+        if self.rotational {
+            flags |= NBD_FLAG_ROTATIONAL;
+        }
+        flags
+    }
+
+    /// Perform the handshake, returning whether the client selected the export, or aborted.
+    fn negotiate(&mut self) -> StorageDeviceResult<bool> {
+        let capabilities = self.storage_device.capabilities()?;
+        self.read_only |= capabilities.contains(Capabilities::READ_ONLY);
+        self.rotational = capabilities.contains(Capabilities::ROTATIONAL);
+
+        self.transport.send(&NBD_MAGIC.to_be_bytes())?;
+        self.transport.send(&NBD_IHAVEOPT.to_be_bytes())?;
+        let handshake_flags = NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES;
+        self.transport.send(&handshake_flags.to_be_bytes())?;
+        self.transport.flush()?;
+
+        let client_flags = recv_u32(&mut self.transport)?;
+        if client_flags & !u32::from(handshake_flags) != 0 {
+            return Err(StorageDeviceError::Unsupported);
+        }
+
+        loop {
+            if recv_u64(&mut self.transport)? != NBD_IHAVEOPT {
+                return Err(StorageDeviceError::Corrupted);
+            }
+            let option = recv_u32(&mut self.transport)?;
+            let len = recv_u32(&mut self.transport)? as usize;
+
+            match option {
+                NBD_OPT_EXPORT_NAME => {
+                    // This option has no error reply: the connection is closed instead.
+                    if !self.recv_export_name(len)? {
+                        return Err(StorageDeviceError::Unsupported);
+                    }
+                    let size = self.storage_device.len()?;
+                    self.transport.send(&size.to_be_bytes())?;
+                    self.transport
+                        .send(&self.transmission_flags().to_be_bytes())?;
+                    if client_flags & u32::from(NBD_FLAG_NO_ZEROES) == 0 {
+                        self.transport.send(&[0; 124])?;
+                    }
+                    self.transport.flush()?;
+                    return Ok(true);
+                }
+                NBD_OPT_INFO | NBD_OPT_GO => {
+                    // The name, followed by the information requests, always sent anyway.
+                    if len < 6 {
+                        skip(&mut self.transport, len)?;
+                        self.reply(option, NBD_REP_ERR_INVALID, &[])?;
+                        continue;
+                    }
+                    let name_len = recv_u32(&mut self.transport)? as usize;
+                    if name_len > len - 6 {
+                        skip(&mut self.transport, len - 4)?;
+                        self.reply(option, NBD_REP_ERR_INVALID, &[])?;
+                        continue;
+                    }
+                    let known = self.recv_export_name(name_len)?;
+                    skip(&mut self.transport, len - 4 - name_len)?;
+                    if !known {
+                        self.reply(option, NBD_REP_ERR_UNKNOWN, &[])?;
+                        continue;
+                    }
+
+                    let mut info = [0u8; 12];
+                    info[..2].copy_from_slice(&NBD_INFO_EXPORT.to_be_bytes());
+                    info[2..10].copy_from_slice(&self.storage_device.len()?.to_be_bytes());
+                    info[10..].copy_from_slice(&self.transmission_flags().to_be_bytes());
+                    self.reply(option, NBD_REP_INFO, &info)?;
+                    self.reply(option, NBD_REP_ACK, &[])?;
+                    if option == NBD_OPT_GO {
+                        return Ok(true);
+                    }
+                }
+                NBD_OPT_ABORT => {
+                    skip(&mut self.transport, len)?;
+                    // The client may close the connection without waiting for the reply.
+                    let _ = self.reply(option, NBD_REP_ACK, &[]);
+                    return Ok(false);
+                }
+                _ => {
+                    skip(&mut self.transport, len)?;
+                    self.reply(option, NBD_REP_ERR_UNSUP, &[])?;
+                }
+            }
+        }
+    }
+
+    /// Receive an export name of ``len`` bytes, returning whether it names the export.
+    fn recv_export_name(&mut self, len: usize) -> StorageDeviceResult<bool> {
+        if len > MAX_EXPORT_NAME_LEN {
+            skip(&mut self.transport, len)?;
+            return Ok(false);
+        }
+        self.buffer.resize(len, 0);
+        self.transport.recv(&mut self.buffer)?;
+        Ok(self.buffer.is_empty() || self.buffer[..] == *self.export_name.as_bytes())
+    }
+
+    /// Send the reply ``reply`` to ``option``, carrying ``data``.
+    fn reply(&mut self, option: u32, reply: u32, data: &[u8]) -> StorageDeviceResult<()> {
+        self.transport.send(&NBD_REPLY_MAGIC.to_be_bytes())?;
+        self.transport.send(&option.to_be_bytes())?;
+        self.transport.send(&reply.to_be_bytes())?;
+        self.transport.send(&(data.len() as u32).to_be_bytes())?;
+        self.transport.send(data)?;
+        self.transport.flush()
+    }
+
+    /// Receive a single request, perform it and send its reply, returning whether the client is
+    /// still connected.
+    fn serve_one(&mut self) -> StorageDeviceResult<bool> {
+        let mut request = [0u8; NBD_REQUEST_LEN];
+        self.transport.recv(&mut request)?;
+        let field = |range: core::ops::Range<usize>| {
+            request[range]
+                .iter()
+                .fold(0u64, |value, &byte| (value << 8) | u64::from(byte))
+        };
+        if field(0..4) != u64::from(NBD_REQUEST_MAGIC) {
+            return Err(StorageDeviceError::Corrupted);
+        }
+        let flags = field(4..6) as u16;
+        let command = field(6..8) as u16;
+        let cookie = field(8..16);
+        let offset = field(16..24);
+        let len = field(24..28) as usize;
+
+        let too_long = len > NBD_MAX_REQUEST_LEN;
+        let result = match Command::from_u16(command) {
+            Some(Command::Disconnect) => return Ok(false),
+            Some(Command::Read) if !too_long => {
+                self.buffer.resize(len, 0);
+                let result = self.storage_device.read(offset, &mut self.buffer);
+                if result.is_ok() {
+                    self.send_reply(cookie, 0)?;
+                    self.transport.send(&self.buffer)?;
+                    self.transport.flush()?;
+                    return Ok(true);
+                }
+                result
+            }
+            Some(Command::Write) if !too_long => {
+                self.buffer.resize(len, 0);
+                self.transport.recv(&mut self.buffer)?;
+                if self.read_only {
+                    Err(StorageDeviceError::WriteError)
+                } else if flags & NBD_CMD_FLAG_FUA != 0 {
+                    self.storage_device.write_fua(offset, &self.buffer)
+                } else {
+                    self.storage_device.write(offset, &self.buffer)
+                }
+            }
+            Some(Command::Write) => {
+                skip(&mut self.transport, len)?;
+                Err(StorageDeviceError::OutOfBounds)
+            }
+            Some(Command::Flush) => self.storage_device.flush(),
+            Some(Command::Trim) | Some(Command::WriteZeroes) if self.read_only => {
+                Err(StorageDeviceError::WriteError)
+            }
+            Some(Command::Trim) => self
+                .storage_device
+                .discard(offset, len as u64)
+                .and_then(|()| self.flush_if_fua(flags)),
+            Some(Command::WriteZeroes) => self
+                .storage_device
+                .write_zeroes(offset, len as u64)
+                .and_then(|()| self.flush_if_fua(flags)),
+            Some(Command::Read) | None => Err(StorageDeviceError::OutOfBounds),
+        };
+
+        // Writes refused on read-only exports get EPERM, as the protocol requires.
+        let error = match result {
+            Err(StorageDeviceError::WriteError) if self.read_only => 1,
+            ref result => encode_error(result),
+        };
+        self.send_reply(cookie, error)?;
+        self.transport.flush()?;
+        Ok(true)
+    }
+
+    /// Flush the device if ``flags`` ask for forced unit access.
+    fn flush_if_fua(&mut self, flags: u16) -> StorageDeviceResult<()> {
+        if flags & NBD_CMD_FLAG_FUA != 0 {
+            self.storage_device.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Send the simple reply to the request of ``cookie`` with the errno ``error``.
+    fn send_reply(&mut self, cookie: u64, error: u32) -> StorageDeviceResult<()> {
+        let mut reply = [0u8; NBD_SIMPLE_REPLY_LEN];
+        reply[..4].copy_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+        reply[4..8].copy_from_slice(&error.to_be_bytes());
+        reply[8..].copy_from_slice(&cookie.to_be_bytes());
+        self.transport.send(&reply)
+    }
+}
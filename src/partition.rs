@@ -0,0 +1,1158 @@
+use crate::crc::crc32;
+use crate::{Block, StorageDevice, StorageDeviceError, StorageDeviceResult};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::ops::Range;
+
+/// The size of the boot sector holding the MBR, in bytes.
+const MBR_LEN: usize = 512;
+
+/// The offset of the disk signature in the MBR.
+const MBR_SIGNATURE_OFFSET: usize = 440;
+
+/// The offset of the partition entries in the MBR.
+const MBR_ENTRIES_OFFSET: usize = 446;
+
+/// The size of a partition entry of the MBR, in bytes.
+const MBR_ENTRY_LEN: usize = 16;
+
+/// The number of partition entries of the MBR.
+const MBR_ENTRIES: usize = 4;
+
+/// The size of the GPT header, in bytes.
+const GPT_HEADER_LEN: usize = 92;
+
+/// The size of a GPT partition entry, in bytes.
+const GPT_ENTRY_LEN: usize = 128;
+
+/// The minimum number of GPT partition entries, filling the 16 KiB the specification reserves
+/// for them.
+const GPT_MIN_ENTRIES: u32 = 128;
+
+/// The size of the largest array of GPT partition entries read, in bytes, far more than the
+/// 16 KiB tools write, so a corrupted header can't make reading it allocate gigabytes.
+const GPT_MAX_ENTRIES_LEN: u64 = 1 << 20;
+
+/// The maximum length of the name of a GPT partition, in UTF-16 code units.
+const GPT_NAME_LEN: usize = 36;
+
+/// The signature of a GPT header.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// The revision of the GPT headers written, 1.0.
+const GPT_REVISION: u32 = 0x0001_0000;
+
+/// A GUID, stored in the mixed-endian layout of GPT.
+#[derive(Copy, Clone, Hash, PartialEq, Eq, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// The nil GUID, marking unused GPT partition entries.
+    pub const NIL: Guid = Guid([0; 16]);
+
+    /// The partition type of an EFI system partition.
+    pub const EFI_SYSTEM: Guid = Guid::from_fields(
+        0xC12A_7328,
+        0xF81F,
+        0x11D2,
+        [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+    );
+
+    /// The partition type of a Microsoft basic data partition, such as a FAT or NTFS one.
+    pub const MICROSOFT_BASIC_DATA: Guid = Guid::from_fields(
+        0xEBD0_A0A2,
+        0xB9E5,
+        0x4433,
+        [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
+    );
+
+    /// The partition type of a Linux filesystem.
+    pub const LINUX_FILESYSTEM: Guid = Guid::from_fields(
+        0x0FC6_3DAF,
+        0x8483,
+        0x4772,
+        [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+    );
+
+    /// The partition type of a Linux swap partition.
+    pub const LINUX_SWAP: Guid = Guid::from_fields(
+        0x0657_FD6D,
+        0xA4AB,
+        0x43C4,
+        [0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F],
+    );
+
+    /// Create a GUID from the fields of its textual form, e.g.
+    /// ``C12A7328-F81F-11D2-BA4B-00A0C93EC93B`` from ``0xC12A7328``, ``0xF81F``, ``0x11D2`` and
+    /// ``[0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]``.
+    pub const fn from_fields(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Guid {
+        let data1 = data1.to_le_bytes();
+        let data2 = data2.to_le_bytes();
+        let data3 = data3.to_le_bytes();
+        Guid([
+            data1[0], data1[1], data1[2], data1[3], data2[0], data2[1], data3[0], data3[1],
+            data4[0], data4[1], data4[2], data4[3], data4[4], data4[5], data4[6], data4[7],
+        ])
+    }
+
+    /// Check whether this is the nil GUID.
+    pub fn is_nil(&self) -> bool {
+        *self == Guid::NIL
+    }
+}
+
+impl core::fmt::Display for Guid {
+    /// Format the GUID in its textual form.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let bytes = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+            bytes[3], bytes[2], bytes[1], bytes[0], bytes[5], bytes[4], bytes[7], bytes[6]
+        )?;
+        for (index, byte) in bytes[8..].iter().enumerate() {
+            if index == 2 {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Guid({})", self)
+    }
+}
+
+/// A primary partition of an MBR.
+///
+/// Its entry is stored in little endian as follow:
+///
+/// | Offset | Size | Field                                |
+/// |--------|------|--------------------------------------|
+/// | 0      | 1    | status, 0x80 if bootable             |
+/// | 1      | 3    | CHS address of the first sector      |
+/// | 4      | 1    | partition_type                       |
+/// | 5      | 3    | CHS address of the last sector       |
+/// | 8      | 4    | start_lba                            |
+/// | 12     | 4    | sectors                              |
+///
+/// The CHS addresses are derived from the LBAs, with 255 heads and 63 sectors per track.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MbrPartition {
+    /// Whether the partition is marked as active, for legacy boot loaders.
+    pub bootable: bool,
+
+    /// The type of the partition, such as [MbrPartition::LINUX].
+    pub partition_type: u8,
+
+    /// The first sector of the partition.
+    pub start_lba: u32,
+
+    /// The size of the partition, in sectors.
+    pub sectors: u32,
+}
+
+impl MbrPartition {
+    /// The partition type of a FAT32 partition addressed by LBA.
+    pub const FAT32_LBA: u8 = 0x0C;
+
+    /// The partition type of an NTFS or exFAT partition.
+    pub const NTFS: u8 = 0x07;
+
+    /// The partition type of a Linux swap partition.
+    pub const LINUX_SWAP: u8 = 0x82;
+
+    /// The partition type of a Linux filesystem.
+    pub const LINUX: u8 = 0x83;
+
+    /// The partition type of the protective partition covering a GPT disk.
+    pub const GPT_PROTECTIVE: u8 = 0xEE;
+
+    /// The partition type of an EFI system partition.
+    pub const EFI_SYSTEM: u8 = 0xEF;
+
+    /// Create a partition of ``partition_type`` covering ``sectors`` sectors from ``start_lba``.
+    pub fn new(partition_type: u8, start_lba: u32, sectors: u32) -> Self {
+        MbrPartition {
+            bootable: false,
+            partition_type,
+            start_lba,
+            sectors,
+        }
+    }
+
+    /// Return the sector following the partition.
+    pub fn end_lba(&self) -> u64 {
+        u64::from(self.start_lba) + u64::from(self.sectors)
+    }
+
+    /// Return the sectors covered by the partition.
+    fn range(&self) -> Range<u64> {
+        u64::from(self.start_lba)..self.end_lba()
+    }
+
+    /// Deserialize the entry of a partition, ignoring its CHS addresses.
+    fn from_bytes(bytes: &[u8]) -> Self {
+        MbrPartition {
+            bootable: bytes[0] & 0x80 != 0,
+            partition_type: bytes[4],
+            start_lba: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            sectors: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+        }
+    }
+
+    /// Serialize the entry of the partition.
+    ///
+    /// The CHS addresses of an empty partition, which has no last sector, are left zeroed, as in
+    /// unused entries.
+    fn to_bytes(self) -> [u8; MBR_ENTRY_LEN] {
+        let mut bytes = [0u8; MBR_ENTRY_LEN];
+        bytes[0] = if self.bootable { 0x80 } else { 0 };
+        bytes[4] = self.partition_type;
+        if self.sectors != 0 {
+            bytes[1..4].copy_from_slice(&chs(u64::from(self.start_lba)));
+            bytes[5..8].copy_from_slice(&chs(self.end_lba() - 1));
+        }
+        bytes[8..12].copy_from_slice(&self.start_lba.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.sectors.to_le_bytes());
+        bytes
+    }
+}
+
+/// Encode ``lba`` as a CHS address, with 255 heads and 63 sectors per track, saturating at the
+/// last address for sectors past the first 8 GiB.
+fn chs(lba: u64) -> [u8; 3] {
+    let cylinder = lba / (255 * 63);
+    if cylinder > 1023 {
+        return [0xFE, 0xFF, 0xFF];
+    }
+    let head = (lba / 63) % 255;
+    let sector = lba % 63 + 1;
+    [
+        head as u8,
+        sector as u8 | ((cylinder >> 2) as u8 & 0xC0),
+        cylinder as u8,
+    ]
+}
+
+/// Check that the partitions covering the sector ranges of ``ranges`` are inside ``usable`` and
+/// don't overlap.
+///
+/// Return [StorageDeviceError::OutOfBounds] if a partition is outside of the usable sectors, and
+/// [StorageDeviceError::InvalidRequest] if a partition is empty or overlaps another one.
+fn check_layout(ranges: &mut [Range<u64>], usable: Range<u64>) -> StorageDeviceResult<()> {
+    ranges.sort_by_key(|range| range.start);
+    for range in ranges.iter() {
+        if range.start >= range.end {
+            return Err(StorageDeviceError::InvalidRequest);
+        }
+        if range.start < usable.start || range.end > usable.end {
+            return Err(StorageDeviceError::OutOfBounds);
+        }
+    }
+    if ranges.windows(2).any(|pair| pair[0].end > pair[1].start) {
+        return Err(StorageDeviceError::InvalidRequest);
+    }
+    Ok(())
+}
+
+/// Check that a partition covering ``range`` isn't empty, starts on a multiple of ``alignment``
+/// sectors, and doesn't overlap any of the ``others``.
+///
+/// Return [StorageDeviceError::InvalidRequest] otherwise.
+fn check_partition(
+    range: Range<u64>,
+    alignment: u64,
+    mut others: impl Iterator<Item = Range<u64>>,
+) -> StorageDeviceResult<()> {
+    if range.start >= range.end || !range.start.is_multiple_of(alignment) {
+        return Err(StorageDeviceError::InvalidRequest);
+    }
+    if others.any(|other| other.start < range.end && range.start < other.end) {
+        return Err(StorageDeviceError::InvalidRequest);
+    }
+    Ok(())
+}
+
+/// Return the number of whole sectors of ``sector_size`` bytes of ``storage_device``.
+fn sector_count<S: StorageDevice + ?Sized>(
+    storage_device: &mut S,
+    sector_size: usize,
+) -> StorageDeviceResult<u64> {
+    Ok(storage_device.len()? / sector_size as u64)
+}
+
+/// A builder creating an MBR partition table, with up to four primary partitions.
+///
+/// The table of a device can be read with [MbrBuilder::read], edited, and written back. The boot
+/// code already present in the first sector is kept, so the table of a bootable device can be
+/// rewritten.
+#[derive(Debug, Clone)]
+pub struct MbrBuilder {
+    /// The disk signature identifying the device.
+    disk_signature: u32,
+
+    /// The size of a sector, in bytes.
+    sector_size: usize,
+
+    /// The number of sectors the start of the partitions added by editing must be a multiple of.
+    alignment: u64,
+
+    /// The partitions, in the order of their entries.
+    partitions: Vec<MbrPartition>,
+}
+
+impl Default for MbrBuilder {
+    fn default() -> Self {
+        MbrBuilder::new()
+    }
+}
+
+impl MbrBuilder {
+    /// Create a builder of an empty table, with a zero disk signature and sectors of
+    /// [Block::LEN] bytes.
+    pub fn new() -> Self {
+        MbrBuilder {
+            disk_signature: 0,
+            sector_size: Block::LEN,
+            alignment: 1,
+            partitions: Vec::new(),
+        }
+    }
+
+    /// Read the table of the first sector of ``storage_device``, addressed in sectors of
+    /// [Block::LEN] bytes, skipping the unused entries.
+    ///
+    /// Return [StorageDeviceError::Corrupted] if the sector doesn't end with the MBR signature.
+    pub fn read<S: StorageDevice + ?Sized>(storage_device: &mut S) -> StorageDeviceResult<Self> {
+        let mut bytes = [0u8; MBR_LEN];
+        storage_device.read(0, &mut bytes)?;
+        if bytes[MBR_LEN - 2..] != [0x55, 0xAA] {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        let mut signature = [0u8; 4];
+        signature.copy_from_slice(&bytes[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 4]);
+        let partitions = bytes[MBR_ENTRIES_OFFSET..MBR_LEN - 2]
+            .chunks_exact(MBR_ENTRY_LEN)
+            .map(MbrPartition::from_bytes)
+            .filter(|partition| partition.partition_type != 0)
+            .collect();
+        Ok(MbrBuilder {
+            disk_signature: u32::from_le_bytes(signature),
+            partitions,
+            ..MbrBuilder::new()
+        })
+    }
+
+    /// Return the disk signature identifying the device.
+    pub fn disk_signature(&self) -> u32 {
+        self.disk_signature
+    }
+
+    /// Identify the device with ``disk_signature``.
+    pub fn with_disk_signature(mut self, disk_signature: u32) -> Self {
+        self.disk_signature = disk_signature;
+        self
+    }
+
+    /// Address the device in sectors of ``sector_size`` bytes, such as 4096 on 4Kn drives.
+    pub fn with_sector_size(mut self, sector_size: usize) -> Self {
+        self.sector_size = sector_size;
+        self
+    }
+
+    /// Require the partitions added, moved or resized afterwards to start on a multiple of
+    /// ``sectors`` sectors, such as 2048 for 1 MiB with sectors of 512 bytes.
+    pub fn with_alignment(mut self, sectors: u64) -> Self {
+        self.alignment = core::cmp::max(sectors, 1);
+        self
+    }
+
+    /// Add ``partition`` to the next entry of the table.
+    ///
+    /// The partition isn't checked until the table is written.
+    pub fn with_partition(mut self, partition: MbrPartition) -> Self {
+        self.partitions.push(partition);
+        self
+    }
+
+    /// Return the partitions, in the order of their entries.
+    pub fn partitions(&self) -> &[MbrPartition] {
+        &self.partitions
+    }
+
+    /// Add ``partition`` to the next entry of the table, returning its index.
+    ///
+    /// Return [StorageDeviceError::InvalidRequest] if the table is full, or if the partition is
+    /// empty, misaligned or overlaps another one.
+    pub fn add_partition(&mut self, partition: MbrPartition) -> StorageDeviceResult<usize> {
+        if self.partitions.len() >= MBR_ENTRIES {
+            return Err(StorageDeviceError::InvalidRequest);
+        }
+        check_partition(partition.range(), self.alignment, self.other_ranges(None))?;
+        self.partitions.push(partition);
+        Ok(self.partitions.len() - 1)
+    }
+
+    /// Remove the partition at ``index``, moving the following ones to the previous entries.
+    pub fn remove_partition(&mut self, index: usize) -> Option<MbrPartition> {
+        if index < self.partitions.len() {
+            Some(self.partitions.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Resize the partition at ``index`` to ``sectors`` sectors, keeping its start.
+    ///
+    /// Return [StorageDeviceError::InvalidRequest] if there is no such partition, or if it would
+    /// be empty or overlap another one, and [StorageDeviceError::OutOfBounds] if it would end
+    /// past the sectors an MBR can address.
+    pub fn resize_partition(&mut self, index: usize, sectors: u64) -> StorageDeviceResult<()> {
+        let partition = *self
+            .partitions
+            .get(index)
+            .ok_or(StorageDeviceError::InvalidRequest)?;
+        let sectors = u32::try_from(sectors).map_err(|_| StorageDeviceError::OutOfBounds)?;
+        self.replace_partition(
+            index,
+            MbrPartition {
+                sectors,
+                ..partition
+            },
+        )
+    }
+
+    /// Move the partition at ``index`` to start at ``start_lba``, keeping its size.
+    ///
+    /// Only the entry is changed: the data of the partition must be moved separately, e.g. with
+    /// [copy_within](crate::copy_within). Return [StorageDeviceError::InvalidRequest] if there is
+    /// no such partition, or if it would be misaligned or overlap another one, and
+    /// [StorageDeviceError::OutOfBounds] if it would end past the sectors an MBR can address.
+    pub fn move_partition(&mut self, index: usize, start_lba: u64) -> StorageDeviceResult<()> {
+        let partition = *self
+            .partitions
+            .get(index)
+            .ok_or(StorageDeviceError::InvalidRequest)?;
+        let start_lba = u32::try_from(start_lba).map_err(|_| StorageDeviceError::OutOfBounds)?;
+        self.replace_partition(
+            index,
+            MbrPartition {
+                start_lba,
+                ..partition
+            },
+        )
+    }
+
+    /// Replace the partition at ``index`` by ``partition``, once checked against the others.
+    fn replace_partition(
+        &mut self,
+        index: usize,
+        partition: MbrPartition,
+    ) -> StorageDeviceResult<()> {
+        if u64::from(partition.start_lba) + u64::from(partition.sectors) > u64::from(u32::MAX) {
+            return Err(StorageDeviceError::OutOfBounds);
+        }
+        check_partition(
+            partition.range(),
+            self.alignment,
+            self.other_ranges(Some(index)),
+        )?;
+        self.partitions[index] = partition;
+        Ok(())
+    }
+
+    /// Return the sectors covered by the partitions, except the one at ``except``.
+    fn other_ranges(&self, except: Option<usize>) -> impl Iterator<Item = Range<u64>> + '_ {
+        self.partitions
+            .iter()
+            .enumerate()
+            .filter(move |&(index, _)| Some(index) != except)
+            .map(|(_, partition)| partition.range())
+    }
+
+    /// Serialize the table, with ``boot_code`` as the first 440 bytes of the sector.
+    ///
+    /// The partitions aren't checked.
+    pub fn to_bytes(&self, boot_code: &[u8; MBR_SIGNATURE_OFFSET]) -> [u8; MBR_LEN] {
+        let mut bytes = [0u8; MBR_LEN];
+        bytes[..MBR_SIGNATURE_OFFSET].copy_from_slice(boot_code);
+        bytes[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 4]
+            .copy_from_slice(&self.disk_signature.to_le_bytes());
+        let entries = bytes[MBR_ENTRIES_OFFSET..MBR_LEN - 2].chunks_exact_mut(MBR_ENTRY_LEN);
+        for (entry, partition) in entries.zip(self.partitions.iter()) {
+            entry.copy_from_slice(&partition.to_bytes());
+        }
+        bytes[MBR_LEN - 2..].copy_from_slice(&[0x55, 0xAA]);
+        bytes
+    }
+
+    /// Check the partitions, then write the table to the first sector of ``storage_device``.
+    ///
+    /// Return [StorageDeviceError::InvalidRequest] if there are more than four partitions, or
+    /// if one is empty or overlaps another one, and [StorageDeviceError::OutOfBounds] if one
+    /// covers the first sector or goes past the end of the device.
+    pub fn write<S: StorageDevice + ?Sized>(
+        &self,
+        storage_device: &mut S,
+    ) -> StorageDeviceResult<()> {
+        if self.partitions.len() > MBR_ENTRIES || self.sector_size < MBR_LEN {
+            return Err(StorageDeviceError::InvalidRequest);
+        }
+        let sectors = sector_count(storage_device, self.sector_size)?;
+        let mut ranges: Vec<_> = self.partitions.iter().map(MbrPartition::range).collect();
+        check_layout(&mut ranges, 1..sectors)?;
+
+        let mut boot_code = [0u8; MBR_SIGNATURE_OFFSET];
+        storage_device.read(0, &mut boot_code)?;
+        storage_device.write(0, &self.to_bytes(&boot_code))?;
+        storage_device.flush()
+    }
+}
+
+/// A partition of a GPT.
+///
+/// Its entry is stored in little endian as follow:
+///
+/// | Offset | Size | Field                               |
+/// |--------|------|-------------------------------------|
+/// | 0      | 16   | type_guid                           |
+/// | 16     | 16   | unique_guid                         |
+/// | 32     | 8    | first_lba                           |
+/// | 40     | 8    | last_lba, inclusive                 |
+/// | 48     | 8    | attributes                          |
+/// | 56     | 72   | name, in UTF-16, padded with zeros  |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    /// The type of the partition, such as [Guid::LINUX_FILESYSTEM].
+    pub type_guid: Guid,
+
+    /// The GUID identifying the partition.
+    pub unique_guid: Guid,
+
+    /// The first sector of the partition.
+    pub first_lba: u64,
+
+    /// The last sector of the partition, inclusive.
+    pub last_lba: u64,
+
+    /// The attribute flags of the partition.
+    pub attributes: u64,
+
+    /// The name of the partition, of at most 36 UTF-16 code units.
+    pub name: String,
+}
+
+impl GptPartition {
+    /// Create an unnamed partition of ``type_guid``, identified by ``unique_guid``, covering the
+    /// sectors from ``first_lba`` to ``last_lba`` inclusive.
+    pub fn new(type_guid: Guid, unique_guid: Guid, first_lba: u64, last_lba: u64) -> Self {
+        GptPartition {
+            type_guid,
+            unique_guid,
+            first_lba,
+            last_lba,
+            attributes: 0,
+            name: String::new(),
+        }
+    }
+
+    /// Name the partition ``name``.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = String::from(name);
+        self
+    }
+
+    /// Return the number of sectors of the partition.
+    pub fn sectors(&self) -> u64 {
+        (self.last_lba + 1).saturating_sub(self.first_lba)
+    }
+
+    /// Return the sectors covered by the partition.
+    fn range(&self) -> Range<u64> {
+        self.first_lba..self.last_lba.saturating_add(1)
+    }
+
+    /// Deserialize the entry of a partition, replacing the invalid UTF-16 of its name.
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let u64_at = |offset: usize| {
+            let mut value = [0u8; 8];
+            value.copy_from_slice(&bytes[offset..offset + 8]);
+            u64::from_le_bytes(value)
+        };
+        let mut type_guid = Guid::NIL;
+        let mut unique_guid = Guid::NIL;
+        type_guid.0.copy_from_slice(&bytes[0..16]);
+        unique_guid.0.copy_from_slice(&bytes[16..32]);
+        let units = bytes[56..56 + GPT_NAME_LEN * 2]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0);
+        let name = core::char::decode_utf16(units)
+            .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+            .collect();
+
+        GptPartition {
+            type_guid,
+            unique_guid,
+            first_lba: u64_at(32),
+            last_lba: u64_at(40),
+            attributes: u64_at(48),
+            name,
+        }
+    }
+
+    /// Serialize the entry of the partition.
+    ///
+    /// Return [StorageDeviceError::InvalidRequest] if the name is too long.
+    fn to_bytes(&self) -> StorageDeviceResult<[u8; GPT_ENTRY_LEN]> {
+        let mut bytes = [0u8; GPT_ENTRY_LEN];
+        bytes[0..16].copy_from_slice(&self.type_guid.0);
+        bytes[16..32].copy_from_slice(&self.unique_guid.0);
+        bytes[32..40].copy_from_slice(&self.first_lba.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.last_lba.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+        let mut name = bytes[56..56 + GPT_NAME_LEN * 2].chunks_exact_mut(2);
+        for unit in self.name.encode_utf16() {
+            name.next()
+                .ok_or(StorageDeviceError::InvalidRequest)?
+                .copy_from_slice(&unit.to_le_bytes());
+        }
+        Ok(bytes)
+    }
+}
+
+/// A GPT header.
+///
+/// It is stored in little endian as follow, padded with zeros to the size of a sector:
+///
+/// | Offset | Size | Field                 |
+/// |--------|------|-----------------------|
+/// | 0      | 8    | signature, "EFI PART" |
+/// | 8      | 4    | revision              |
+/// | 12     | 4    | header size, 92       |
+/// | 16     | 4    | header_crc            |
+/// | 20     | 4    | reserved              |
+/// | 24     | 8    | my_lba                |
+/// | 32     | 8    | alternate_lba         |
+/// | 40     | 8    | first_usable_lba      |
+/// | 48     | 8    | last_usable_lba       |
+/// | 56     | 16   | disk_guid             |
+/// | 72     | 8    | entries_lba           |
+/// | 80     | 4    | entry_count           |
+/// | 84     | 4    | entry size, 128       |
+/// | 88     | 4    | entries_crc           |
+///
+/// The CRC-32 of the header covers its 92 bytes, with the header_crc field set to zero.
+#[derive(Debug, Copy, Clone)]
+struct GptHeader {
+    /// The sector holding this header.
+    my_lba: u64,
+
+    /// The sector holding the other copy of the header.
+    alternate_lba: u64,
+
+    /// The first sector partitions may use.
+    first_usable_lba: u64,
+
+    /// The last sector partitions may use, inclusive.
+    last_usable_lba: u64,
+
+    /// The GUID identifying the device.
+    disk_guid: Guid,
+
+    /// The first sector of the partition entries.
+    entries_lba: u64,
+
+    /// The number of partition entries.
+    entry_count: u32,
+
+    /// The size of a partition entry, in bytes.
+    entry_size: u32,
+
+    /// The CRC-32 of the partition entries.
+    entries_crc: u32,
+}
+
+impl GptHeader {
+    /// Serialize the header, computing its CRC-32.
+    fn to_bytes(self) -> [u8; GPT_HEADER_LEN] {
+        let mut bytes = [0u8; GPT_HEADER_LEN];
+        bytes[0..8].copy_from_slice(GPT_SIGNATURE);
+        bytes[8..12].copy_from_slice(&GPT_REVISION.to_le_bytes());
+        bytes[12..16].copy_from_slice(&(GPT_HEADER_LEN as u32).to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.my_lba.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.alternate_lba.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.first_usable_lba.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.last_usable_lba.to_le_bytes());
+        bytes[56..72].copy_from_slice(&self.disk_guid.0);
+        bytes[72..80].copy_from_slice(&self.entries_lba.to_le_bytes());
+        bytes[80..84].copy_from_slice(&self.entry_count.to_le_bytes());
+        bytes[84..88].copy_from_slice(&self.entry_size.to_le_bytes());
+        bytes[88..92].copy_from_slice(&self.entries_crc.to_le_bytes());
+        let crc = crc32(&bytes);
+        bytes[16..20].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialize the header at the start of ``sector``, returning None if its signature, size
+    /// or CRC-32 is invalid, or if its entries are smaller than 128 bytes.
+    fn from_bytes(sector: &[u8]) -> Option<Self> {
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                sector[offset],
+                sector[offset + 1],
+                sector[offset + 2],
+                sector[offset + 3],
+            ])
+        };
+        let u64_at =
+            |offset: usize| u64::from(u32_at(offset)) | u64::from(u32_at(offset + 4)) << 32;
+
+        let header_len = u32_at(12) as usize;
+        if sector[0..8] != *GPT_SIGNATURE
+            || header_len < GPT_HEADER_LEN
+            || header_len > sector.len()
+        {
+            return None;
+        }
+        let mut header = sector[..header_len].to_vec();
+        header[16..20].fill(0);
+        if crc32(&header) != u32_at(16) || (u32_at(84) as usize) < GPT_ENTRY_LEN {
+            return None;
+        }
+
+        let mut disk_guid = Guid::NIL;
+        disk_guid.0.copy_from_slice(&sector[56..72]);
+        Some(GptHeader {
+            my_lba: u64_at(24),
+            alternate_lba: u64_at(32),
+            first_usable_lba: u64_at(40),
+            last_usable_lba: u64_at(48),
+            disk_guid,
+            entries_lba: u64_at(72),
+            entry_count: u32_at(80),
+            entry_size: u32_at(84),
+            entries_crc: u32_at(88),
+        })
+    }
+}
+
+/// A builder creating a GPT, with its protective MBR and backup copy.
+///
+/// The primary header is written to the second sector, followed by the partition entries, and
+/// the backup entries and header to the last sectors of the device, as the specification
+/// requires. The sectors between them are usable by the partitions, which are placed by the
+/// caller, e.g. from [GptBuilder::first_usable_lba] aligned to 1 MiB.
+///
+/// GUIDs aren't generated, as this crate has no source of randomness: the caller provides them.
+///
+/// The table of a device can be read with [GptBuilder::read], edited, and written back.
+/// Entries are kept in order, so removing a partition renumbers the following ones.
+#[derive(Debug, Clone)]
+pub struct GptBuilder {
+    /// The GUID identifying the device.
+    disk_guid: Guid,
+
+    /// The size of a sector, in bytes.
+    sector_size: usize,
+
+    /// The number of partition entries.
+    entry_count: u32,
+
+    /// The number of sectors the start of the partitions added by editing must be a multiple of.
+    alignment: u64,
+
+    /// The partitions, in the order of their entries.
+    partitions: Vec<GptPartition>,
+}
+
+impl GptBuilder {
+    /// Create a builder of an empty table for the device identified by ``disk_guid``, with 128
+    /// entries and sectors of [Block::LEN] bytes.
+    pub fn new(disk_guid: Guid) -> Self {
+        GptBuilder {
+            disk_guid,
+            sector_size: Block::LEN,
+            entry_count: GPT_MIN_ENTRIES,
+            alignment: 1,
+            partitions: Vec::new(),
+        }
+    }
+
+    /// Read the table of ``storage_device``, addressed in sectors of ``sector_size`` bytes,
+    /// skipping the unused entries.
+    ///
+    /// The primary copy is used, unless its header or entries are corrupted, in which case the
+    /// backup copy is. Return [StorageDeviceError::Corrupted] if both are.
+    pub fn read<S: StorageDevice + ?Sized>(
+        storage_device: &mut S,
+        sector_size: usize,
+    ) -> StorageDeviceResult<Self> {
+        if sector_size < MBR_LEN {
+            return Err(StorageDeviceError::InvalidRequest);
+        }
+        let sectors = sector_count(storage_device, sector_size)?;
+        if sectors < 3 {
+            return Err(StorageDeviceError::Corrupted);
+        }
+        for lba in [1, sectors - 1] {
+            if let Some(builder) = GptBuilder::read_copy(storage_device, sector_size, sectors, lba)?
+            {
+                return Ok(builder);
+            }
+        }
+        Err(StorageDeviceError::Corrupted)
+    }
+
+    /// Read the copy of the table whose header is at ``lba`` of a device of ``sectors`` sectors,
+    /// returning None if it is corrupted.
+    ///
+    /// The entries are considered corrupted if they don't fit in the device, or are bigger than
+    /// [GPT_MAX_ENTRIES_LEN], before anything is allocated for them.
+    fn read_copy<S: StorageDevice + ?Sized>(
+        storage_device: &mut S,
+        sector_size: usize,
+        sectors: u64,
+        lba: u64,
+    ) -> StorageDeviceResult<Option<Self>> {
+        let mut sector = alloc::vec![0u8; sector_size];
+        storage_device.read(lba * sector_size as u64, &mut sector)?;
+        let header = match GptHeader::from_bytes(&sector) {
+            Some(header) if header.my_lba == lba => header,
+            _ => return Ok(None),
+        };
+
+        let entries_len = u64::from(header.entry_count) * u64::from(header.entry_size);
+        let entries_end = header
+            .entries_lba
+            .checked_add(entries_len.div_ceil(sector_size as u64));
+        if entries_len > GPT_MAX_ENTRIES_LEN || entries_end.is_none_or(|end| end > sectors) {
+            return Ok(None);
+        }
+        let mut entries = alloc::vec![0u8; entries_len as usize];
+        storage_device.read(header.entries_lba * sector_size as u64, &mut entries)?;
+        if crc32(&entries) != header.entries_crc {
+            return Ok(None);
+        }
+
+        let partitions = entries
+            .chunks_exact(header.entry_size as usize)
+            .map(GptPartition::from_bytes)
+            .filter(|partition| !partition.type_guid.is_nil())
+            .collect();
+        Ok(Some(GptBuilder {
+            disk_guid: header.disk_guid,
+            sector_size,
+            entry_count: core::cmp::max(header.entry_count, GPT_MIN_ENTRIES),
+            alignment: 1,
+            partitions,
+        }))
+    }
+
+    /// Return the GUID identifying the device.
+    pub fn disk_guid(&self) -> Guid {
+        self.disk_guid
+    }
+
+    /// Address the device in sectors of ``sector_size`` bytes, such as 4096 on 4Kn drives.
+    pub fn with_sector_size(mut self, sector_size: usize) -> Self {
+        self.sector_size = sector_size;
+        self
+    }
+
+    /// Reserve ``entry_count`` partition entries, from 128 to 8192, instead of 128.
+    pub fn with_entry_count(mut self, entry_count: u32) -> Self {
+        let max_entries = (GPT_MAX_ENTRIES_LEN / GPT_ENTRY_LEN as u64) as u32;
+        self.entry_count = entry_count.clamp(GPT_MIN_ENTRIES, max_entries);
+        self
+    }
+
+    /// Require the partitions added, moved or resized afterwards to start on a multiple of
+    /// ``sectors`` sectors, such as 2048 for 1 MiB with sectors of 512 bytes.
+    pub fn with_alignment(mut self, sectors: u64) -> Self {
+        self.alignment = core::cmp::max(sectors, 1);
+        self
+    }
+
+    /// Add ``partition`` to the next entry of the table.
+    ///
+    /// The partition isn't checked until the table is written.
+    pub fn with_partition(mut self, partition: GptPartition) -> Self {
+        self.partitions.push(partition);
+        self
+    }
+
+    /// Return the partitions, in the order of their entries.
+    pub fn partitions(&self) -> &[GptPartition] {
+        &self.partitions
+    }
+
+    /// Add ``partition`` to the next entry of the table, returning its index.
+    ///
+    /// Return [StorageDeviceError::InvalidRequest] if every entry is used, or if the partition
+    /// is empty, misaligned or overlaps another one, and [StorageDeviceError::OutOfBounds] if it
+    /// starts before [GptBuilder::first_usable_lba]. Its end is checked against the size of the
+    /// device when the table is written.
+    pub fn add_partition(&mut self, partition: GptPartition) -> StorageDeviceResult<usize> {
+        if self.partitions.len() >= self.entry_count as usize {
+            return Err(StorageDeviceError::InvalidRequest);
+        }
+        self.check_partition(&partition, None)?;
+        self.partitions.push(partition);
+        Ok(self.partitions.len() - 1)
+    }
+
+    /// Remove the partition at ``index``, moving the following ones to the previous entries.
+    pub fn remove_partition(&mut self, index: usize) -> Option<GptPartition> {
+        if index < self.partitions.len() {
+            Some(self.partitions.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Resize the partition at ``index`` to ``sectors`` sectors, keeping its start.
+    ///
+    /// Return [StorageDeviceError::InvalidRequest] if there is no such partition, or if it would
+    /// be empty or overlap another one.
+    pub fn resize_partition(&mut self, index: usize, sectors: u64) -> StorageDeviceResult<()> {
+        let partition = self
+            .partitions
+            .get(index)
+            .ok_or(StorageDeviceError::InvalidRequest)?;
+        let last_lba = sectors
+            .checked_sub(1)
+            .and_then(|sectors| partition.first_lba.checked_add(sectors))
+            .ok_or(StorageDeviceError::InvalidRequest)?;
+        let partition = GptPartition {
+            last_lba,
+            ..partition.clone()
+        };
+        self.check_partition(&partition, Some(index))?;
+        self.partitions[index] = partition;
+        Ok(())
+    }
+
+    /// Move the partition at ``index`` to start at ``first_lba``, keeping its size.
+    ///
+    /// Only the entry is changed: the data of the partition must be moved separately, e.g. with
+    /// [copy_within](crate::copy_within). Return [StorageDeviceError::InvalidRequest] if there is
+    /// no such partition, or if it would be misaligned or overlap another one, and
+    /// [StorageDeviceError::OutOfBounds] if it would start before
+    /// [GptBuilder::first_usable_lba].
+    pub fn move_partition(&mut self, index: usize, first_lba: u64) -> StorageDeviceResult<()> {
+        let partition = self
+            .partitions
+            .get(index)
+            .ok_or(StorageDeviceError::InvalidRequest)?;
+        let last_lba = partition
+            .sectors()
+            .checked_sub(1)
+            .and_then(|sectors| first_lba.checked_add(sectors))
+            .ok_or(StorageDeviceError::InvalidRequest)?;
+        let partition = GptPartition {
+            first_lba,
+            last_lba,
+            ..partition.clone()
+        };
+        self.check_partition(&partition, Some(index))?;
+        self.partitions[index] = partition;
+        Ok(())
+    }
+
+    /// Check ``partition`` against the partitions, except the one at ``except``.
+    fn check_partition(
+        &self,
+        partition: &GptPartition,
+        except: Option<usize>,
+    ) -> StorageDeviceResult<()> {
+        if partition.first_lba < self.first_usable_lba() {
+            return Err(StorageDeviceError::OutOfBounds);
+        }
+        let others = self
+            .partitions
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| Some(index) != except)
+            .map(|(_, other)| other.range());
+        check_partition(partition.range(), self.alignment, others)
+    }
+
+    /// Return the number of sectors of each copy of the partition entries.
+    fn entry_sectors(&self) -> u64 {
+        (u64::from(self.entry_count) * GPT_ENTRY_LEN as u64).div_ceil(self.sector_size as u64)
+    }
+
+    /// Return the first sector partitions may use.
+    pub fn first_usable_lba(&self) -> u64 {
+        2 + self.entry_sectors()
+    }
+
+    /// Return the last sector partitions may use on a device of ``sectors`` sectors, inclusive,
+    /// or None if the device is too small to hold the table.
+    pub fn last_usable_lba(&self, sectors: u64) -> Option<u64> {
+        let last = sectors
+            .checked_sub(2 + self.entry_sectors())
+            .filter(|&last| last >= self.first_usable_lba())?;
+        Some(last)
+    }
+
+    /// Serialize the partition entries.
+    fn entries_bytes(&self) -> StorageDeviceResult<Vec<u8>> {
+        let len = usize::try_from(self.entry_sectors())
+            .ok()
+            .and_then(|sectors| sectors.checked_mul(self.sector_size))
+            .ok_or(StorageDeviceError::OutOfSpace)?;
+        let mut bytes = alloc::vec![0u8; len];
+        let entries = bytes.chunks_exact_mut(GPT_ENTRY_LEN);
+        for (entry, partition) in entries.zip(self.partitions.iter()) {
+            entry.copy_from_slice(&partition.to_bytes()?);
+        }
+        Ok(bytes)
+    }
+
+    /// Check the partitions, then write the protective MBR, both copies of the table and flush
+    /// ``storage_device``.
+    ///
+    /// The backup copy is written first, then the primary one, with a barrier between them, so
+    /// a crash leaves a valid copy of either the old or the new table: readers use the primary
+    /// copy, falling back to the backup one if it is corrupted. Writing through a
+    /// [Transaction](crate::Transaction) makes the whole table atomic with other writes.
+    ///
+    /// Return [StorageDeviceError::OutOfSpace] if the device is too small to hold the table,
+    /// [StorageDeviceError::InvalidRequest] if there are more partitions than entries, if a name
+    /// is too long, or if a partition is empty or overlaps another one, and
+    /// [StorageDeviceError::OutOfBounds] if one goes past the usable sectors.
+    pub fn write<S: StorageDevice + ?Sized>(
+        &self,
+        storage_device: &mut S,
+    ) -> StorageDeviceResult<()> {
+        if self.partitions.len() > self.entry_count as usize || self.sector_size < MBR_LEN {
+            return Err(StorageDeviceError::InvalidRequest);
+        }
+        let sectors = sector_count(storage_device, self.sector_size)?;
+        let last_usable_lba = self
+            .last_usable_lba(sectors)
+            .ok_or(StorageDeviceError::OutOfSpace)?;
+        let mut ranges: Vec<_> = self.partitions.iter().map(GptPartition::range).collect();
+        check_layout(&mut ranges, self.first_usable_lba()..last_usable_lba + 1)?;
+
+        let entries = self.entries_bytes()?;
+        let last_lba = sectors - 1;
+        let primary = GptHeader {
+            my_lba: 1,
+            alternate_lba: last_lba,
+            first_usable_lba: self.first_usable_lba(),
+            last_usable_lba,
+            disk_guid: self.disk_guid,
+            entries_lba: 2,
+            entry_count: self.entry_count,
+            entry_size: GPT_ENTRY_LEN as u32,
+            entries_crc: crc32(&entries[..self.entry_count as usize * GPT_ENTRY_LEN]),
+        };
+        let backup = GptHeader {
+            my_lba: last_lba,
+            alternate_lba: 1,
+            entries_lba: last_usable_lba + 1,
+            ..primary
+        };
+
+        let protective_sectors = u32::try_from(last_lba).unwrap_or(u32::MAX);
+        let mbr = MbrBuilder::new()
+            .with_sector_size(self.sector_size)
+            .with_partition(MbrPartition::new(
+                MbrPartition::GPT_PROTECTIVE,
+                1,
+                protective_sectors,
+            ));
+        let mut boot_code = [0u8; MBR_SIGNATURE_OFFSET];
+        storage_device.read(0, &mut boot_code)?;
+
+        let sector_size = self.sector_size as u64;
+        let mut header = alloc::vec![0u8; self.sector_size];
+        for copy in [backup, primary] {
+            header[..GPT_HEADER_LEN].copy_from_slice(&copy.to_bytes());
+            storage_device.write(copy.entries_lba * sector_size, &entries)?;
+            storage_device.write(copy.my_lba * sector_size, &header)?;
+            storage_device.barrier()?;
+        }
+        storage_device.write(0, &mbr.to_bytes(&boot_code))?;
+        storage_device.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// The number of sectors of the disks of the tests, of 512 bytes.
+    const SECTORS: u64 = 256;
+
+    /// Return the header at ``lba`` of ``disk``, after checking its CRC-32 by hand.
+    fn header_at(disk: &[u8], lba: u64) -> GptHeader {
+        let sector = &disk[lba as usize * 512..][..512];
+        let mut header = sector[..GPT_HEADER_LEN].to_vec();
+        let crc = u32::from_le_bytes([header[16], header[17], header[18], header[19]]);
+        header[16..20].fill(0);
+        assert_eq!(crc32(&header), crc);
+        GptHeader::from_bytes(sector).unwrap()
+    }
+
+    #[test]
+    fn both_copies_of_the_table_are_written_and_read_back() {
+        let esp = GptPartition::new(Guid::EFI_SYSTEM, Guid([1; 16]), 40, 99).with_name("ESP");
+        let root =
+            GptPartition::new(Guid::LINUX_FILESYSTEM, Guid([2; 16]), 100, 222).with_name("root");
+        let builder = GptBuilder::new(Guid([0xAB; 16]))
+            .with_partition(esp)
+            .with_partition(root);
+        let mut disk = vec![0u8; SECTORS as usize * 512];
+        builder.write(&mut disk).unwrap();
+
+        // 128 entries of 128 bytes fill 32 sectors after the primary header, and before the
+        // backup one, in the last sector.
+        let primary = header_at(&disk, 1);
+        let backup = header_at(&disk, SECTORS - 1);
+        assert_eq!((primary.my_lba, primary.alternate_lba), (1, SECTORS - 1));
+        assert_eq!((backup.my_lba, backup.alternate_lba), (SECTORS - 1, 1));
+        assert_eq!(
+            (primary.first_usable_lba, primary.last_usable_lba),
+            (34, 222)
+        );
+        assert_eq!((backup.first_usable_lba, backup.last_usable_lba), (34, 222));
+        assert_eq!((primary.entries_lba, backup.entries_lba), (2, 223));
+
+        let entries_len = 128 * GPT_ENTRY_LEN;
+        let primary_entries = &disk[2 * 512..][..entries_len];
+        let backup_entries = &disk[223 * 512..][..entries_len];
+        assert_eq!(primary_entries, backup_entries);
+        assert_eq!(crc32(primary_entries), primary.entries_crc);
+        assert_eq!(crc32(backup_entries), backup.entries_crc);
+
+        // The protective MBR covers the whole disk.
+        assert_eq!(disk[MBR_ENTRIES_OFFSET + 4], MbrPartition::GPT_PROTECTIVE);
+        assert_eq!(disk[510..512], [0x55, 0xAA]);
+
+        let read = GptBuilder::read(&mut disk, 512).unwrap();
+        assert_eq!(read.disk_guid(), Guid([0xAB; 16]));
+        assert_eq!(read.partitions(), builder.partitions());
+
+        // A corrupted primary header falls back to the backup copy, and corrupted backup
+        // entries leave nothing to read.
+        disk[512 + 60] ^= 1;
+        let read = GptBuilder::read(&mut disk, 512).unwrap();
+        assert_eq!(read.partitions(), builder.partitions());
+        disk[223 * 512 + 60] ^= 1;
+        assert_eq!(
+            GptBuilder::read(&mut disk, 512).err(),
+            Some(StorageDeviceError::Corrupted)
+        );
+    }
+}
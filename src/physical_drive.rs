@@ -0,0 +1,178 @@
+use crate::aligned_io::AlignedFile;
+use crate::{
+    Block, BlockCount, BlockDevice, BlockError, BlockIndex, BlockResult, Capabilities,
+    DeviceIdentity, StorageDevice, StorageDeviceError, StorageDeviceResult,
+};
+use std::fs::{File, OpenOptions};
+use std::os::windows::fs::OpenOptionsExt;
+use windows_sys::Win32::Storage::FileSystem::{
+    FILE_FLAG_NO_BUFFERING, FILE_FLAG_WRITE_THROUGH, FILE_SHARE_READ, FILE_SHARE_WRITE,
+};
+
+/// The default size of the bounce buffer of a [PhysicalDrive].
+pub const DEFAULT_PHYSICAL_DRIVE_BUFFER_SIZE: usize = 128 * 1024;
+
+/// A raw Windows disk, opened as ``\\.\PhysicalDriveN``.
+///
+/// Raw disks only accept transfers aligned on their sector size, in offset, size and memory.
+/// Requests are transferred through an aligned bounce buffer, reading the surrounding sectors
+/// first when a write doesn't cover them whole. The disk is opened unbuffered and write-through,
+/// so a completed write is on the disk.
+///
+/// Writing to a disk with mounted volumes requires locking or dismounting them first, which is
+/// left to the caller.
+pub struct PhysicalDrive {
+    /// The disk handle and its bounce buffer.
+    inner: AlignedFile,
+
+    /// The size of the disk in bytes, queried once when opening it.
+    len: u64,
+}
+
+impl core::fmt::Debug for PhysicalDrive {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+        fmt.debug_struct("PhysicalDrive")
+            .field("file", &self.inner.file)
+            .field("sector_size", &self.inner.alignment)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl PhysicalDrive {
+    /// Open ``\\.\PhysicalDrive<number>``.
+    ///
+    /// Opening a physical drive requires administrator privileges.
+    pub fn open(number: u32, writable: bool) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE)
+            .custom_flags(FILE_FLAG_NO_BUFFERING | FILE_FLAG_WRITE_THROUGH)
+            .open(std::format!(r"\\.\PhysicalDrive{}", number))?;
+
+        Self::from_file(file, DEFAULT_PHYSICAL_DRIVE_BUFFER_SIZE)
+    }
+
+    /// Create a device from a ``file`` handle to a disk opened with ``FILE_FLAG_NO_BUFFERING``.
+    ///
+    /// The sector size and size of the disk are queried from the handle, and ``buffer_size`` is
+    /// rounded up to a multiple of the sector size.
+    pub fn from_file(file: File, buffer_size: usize) -> std::io::Result<Self> {
+        let sector_size = crate::sys::sector_size(&file)? as usize;
+        if !sector_size.is_power_of_two() {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        let len = crate::sys::file_len(&file)?;
+
+        Ok(PhysicalDrive {
+            inner: AlignedFile::new(file, sector_size, buffer_size),
+            len,
+        })
+    }
+
+    /// Return the logical sector size of the disk.
+    pub fn sector_size(&self) -> usize {
+        self.inner.alignment
+    }
+
+    /// Return a reference to the disk handle.
+    pub fn get_ref(&self) -> &File {
+        &self.inner.file
+    }
+
+    /// Consume the device, returning the disk handle.
+    pub fn into_inner(self) -> File {
+        self.inner.file
+    }
+
+    /// Check that ``len`` bytes at ``offset`` are on the disk.
+    fn check_bounds(&self, offset: u64, len: usize) -> StorageDeviceResult<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+}
+
+impl StorageDevice for PhysicalDrive {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        self.inner.read(offset, buf)
+    }
+
+    /// Write through the bounce buffer, reading the partially covered sectors first.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        self.inner.write(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.len)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.inner
+            .file
+            .sync_all()
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+
+    /// Query the identity of the disk or file from the operating system.
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        crate::sys::identity(&self.inner.file)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+    }
+
+    /// Query the capabilities of the disk or file from the operating system, keeping those this
+    /// device makes use of.
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        let capabilities = crate::sys::capabilities(&self.inner.file);
+        Ok(capabilities
+            & (Capabilities::FLUSH | Capabilities::ROTATIONAL | Capabilities::READ_ONLY))
+    }
+}
+
+impl BlockDevice for PhysicalDrive {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        let mut offset = index.into_offset();
+        for block in blocks.iter_mut() {
+            StorageDevice::read(self, offset, &mut block.contents)
+                .map_err(|err| err.into_block_error(BlockError::ReadError))?;
+            offset += Block::LEN_U64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        let mut offset = index.into_offset();
+        for block in blocks.iter() {
+            StorageDevice::write(self, offset, &block.contents)
+                .map_err(|err| err.into_block_error(BlockError::WriteError))?;
+            offset += Block::LEN_U64;
+        }
+        Ok(())
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        Ok(BlockCount(self.len / Block::LEN_U64))
+    }
+
+    fn flush(&mut self) -> BlockResult<()> {
+        StorageDevice::flush(self).map_err(|err| err.into_block_error(BlockError::WriteError))
+    }
+
+    /// Query the identity of the disk or file from the operating system.
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        crate::sys::identity(&self.inner.file)
+            .map_err(|err| crate::sys::block_error(&err, BlockError::Unknown))
+    }
+
+    /// Query the capabilities of the disk or file from the operating system, keeping those this
+    /// device makes use of.
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        let capabilities = crate::sys::capabilities(&self.inner.file);
+        Ok(capabilities
+            & (Capabilities::FLUSH | Capabilities::ROTATIONAL | Capabilities::READ_ONLY))
+    }
+}
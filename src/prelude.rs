@@ -0,0 +1,25 @@
+pub use crate::{
+    AsyncStorageDevice, Block, BlockCount, BlockDevice, BlockError, BlockIndex, BlockResult, Clock,
+    Delay, DynBlockDevice, Resizable, SharedStorageDevice, StorageBlockDevice, StorageDevice,
+    StorageDeviceError, StorageDeviceExt, StorageDeviceResult,
+};
+
+#[cfg(any(
+    feature = "cached-block-device",
+    feature = "cached-block-device-nightly"
+))]
+pub use crate::CachedBlockDevice;
+
+/// A file accessed byte by byte through its blocks.
+#[cfg(feature = "std")]
+pub type FileStorage = StorageBlockDevice<std::fs::File>;
+
+/// A file accessed byte by byte through a cache of its blocks.
+#[cfg(all(
+    feature = "std",
+    any(
+        feature = "cached-block-device",
+        feature = "cached-block-device-nightly"
+    )
+))]
+pub type CachedFileStorage = StorageBlockDevice<CachedBlockDevice<std::fs::File>>;
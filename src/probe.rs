@@ -0,0 +1,192 @@
+use crate::{StorageDevice, StorageDeviceResult};
+
+/// The page sizes Linux swap areas are probed with, the signature ending the first page.
+const SWAP_PAGE_SIZES: [u64; 4] = [4096, 8192, 16384, 65536];
+
+/// The ext superblock features an ext3 driver doesn't know about, making the filesystem ext4:
+/// every incompatible feature but filetype, recover and meta_bg.
+const EXT4_INCOMPAT: u32 = !(0x0002 | 0x0004 | 0x0010);
+
+/// The ext superblock read-only features an ext3 driver doesn't know about, making the
+/// filesystem ext4: every one but sparse_super, large_file and btree_dir.
+const EXT4_RO_COMPAT: u32 = !(0x0001 | 0x0002 | 0x0004);
+
+/// The ext superblock compatible feature of filesystems with a journal.
+const EXT_HAS_JOURNAL: u32 = 0x0004;
+
+/// A filesystem or container format identified by its signature.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Filesystem {
+    /// FAT12, FAT16 or FAT32.
+    Fat,
+
+    /// exFAT.
+    ExFat,
+
+    /// ext2, without a journal.
+    Ext2,
+
+    /// ext3, with a journal but none of the ext4 features.
+    Ext3,
+
+    /// ext4.
+    Ext4,
+
+    /// NTFS.
+    Ntfs,
+
+    /// ISO 9660, the filesystem of optical discs.
+    Iso9660,
+
+    /// A LUKS encrypted container, version 1 or 2.
+    Luks,
+
+    /// A Linux swap area.
+    Swap,
+}
+
+impl Filesystem {
+    /// Return the name of the format, as reported by ``blkid``.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Filesystem::Fat => "vfat",
+            Filesystem::ExFat => "exfat",
+            Filesystem::Ext2 => "ext2",
+            Filesystem::Ext3 => "ext3",
+            Filesystem::Ext4 => "ext4",
+            Filesystem::Ntfs => "ntfs",
+            Filesystem::Iso9660 => "iso9660",
+            Filesystem::Luks => "crypto_LUKS",
+            Filesystem::Swap => "swap",
+        }
+    }
+}
+
+impl core::fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Read ``buf`` at ``offset`` of a device of ``len`` bytes, returning false without reading if
+/// it goes past the end of the device.
+fn read_if_inside<S: StorageDevice + ?Sized>(
+    storage_device: &mut S,
+    len: u64,
+    offset: u64,
+    buf: &mut [u8],
+) -> StorageDeviceResult<bool> {
+    match offset.checked_add(buf.len() as u64) {
+        Some(end) if end <= len => {
+            storage_device.read(offset, buf)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Identify the filesystem or container at the start of ``storage_device`` from its signature.
+///
+/// Return None if no known signature is found.
+pub fn probe<S: StorageDevice + ?Sized>(
+    storage_device: &mut S,
+) -> StorageDeviceResult<Option<Filesystem>> {
+    probe_at(storage_device, 0)
+}
+
+/// Identify the filesystem or container starting at ``offset`` of ``storage_device``, such as
+/// the first byte of a partition, from its signature.
+///
+/// Containers are checked first, as they may hold the remains of a filesystem formatted before
+/// them, then the formats whose signature is the least likely to appear by chance. Return None
+/// if no known signature is found.
+pub fn probe_at<S: StorageDevice + ?Sized>(
+    storage_device: &mut S,
+    offset: u64,
+) -> StorageDeviceResult<Option<Filesystem>> {
+    let len = storage_device.len()?;
+    let mut boot_sector = [0u8; 512];
+    let has_boot_sector = read_if_inside(storage_device, len, offset, &mut boot_sector)?;
+
+    if has_boot_sector && boot_sector[0..6] == *b"LUKS\xba\xbe" {
+        return Ok(Some(Filesystem::Luks));
+    }
+
+    for page_size in SWAP_PAGE_SIZES.iter() {
+        let mut signature = [0u8; 10];
+        if read_if_inside(
+            storage_device,
+            len,
+            offset.saturating_add(page_size - 10),
+            &mut signature,
+        )? && (signature == *b"SWAPSPACE2" || signature == *b"SWAP-SPACE")
+        {
+            return Ok(Some(Filesystem::Swap));
+        }
+    }
+
+    let mut superblock = [0u8; 0x68];
+    if read_if_inside(
+        storage_device,
+        len,
+        offset.saturating_add(1024),
+        &mut superblock,
+    )? && superblock[0x38..0x3A] == [0x53, 0xEF]
+    {
+        let feature = |at: usize| {
+            u32::from_le_bytes([
+                superblock[at],
+                superblock[at + 1],
+                superblock[at + 2],
+                superblock[at + 3],
+            ])
+        };
+        let filesystem =
+            if feature(0x60) & EXT4_INCOMPAT != 0 || feature(0x64) & EXT4_RO_COMPAT != 0 {
+                Filesystem::Ext4
+            } else if feature(0x5C) & EXT_HAS_JOURNAL != 0 {
+                Filesystem::Ext3
+            } else {
+                Filesystem::Ext2
+            };
+        return Ok(Some(filesystem));
+    }
+
+    let mut volume_descriptor = [0u8; 6];
+    if read_if_inside(
+        storage_device,
+        len,
+        offset.saturating_add(0x8000),
+        &mut volume_descriptor,
+    )? && volume_descriptor[1..6] == *b"CD001"
+    {
+        return Ok(Some(Filesystem::Iso9660));
+    }
+
+    if !has_boot_sector {
+        return Ok(None);
+    }
+    if boot_sector[3..11] == *b"NTFS    " {
+        return Ok(Some(Filesystem::Ntfs));
+    }
+    if boot_sector[3..11] == *b"EXFAT   " {
+        return Ok(Some(Filesystem::ExFat));
+    }
+    // The type string of FAT is informative only, so the BIOS parameter block is checked too: a
+    // power of two bytes per sector from 512 to 4096, a non-zero power of two sectors per
+    // cluster, and at least one FAT.
+    let bytes_per_sector = u16::from_le_bytes([boot_sector[11], boot_sector[12]]);
+    let sectors_per_cluster = boot_sector[13];
+    let has_type = boot_sector[0x36..0x39] == *b"FAT" || boot_sector[0x52..0x57] == *b"FAT32";
+    if has_type
+        && boot_sector[510..512] == [0x55, 0xAA]
+        && bytes_per_sector.is_power_of_two()
+        && (512..=4096).contains(&bytes_per_sector)
+        && sectors_per_cluster.is_power_of_two()
+        && boot_sector[16] != 0
+    {
+        return Ok(Some(Filesystem::Fat));
+    }
+
+    Ok(None)
+}
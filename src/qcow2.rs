@@ -0,0 +1,824 @@
+use crate::{StorageDevice, StorageDeviceError, StorageDeviceResult};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// The magic at the start of a qcow2 image, ``QFI\xfb``.
+const QCOW2_MAGIC: u32 = 0x5146_49FB;
+
+/// The mask of the host offset in L1 and L2 entries, and in refcount table entries.
+const OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+
+/// Set in L1 and L2 entries whose cluster has a refcount of exactly one, and can be written in
+/// place.
+const FLAG_COPIED: u64 = 1 << 63;
+
+/// Set in L2 entries of compressed clusters.
+const FLAG_COMPRESSED: u64 = 1 << 62;
+
+/// Set in L2 entries of clusters reading as zeros, in version 3.
+const FLAG_ZERO: u64 = 1;
+
+/// The incompatible feature bit marking an image whose refcounts may be inconsistent.
+const INCOMPATIBLE_DIRTY: u64 = 1 << 0;
+
+/// The incompatible feature bit marking an image found corrupted.
+const INCOMPATIBLE_CORRUPT: u64 = 1 << 1;
+
+/// The header of a qcow2 image.
+///
+/// Only the fields needed to access the data are kept. They are stored in big endian as follow:
+///
+/// | Offset | Size | Field                   |
+/// |--------|------|-------------------------|
+/// | 0      | 4    | magic                   |
+/// | 4      | 4    | version                 |
+/// | 8      | 8    | backing_file_offset     |
+/// | 16     | 4    | backing_file_size       |
+/// | 20     | 4    | cluster_bits            |
+/// | 24     | 8    | size                    |
+/// | 32     | 4    | crypt_method            |
+/// | 36     | 4    | l1_size                 |
+/// | 40     | 8    | l1_table_offset         |
+/// | 48     | 8    | refcount_table_offset   |
+/// | 56     | 4    | refcount_table_clusters |
+/// | 60     | 4    | nb_snapshots            |
+/// | 64     | 8    | snapshots_offset        |
+///
+/// Version 3 adds:
+///
+/// | Offset | Size | Field                   |
+/// |--------|------|-------------------------|
+/// | 72     | 8    | incompatible_features   |
+/// | 80     | 8    | compatible_features     |
+/// | 88     | 8    | autoclear_features      |
+/// | 96     | 4    | refcount_order          |
+/// | 100    | 4    | header_length           |
+#[derive(Debug, Copy, Clone)]
+struct Qcow2Header {
+    /// The offset of the name of the backing file, or zero.
+    backing_file_offset: u64,
+
+    /// The length of the name of the backing file.
+    backing_file_size: u32,
+
+    /// The log2 of the cluster size.
+    cluster_bits: u32,
+
+    /// The size of the virtual disk in bytes.
+    size: u64,
+
+    /// The number of entries of the L1 table.
+    l1_size: u32,
+
+    /// The offset of the L1 table.
+    l1_table_offset: u64,
+
+    /// The offset of the refcount table.
+    refcount_table_offset: u64,
+
+    /// The number of clusters of the refcount table.
+    refcount_table_clusters: u32,
+
+    /// The incompatible features used by the image.
+    incompatible_features: u64,
+
+    /// The log2 of the width of refcounts, in bits.
+    refcount_order: u32,
+}
+
+/// Read the big endian ``u32`` at ``offset`` in ``buf``.
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+/// Read the big endian ``u64`` at ``offset`` in ``buf``.
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+impl Qcow2Header {
+    /// Read and check the header of ``image``.
+    fn read<S: StorageDevice + ?Sized>(image: &mut S) -> StorageDeviceResult<Self> {
+        let mut buf = [0u8; 104];
+        image.read(0, &mut buf[..72])?;
+        if be_u32(&buf, 0) != QCOW2_MAGIC {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        let version = be_u32(&buf, 4);
+        let (incompatible_features, refcount_order) = match version {
+            2 => (0, 4),
+            3 => {
+                image.read(72, &mut buf[72..104])?;
+                (be_u64(&buf, 72), be_u32(&buf, 96))
+            }
+            _ => return Err(StorageDeviceError::Unsupported),
+        };
+
+        if incompatible_features & INCOMPATIBLE_CORRUPT != 0 {
+            return Err(StorageDeviceError::Corrupted);
+        }
+        // Only the dirty bit can be handled, by not writing.
+        if incompatible_features & !INCOMPATIBLE_DIRTY != 0 {
+            return Err(StorageDeviceError::Unsupported);
+        }
+        // Encrypted images are not supported.
+        if be_u32(&buf, 32) != 0 {
+            return Err(StorageDeviceError::Unsupported);
+        }
+
+        let header = Qcow2Header {
+            backing_file_offset: be_u64(&buf, 8),
+            backing_file_size: be_u32(&buf, 16),
+            cluster_bits: be_u32(&buf, 20),
+            size: be_u64(&buf, 24),
+            l1_size: be_u32(&buf, 36),
+            l1_table_offset: be_u64(&buf, 40),
+            refcount_table_offset: be_u64(&buf, 48),
+            refcount_table_clusters: be_u32(&buf, 56),
+            incompatible_features,
+            refcount_order,
+        };
+
+        if !(9..=21).contains(&header.cluster_bits) || header.refcount_order > 6 {
+            return Err(StorageDeviceError::Corrupted);
+        }
+        let l2_coverage = header.cluster_size() * header.l2_entries();
+        if u64::from(header.l1_size) < header.size.div_ceil(l2_coverage) {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        Ok(header)
+    }
+
+    /// Return the size of a cluster in bytes.
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Return the number of entries of an L2 table.
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    /// Return the number of refcounts in a refcount block.
+    fn refcount_block_entries(&self) -> u64 {
+        (self.cluster_size() * 8) >> self.refcount_order
+    }
+}
+
+/// Read the table of ``len`` big endian ``u64`` at ``offset`` in ``image``.
+///
+/// Fail with [StorageDeviceError::Corrupted] if the table goes past the end of the image.
+fn read_table<S: StorageDevice + ?Sized>(
+    image: &mut S,
+    offset: u64,
+    len: u64,
+) -> StorageDeviceResult<Vec<u64>> {
+    // Check the size first, so a corrupted header can't make us allocate an absurd table.
+    match len.checked_mul(8).and_then(|size| size.checked_add(offset)) {
+        Some(end) if end <= image.len()? => (),
+        _ => return Err(StorageDeviceError::Corrupted),
+    }
+
+    let mut bytes = alloc::vec![0u8; len as usize * 8];
+    image.read(offset, &mut bytes)?;
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|entry| be_u64(entry, 0))
+        .collect())
+}
+
+/// Where the data of a guest cluster is.
+#[derive(Debug, Copy, Clone)]
+enum ClusterMapping {
+    /// Not allocated in this image, the data comes from the backing file.
+    Unallocated,
+
+    /// The cluster reads as zeros, and is preallocated at ``host_offset`` if it isn't zero.
+    Zero {
+        /// The offset of the preallocated cluster in the image, or zero.
+        host_offset: u64,
+    },
+
+    /// The cluster is stored at the given host offset, and can be written in place if ``copied``.
+    Data {
+        /// The offset of the cluster in the image.
+        host_offset: u64,
+        /// Whether the cluster is only referenced once.
+        copied: bool,
+    },
+
+    /// The cluster is compressed.
+    Compressed,
+}
+
+/// A storage device over a qcow2 image, the format of QEMU virtual disks.
+///
+/// Clusters are looked up through the L1 and L2 tables. Clusters not allocated in the image are
+/// read from the backing device if the image has one, and clusters written for the first time
+/// are appended to the image, with their refcounts updated. Versions 2 and 3 are supported,
+/// without encryption, compression or external data files.
+///
+/// Writes fail with [StorageDeviceError::Unsupported] when the image is marked dirty, as its
+/// refcounts can't be trusted, and when they would modify a cluster shared with a snapshot.
+#[derive(Debug)]
+pub struct Qcow2Device<S: StorageDevice, B: StorageDevice = S> {
+    /// The qcow2 image.
+    image: S,
+
+    /// The backing device, if the image has a backing file.
+    backing: Option<B>,
+
+    /// The header of the image.
+    header: Qcow2Header,
+
+    /// The L1 table.
+    l1_table: Vec<u64>,
+
+    /// The refcount table.
+    refcount_table: Vec<u64>,
+
+    /// The offset of the next cluster to allocate, at the end of the image.
+    next_free: u64,
+}
+
+impl<S: StorageDevice> Qcow2Device<S, S> {
+    /// Open the qcow2 image ``image``, which must not have a backing file.
+    ///
+    /// Fail with [StorageDeviceError::Unsupported] if it has one, see
+    /// [Qcow2Device::open_with_backing].
+    pub fn open(image: S) -> StorageDeviceResult<Self> {
+        Self::open_inner(image, None)
+    }
+}
+
+impl<S: StorageDevice, B: StorageDevice> Qcow2Device<S, B> {
+    /// Open the qcow2 image ``image``, reading the clusters it doesn't hold from ``backing``.
+    ///
+    /// Use [Qcow2Device::backing_file_name] to find which file to open as ``backing``.
+    pub fn open_with_backing(image: S, backing: B) -> StorageDeviceResult<Self> {
+        Self::open_inner(image, Some(backing))
+    }
+
+    /// Return the name of the backing file of ``image``, if it has one.
+    pub fn backing_file_name(image: &mut S) -> StorageDeviceResult<Option<String>> {
+        let header = Qcow2Header::read(image)?;
+        if header.backing_file_offset == 0 {
+            return Ok(None);
+        }
+
+        // QEMU limits the name to 1023 bytes.
+        if header.backing_file_size > 1023 {
+            return Err(StorageDeviceError::Corrupted);
+        }
+        let mut name = alloc::vec![0u8; header.backing_file_size as usize];
+        image.read(header.backing_file_offset, &mut name)?;
+        String::from_utf8(name)
+            .map(Some)
+            .map_err(|_| StorageDeviceError::Corrupted)
+    }
+
+    /// Open ``image``, with its backing device if any.
+    fn open_inner(mut image: S, backing: Option<B>) -> StorageDeviceResult<Self> {
+        let header = Qcow2Header::read(&mut image)?;
+        if header.backing_file_offset != 0 && backing.is_none() {
+            return Err(StorageDeviceError::Unsupported);
+        }
+
+        let l1_table = read_table(
+            &mut image,
+            header.l1_table_offset,
+            u64::from(header.l1_size),
+        )?;
+        let refcount_table = read_table(
+            &mut image,
+            header.refcount_table_offset,
+            u64::from(header.refcount_table_clusters) * header.l2_entries(),
+        )?;
+        let next_free = image.len()?.next_multiple_of(header.cluster_size());
+
+        Ok(Qcow2Device {
+            image,
+            backing,
+            header,
+            l1_table,
+            refcount_table,
+            next_free,
+        })
+    }
+
+    /// Return the size of a cluster in bytes.
+    pub fn cluster_size(&self) -> u64 {
+        self.header.cluster_size()
+    }
+
+    /// Return a reference to the image.
+    pub fn get_ref(&self) -> &S {
+        &self.image
+    }
+
+    /// Return a reference to the backing device, if any.
+    pub fn backing(&self) -> Option<&B> {
+        self.backing.as_ref()
+    }
+
+    /// Consume the device, returning the image and the backing device.
+    pub fn into_inner(self) -> (S, Option<B>) {
+        (self.image, self.backing)
+    }
+
+    /// Return the index in the L1 table and the offset in the L2 table of the entry of the
+    /// cluster holding ``offset``.
+    fn l2_position(&self, offset: u64) -> (usize, u64) {
+        let cluster = offset >> self.header.cluster_bits;
+        let l2_entries = self.header.l2_entries();
+        ((cluster / l2_entries) as usize, (cluster % l2_entries) * 8)
+    }
+
+    /// Return where the data of the cluster holding ``offset`` is.
+    fn lookup(&mut self, offset: u64) -> StorageDeviceResult<ClusterMapping> {
+        let (l1_index, l2_entry_offset) = self.l2_position(offset);
+        let l2_table = self.l1_table[l1_index] & OFFSET_MASK;
+        if l2_table == 0 {
+            return Ok(ClusterMapping::Unallocated);
+        }
+
+        let mut entry = [0u8; 8];
+        self.image.read(l2_table + l2_entry_offset, &mut entry)?;
+        let entry = u64::from_be_bytes(entry);
+
+        if entry & FLAG_COMPRESSED != 0 {
+            Ok(ClusterMapping::Compressed)
+        } else if entry & FLAG_ZERO != 0 {
+            Ok(ClusterMapping::Zero {
+                host_offset: entry & OFFSET_MASK,
+            })
+        } else if entry & OFFSET_MASK == 0 {
+            Ok(ClusterMapping::Unallocated)
+        } else {
+            Ok(ClusterMapping::Data {
+                host_offset: entry & OFFSET_MASK,
+                copied: entry & FLAG_COPIED != 0,
+            })
+        }
+    }
+
+    /// Read the data at ``offset`` of the backing device, which reads as zeros past its end.
+    fn read_backing(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        buf.fill(0);
+        if let Some(backing) = &mut self.backing {
+            let backing_len = backing.len()?;
+            if offset < backing_len {
+                let len = core::cmp::min(buf.len() as u64, backing_len - offset) as usize;
+                backing.read(offset, &mut buf[..len])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the refcount of the cluster at ``host_offset`` to ``value``, allocating a refcount
+    /// block if needed.
+    fn set_refcount(&mut self, host_offset: u64, value: u64) -> StorageDeviceResult<()> {
+        let cluster_size = self.header.cluster_size();
+        let cluster = host_offset / cluster_size;
+        let block_entries = self.header.refcount_block_entries();
+        let table_index =
+            usize::try_from(cluster / block_entries).map_err(|_| StorageDeviceError::OutOfSpace)?;
+        if table_index >= self.refcount_table.len() {
+            // Growing the refcount table isn't supported.
+            return Err(StorageDeviceError::OutOfSpace);
+        }
+
+        let mut block = self.refcount_table[table_index] & OFFSET_MASK;
+        if block == 0 {
+            block = self.next_free;
+            self.next_free += cluster_size;
+            self.image.write_zeroes(block, cluster_size)?;
+            // Only point to the block once it is zeroed.
+            self.image.barrier()?;
+            self.refcount_table[table_index] = block;
+            self.image.write(
+                self.header.refcount_table_offset + table_index as u64 * 8,
+                &block.to_be_bytes(),
+            )?;
+            self.set_refcount(block, 1)?;
+        }
+
+        // Refcounts are big endian integers of 8 to 64 bits.
+        let width = (1u64 << self.header.refcount_order) / 8;
+        let bytes = value.to_be_bytes();
+        self.image.write(
+            block + (cluster % block_entries) * width,
+            &bytes[8 - width as usize..],
+        )
+    }
+
+    /// Return the refcount of the cluster at ``host_offset``.
+    fn refcount(&mut self, host_offset: u64) -> StorageDeviceResult<u64> {
+        let cluster = host_offset / self.header.cluster_size();
+        let block_entries = self.header.refcount_block_entries();
+        let block = usize::try_from(cluster / block_entries)
+            .ok()
+            .and_then(|index| self.refcount_table.get(index))
+            .map_or(0, |entry| entry & OFFSET_MASK);
+        if block == 0 {
+            return Ok(0);
+        }
+
+        let width = (1u64 << self.header.refcount_order) / 8;
+        let mut bytes = [0u8; 8];
+        self.image.read(
+            block + (cluster % block_entries) * width,
+            &mut bytes[8 - width as usize..],
+        )?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// Allocate a cluster at the end of the image, returning its offset.
+    fn allocate_cluster(&mut self) -> StorageDeviceResult<u64> {
+        let host_offset = self.next_free;
+        self.next_free += self.header.cluster_size();
+        self.set_refcount(host_offset, 1)?;
+        Ok(host_offset)
+    }
+
+    /// Write ``buf`` at ``offset``, inside a single cluster.
+    fn write_cluster(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let cluster_size = self.header.cluster_size();
+        let in_cluster = (offset % cluster_size) as usize;
+
+        let mapping = self.lookup(offset)?;
+        let (fill_from_backing, preallocated) = match mapping {
+            ClusterMapping::Data {
+                host_offset,
+                copied: true,
+            } => return self.image.write(host_offset + in_cluster as u64, buf),
+            // Writing shared or compressed clusters requires copying them first.
+            ClusterMapping::Data { copied: false, .. } | ClusterMapping::Compressed => {
+                return Err(StorageDeviceError::Unsupported)
+            }
+            ClusterMapping::Unallocated => (true, 0),
+            ClusterMapping::Zero { host_offset } => (false, host_offset),
+        };
+
+        // Make sure the L2 table exists.
+        let (l1_index, l2_entry_offset) = self.l2_position(offset);
+        let mut l2_table = self.l1_table[l1_index] & OFFSET_MASK;
+        if l2_table == 0 {
+            l2_table = self.allocate_cluster()?;
+            self.image.write_zeroes(l2_table, cluster_size)?;
+            // Only point to the table once it and its refcount are written.
+            self.image.barrier()?;
+            self.l1_table[l1_index] = l2_table | FLAG_COPIED;
+            self.image.write(
+                self.header.l1_table_offset + l1_index as u64 * 8,
+                &self.l1_table[l1_index].to_be_bytes(),
+            )?;
+        }
+
+        // A preallocated zero cluster only referenced here is written in place, otherwise the
+        // data goes to a new cluster and the reference to the old one is dropped afterwards.
+        let (host_offset, release) = if preallocated == 0 {
+            (self.allocate_cluster()?, 0)
+        } else if self.refcount(preallocated)? == 1 {
+            (preallocated, 0)
+        } else {
+            (self.allocate_cluster()?, preallocated)
+        };
+
+        // Write the whole cluster, completing the data with its previous content.
+        if buf.len() as u64 == cluster_size {
+            self.image.write(host_offset, buf)?;
+        } else {
+            let mut cluster = alloc::vec![0u8; cluster_size as usize];
+            if fill_from_backing {
+                self.read_backing(offset - in_cluster as u64, &mut cluster)?;
+            }
+            cluster[in_cluster..in_cluster + buf.len()].copy_from_slice(buf);
+            self.image.write(host_offset, &cluster)?;
+        }
+
+        // Only point to the data once it and its refcount are written.
+        self.image.barrier()?;
+        self.image.write(
+            l2_table + l2_entry_offset,
+            &(host_offset | FLAG_COPIED).to_be_bytes(),
+        )?;
+
+        if release != 0 {
+            // The old cluster is no longer referenced from here, a crash before this only leaks
+            // a reference.
+            self.image.barrier()?;
+            let refcount = self.refcount(release)?;
+            self.set_refcount(release, refcount.saturating_sub(1))?;
+        }
+        Ok(())
+    }
+
+    /// Check whether the ``len`` bytes at ``offset`` are inside the virtual disk.
+    fn check_bounds(&self, offset: u64, len: usize) -> StorageDeviceResult<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.header.size => Ok(()),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+
+    /// Return how many of the ``len`` bytes at ``offset`` are in the same cluster.
+    fn run_len(&self, offset: u64, len: usize) -> usize {
+        let cluster_size = self.header.cluster_size();
+        core::cmp::min(cluster_size - offset % cluster_size, len as u64) as usize
+    }
+}
+
+impl<S: StorageDevice, B: StorageDevice> StorageDevice for Qcow2Device<S, B> {
+    fn read(&mut self, mut offset: u64, mut buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        while !buf.is_empty() {
+            let run = self.run_len(offset, buf.len());
+            let (head, tail) = buf.split_at_mut(run);
+            match self.lookup(offset)? {
+                ClusterMapping::Data { host_offset, .. } => {
+                    let in_cluster = offset % self.header.cluster_size();
+                    self.image.read(host_offset + in_cluster, head)?
+                }
+                ClusterMapping::Zero { .. } => head.fill(0),
+                ClusterMapping::Unallocated => self.read_backing(offset, head)?,
+                ClusterMapping::Compressed => return Err(StorageDeviceError::Unsupported),
+            }
+            buf = tail;
+            offset += run as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, mut offset: u64, mut buf: &[u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        if self.header.incompatible_features & INCOMPATIBLE_DIRTY != 0
+            || self.header.refcount_order < 3
+        {
+            return Err(StorageDeviceError::Unsupported);
+        }
+
+        while !buf.is_empty() {
+            let run = self.run_len(offset, buf.len());
+            let (head, tail) = buf.split_at(run);
+            self.write_cluster(offset, head)?;
+            buf = tail;
+            offset += run as u64;
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.header.size)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.image.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// The size of the clusters of the test images.
+    const CLUSTER: usize = 512;
+
+    /// The size of the virtual disk of the test images, covered by two L2 tables.
+    const DISK_LEN: u64 = 64 * 1024;
+
+    /// Write the big endian ``value`` at ``offset`` in ``buf``.
+    fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// Write the big endian ``value`` at ``offset`` in ``buf``.
+    fn put_u64(buf: &mut [u8], offset: usize, value: u64) {
+        buf[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// Build an empty image of the given version, with refcounts of ``1 << refcount_order`` bits
+    /// in version 3.
+    ///
+    /// The header, the L1 table, the refcount table and its first refcount block take the first
+    /// four clusters, and have a refcount of one.
+    fn empty_image(version: u32, refcount_order: u32) -> Vec<u8> {
+        let mut image = vec![0u8; 4 * CLUSTER];
+        put_u32(&mut image, 0, QCOW2_MAGIC);
+        put_u32(&mut image, 4, version);
+        put_u32(&mut image, 20, 9);
+        put_u64(&mut image, 24, DISK_LEN);
+        put_u32(&mut image, 36, 2);
+        put_u64(&mut image, 40, CLUSTER as u64);
+        put_u64(&mut image, 48, 2 * CLUSTER as u64);
+        put_u32(&mut image, 56, 1);
+        if version == 3 {
+            put_u32(&mut image, 96, refcount_order);
+            put_u32(&mut image, 100, 104);
+        }
+
+        put_u64(&mut image, 2 * CLUSTER, 3 * CLUSTER as u64);
+        let width = (1 << refcount_order) / 8;
+        for cluster in 0..4 {
+            image[3 * CLUSTER + (cluster + 1) * width - 1] = 1;
+        }
+        image
+    }
+
+    /// Point the first L2 entry of ``image`` to a preallocated zero cluster with the given
+    /// refcount, with 16 bits refcounts.
+    fn with_preallocated_zero_cluster(mut image: Vec<u8>, refcount: u8) -> Vec<u8> {
+        // The L2 table is cluster 4, the zero cluster is cluster 5 and is filled with garbage.
+        put_u64(&mut image, CLUSTER, (4 * CLUSTER as u64) | FLAG_COPIED);
+        image.resize(6 * CLUSTER, 0xee);
+        image[4 * CLUSTER..5 * CLUSTER].fill(0);
+        put_u64(&mut image, 4 * CLUSTER, (5 * CLUSTER as u64) | FLAG_ZERO);
+        image[3 * CLUSTER + 9] = 1;
+        image[3 * CLUSTER + 11] = refcount;
+        image
+    }
+
+    #[test]
+    fn unallocated_clusters_read_as_zeros() {
+        for version in [2, 3] {
+            let mut device = Qcow2Device::open(empty_image(version, 4)).unwrap();
+            assert_eq!(StorageDevice::len(&mut device), Ok(DISK_LEN));
+
+            let mut content = vec![0xffu8; DISK_LEN as usize];
+            device.read(0, &mut content).unwrap();
+            assert!(content.iter().all(|byte| *byte == 0));
+            assert_eq!(
+                device.read(DISK_LEN - 1, &mut [0; 2]),
+                Err(StorageDeviceError::OutOfBounds)
+            );
+        }
+    }
+
+    #[test]
+    fn writes_survive_reopening() {
+        for version in [2, 3] {
+            let mut device = Qcow2Device::open(empty_image(version, 4)).unwrap();
+            device.write(100, &[0x22; 50]).unwrap();
+            device.write(40_000, &[0x33; 2000]).unwrap();
+            device.flush().unwrap();
+
+            let (image, _) = device.into_inner();
+            let mut device = Qcow2Device::open(image).unwrap();
+            let mut expected = vec![0u8; DISK_LEN as usize];
+            expected[100..150].fill(0x22);
+            expected[40_000..42_000].fill(0x33);
+            let mut content = vec![0u8; DISK_LEN as usize];
+            device.read(0, &mut content).unwrap();
+            assert_eq!(content, expected);
+
+            // Rewriting allocated clusters happens in place.
+            let len = device.get_ref().len();
+            device.write(120, &[0x44; 10]).unwrap();
+            assert_eq!(device.get_ref().len(), len);
+        }
+    }
+
+    #[test]
+    fn unallocated_clusters_come_from_the_backing_file() {
+        let mut image = empty_image(2, 4);
+        put_u64(&mut image, 8, 200);
+        put_u32(&mut image, 16, 8);
+        image[200..208].copy_from_slice(b"base.img");
+        assert_eq!(
+            Qcow2Device::<Vec<u8>>::backing_file_name(&mut image),
+            Ok(Some(String::from("base.img")))
+        );
+        assert_eq!(
+            Qcow2Device::open(image.clone()).err(),
+            Some(StorageDeviceError::Unsupported)
+        );
+
+        // The backing file is shorter than the disk, and reads as zeros past its end.
+        let backing = vec![0x11u8; DISK_LEN as usize / 2];
+        let mut device = Qcow2Device::open_with_backing(image, backing).unwrap();
+        device.write(1000, &[0x22; 10]).unwrap();
+
+        let mut expected = vec![0u8; DISK_LEN as usize];
+        expected[..DISK_LEN as usize / 2].fill(0x11);
+        expected[1000..1010].fill(0x22);
+        let mut content = vec![0u8; DISK_LEN as usize];
+        device.read(0, &mut content).unwrap();
+        assert_eq!(content, expected);
+
+        // The rest of the written cluster was copied from the backing file.
+        let (image, backing) = device.into_inner();
+        let mut device = Qcow2Device::open_with_backing(image, vec![0u8; 0]).unwrap();
+        assert!(backing.unwrap().iter().all(|byte| *byte == 0x11));
+        let mut cluster = vec![0u8; CLUSTER];
+        device.read(512, &mut cluster).unwrap();
+        assert_eq!(cluster, expected[512..512 + CLUSTER]);
+    }
+
+    #[test]
+    fn refcount_blocks_are_allocated_when_needed() {
+        // With 64 bits refcounts, a refcount block covers 64 clusters.
+        let mut device = Qcow2Device::open(empty_image(3, 6)).unwrap();
+        device.write(0, &vec![0x55u8; DISK_LEN as usize]).unwrap();
+        assert!(device.refcount_table[1] != 0 && device.refcount_table[2] != 0);
+
+        let (image, _) = device.into_inner();
+        let clusters = image.len() / CLUSTER;
+        // The header and tables, two L2 tables, the data and two more refcount blocks.
+        assert_eq!(clusters, 4 + 2 + DISK_LEN as usize / CLUSTER + 2);
+        let mut device = Qcow2Device::open(image).unwrap();
+        for cluster in 0..clusters {
+            assert_eq!(device.refcount((cluster * CLUSTER) as u64), Ok(1));
+        }
+        let mut content = vec![0u8; DISK_LEN as usize];
+        device.read(0, &mut content).unwrap();
+        assert!(content.iter().all(|byte| *byte == 0x55));
+    }
+
+    #[test]
+    fn preallocated_zero_clusters_are_reused_when_not_shared() {
+        let image = with_preallocated_zero_cluster(empty_image(3, 4), 1);
+        let mut device = Qcow2Device::open(image).unwrap();
+        let mut cluster = vec![0xffu8; CLUSTER];
+        device.read(0, &mut cluster).unwrap();
+        assert!(cluster.iter().all(|byte| *byte == 0));
+
+        device.write(10, &[0x22; 10]).unwrap();
+        assert_eq!(device.get_ref().len(), 6 * CLUSTER);
+        let entry = be_u64(device.get_ref(), 4 * CLUSTER);
+        assert_eq!(entry, (5 * CLUSTER as u64) | FLAG_COPIED);
+
+        let mut expected = vec![0u8; CLUSTER];
+        expected[10..20].fill(0x22);
+        device.read(0, &mut cluster).unwrap();
+        assert_eq!(cluster, expected);
+    }
+
+    #[test]
+    fn shared_preallocated_zero_clusters_are_released() {
+        let image = with_preallocated_zero_cluster(empty_image(3, 4), 2);
+        let mut device = Qcow2Device::open(image).unwrap();
+        device.write(10, &[0x22; 10]).unwrap();
+
+        assert_eq!(device.get_ref().len(), 7 * CLUSTER);
+        let entry = be_u64(device.get_ref(), 4 * CLUSTER);
+        assert_eq!(entry, (6 * CLUSTER as u64) | FLAG_COPIED);
+        assert_eq!(device.refcount(5 * CLUSTER as u64), Ok(1));
+        assert_eq!(device.refcount(6 * CLUSTER as u64), Ok(1));
+    }
+
+    #[test]
+    fn unsupported_headers_are_rejected() {
+        let mut image = empty_image(3, 4);
+        image[0] = 0;
+        assert_eq!(
+            Qcow2Device::open(image).err(),
+            Some(StorageDeviceError::Corrupted)
+        );
+
+        let mut image = empty_image(3, 4);
+        put_u64(&mut image, 72, INCOMPATIBLE_CORRUPT);
+        assert_eq!(
+            Qcow2Device::open(image).err(),
+            Some(StorageDeviceError::Corrupted)
+        );
+
+        let mut image = empty_image(2, 4);
+        put_u32(&mut image, 32, 1);
+        assert_eq!(
+            Qcow2Device::open(image).err(),
+            Some(StorageDeviceError::Unsupported)
+        );
+
+        let mut image = empty_image(3, 4);
+        put_u64(&mut image, 72, 1 << 5);
+        assert_eq!(
+            Qcow2Device::open(image).err(),
+            Some(StorageDeviceError::Unsupported)
+        );
+
+        let mut image = empty_image(3, 4);
+        put_u32(&mut image, 4, 4);
+        assert_eq!(
+            Qcow2Device::open(image).err(),
+            Some(StorageDeviceError::Unsupported)
+        );
+
+        // Dirty images can be read, but not written.
+        let mut image = empty_image(3, 4);
+        put_u64(&mut image, 72, INCOMPATIBLE_DIRTY);
+        let mut device = Qcow2Device::open(image).unwrap();
+        device.read(0, &mut [0; 10]).unwrap();
+        assert_eq!(
+            device.write(0, &[0; 10]),
+            Err(StorageDeviceError::Unsupported)
+        );
+    }
+}
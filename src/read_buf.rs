@@ -0,0 +1,172 @@
+use core::mem::MaybeUninit;
+
+/// A buffer being filled by reads, which may start uninitialized.
+///
+/// The buffer is split in three parts: the bytes filled by reads so far, the bytes initialized
+/// but not filled yet, and the uninitialized bytes. Reading a large image into it avoids zeroing
+/// the whole buffer first, as devices copying their data into it directly, such as in-memory
+/// devices or files, never read the uninitialized bytes.
+///
+/// Bytes initialized once stay initialized after the buffer is [cleared](ReadBuf::clear), so
+/// reusing the buffer only pays for the initialization once on devices which need it.
+pub struct ReadBuf<'a> {
+    /// The whole buffer.
+    buf: &'a mut [MaybeUninit<u8>],
+
+    /// The number of bytes at the start of the buffer filled by reads.
+    filled: usize,
+
+    /// The number of bytes at the start of the buffer that are initialized.
+    initialized: usize,
+}
+
+impl core::fmt::Debug for ReadBuf<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ReadBuf")
+            .field("capacity", &self.capacity())
+            .field("filled", &self.filled)
+            .field("initialized", &self.initialized)
+            .finish()
+    }
+}
+
+impl<'a> ReadBuf<'a> {
+    /// Create an empty buffer over the initialized bytes of ``buf``.
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        let initialized = buf.len();
+        // SAFETY: MaybeUninit<u8> has the layout of u8, and the bytes can only be replaced by
+        // uninitialized ones through the unsafe ReadBuf::unfilled_mut, whose callers must not.
+        let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        ReadBuf {
+            buf,
+            filled: 0,
+            initialized,
+        }
+    }
+
+    /// Create an empty buffer over the uninitialized bytes of ``buf``.
+    pub fn uninit(buf: &'a mut [MaybeUninit<u8>]) -> Self {
+        ReadBuf {
+            buf,
+            filled: 0,
+            initialized: 0,
+        }
+    }
+
+    /// Return the size of the whole buffer, in bytes.
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Return the number of bytes left to fill.
+    pub fn remaining(&self) -> usize {
+        self.capacity() - self.filled
+    }
+
+    /// Return the number of bytes at the start of the buffer that are initialized.
+    pub fn initialized_len(&self) -> usize {
+        self.initialized
+    }
+
+    /// Return the bytes filled so far.
+    pub fn filled(&self) -> &[u8] {
+        // SAFETY: the filled bytes are initialized.
+        unsafe { &*(&self.buf[..self.filled] as *const [MaybeUninit<u8>] as *const [u8]) }
+    }
+
+    /// Return the bytes filled so far, mutably.
+    pub fn filled_mut(&mut self) -> &mut [u8] {
+        // SAFETY: the filled bytes are initialized.
+        unsafe { &mut *(&mut self.buf[..self.filled] as *mut [MaybeUninit<u8>] as *mut [u8]) }
+    }
+
+    /// Consume the buffer, returning the bytes filled.
+    pub fn into_filled(self) -> &'a mut [u8] {
+        let filled = self.filled;
+        // SAFETY: the filled bytes are initialized.
+        unsafe { &mut *(&mut self.buf[..filled] as *mut [MaybeUninit<u8>] as *mut [u8]) }
+    }
+
+    /// Mark every byte as unfilled, keeping them initialized.
+    pub fn clear(&mut self) {
+        self.filled = 0;
+    }
+
+    /// Initialize the bytes left to fill, zeroing the ones that aren't yet, and return them.
+    ///
+    /// Once filled, they are marked as such with [ReadBuf::advance].
+    pub fn initialize_unfilled(&mut self) -> &mut [u8] {
+        let remaining = self.remaining();
+        self.initialize_unfilled_to(remaining)
+    }
+
+    /// Initialize the first ``n`` bytes left to fill, zeroing the ones that aren't yet, and
+    /// return them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if fewer than ``n`` bytes are left to fill.
+    pub fn initialize_unfilled_to(&mut self, n: usize) -> &mut [u8] {
+        assert!(n <= self.remaining(), "n overflows the buffer");
+        let end = self.filled + n;
+        if end > self.initialized {
+            for byte in &mut self.buf[self.initialized..end] {
+                *byte = MaybeUninit::new(0);
+            }
+            self.initialized = end;
+        }
+        // SAFETY: the bytes up to end are initialized.
+        unsafe { &mut *(&mut self.buf[self.filled..end] as *mut [MaybeUninit<u8>] as *mut [u8]) }
+    }
+
+    /// Mark the next ``n`` initialized bytes as filled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if fewer than ``n`` bytes are initialized but not filled.
+    pub fn advance(&mut self, n: usize) {
+        assert!(
+            n <= self.initialized - self.filled,
+            "n overflows the initialized bytes"
+        );
+        self.filled += n;
+    }
+
+    /// Copy ``data`` to the bytes left to fill, marking them as filled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if fewer than ``data.len()`` bytes are left to fill.
+    pub fn append(&mut self, data: &[u8]) {
+        assert!(data.len() <= self.remaining(), "data overflows the buffer");
+        let end = self.filled + data.len();
+        for (byte, value) in self.buf[self.filled..end].iter_mut().zip(data) {
+            *byte = MaybeUninit::new(*value);
+        }
+        self.filled = end;
+        self.initialized = core::cmp::max(self.initialized, end);
+    }
+
+    /// Return the bytes left to fill, which may be uninitialized.
+    ///
+    /// Once written to, they are marked as initialized and filled with [ReadBuf::assume_init].
+    ///
+    /// # Safety
+    ///
+    /// The caller must not write uninitialized bytes to the buffer, as it may have been created
+    /// from initialized bytes.
+    pub unsafe fn unfilled_mut(&mut self) -> &mut [MaybeUninit<u8>] {
+        &mut self.buf[self.filled..]
+    }
+
+    /// Mark the next ``n`` bytes as initialized and filled.
+    ///
+    /// # Safety
+    ///
+    /// The caller must have initialized the next ``n`` bytes left to fill.
+    pub unsafe fn assume_init(&mut self, n: usize) {
+        debug_assert!(n <= self.remaining());
+        self.filled += n;
+        self.initialized = core::cmp::max(self.initialized, self.filled);
+    }
+}
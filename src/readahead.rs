@@ -0,0 +1,206 @@
+use crate::{Block, Capabilities, DeviceIdentity, DeviceInfo, StorageDevice, StorageDeviceResult};
+use alloc::vec::Vec;
+
+/// The default number of sequential reads after which data is prefetched.
+pub const DEFAULT_SEQUENTIAL_THRESHOLD: u32 = 2;
+
+/// A storage device prefetching the data following sequential reads.
+///
+/// Filesystems reading a file block by block send many small requests to the device. Once
+/// enough reads followed each other, the next one also fetches the following blocks into a
+/// buffer, in the same request, and the reads after it are answered from the buffer.
+///
+/// Writes and discards going through this wrapper update or drop the prefetched data, but
+/// changes made to the inner device through [ReadAheadDevice::get_mut] aren't seen until
+/// [ReadAheadDevice::invalidate] is called.
+#[derive(Debug)]
+pub struct ReadAheadDevice<S: StorageDevice> {
+    /// The inner storage device.
+    storage_device: S,
+
+    /// The prefetched data, as big as the read-ahead window.
+    window: Vec<u8>,
+
+    /// The offset of the prefetched data in the device.
+    window_offset: u64,
+
+    /// How many bytes of the window hold prefetched data.
+    window_len: usize,
+
+    /// Where the next read starts if the access is sequential.
+    next_offset: u64,
+
+    /// The number of reads that followed each other so far.
+    streak: u32,
+
+    /// The number of sequential reads after which data is prefetched.
+    threshold: u32,
+
+    /// The number of reads answered from the prefetched data.
+    hits: u64,
+}
+
+impl<S: StorageDevice> ReadAheadDevice<S> {
+    /// Wrap ``storage_device``, prefetching ``blocks`` blocks on sequential reads.
+    pub fn new(storage_device: S, blocks: usize) -> Self {
+        ReadAheadDevice {
+            storage_device,
+            window: alloc::vec![0u8; blocks * Block::LEN],
+            window_offset: 0,
+            window_len: 0,
+            next_offset: 0,
+            streak: 0,
+            threshold: DEFAULT_SEQUENTIAL_THRESHOLD,
+            hits: 0,
+        }
+    }
+
+    /// Start prefetching after ``threshold`` reads followed each other.
+    pub fn with_threshold(mut self, threshold: u32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Return the number of reads answered from the prefetched data so far.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Drop the prefetched data, e.g. after the inner device was modified directly.
+    pub fn invalidate(&mut self) {
+        self.window_len = 0;
+    }
+
+    /// Return a reference to the inner storage device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the inner storage device.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Consume the wrapper, returning the inner storage device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Return the range of the window holding the ``len`` bytes at ``offset``, if it does.
+    fn window_range(&self, offset: u64, len: usize) -> Option<core::ops::Range<usize>> {
+        let start = offset.checked_sub(self.window_offset)?;
+        let end = start.checked_add(len as u64)?;
+        if end > self.window_len as u64 {
+            return None;
+        }
+        Some(start as usize..end as usize)
+    }
+
+    /// Drop the prefetched data if it overlaps the ``len`` bytes at ``offset``.
+    fn invalidate_range(&mut self, offset: u64, len: u64) {
+        let window_end = self.window_offset + self.window_len as u64;
+        if offset < window_end && offset.saturating_add(len) > self.window_offset {
+            self.invalidate();
+        }
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for ReadAheadDevice<S> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        if offset == self.next_offset {
+            self.streak = self.streak.saturating_add(1);
+        } else {
+            self.streak = 0;
+        }
+        self.next_offset = offset.saturating_add(buf.len() as u64);
+
+        if let Some(range) = self.window_range(offset, buf.len()) {
+            buf.copy_from_slice(&self.window[range]);
+            self.hits += 1;
+            return Ok(());
+        }
+
+        if self.streak < self.threshold || buf.len() >= self.window.len() {
+            return self.storage_device.read(offset, buf);
+        }
+
+        // Fetch the request and the data following it at once, without going past the end.
+        let len = self.storage_device.len()?;
+        let window_len = core::cmp::min(self.window.len() as u64, len.saturating_sub(offset));
+        if window_len < buf.len() as u64 {
+            return self.storage_device.read(offset, buf);
+        }
+
+        self.window_len = 0;
+        self.storage_device
+            .read(offset, &mut self.window[..window_len as usize])?;
+        self.window_offset = offset;
+        self.window_len = window_len as usize;
+        buf.copy_from_slice(&self.window[..buf.len()]);
+        Ok(())
+    }
+
+    /// Write through, updating the prefetched data it overlaps.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.storage_device.write(offset, buf)?;
+
+        let window_end = self.window_offset + self.window_len as u64;
+        let end = offset.saturating_add(buf.len() as u64);
+        if offset < window_end && end > self.window_offset {
+            let start = core::cmp::max(offset, self.window_offset);
+            let end = core::cmp::min(end, window_end);
+            let window = (start - self.window_offset) as usize..(end - self.window_offset) as usize;
+            let data = (start - offset) as usize..(end - offset) as usize;
+            self.window[window].copy_from_slice(&buf[data]);
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.storage_device.len()
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.invalidate_range(offset, len);
+        self.storage_device.write_zeroes(offset, len)
+    }
+
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        self.invalidate_range(offset, len);
+        self.storage_device.fill(offset, len, byte)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.invalidate_range(offset, len);
+        self.storage_device.discard(offset, len)
+    }
+
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        self.storage_device.next_data(offset)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.storage_device.capabilities()
+    }
+
+    fn sanitize(&mut self) -> StorageDeviceResult<()> {
+        self.invalidate();
+        self.storage_device.sanitize()
+    }
+}
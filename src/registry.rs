@@ -0,0 +1,252 @@
+use crate::{
+    Capabilities, DeviceIdentity, DeviceInfo, SharedStorageDevice, StorageDevice,
+    StorageDeviceError, StorageDeviceResult,
+};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+/// What identifies a device in a [DeviceRegistry].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeviceKey {
+    /// The path of the backing file or device node. Should be canonicalized, so the same file
+    /// isn't registered twice under different names.
+    Path(PathBuf),
+
+    /// The UUID of the device, e.g. from its partition table or superblock.
+    Uuid([u8; 16]),
+}
+
+/// The device shared by a [SharedDevice].
+type SharedInner = Mutex<dyn StorageDevice + Send>;
+
+/// A storage device shared between several users, each request locking it for its duration.
+///
+/// Cloning it gives another handle to the same device.
+#[derive(Clone)]
+pub struct SharedDevice {
+    /// The shared device.
+    inner: Arc<SharedInner>,
+}
+
+impl core::fmt::Debug for SharedDevice {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+        fmt.debug_struct("SharedDevice")
+            .field("storage_device", &&*self.lock())
+            .finish()
+    }
+}
+
+impl SharedDevice {
+    /// Share ``storage_device``.
+    pub fn new<S: StorageDevice + Send + 'static>(storage_device: S) -> Self {
+        SharedDevice {
+            inner: Arc::new(Mutex::new(storage_device)),
+        }
+    }
+
+    /// Lock the device, to perform several requests without other users interleaving theirs.
+    pub fn lock(&self) -> MutexGuard<'_, dyn StorageDevice + Send + 'static> {
+        // A panic in another user doesn't break the device, so keep going.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Return whether ``self`` and ``other`` are handles to the same device.
+    pub fn ptr_eq(&self, other: &SharedDevice) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl StorageDevice for SharedDevice {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.lock().read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.lock().write(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.lock().len()
+    }
+
+    fn read_vectored(&mut self, offset: u64, bufs: &mut [&mut [u8]]) -> StorageDeviceResult<()> {
+        self.lock().read_vectored(offset, bufs)
+    }
+
+    fn write_vectored(&mut self, offset: u64, bufs: &[&[u8]]) -> StorageDeviceResult<()> {
+        self.lock().write_vectored(offset, bufs)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.lock().write_zeroes(offset, len)
+    }
+
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        self.lock().fill(offset, len, byte)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.lock().discard(offset, len)
+    }
+
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        self.lock().next_data(offset)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.lock().flush()
+    }
+
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.lock().write_fua(offset, buf)
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.lock().barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.lock().info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.lock().identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.lock().capabilities()
+    }
+
+    fn sanitize(&mut self) -> StorageDeviceResult<()> {
+        self.lock().sanitize()
+    }
+}
+
+impl SharedStorageDevice for SharedDevice {
+    fn read_shared(&self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.lock().read(offset, buf)
+    }
+
+    fn write_shared(&self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.lock().write(offset, buf)
+    }
+
+    fn len_shared(&self) -> StorageDeviceResult<u64> {
+        self.lock().len()
+    }
+
+    fn discard_shared(&self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.lock().discard(offset, len)
+    }
+
+    fn flush_shared(&self) -> StorageDeviceResult<()> {
+        self.lock().flush()
+    }
+
+    fn info_shared(&self) -> StorageDeviceResult<DeviceInfo> {
+        self.lock().info()
+    }
+}
+
+/// A registry of opened devices, so subsystems share a device instead of opening its backing
+/// storage a second time and corrupting it with uncoordinated caches.
+///
+/// Devices are held weakly: once every [SharedDevice] handle is dropped, the device is closed and
+/// its key can be opened again.
+#[derive(Debug)]
+pub struct DeviceRegistry {
+    /// The registered devices.
+    devices: Mutex<BTreeMap<DeviceKey, Weak<SharedInner>>>,
+}
+
+/// The registry returned by [DeviceRegistry::global].
+static GLOBAL_REGISTRY: DeviceRegistry = DeviceRegistry::new();
+
+impl DeviceRegistry {
+    /// Create an empty registry.
+    pub const fn new() -> Self {
+        DeviceRegistry {
+            devices: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Return the registry shared by the whole process.
+    pub fn global() -> &'static DeviceRegistry {
+        &GLOBAL_REGISTRY
+    }
+
+    /// Lock the map of devices, dropping the ones that were closed.
+    fn devices(&self) -> MutexGuard<'_, BTreeMap<DeviceKey, Weak<SharedInner>>> {
+        let mut devices = self
+            .devices
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        devices.retain(|_, device| device.strong_count() != 0);
+        devices
+    }
+
+    /// Return the device registered as ``key``, if it is still open.
+    pub fn get(&self, key: &DeviceKey) -> Option<SharedDevice> {
+        self.devices()
+            .get(key)
+            .and_then(Weak::upgrade)
+            .map(|inner| SharedDevice { inner })
+    }
+
+    /// Register ``storage_device`` as ``key``, and return a handle to it.
+    ///
+    /// Fail with [StorageDeviceError::Busy] if a device is already open under this key.
+    pub fn register<S: StorageDevice + Send + 'static>(
+        &self,
+        key: DeviceKey,
+        storage_device: S,
+    ) -> StorageDeviceResult<SharedDevice> {
+        let mut devices = self.devices();
+        if devices.contains_key(&key) {
+            return Err(StorageDeviceError::Busy);
+        }
+
+        let shared = SharedDevice::new(storage_device);
+        let weak: Weak<SharedInner> = Arc::downgrade(&shared.inner);
+        devices.insert(key, weak);
+        Ok(shared)
+    }
+
+    /// Return the device registered as ``key``, or open it with ``open`` and register it.
+    ///
+    /// The registry stays locked while opening, so concurrent callers never open the device twice.
+    pub fn get_or_open<S, F>(&self, key: DeviceKey, open: F) -> StorageDeviceResult<SharedDevice>
+    where
+        S: StorageDevice + Send + 'static,
+        F: FnOnce() -> StorageDeviceResult<S>,
+    {
+        let mut devices = self.devices();
+        if let Some(inner) = devices.get(&key).and_then(Weak::upgrade) {
+            return Ok(SharedDevice { inner });
+        }
+
+        let shared = SharedDevice::new(open()?);
+        let weak: Weak<SharedInner> = Arc::downgrade(&shared.inner);
+        devices.insert(key, weak);
+        Ok(shared)
+    }
+
+    /// Forget the device registered as ``key``, returning it if it is still open.
+    ///
+    /// Existing handles keep working, but the key can be registered again.
+    pub fn unregister(&self, key: &DeviceKey) -> Option<SharedDevice> {
+        self.devices()
+            .remove(key)
+            .and_then(|device| device.upgrade())
+            .map(|inner| SharedDevice { inner })
+    }
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> Self {
+        DeviceRegistry::new()
+    }
+}
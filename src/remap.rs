@@ -0,0 +1,594 @@
+use crate::crc::crc32;
+use crate::{
+    Block, BlockCount, BlockDevice, BlockError, BlockGeometry, BlockIndex, BlockResult,
+    Capabilities, DeviceIdentity, StorageDeviceError, StorageDeviceResult,
+};
+
+/// The number of blocks at the start of the device holding the header, and its backup copy.
+pub const REMAP_HEADER_BLOCKS: u64 = 2;
+
+/// The magic identifying the header of a [ScrambledBlockDevice].
+const REMAP_MAGIC: u32 = u32::from_le_bytes(*b"SCRM");
+
+/// The version of the layout of the header.
+const REMAP_VERSION: u32 = 1;
+
+/// The number of rounds of the Feistel network.
+const ROUNDS: u64 = 4;
+
+/// The parameters of the mapping, stored in the header.
+///
+/// It is stored in little endian as follow:
+///
+/// | Offset | Size | Field         |
+/// |--------|------|---------------|
+/// | 0      | 4    | magic         |
+/// | 4      | 4    | version       |
+/// | 8      | 8    | seed          |
+/// | 16     | 4    | extent_blocks |
+/// | 20     | 4    | crc           |
+///
+/// The CRC-32 covers the first 20 bytes.
+#[derive(Debug, Copy, Clone)]
+struct RemapHeader {
+    /// The seed of the permutation.
+    seed: u64,
+
+    /// The number of consecutive blocks moved together.
+    extent_blocks: u32,
+}
+
+impl RemapHeader {
+    /// Serialize the header into a block.
+    fn to_block(self) -> Block {
+        let mut block = Block::new();
+        block[0..4].copy_from_slice(&REMAP_MAGIC.to_le_bytes());
+        block[4..8].copy_from_slice(&REMAP_VERSION.to_le_bytes());
+        block[8..16].copy_from_slice(&self.seed.to_le_bytes());
+        block[16..20].copy_from_slice(&self.extent_blocks.to_le_bytes());
+        let crc = crc32(&block[0..20]);
+        block[20..24].copy_from_slice(&crc.to_le_bytes());
+        block
+    }
+
+    /// Deserialize the header from a block, returning None if it isn't valid.
+    fn from_block(block: &Block) -> Option<Self> {
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        let mut seed = [0u8; 8];
+        let mut extent_blocks = [0u8; 4];
+        let mut crc = [0u8; 4];
+
+        magic.copy_from_slice(&block[0..4]);
+        version.copy_from_slice(&block[4..8]);
+        seed.copy_from_slice(&block[8..16]);
+        extent_blocks.copy_from_slice(&block[16..20]);
+        crc.copy_from_slice(&block[20..24]);
+
+        if u32::from_le_bytes(magic) != REMAP_MAGIC
+            || u32::from_le_bytes(version) != REMAP_VERSION
+            || u32::from_le_bytes(crc) != crc32(&block[0..20])
+            || u32::from_le_bytes(extent_blocks) == 0
+        {
+            return None;
+        }
+
+        Some(RemapHeader {
+            seed: u64::from_le_bytes(seed),
+            extent_blocks: u32::from_le_bytes(extent_blocks),
+        })
+    }
+}
+
+/// Mix the bits of ``value``, the finalizer of SplitMix64.
+pub(crate) fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// A block device spreading its blocks across the medium with a fixed, seeded permutation of
+/// their addresses.
+///
+/// On simple flash without a translation layer, filesystems hammer a few blocks (FAT tables,
+/// superblocks, ...) which then wear out long before the rest of the medium. Scrambling the
+/// addresses doesn't level the wear, but places those hot blocks at unrelated positions on every
+/// device, e.g. by seeding from the serial number, so that a fleet doesn't fail at the same spot.
+///
+/// Blocks are moved by extents of consecutive blocks, which keeps multi-block requests contiguous
+/// within an extent. The seed and the extent size are stored in the first
+/// [REMAP_HEADER_BLOCKS] blocks of the device, so the mapping is the same across boots.
+#[derive(Debug)]
+pub struct ScrambledBlockDevice<B: BlockDevice> {
+    /// The inner block device.
+    block_device: B,
+
+    /// The parameters of the mapping.
+    header: RemapHeader,
+
+    /// The number of extents exposed.
+    extents: u64,
+
+    /// Half the number of bits of the permutation domain.
+    half_bits: u32,
+}
+
+impl<B: BlockDevice> ScrambledBlockDevice<B> {
+    /// Set up a new mapping on ``block_device``, writing its header.
+    ///
+    /// Blocks are moved by groups of ``extent_blocks``, which must not be zero. The existing
+    /// content of the device is meaningless afterwards.
+    pub fn format(mut block_device: B, seed: u64, extent_blocks: u32) -> StorageDeviceResult<Self> {
+        if extent_blocks == 0 {
+            return Err(StorageDeviceError::Unsupported);
+        }
+
+        let header = RemapHeader {
+            seed,
+            extent_blocks,
+        };
+        let block = header.to_block();
+        for index in 0..REMAP_HEADER_BLOCKS {
+            block_device.write(core::slice::from_ref(&block), BlockIndex(index))?;
+        }
+        block_device.flush()?;
+
+        Self::with_header(block_device, header)
+    }
+
+    /// Open the mapping previously set up on ``block_device`` with [ScrambledBlockDevice::format].
+    ///
+    /// Return [StorageDeviceError::Corrupted] if no copy of the header is valid.
+    pub fn open(mut block_device: B) -> StorageDeviceResult<Self> {
+        let mut block = [Block::new()];
+        for index in 0..REMAP_HEADER_BLOCKS {
+            if block_device.read(&mut block, BlockIndex(index)).is_err() {
+                continue;
+            }
+            if let Some(header) = RemapHeader::from_block(&block[0]) {
+                return Self::with_header(block_device, header);
+            }
+        }
+        Err(StorageDeviceError::Corrupted)
+    }
+
+    /// Build the device from its header.
+    fn with_header(mut block_device: B, header: RemapHeader) -> StorageDeviceResult<Self> {
+        let count = block_device.count()?.0;
+        let extents = count.saturating_sub(REMAP_HEADER_BLOCKS) / u64::from(header.extent_blocks);
+
+        // The permutation works on a domain of an even number of bits, at least as big as the
+        // number of extents.
+        let bits = 64 - extents.saturating_sub(1).leading_zeros();
+        let half_bits = core::cmp::max(1, bits.div_ceil(2));
+
+        Ok(ScrambledBlockDevice {
+            block_device,
+            header,
+            extents,
+            half_bits,
+        })
+    }
+
+    /// Return the seed of the permutation.
+    pub fn seed(&self) -> u64 {
+        self.header.seed
+    }
+
+    /// Return the number of consecutive blocks moved together.
+    pub fn extent_blocks(&self) -> u32 {
+        self.header.extent_blocks
+    }
+
+    /// Return a reference to the inner block device.
+    pub fn get_ref(&self) -> &B {
+        &self.block_device
+    }
+
+    /// Return a mutable reference to the inner block device.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.block_device
+    }
+
+    /// Consume the wrapper, returning the inner block device.
+    pub fn into_inner(self) -> B {
+        self.block_device
+    }
+
+    /// Apply the Feistel network to ``value``, a bijection of the permutation domain.
+    fn feistel(&self, value: u64) -> u64 {
+        let mask = (1u64 << self.half_bits) - 1;
+        let mut left = value >> self.half_bits;
+        let mut right = value & mask;
+        for round in 0..ROUNDS {
+            let f = mix(self.header.seed ^ (round << 56) ^ right) & mask;
+            let next = left ^ f;
+            left = right;
+            right = next;
+        }
+        (left << self.half_bits) | right
+    }
+
+    /// Return the physical extent holding the logical ``extent``.
+    fn physical_extent(&self, extent: u64) -> u64 {
+        // Walk the cycle of the permutation until landing inside the device, which takes less
+        // than 4 steps on average, as the domain is less than 4 times bigger than the device.
+        let mut value = self.feistel(extent);
+        while value >= self.extents {
+            value = self.feistel(value);
+        }
+        value
+    }
+
+    /// Return where the logical block at ``index`` is stored on the inner block device.
+    pub fn physical_index(&self, index: BlockIndex) -> BlockIndex {
+        let extent_blocks = u64::from(self.header.extent_blocks);
+        let extent = self.physical_extent(index.0 / extent_blocks);
+        BlockIndex(REMAP_HEADER_BLOCKS + extent * extent_blocks + index.0 % extent_blocks)
+    }
+
+    /// Return how many of the ``count`` blocks starting at ``index`` are stored contiguously.
+    fn run_len(&self, index: BlockIndex, count: u64) -> u64 {
+        let extent_blocks = u64::from(self.header.extent_blocks);
+        core::cmp::min(extent_blocks - index.0 % extent_blocks, count)
+    }
+
+    /// Return the number of blocks exposed.
+    fn block_count(&self) -> u64 {
+        self.extents * u64::from(self.header.extent_blocks)
+    }
+
+    /// Check whether ``count`` blocks starting at ``index`` are inside the device.
+    fn in_bounds(&self, index: BlockIndex, count: usize) -> bool {
+        matches!(index.0.checked_add(count as u64), Some(end) if end <= self.block_count())
+    }
+}
+
+impl<B: BlockDevice> BlockDevice for ScrambledBlockDevice<B> {
+    fn read(&mut self, mut blocks: &mut [Block], mut index: BlockIndex) -> BlockResult<()> {
+        if !self.in_bounds(index, blocks.len()) {
+            return Err(BlockError::ReadError);
+        }
+        while !blocks.is_empty() {
+            let run = self.run_len(index, blocks.len() as u64) as usize;
+            let (head, tail) = blocks.split_at_mut(run);
+            self.block_device.read(head, self.physical_index(index))?;
+            blocks = tail;
+            index += BlockCount(run as u64);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, mut blocks: &[Block], mut index: BlockIndex) -> BlockResult<()> {
+        if !self.in_bounds(index, blocks.len()) {
+            return Err(BlockError::WriteError);
+        }
+        while !blocks.is_empty() {
+            let run = self.run_len(index, blocks.len() as u64) as usize;
+            let (head, tail) = blocks.split_at(run);
+            self.block_device.write(head, self.physical_index(index))?;
+            blocks = tail;
+            index += BlockCount(run as u64);
+        }
+        Ok(())
+    }
+
+    /// Return the number of blocks in whole extents, without the header.
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        Ok(BlockCount(self.block_count()))
+    }
+
+    fn flush(&mut self) -> BlockResult<()> {
+        self.block_device.flush()
+    }
+
+    fn write_fua(&mut self, mut blocks: &[Block], mut index: BlockIndex) -> BlockResult<()> {
+        if !self.in_bounds(index, blocks.len()) {
+            return Err(BlockError::WriteError);
+        }
+        while !blocks.is_empty() {
+            let run = self.run_len(index, blocks.len() as u64) as usize;
+            let (head, tail) = blocks.split_at(run);
+            self.block_device
+                .write_fua(head, self.physical_index(index))?;
+            blocks = tail;
+            index += BlockCount(run as u64);
+        }
+        Ok(())
+    }
+
+    fn barrier(&mut self) -> BlockResult<()> {
+        self.block_device.barrier()
+    }
+
+    fn discard(&mut self, mut index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        let end = core::cmp::min(index.0.saturating_add(count.0), self.block_count());
+        while index.0 < end {
+            let run = self.run_len(index, end - index.0);
+            self.block_device
+                .discard(self.physical_index(index), BlockCount(run))?;
+            index = BlockIndex(index.0 + run);
+        }
+        Ok(())
+    }
+
+    fn geometry(&mut self) -> BlockResult<BlockGeometry> {
+        self.block_device.geometry()
+    }
+
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        self.block_device.identity()
+    }
+
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        self.block_device.capabilities()
+    }
+}
+
+/// Where a run of logical blocks of a [RemappedDevice] is stored, as returned by a [BlockMap].
+#[derive(Debug, Copy, Clone)]
+pub struct BlockMapping {
+    /// The physical block holding the first block of the run, or None if the run is a hole.
+    pub physical: Option<BlockIndex>,
+
+    /// The number of blocks of the run, stored contiguously, at least 1.
+    pub count: BlockCount,
+}
+
+/// The translation of the logical blocks of a [RemappedDevice] to the physical blocks of the
+/// inner device.
+///
+/// It is implemented for closures taking a logical block index, and by [ExtentTable].
+pub trait BlockMap {
+    /// Return where the logical block at ``index`` is stored, along with the number of blocks
+    /// after it stored contiguously, or forming the same hole.
+    fn lookup(&mut self, index: BlockIndex) -> BlockResult<BlockMapping>;
+}
+
+impl<F: FnMut(BlockIndex) -> BlockResult<BlockMapping>> BlockMap for F {
+    fn lookup(&mut self, index: BlockIndex) -> BlockResult<BlockMapping> {
+        self(index)
+    }
+}
+
+/// A run of logical blocks stored contiguously on the inner device of a [RemappedDevice].
+#[derive(Debug, Copy, Clone)]
+pub struct RemapExtent {
+    /// The first logical block of the run.
+    pub logical: BlockIndex,
+
+    /// The physical block holding the first logical block.
+    pub physical: BlockIndex,
+
+    /// The number of blocks of the run.
+    pub count: BlockCount,
+}
+
+/// A [BlockMap] made of a table of extents, in any order, the blocks outside of them being holes.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, Default)]
+pub struct ExtentTable {
+    /// The extents, sorted by logical block.
+    extents: alloc::vec::Vec<RemapExtent>,
+}
+
+#[cfg(feature = "alloc")]
+impl ExtentTable {
+    /// Create a table from ``extents``, in any order.
+    ///
+    /// Return [BlockError::Unsupported] if logical ranges of extents overlap. Empty extents are
+    /// dropped.
+    pub fn new(mut extents: alloc::vec::Vec<RemapExtent>) -> BlockResult<Self> {
+        extents.retain(|extent| extent.count.0 != 0);
+        extents.sort_unstable_by_key(|extent| extent.logical.0);
+        for pair in extents.windows(2) {
+            match pair[0].logical.0.checked_add(pair[0].count.0) {
+                Some(end) if end <= pair[1].logical.0 => {}
+                _ => return Err(BlockError::Unsupported),
+            }
+        }
+        Ok(ExtentTable { extents })
+    }
+
+    /// Return the extents, sorted by logical block.
+    pub fn extents(&self) -> &[RemapExtent] {
+        &self.extents
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl BlockMap for ExtentTable {
+    fn lookup(&mut self, index: BlockIndex) -> BlockResult<BlockMapping> {
+        let position = self
+            .extents
+            .partition_point(|extent| extent.logical.0 + extent.count.0 <= index.0);
+        Ok(match self.extents.get(position) {
+            Some(extent) if extent.logical.0 <= index.0 => {
+                let offset = index.0 - extent.logical.0;
+                BlockMapping {
+                    physical: Some(BlockIndex(extent.physical.0 + offset)),
+                    count: BlockCount(extent.count.0 - offset),
+                }
+            }
+            Some(extent) => BlockMapping {
+                physical: None,
+                count: BlockCount(extent.logical.0 - index.0),
+            },
+            None => BlockMapping {
+                physical: None,
+                count: BlockCount(u64::MAX - index.0),
+            },
+        })
+    }
+}
+
+/// A block device translating its block addresses through a [BlockMap], such as a table of
+/// extents or a closure.
+///
+/// Logical blocks may be stored in any order on the inner device, and the blocks the map reports
+/// as holes read as zeroes. Writing to a hole fails with [BlockError::WriteError], and discarding
+/// one does nothing: devices allocating blocks on write do it in their map. Requests are split in
+/// runs of blocks stored contiguously.
+///
+/// This is the building block of sparse formats, flash translation layers and partition
+/// migration, which only have to maintain their map.
+pub struct RemappedDevice<B: BlockDevice, M: BlockMap> {
+    /// The inner block device.
+    block_device: B,
+
+    /// The translation of logical blocks to physical ones.
+    map: M,
+
+    /// The number of logical blocks.
+    count: BlockCount,
+}
+
+impl<B: BlockDevice, M: BlockMap> core::fmt::Debug for RemappedDevice<B, M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RemappedDevice")
+            .field("block_device", &self.block_device)
+            .field("count", &self.count)
+            .finish()
+    }
+}
+
+impl<B: BlockDevice, M: BlockMap> RemappedDevice<B, M> {
+    /// Expose ``count`` logical blocks, stored on ``block_device`` as ``map`` tells.
+    pub fn new(block_device: B, map: M, count: BlockCount) -> Self {
+        RemappedDevice {
+            block_device,
+            map,
+            count,
+        }
+    }
+
+    /// Return a reference to the map.
+    pub fn map(&self) -> &M {
+        &self.map
+    }
+
+    /// Return a mutable reference to the map, e.g. to move extents.
+    pub fn map_mut(&mut self) -> &mut M {
+        &mut self.map
+    }
+
+    /// Return a reference to the inner block device.
+    pub fn get_ref(&self) -> &B {
+        &self.block_device
+    }
+
+    /// Return a mutable reference to the inner block device.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.block_device
+    }
+
+    /// Consume the wrapper, returning the inner block device and the map.
+    pub fn into_inner(self) -> (B, M) {
+        (self.block_device, self.map)
+    }
+
+    /// Return where the block at ``index`` is stored, and how many of the ``count`` blocks
+    /// starting there are stored with it.
+    fn lookup_run(
+        &mut self,
+        index: BlockIndex,
+        count: u64,
+    ) -> BlockResult<(Option<BlockIndex>, u64)> {
+        let mapping = self.map.lookup(index)?;
+        Ok((mapping.physical, mapping.count.0.clamp(1, count)))
+    }
+
+    /// Check whether ``count`` blocks starting at ``index`` are inside the device.
+    fn in_bounds(&self, index: BlockIndex, count: usize) -> bool {
+        matches!(index.0.checked_add(count as u64), Some(end) if end <= self.count.0)
+    }
+
+    /// Write ``blocks`` at ``index`` run by run, with ``write`` or ``write_fua``.
+    fn write_runs(
+        &mut self,
+        mut blocks: &[Block],
+        mut index: BlockIndex,
+        fua: bool,
+    ) -> BlockResult<()> {
+        if !self.in_bounds(index, blocks.len()) {
+            return Err(BlockError::WriteError);
+        }
+        while !blocks.is_empty() {
+            let (physical, run) = self.lookup_run(index, blocks.len() as u64)?;
+            let physical = physical.ok_or(BlockError::WriteError)?;
+            let (head, tail) = blocks.split_at(run as usize);
+            if fua {
+                self.block_device.write_fua(head, physical)?;
+            } else {
+                self.block_device.write(head, physical)?;
+            }
+            blocks = tail;
+            index = BlockIndex(index.0 + run);
+        }
+        Ok(())
+    }
+}
+
+impl<B: BlockDevice, M: BlockMap> BlockDevice for RemappedDevice<B, M> {
+    fn read(&mut self, mut blocks: &mut [Block], mut index: BlockIndex) -> BlockResult<()> {
+        if !self.in_bounds(index, blocks.len()) {
+            return Err(BlockError::ReadError);
+        }
+        while !blocks.is_empty() {
+            let (physical, run) = self.lookup_run(index, blocks.len() as u64)?;
+            let (head, tail) = blocks.split_at_mut(run as usize);
+            match physical {
+                Some(physical) => self.block_device.read(head, physical)?,
+                None => head.fill(Block::new()),
+            }
+            blocks = tail;
+            index = BlockIndex(index.0 + run);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.write_runs(blocks, index, false)
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        Ok(self.count)
+    }
+
+    fn flush(&mut self) -> BlockResult<()> {
+        self.block_device.flush()
+    }
+
+    fn write_fua(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.write_runs(blocks, index, true)
+    }
+
+    fn barrier(&mut self) -> BlockResult<()> {
+        self.block_device.barrier()
+    }
+
+    fn discard(&mut self, mut index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        let end = core::cmp::min(index.0.saturating_add(count.0), self.count.0);
+        while index.0 < end {
+            let (physical, run) = self.lookup_run(index, end - index.0)?;
+            if let Some(physical) = physical {
+                self.block_device.discard(physical, BlockCount(run))?;
+            }
+            index = BlockIndex(index.0 + run);
+        }
+        Ok(())
+    }
+
+    fn geometry(&mut self) -> BlockResult<BlockGeometry> {
+        self.block_device.geometry()
+    }
+
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        self.block_device.identity()
+    }
+
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        self.block_device.capabilities()
+    }
+}
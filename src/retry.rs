@@ -0,0 +1,197 @@
+use crate::clock::Delay;
+use crate::{
+    Capabilities, DeviceIdentity, DeviceInfo, Resizable, StorageDevice, StorageDeviceError,
+    StorageDeviceResult,
+};
+
+/// When and how often a [RetryingDevice] retries a failed operation.
+#[derive(Debug, Copy, Clone)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+
+    /// The time to wait before the first retry, in nanoseconds.
+    pub initial_delay: u64,
+
+    /// The factor the time to wait is multiplied by after every retry.
+    pub multiplier: u32,
+
+    /// The maximum time to wait before a retry, in nanoseconds.
+    pub max_delay: u64,
+
+    /// Whether an error is worth retrying.
+    pub should_retry: fn(StorageDeviceError) -> bool,
+}
+
+impl Default for RetryPolicy {
+    /// Make up to 3 attempts, waiting 1ms then 2ms, on transient errors.
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay: 1_000_000,
+            multiplier: 2,
+            max_delay: 100_000_000,
+            should_retry: |err| err.is_transient(),
+        }
+    }
+}
+
+/// A storage device retrying the operations that fail with a transient error.
+///
+/// SD cards and USB bridges routinely fail requests that succeed when tried again. This wrapper
+/// retries them according to a [RetryPolicy], backing off exponentially between attempts, so
+/// callers don't all have to.
+///
+/// Retried writes are written again in full, which is harmless as writes are idempotent.
+#[derive(Debug)]
+pub struct RetryingDevice<S: StorageDevice, D: Delay> {
+    /// The inner storage device.
+    storage_device: S,
+
+    /// How to wait between attempts.
+    delay: D,
+
+    /// When and how often to retry.
+    policy: RetryPolicy,
+
+    /// The number of retries performed so far.
+    retries: u64,
+}
+
+impl<S: StorageDevice, D: Delay> RetryingDevice<S, D> {
+    /// Wrap ``storage_device``, waiting with ``delay`` between attempts according to the default
+    /// [RetryPolicy].
+    pub fn new(storage_device: S, delay: D) -> Self {
+        RetryingDevice {
+            storage_device,
+            delay,
+            policy: RetryPolicy::default(),
+            retries: 0,
+        }
+    }
+
+    /// Set when and how often to retry.
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Return the number of retries performed so far, a hint at the health of the device.
+    pub fn retries(&self) -> u64 {
+        self.retries
+    }
+
+    /// Return a reference to the inner storage device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the inner storage device.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Consume the wrapper, returning the inner storage device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Perform ``operation``, retrying it according to the policy.
+    ///
+    /// Return the error of the last attempt if they all failed.
+    fn retry<T>(
+        &mut self,
+        mut operation: impl FnMut(&mut S) -> StorageDeviceResult<T>,
+    ) -> StorageDeviceResult<T> {
+        let mut delay = self.policy.initial_delay;
+        let mut attempt = 1;
+        loop {
+            match operation(&mut self.storage_device) {
+                Err(err)
+                    if attempt < self.policy.max_attempts && (self.policy.should_retry)(err) =>
+                {
+                    self.delay.delay(delay);
+                    delay = core::cmp::min(
+                        delay.saturating_mul(u64::from(self.policy.multiplier)),
+                        self.policy.max_delay,
+                    );
+                    attempt += 1;
+                    self.retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<S: StorageDevice, D: Delay> StorageDevice for RetryingDevice<S, D> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.retry(|storage_device| storage_device.read(offset, buf))
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.retry(|storage_device| storage_device.write(offset, buf))
+    }
+
+    fn read_partial(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        self.retry(|storage_device| storage_device.read_partial(offset, buf))
+    }
+
+    fn write_partial(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<usize> {
+        self.retry(|storage_device| storage_device.write_partial(offset, buf))
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.retry(|storage_device| storage_device.len())
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.retry(|storage_device| storage_device.write_zeroes(offset, len))
+    }
+
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        self.retry(|storage_device| storage_device.fill(offset, len, byte))
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.retry(|storage_device| storage_device.discard(offset, len))
+    }
+
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        self.retry(|storage_device| storage_device.next_data(offset))
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.retry(|storage_device| storage_device.flush())
+    }
+
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.retry(|storage_device| storage_device.write_fua(offset, buf))
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.retry(|storage_device| storage_device.barrier())
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.retry(|storage_device| storage_device.info())
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.retry(|storage_device| storage_device.identity())
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.retry(|storage_device| storage_device.capabilities())
+    }
+
+    fn sanitize(&mut self) -> StorageDeviceResult<()> {
+        self.retry(|storage_device| storage_device.sanitize())
+    }
+}
+
+impl<S: Resizable, D: Delay> Resizable for RetryingDevice<S, D> {
+    fn set_len(&mut self, len: u64) -> StorageDeviceResult<()> {
+        self.retry(|storage_device| storage_device.set_len(len))
+    }
+}
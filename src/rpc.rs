@@ -0,0 +1,447 @@
+use crate::{DeviceInfo, StorageDevice, StorageDeviceError, StorageDeviceResult};
+
+/// The version of the protocol implemented by [RpcClient] and [RpcServer].
+pub const RPC_PROTOCOL_VERSION: u32 = 1;
+
+/// The magic sent by the client when connecting.
+const RPC_MAGIC: [u8; 4] = *b"SDRP";
+
+/// The maximum number of bytes transferred by a single read or write request.
+///
+/// Bigger transfers are split by the client, so the server only needs a small, fixed buffer.
+pub const RPC_MAX_TRANSFER: usize = 4096;
+
+/// The size of the header of a request: opcode, offset and length.
+const REQUEST_HEADER_LEN: usize = 1 + 8 + 4;
+
+/// A bidirectional byte stream carrying the frames of the protocol, such as a TCP connection,
+/// a vsock or a serial port.
+pub trait Transport: core::fmt::Debug {
+    /// Send all of ``buf``.
+    fn send(&mut self, buf: &[u8]) -> StorageDeviceResult<()>;
+
+    /// Fill ``buf`` with the next received bytes, waiting for them if needed.
+    fn recv(&mut self, buf: &mut [u8]) -> StorageDeviceResult<()>;
+
+    /// Push the bytes sent so far to the other end.
+    ///
+    /// Called after every frame. The default implementation does nothing.
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        Ok(())
+    }
+}
+
+/// A [Transport] over a [std::io::Read] and [std::io::Write] stream.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct IoTransport<T> {
+    /// The underlying stream.
+    stream: T,
+}
+
+#[cfg(feature = "std")]
+impl<T: std::io::Read + std::io::Write + core::fmt::Debug> IoTransport<T> {
+    /// Use ``stream`` as a transport.
+    pub fn new(stream: T) -> Self {
+        IoTransport { stream }
+    }
+
+    /// Return a reference to the underlying stream.
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    /// Consume the transport, returning the underlying stream.
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: std::io::Read + std::io::Write + core::fmt::Debug> Transport for IoTransport<T> {
+    fn send(&mut self, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.stream
+            .write_all(buf)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.stream
+            .read_exact(buf)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::ReadError))
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.stream
+            .flush()
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+}
+
+/// The operation requested by a frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Opcode {
+    /// Check the protocol version. The payload is the magic followed by the version.
+    Hello = 0,
+
+    /// Read ``length`` bytes at ``offset``.
+    Read = 1,
+
+    /// Write the payload at ``offset``.
+    Write = 2,
+
+    /// Flush the device.
+    Flush = 3,
+
+    /// Return the information about the device.
+    Info = 4,
+
+    /// Discard ``length`` bytes at ``offset``.
+    Discard = 5,
+
+    /// Return the size of the device.
+    Len = 6,
+}
+
+impl Opcode {
+    /// Decode an opcode.
+    fn from_u8(value: u8) -> Option<Opcode> {
+        match value {
+            0 => Some(Opcode::Hello),
+            1 => Some(Opcode::Read),
+            2 => Some(Opcode::Write),
+            3 => Some(Opcode::Flush),
+            4 => Some(Opcode::Info),
+            5 => Some(Opcode::Discard),
+            6 => Some(Opcode::Len),
+            _ => None,
+        }
+    }
+}
+
+/// Encode the result of a request as the status byte of its response.
+fn encode_status(result: &StorageDeviceResult<()>) -> u8 {
+    match result {
+        Ok(()) => 0,
+        Err(StorageDeviceError::ReadError) => 1,
+        Err(StorageDeviceError::WriteError) => 2,
+        Err(StorageDeviceError::OutOfBounds) => 3,
+        Err(StorageDeviceError::Corrupted) => 4,
+        Err(StorageDeviceError::Busy) => 5,
+        Err(StorageDeviceError::MediaError) => 6,
+        Err(StorageDeviceError::OutOfSpace) => 7,
+        Err(StorageDeviceError::Unsupported) => 8,
+        Err(StorageDeviceError::VerificationFailed) => 9,
+        Err(StorageDeviceError::InvalidRequest) => 10,
+        Err(StorageDeviceError::Unknown) => 255,
+    }
+}
+
+/// Decode the status byte of a response.
+fn decode_status(status: u8) -> StorageDeviceResult<()> {
+    match status {
+        0 => Ok(()),
+        1 => Err(StorageDeviceError::ReadError),
+        2 => Err(StorageDeviceError::WriteError),
+        3 => Err(StorageDeviceError::OutOfBounds),
+        4 => Err(StorageDeviceError::Corrupted),
+        5 => Err(StorageDeviceError::Busy),
+        6 => Err(StorageDeviceError::MediaError),
+        7 => Err(StorageDeviceError::OutOfSpace),
+        8 => Err(StorageDeviceError::Unsupported),
+        9 => Err(StorageDeviceError::VerificationFailed),
+        10 => Err(StorageDeviceError::InvalidRequest),
+        _ => Err(StorageDeviceError::Unknown),
+    }
+}
+
+/// Send a frame made of ``parts``, prefixed by their total length.
+fn send_frame<T: Transport>(transport: &mut T, parts: &[&[u8]]) -> StorageDeviceResult<()> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    transport.send(&(len as u32).to_le_bytes())?;
+    for part in parts {
+        transport.send(part)?;
+    }
+    transport.flush()
+}
+
+/// Receive the length prefix of the next frame.
+fn recv_frame_len<T: Transport>(transport: &mut T) -> StorageDeviceResult<usize> {
+    let mut len = [0u8; 4];
+    transport.recv(&mut len)?;
+    Ok(u32::from_le_bytes(len) as usize)
+}
+
+/// Receive and drop ``len`` bytes, to skip the rest of a frame.
+fn skip<T: Transport>(transport: &mut T, mut len: usize) -> StorageDeviceResult<()> {
+    let mut buf = [0u8; 64];
+    while len != 0 {
+        let chunk = core::cmp::min(len, buf.len());
+        transport.recv(&mut buf[..chunk])?;
+        len -= chunk;
+    }
+    Ok(())
+}
+
+/// A storage device served by an [RpcServer] at the other end of a [Transport].
+///
+/// Every request waits for its response before returning.
+#[derive(Debug)]
+pub struct RpcClient<T: Transport> {
+    /// The transport connected to the server.
+    transport: T,
+}
+
+impl<T: Transport> RpcClient<T> {
+    /// Connect to the server at the other end of ``transport``, checking that it speaks the same
+    /// version of the protocol.
+    ///
+    /// Fail with [StorageDeviceError::Unsupported] if it doesn't.
+    pub fn connect(transport: T) -> StorageDeviceResult<Self> {
+        let mut client = RpcClient { transport };
+
+        let mut hello = [0u8; 8];
+        hello[..4].copy_from_slice(&RPC_MAGIC);
+        hello[4..].copy_from_slice(&RPC_PROTOCOL_VERSION.to_le_bytes());
+
+        let mut version = [0u8; 4];
+        client.call(Opcode::Hello, 0, 0, &hello, &mut version)?;
+        if u32::from_le_bytes(version) != RPC_PROTOCOL_VERSION {
+            return Err(StorageDeviceError::Unsupported);
+        }
+
+        Ok(client)
+    }
+
+    /// Return a reference to the transport.
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    /// Consume the client, returning the transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Send a request, and receive its response into ``response``, which must be of the exact
+    /// expected size.
+    fn call(
+        &mut self,
+        opcode: Opcode,
+        offset: u64,
+        len: u32,
+        payload: &[u8],
+        response: &mut [u8],
+    ) -> StorageDeviceResult<()> {
+        let mut header = [0u8; REQUEST_HEADER_LEN];
+        header[0] = opcode as u8;
+        header[1..9].copy_from_slice(&offset.to_le_bytes());
+        header[9..13].copy_from_slice(&len.to_le_bytes());
+        send_frame(&mut self.transport, &[&header, payload])?;
+
+        let frame_len = recv_frame_len(&mut self.transport)?;
+        if frame_len == 0 {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        let mut status = [0u8];
+        self.transport.recv(&mut status)?;
+        if let Err(err) = decode_status(status[0]) {
+            skip(&mut self.transport, frame_len - 1)?;
+            return Err(err);
+        }
+
+        if frame_len - 1 != response.len() {
+            skip(&mut self.transport, frame_len - 1)?;
+            return Err(StorageDeviceError::Corrupted);
+        }
+        self.transport.recv(response)
+    }
+}
+
+impl<T: Transport> StorageDevice for RpcClient<T> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        let mut read_size = 0;
+        for chunk in buf.chunks_mut(RPC_MAX_TRANSFER) {
+            let chunk_offset = offset
+                .checked_add(read_size as u64)
+                .ok_or(StorageDeviceError::OutOfBounds)?;
+            self.call(Opcode::Read, chunk_offset, chunk.len() as u32, &[], chunk)?;
+            read_size += chunk.len();
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let mut write_size = 0;
+        for chunk in buf.chunks(RPC_MAX_TRANSFER) {
+            let chunk_offset = offset
+                .checked_add(write_size as u64)
+                .ok_or(StorageDeviceError::OutOfBounds)?;
+            self.call(
+                Opcode::Write,
+                chunk_offset,
+                chunk.len() as u32,
+                chunk,
+                &mut [],
+            )?;
+            write_size += chunk.len();
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        let mut len = [0u8; 8];
+        self.call(Opcode::Len, 0, 0, &[], &mut len)?;
+        Ok(u64::from_le_bytes(len))
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        // The length field is 32 bits wide, split huge discards.
+        let end = offset
+            .checked_add(len)
+            .ok_or(StorageDeviceError::OutOfBounds)?;
+        let mut current = offset;
+        while current < end {
+            let chunk = core::cmp::min(end - current, u64::from(u32::MAX));
+            self.call(Opcode::Discard, current, chunk as u32, &[], &mut [])?;
+            current += chunk;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.call(Opcode::Flush, 0, 0, &[], &mut [])
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        let mut flags = [0u8];
+        self.call(Opcode::Info, 0, 0, &[], &mut flags)?;
+        Ok(DeviceInfo {
+            supports_holes: flags[0] & 1 != 0,
+            ..DeviceInfo::default()
+        })
+    }
+}
+
+/// Serve a local storage device to an [RpcClient] at the other end of a [Transport].
+#[derive(Debug)]
+pub struct RpcServer<S: StorageDevice, T: Transport> {
+    /// The device being served.
+    storage_device: S,
+
+    /// The transport connected to the client.
+    transport: T,
+}
+
+impl<S: StorageDevice, T: Transport> RpcServer<S, T> {
+    /// Serve ``storage_device`` over ``transport``.
+    pub fn new(storage_device: S, transport: T) -> Self {
+        RpcServer {
+            storage_device,
+            transport,
+        }
+    }
+
+    /// Return a reference to the device being served.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the device being served.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Consume the server, returning the device and the transport.
+    pub fn into_inner(self) -> (S, T) {
+        (self.storage_device, self.transport)
+    }
+
+    /// Serve requests until the transport fails, which usually means the client disconnected.
+    ///
+    /// Return the error of the transport.
+    pub fn serve(&mut self) -> StorageDeviceError {
+        loop {
+            if let Err(err) = self.serve_one() {
+                return err;
+            }
+        }
+    }
+
+    /// Receive a single request, perform it and send its response.
+    ///
+    /// Errors of the device are sent to the client: an error is only returned if the transport
+    /// failed.
+    pub fn serve_one(&mut self) -> StorageDeviceResult<()> {
+        let frame_len = recv_frame_len(&mut self.transport)?;
+        if frame_len < REQUEST_HEADER_LEN {
+            skip(&mut self.transport, frame_len)?;
+            return self.respond(Err(StorageDeviceError::Corrupted), &[]);
+        }
+
+        let mut header = [0u8; REQUEST_HEADER_LEN];
+        self.transport.recv(&mut header)?;
+        let payload_len = frame_len - REQUEST_HEADER_LEN;
+
+        let mut offset = [0u8; 8];
+        offset.copy_from_slice(&header[1..9]);
+        let offset = u64::from_le_bytes(offset);
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[9..13]);
+        let len = u32::from_le_bytes(len) as usize;
+
+        let mut buf = [0u8; RPC_MAX_TRANSFER];
+
+        match Opcode::from_u8(header[0]) {
+            Some(Opcode::Hello) if payload_len == 8 => {
+                self.transport.recv(&mut buf[..8])?;
+                if buf[..4] != RPC_MAGIC {
+                    return self.respond(Err(StorageDeviceError::Unsupported), &[]);
+                }
+                self.respond(Ok(()), &RPC_PROTOCOL_VERSION.to_le_bytes())
+            }
+            Some(Opcode::Read) if payload_len == 0 && len <= RPC_MAX_TRANSFER => {
+                match self.storage_device.read(offset, &mut buf[..len]) {
+                    Ok(()) => self.respond(Ok(()), &buf[..len]),
+                    Err(err) => self.respond(Err(err), &[]),
+                }
+            }
+            Some(Opcode::Write) if payload_len == len && len <= RPC_MAX_TRANSFER => {
+                self.transport.recv(&mut buf[..len])?;
+                let result = self.storage_device.write(offset, &buf[..len]);
+                self.respond(result, &[])
+            }
+            Some(Opcode::Flush) if payload_len == 0 => {
+                let result = self.storage_device.flush();
+                self.respond(result, &[])
+            }
+            Some(Opcode::Info) if payload_len == 0 => match self.storage_device.info() {
+                Ok(info) => self.respond(Ok(()), &[info.supports_holes as u8]),
+                Err(err) => self.respond(Err(err), &[]),
+            },
+            Some(Opcode::Discard) if payload_len == 0 => {
+                let result = self.storage_device.discard(offset, len as u64);
+                self.respond(result, &[])
+            }
+            Some(Opcode::Len) if payload_len == 0 => match self.storage_device.len() {
+                Ok(device_len) => self.respond(Ok(()), &device_len.to_le_bytes()),
+                Err(err) => self.respond(Err(err), &[]),
+            },
+            // Unknown or malformed request, skip it.
+            _ => {
+                skip(&mut self.transport, payload_len)?;
+                self.respond(Err(StorageDeviceError::Unsupported), &[])
+            }
+        }
+    }
+
+    /// Send the response to a request.
+    fn respond(
+        &mut self,
+        result: StorageDeviceResult<()>,
+        payload: &[u8],
+    ) -> StorageDeviceResult<()> {
+        send_frame(&mut self.transport, &[&[encode_status(&result)], payload])
+    }
+}
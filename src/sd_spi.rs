@@ -0,0 +1,477 @@
+use crate::{
+    Block, BlockCount, BlockDevice, BlockError, BlockGeometry, BlockIndex, BlockResult,
+    DeviceIdentity, IdentityString,
+};
+use core::convert::TryFrom;
+use core::fmt::Write;
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+
+/// The time given to the card to leave its idle state during initialization, in microseconds.
+const INIT_TIMEOUT_US: u32 = 1_000_000;
+
+/// The time given to the card to send the data of a read, in microseconds.
+const READ_TIMEOUT_US: u32 = 100_000;
+
+/// The time given to the card to program the data of a write, in microseconds.
+const WRITE_TIMEOUT_US: u32 = 500_000;
+
+/// The time waited between two polls of a card not ready yet, in microseconds.
+const POLL_DELAY_US: u32 = 100;
+
+/// The number of bytes polled between two waits.
+const POLL_BURST: usize = 32;
+
+/// Reset the card, entering SPI mode if it is selected.
+const GO_IDLE_STATE: u8 = 0;
+
+/// Check the voltage range, which only cards of version 2 and above know.
+const SEND_IF_COND: u8 = 8;
+
+/// Read the Card-Specific Data register.
+const SEND_CSD: u8 = 9;
+
+/// Read the Card Identification register.
+const SEND_CID: u8 = 10;
+
+/// Set the block length of cards addressed by bytes.
+const SET_BLOCKLEN: u8 = 16;
+
+/// Read a single block.
+const READ_SINGLE_BLOCK: u8 = 17;
+
+/// Write a single block.
+const WRITE_BLOCK: u8 = 24;
+
+/// Announce an application-specific command.
+const APP_CMD: u8 = 55;
+
+/// Read the Operation Conditions Register.
+const READ_OCR: u8 = 58;
+
+/// Turn the checking of CRCs on or off.
+const CRC_ON_OFF: u8 = 59;
+
+/// Start the initialization of the card, application-specific.
+const SD_SEND_OP_COND: u8 = 41;
+
+/// The bit of the R1 response set while the card is initializing.
+const R1_IDLE: u8 = 0x01;
+
+/// The bit of the R1 response set when the command is unknown.
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+
+/// The token starting a block of data.
+const DATA_START: u8 = 0xFE;
+
+/// The mask of the status of a data response.
+const DATA_RESPONSE_MASK: u8 = 0x1F;
+
+/// The data response of an accepted write.
+const DATA_ACCEPTED: u8 = 0x05;
+
+/// The bit of the OCR set for high capacity cards, addressed by blocks.
+const OCR_CCS: u32 = 1 << 30;
+
+/// The kind of an SD card, telling how it is initialized and addressed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SdCardType {
+    /// A standard capacity card of version 1, addressed by bytes.
+    V1,
+
+    /// A standard capacity card of version 2 and above, addressed by bytes.
+    V2,
+
+    /// A high or extended capacity card (SDHC or SDXC), addressed by blocks.
+    HighCapacity,
+}
+
+/// Compute the CRC-7 of a command.
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        for bit in (0..8).rev() {
+            let feedback = ((crc >> 6) ^ (byte >> bit)) & 1;
+            crc = (crc << 1) & 0x7F;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// Compute the CRC-16 of a block of data.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// A block device speaking the SPI protocol of SD cards, over an
+/// [embedded_hal::spi::SpiBus] with the chip select of the card on an
+/// [embedded_hal::digital::OutputPin].
+///
+/// Commands and data are protected by CRCs, and blocks are read and written one at a time. The
+/// bus must run at 400kHz at most until [SdSpiBlockDevice::new] returns, it can then be sped up
+/// to 25MHz through [SdSpiBlockDevice::spi_mut]. MMC cards aren't supported.
+pub struct SdSpiBlockDevice<SPI: SpiBus, CS: OutputPin, D: DelayNs> {
+    /// The SPI bus.
+    spi: SPI,
+
+    /// The chip select of the card, active low.
+    cs: CS,
+
+    /// The source of delays, to poll the card.
+    delay: D,
+
+    /// The kind of the card.
+    card_type: SdCardType,
+
+    /// The number of blocks of the card.
+    blocks: u64,
+}
+
+impl<SPI: SpiBus, CS: OutputPin, D: DelayNs> core::fmt::Debug for SdSpiBlockDevice<SPI, CS, D> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SdSpiBlockDevice")
+            .field("card_type", &self.card_type)
+            .field("blocks", &self.blocks)
+            .finish()
+    }
+}
+
+impl<SPI: SpiBus, CS: OutputPin, D: DelayNs> SdSpiBlockDevice<SPI, CS, D> {
+    /// Initialize the card on ``spi``, selected by ``cs``, waiting with ``delay``.
+    ///
+    /// Fail with [BlockError::ReadError] if no card answers in time, and with
+    /// [BlockError::Unsupported] if the card isn't an SD card.
+    pub fn new(spi: SPI, cs: CS, delay: D) -> BlockResult<Self> {
+        let mut device = SdSpiBlockDevice {
+            spi,
+            cs,
+            delay,
+            card_type: SdCardType::V1,
+            blocks: 0,
+        };
+
+        // At least 74 clock cycles with the card deselected let it enter its native mode.
+        device.cs.set_high().map_err(|_| BlockError::ReadError)?;
+        device
+            .spi
+            .write(&[0xFF; 10])
+            .map_err(|_| BlockError::ReadError)?;
+
+        device.selected(Self::initialize)?;
+        Ok(device)
+    }
+
+    /// Return the kind of the card.
+    pub fn card_type(&self) -> SdCardType {
+        self.card_type
+    }
+
+    /// Return a mutable reference to the SPI bus, to change its speed.
+    pub fn spi_mut(&mut self) -> &mut SPI {
+        &mut self.spi
+    }
+
+    /// Consume the device, returning the SPI bus, the chip select and the source of delays.
+    pub fn into_inner(self) -> (SPI, CS, D) {
+        (self.spi, self.cs, self.delay)
+    }
+
+    /// Run ``f`` with the card selected, then deselect it.
+    fn selected<T>(&mut self, f: impl FnOnce(&mut Self) -> BlockResult<T>) -> BlockResult<T> {
+        self.cs.set_low().map_err(|_| BlockError::ReadError)?;
+        let result = f(self);
+        // The card only releases the bus on the next clock cycle after being deselected.
+        let deselected = self.cs.set_high().is_ok() && self.spi.write(&[0xFF]).is_ok();
+        match result {
+            Ok(_) if !deselected => Err(BlockError::ReadError),
+            result => result,
+        }
+    }
+
+    /// Reset the card and bring it to its ready state, learning its kind and size.
+    fn initialize(&mut self) -> BlockResult<()> {
+        let mut idle = false;
+        for _ in 0..INIT_TIMEOUT_US / POLL_DELAY_US {
+            if self.command(GO_IDLE_STATE, 0) == Ok(R1_IDLE) {
+                idle = true;
+                break;
+            }
+            self.delay.delay_us(POLL_DELAY_US);
+        }
+        if !idle {
+            return Err(BlockError::ReadError);
+        }
+
+        self.card_type = SdCardType::V2;
+        if self.command(SEND_IF_COND, 0x1AA)? & R1_ILLEGAL_COMMAND != 0 {
+            self.card_type = SdCardType::V1;
+        } else {
+            let mut echo = [0; 4];
+            self.receive(&mut echo)?;
+            if echo[2] & 0x0F != 0x01 || echo[3] != 0xAA {
+                return Err(BlockError::Unsupported);
+            }
+        }
+
+        if self.command(CRC_ON_OFF, 1)? & !R1_IDLE != 0 {
+            return Err(BlockError::Unsupported);
+        }
+
+        let argument = if self.card_type == SdCardType::V1 {
+            0
+        } else {
+            OCR_CCS
+        };
+        let mut ready = false;
+        for _ in 0..INIT_TIMEOUT_US / POLL_DELAY_US {
+            self.command(APP_CMD, 0)?;
+            match self.command(SD_SEND_OP_COND, argument)? {
+                0 => {
+                    ready = true;
+                    break;
+                }
+                R1_IDLE => self.delay.delay_us(POLL_DELAY_US),
+                _ => return Err(BlockError::Unsupported),
+            }
+        }
+        if !ready {
+            return Err(BlockError::ReadError);
+        }
+
+        if self.card_type == SdCardType::V2 {
+            if self.command(READ_OCR, 0)? != 0 {
+                return Err(BlockError::ReadError);
+            }
+            let mut ocr = [0; 4];
+            self.receive(&mut ocr)?;
+            if u32::from_be_bytes(ocr) & OCR_CCS != 0 {
+                self.card_type = SdCardType::HighCapacity;
+            }
+        }
+        if self.card_type != SdCardType::HighCapacity
+            && self.command(SET_BLOCKLEN, Block::LEN as u32)? != 0
+        {
+            return Err(BlockError::ReadError);
+        }
+
+        let mut csd = [0; 16];
+        if self.command(SEND_CSD, 0)? != 0 {
+            return Err(BlockError::ReadError);
+        }
+        self.receive_data(&mut csd)?;
+        self.blocks = match csd[0] >> 6 {
+            0 => {
+                let read_bl_len = u32::from(csd[5] & 0x0F);
+                let c_size = (u64::from(csd[6] & 0x03) << 10)
+                    | (u64::from(csd[7]) << 2)
+                    | u64::from(csd[8] >> 6);
+                let c_size_mult = u32::from(((csd[9] & 0x03) << 1) | (csd[10] >> 7));
+                ((c_size + 1) << (c_size_mult + 2 + read_bl_len)) / Block::LEN_U64
+            }
+            1 => {
+                let c_size =
+                    (u64::from(csd[7] & 0x3F) << 16) | (u64::from(csd[8]) << 8) | u64::from(csd[9]);
+                (c_size + 1) * 1024
+            }
+            _ => return Err(BlockError::Unsupported),
+        };
+        Ok(())
+    }
+
+    /// Receive ``buf``, sending ``0xFF`` bytes meanwhile as the card expects.
+    fn receive(&mut self, buf: &mut [u8]) -> BlockResult<()> {
+        buf.fill(0xFF);
+        self.spi
+            .transfer_in_place(buf)
+            .map_err(|_| BlockError::ReadError)
+    }
+
+    /// Receive bytes until one matches ``done``, returning it, or fail with ``error`` after
+    /// ``timeout_us`` microseconds.
+    fn wait_for(
+        &mut self,
+        done: impl Fn(u8) -> bool,
+        timeout_us: u32,
+        error: BlockError,
+    ) -> BlockResult<u8> {
+        let mut byte = [0];
+        for _ in 0..timeout_us / POLL_DELAY_US {
+            for _ in 0..POLL_BURST {
+                self.receive(&mut byte)?;
+                if done(byte[0]) {
+                    return Ok(byte[0]);
+                }
+            }
+            self.delay.delay_us(POLL_DELAY_US);
+        }
+        Err(error)
+    }
+
+    /// Send the command ``index`` with ``argument``, returning its R1 response.
+    fn command(&mut self, index: u8, argument: u32) -> BlockResult<u8> {
+        // The card may still be busy with a previous write.
+        self.wait_for(|byte| byte == 0xFF, WRITE_TIMEOUT_US, BlockError::Busy)?;
+
+        let [a3, a2, a1, a0] = argument.to_be_bytes();
+        let mut command = [0x40 | index, a3, a2, a1, a0, 0];
+        command[5] = (crc7(&command[..5]) << 1) | 1;
+        self.spi
+            .write(&command)
+            .map_err(|_| BlockError::ReadError)?;
+
+        // The response comes within 8 bytes, and its first bit is cleared.
+        let mut response = [0xFF];
+        for _ in 0..8 {
+            self.receive(&mut response)?;
+            if response[0] & 0x80 == 0 {
+                return Ok(response[0]);
+            }
+        }
+        Err(BlockError::ReadError)
+    }
+
+    /// Receive a block of data into ``buf``, checking its CRC.
+    fn receive_data(&mut self, buf: &mut [u8]) -> BlockResult<()> {
+        let token = self.wait_for(|byte| byte != 0xFF, READ_TIMEOUT_US, BlockError::ReadError)?;
+        if token != DATA_START {
+            // An error token.
+            return Err(BlockError::ReadError);
+        }
+        self.receive(buf)?;
+        let mut crc = [0; 2];
+        self.receive(&mut crc)?;
+        if u16::from_be_bytes(crc) != crc16(buf) {
+            return Err(BlockError::ReadError);
+        }
+        Ok(())
+    }
+
+    /// Return the argument addressing the block at ``index``.
+    fn address(&self, index: u64) -> BlockResult<u32> {
+        let address = match self.card_type {
+            SdCardType::HighCapacity => index,
+            SdCardType::V1 | SdCardType::V2 => index * Block::LEN_U64,
+        };
+        u32::try_from(address).map_err(|_| BlockError::ReadError)
+    }
+
+    /// Check that ``len`` blocks at ``index`` are on the card, returning ``error`` otherwise.
+    fn check_bounds(&self, index: BlockIndex, len: usize, error: BlockError) -> BlockResult<()> {
+        match index.0.checked_add(len as u64) {
+            Some(end) if end <= self.blocks => Ok(()),
+            _ => Err(error),
+        }
+    }
+}
+
+impl<SPI: SpiBus, CS: OutputPin, D: DelayNs> BlockDevice for SdSpiBlockDevice<SPI, CS, D> {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        self.check_bounds(index, blocks.len(), BlockError::ReadError)?;
+        for (i, block) in blocks.iter_mut().enumerate() {
+            let address = self.address(index.0 + i as u64)?;
+            self.selected(|this| {
+                if this.command(READ_SINGLE_BLOCK, address)? != 0 {
+                    return Err(BlockError::ReadError);
+                }
+                this.receive_data(&mut block[..])
+            })?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.check_bounds(index, blocks.len(), BlockError::WriteError)?;
+        for (i, block) in blocks.iter().enumerate() {
+            let address = self.address(index.0 + i as u64)?;
+            self.selected(|this| {
+                if this.command(WRITE_BLOCK, address)? != 0 {
+                    return Err(BlockError::WriteError);
+                }
+                let crc = crc16(&block[..]).to_be_bytes();
+                this.spi
+                    .write(&[0xFF, DATA_START])
+                    .and_then(|()| this.spi.write(&block[..]))
+                    .and_then(|()| this.spi.write(&crc))
+                    .map_err(|_| BlockError::WriteError)?;
+
+                let response = this.wait_for(
+                    |byte| byte & 0x11 == 0x01,
+                    READ_TIMEOUT_US,
+                    BlockError::WriteError,
+                )?;
+                if response & DATA_RESPONSE_MASK != DATA_ACCEPTED {
+                    return Err(BlockError::WriteError);
+                }
+                // The card holds the bus low while it programs the block.
+                this.wait_for(
+                    |byte| byte == 0xFF,
+                    WRITE_TIMEOUT_US,
+                    BlockError::WriteError,
+                )?;
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        Ok(BlockCount(self.blocks))
+    }
+
+    /// Report single-block transfers, as blocks are read and written one command at a time.
+    fn geometry(&mut self) -> BlockResult<BlockGeometry> {
+        Ok(BlockGeometry {
+            max_transfer_size: Block::LEN,
+            ..BlockGeometry::default()
+        })
+    }
+
+    /// Read the Card Identification register, holding the name, revision and serial number of
+    /// the product, unique with the identifiers of its manufacturer.
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        let mut cid = [0; 16];
+        self.selected(|this| {
+            if this.command(SEND_CID, 0)? != 0 {
+                return Err(BlockError::ReadError);
+            }
+            this.receive_data(&mut cid)
+        })?;
+
+        let mut identity = DeviceIdentity {
+            model: IdentityString::from_ascii(&cid[3..8]),
+            ..DeviceIdentity::default()
+        };
+        let _ = write!(
+            identity.firmware_revision,
+            "{}.{}",
+            cid[8] >> 4,
+            cid[8] & 0x0F
+        );
+        let _ = write!(
+            identity.serial,
+            "{:08x}",
+            u32::from_be_bytes([cid[9], cid[10], cid[11], cid[12]])
+        );
+        // The manufacturer, OEM, product name, revision, serial number and manufacturing date.
+        identity.unique_id.push_str("sd.");
+        for byte in &cid[..15] {
+            let _ = write!(identity.unique_id, "{:02x}", byte);
+        }
+        Ok(identity)
+    }
+}
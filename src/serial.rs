@@ -0,0 +1,400 @@
+use crate::clock::Clock;
+use crate::crc::crc32;
+use crate::rpc::Transport;
+use crate::{StorageDeviceError, StorageDeviceResult};
+
+/// The maximum number of bytes carried by a single packet.
+pub const SERIAL_MAX_PAYLOAD: usize = 64;
+
+/// The number of packets that can be sent before waiting for them to be acknowledged.
+pub const SERIAL_WINDOW: usize = 4;
+
+/// The default time to wait for an acknowledgment before sending the packets again, in nanoseconds.
+pub const DEFAULT_RETRANSMIT_TIMEOUT: u64 = 100_000_000;
+
+/// The default number of retransmissions without progress before giving up.
+pub const DEFAULT_MAX_RETRIES: u32 = 16;
+
+/// The byte delimiting packets on the link.
+const FLAG: u8 = 0x7E;
+
+/// The byte escaping [FLAG] and itself inside packets.
+const ESCAPE: u8 = 0x7D;
+
+/// The kind of a packet carrying data.
+const KIND_DATA: u8 = 0;
+
+/// The kind of a packet acknowledging every data packet before its sequence number.
+const KIND_ACK: u8 = 1;
+
+/// The size of a decoded packet: kind, sequence number, payload and CRC-32.
+const MAX_PACKET_LEN: usize = 2 + SERIAL_MAX_PAYLOAD + 4;
+
+/// A raw, possibly lossy, serial link such as a UART.
+pub trait SerialLink: core::fmt::Debug {
+    /// Send all of ``buf``.
+    fn write(&mut self, buf: &[u8]) -> StorageDeviceResult<()>;
+
+    /// Receive the bytes available into ``buf``, returning how many were received.
+    ///
+    /// May wait for a short while, but must return 0 if nothing arrived instead of blocking
+    /// forever, so packets can be retransmitted.
+    fn read(&mut self, buf: &mut [u8]) -> StorageDeviceResult<usize>;
+}
+
+/// A [SerialLink] over a [std::io::Read] and [std::io::Write] stream, such as a serial port
+/// opened with a read timeout.
+///
+/// Timeouts are reported as nothing being received.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct IoSerialLink<T> {
+    /// The underlying stream.
+    stream: T,
+}
+
+#[cfg(feature = "std")]
+impl<T: std::io::Read + std::io::Write + core::fmt::Debug> IoSerialLink<T> {
+    /// Use ``stream`` as a serial link.
+    pub fn new(stream: T) -> Self {
+        IoSerialLink { stream }
+    }
+
+    /// Consume the link, returning the underlying stream.
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: std::io::Read + std::io::Write + core::fmt::Debug> SerialLink for IoSerialLink<T> {
+    fn write(&mut self, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.stream
+            .write_all(buf)
+            .and_then(|()| self.stream.flush())
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        match self.stream.read(buf) {
+            Ok(len) => Ok(len),
+            Err(err)
+                if err.kind() == std::io::ErrorKind::TimedOut
+                    || err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::Interrupted =>
+            {
+                Ok(0)
+            }
+            Err(err) => Err(crate::sys::storage_error(
+                &err,
+                StorageDeviceError::ReadError,
+            )),
+        }
+    }
+}
+
+/// A packet waiting to be acknowledged.
+#[derive(Debug, Copy, Clone)]
+struct Packet {
+    /// The number of bytes used in ``data``.
+    len: usize,
+
+    /// The payload of the packet.
+    data: [u8; SERIAL_MAX_PAYLOAD],
+}
+
+/// A reliable [Transport] over a lossy [SerialLink], so an [RpcClient](crate::RpcClient) and an
+/// [RpcServer](crate::RpcServer) can talk over a UART.
+///
+/// Bytes are sent in small packets delimited by flag bytes and protected by a CRC-32. Up to
+/// [SERIAL_WINDOW] packets are in flight at once: when they aren't acknowledged in time, they are
+/// all sent again. Corrupted and out of order packets are dropped, and left for the sender to
+/// retransmit.
+///
+/// Both ends must be created together, as they start counting packets from zero.
+pub struct SerialTransport<L: SerialLink, C: Clock> {
+    /// The underlying link.
+    link: L,
+
+    /// The clock timing retransmissions.
+    clock: C,
+
+    /// The time to wait for an acknowledgment before retransmitting, in nanoseconds.
+    retransmit_timeout: u64,
+
+    /// The number of retransmissions without progress before giving up.
+    max_retries: u32,
+
+    /// The packets sent but not acknowledged yet, indexed by their sequence number modulo the
+    /// window.
+    window: [Packet; SERIAL_WINDOW],
+
+    /// The sequence number of the next packet to send.
+    next_seq: u8,
+
+    /// The number of packets in flight.
+    in_flight: usize,
+
+    /// The time the packets in flight were last sent or made progress.
+    last_progress: u64,
+
+    /// The number of retransmissions since the last progress.
+    retries: u32,
+
+    /// The sequence number of the next packet to receive.
+    expected_seq: u8,
+
+    /// The payload of the last received packet.
+    received: Packet,
+
+    /// The number of bytes of ``received`` already returned.
+    received_pos: usize,
+
+    /// The packet being decoded.
+    frame: [u8; MAX_PACKET_LEN],
+
+    /// The number of bytes of ``frame`` decoded so far.
+    frame_len: usize,
+
+    /// Whether the previous byte was [ESCAPE].
+    escaping: bool,
+
+    /// Whether the packet being decoded is too big, and must be dropped.
+    overflow: bool,
+}
+
+impl<L: SerialLink, C: Clock> core::fmt::Debug for SerialTransport<L, C> {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+        fmt.debug_struct("SerialTransport")
+            .field("link", &self.link)
+            .field("clock", &self.clock)
+            .field("retransmit_timeout", &self.retransmit_timeout)
+            .field("max_retries", &self.max_retries)
+            .field("next_seq", &self.next_seq)
+            .field("in_flight", &self.in_flight)
+            .field("expected_seq", &self.expected_seq)
+            .finish()
+    }
+}
+
+impl<L: SerialLink, C: Clock> SerialTransport<L, C> {
+    /// Create a transport over ``link``, using ``clock`` to time retransmissions.
+    pub fn new(link: L, clock: C) -> Self {
+        let empty = Packet {
+            len: 0,
+            data: [0; SERIAL_MAX_PAYLOAD],
+        };
+        SerialTransport {
+            link,
+            clock,
+            retransmit_timeout: DEFAULT_RETRANSMIT_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+            window: [empty; SERIAL_WINDOW],
+            next_seq: 0,
+            in_flight: 0,
+            last_progress: 0,
+            retries: 0,
+            expected_seq: 0,
+            received: empty,
+            received_pos: 0,
+            frame: [0; MAX_PACKET_LEN],
+            frame_len: 0,
+            escaping: false,
+            overflow: false,
+        }
+    }
+
+    /// Set the time to wait for an acknowledgment before retransmitting, in nanoseconds.
+    pub fn with_retransmit_timeout(mut self, retransmit_timeout: u64) -> Self {
+        self.retransmit_timeout = retransmit_timeout;
+        self
+    }
+
+    /// Set the number of retransmissions without progress before giving up.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Return a reference to the underlying link.
+    pub fn get_ref(&self) -> &L {
+        &self.link
+    }
+
+    /// Consume the transport, returning the underlying link.
+    pub fn into_inner(self) -> L {
+        self.link
+    }
+
+    /// Encode and send a packet.
+    fn send_packet(link: &mut L, kind: u8, seq: u8, payload: &[u8]) -> StorageDeviceResult<()> {
+        let mut packet = [0u8; MAX_PACKET_LEN];
+        packet[0] = kind;
+        packet[1] = seq;
+        packet[2..2 + payload.len()].copy_from_slice(payload);
+        let crc = crc32(&packet[..2 + payload.len()]);
+        packet[2 + payload.len()..6 + payload.len()].copy_from_slice(&crc.to_le_bytes());
+
+        let mut encoded = [0u8; 2 + 2 * MAX_PACKET_LEN];
+        let mut len = 0;
+        encoded[len] = FLAG;
+        len += 1;
+        for byte in &packet[..6 + payload.len()] {
+            if *byte == FLAG || *byte == ESCAPE {
+                encoded[len] = ESCAPE;
+                encoded[len + 1] = *byte ^ 0x20;
+                len += 2;
+            } else {
+                encoded[len] = *byte;
+                len += 1;
+            }
+        }
+        encoded[len] = FLAG;
+        len += 1;
+
+        link.write(&encoded[..len])
+    }
+
+    /// Send the data packet with sequence number ``seq``, which must be in the window.
+    fn transmit(&mut self, seq: u8) -> StorageDeviceResult<()> {
+        let packet = &self.window[usize::from(seq) % SERIAL_WINDOW];
+        Self::send_packet(&mut self.link, KIND_DATA, seq, &packet.data[..packet.len])
+    }
+
+    /// Handle a decoded packet.
+    fn handle_packet(&mut self) -> StorageDeviceResult<()> {
+        let len = self.frame_len;
+        if len < 6 {
+            return Ok(());
+        }
+
+        let mut crc = [0u8; 4];
+        crc.copy_from_slice(&self.frame[len - 4..len]);
+        if crc32(&self.frame[..len - 4]) != u32::from_le_bytes(crc) {
+            // Corrupted, the sender will retransmit it.
+            return Ok(());
+        }
+
+        let seq = self.frame[1];
+        match self.frame[0] {
+            KIND_DATA => {
+                // Only accept the next packet, and only once the previous one has been consumed.
+                if seq == self.expected_seq && self.received_pos == self.received.len {
+                    let payload = &self.frame[2..len - 4];
+                    self.received.data[..payload.len()].copy_from_slice(payload);
+                    self.received.len = payload.len();
+                    self.received_pos = 0;
+                    self.expected_seq = self.expected_seq.wrapping_add(1);
+                }
+                // Acknowledge everything received so far, even on duplicates, in case the
+                // previous acknowledgment was lost.
+                Self::send_packet(&mut self.link, KIND_ACK, self.expected_seq, &[])
+            }
+            KIND_ACK => {
+                let base = self.next_seq.wrapping_sub(self.in_flight as u8);
+                let acked = usize::from(seq.wrapping_sub(base));
+                if acked != 0 && acked <= self.in_flight {
+                    self.in_flight -= acked;
+                    self.retries = 0;
+                    self.last_progress = self.clock.now();
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Process the bytes received from the link, and retransmit the packets in flight if they
+    /// weren't acknowledged in time.
+    fn poll(&mut self) -> StorageDeviceResult<()> {
+        let mut buf = [0u8; 32];
+        let len = self.link.read(&mut buf)?;
+        for byte in &buf[..len] {
+            match *byte {
+                FLAG => {
+                    if !self.overflow {
+                        self.handle_packet()?;
+                    }
+                    self.frame_len = 0;
+                    self.escaping = false;
+                    self.overflow = false;
+                }
+                ESCAPE => self.escaping = true,
+                mut byte => {
+                    if self.escaping {
+                        byte ^= 0x20;
+                        self.escaping = false;
+                    }
+                    if self.frame_len < MAX_PACKET_LEN {
+                        self.frame[self.frame_len] = byte;
+                        self.frame_len += 1;
+                    } else {
+                        self.overflow = true;
+                    }
+                }
+            }
+        }
+
+        if self.in_flight != 0
+            && self.clock.now().saturating_sub(self.last_progress) >= self.retransmit_timeout
+        {
+            if self.retries >= self.max_retries {
+                return Err(StorageDeviceError::WriteError);
+            }
+            self.retries += 1;
+            let base = self.next_seq.wrapping_sub(self.in_flight as u8);
+            for i in 0..self.in_flight {
+                self.transmit(base.wrapping_add(i as u8))?;
+            }
+            self.last_progress = self.clock.now();
+        }
+
+        Ok(())
+    }
+}
+
+impl<L: SerialLink, C: Clock> Transport for SerialTransport<L, C> {
+    fn send(&mut self, buf: &[u8]) -> StorageDeviceResult<()> {
+        for chunk in buf.chunks(SERIAL_MAX_PAYLOAD) {
+            while self.in_flight == SERIAL_WINDOW {
+                self.poll()?;
+            }
+
+            let seq = self.next_seq;
+            let packet = &mut self.window[usize::from(seq) % SERIAL_WINDOW];
+            packet.data[..chunk.len()].copy_from_slice(chunk);
+            packet.len = chunk.len();
+            if self.in_flight == 0 {
+                self.last_progress = self.clock.now();
+            }
+            self.next_seq = seq.wrapping_add(1);
+            self.in_flight += 1;
+            self.transmit(seq)?;
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            if self.received_pos == self.received.len {
+                self.poll()?;
+                continue;
+            }
+            let available = &self.received.data[self.received_pos..self.received.len];
+            let len = core::cmp::min(available.len(), buf.len() - pos);
+            buf[pos..pos + len].copy_from_slice(&available[..len]);
+            self.received_pos += len;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Wait for every packet sent to be acknowledged.
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        while self.in_flight != 0 {
+            self.poll()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{
+    Capabilities, DeviceIdentity, DeviceInfo, StorageDevice, StorageDeviceError,
+    StorageDeviceResult,
+};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// A storage device checking the device it wraps against an in-memory reference model.
+///
+/// The model starts as a copy of the device. Every operation is applied to both, and reads
+/// compare the data returned by the device with the model. Any difference, in the data or in the
+/// success of an operation, is a divergence: it fails the operation with
+/// [StorageDeviceError::VerificationFailed], or panics if
+/// [ShadowCheckedDevice::with_panic_on_divergence] was set, which is handier in tests.
+///
+/// Wrapping a [StorageBlockDevice] over a custom [BlockDevice] catches bugs in the block device
+/// and in the splitting of requests in blocks alike.
+///
+/// The whole device is held in memory, so this is meant for small test devices.
+///
+/// [StorageBlockDevice]: crate::StorageBlockDevice
+/// [BlockDevice]: crate::BlockDevice
+#[derive(Debug)]
+pub struct ShadowCheckedDevice<S: StorageDevice> {
+    /// The device under test.
+    storage_device: S,
+
+    /// The expected content of the device.
+    model: Vec<u8>,
+
+    /// Whether to panic on divergence instead of returning an error.
+    panic_on_divergence: bool,
+
+    /// The number of divergences found so far.
+    divergences: u64,
+}
+
+impl<S: StorageDevice> ShadowCheckedDevice<S> {
+    /// Wrap ``storage_device``, reading its whole content into the model.
+    pub fn new(mut storage_device: S) -> StorageDeviceResult<Self> {
+        let len =
+            usize::try_from(storage_device.len()?).map_err(|_| StorageDeviceError::OutOfSpace)?;
+        let mut model = alloc::vec![0u8; len];
+        storage_device.read(0, &mut model)?;
+
+        Ok(ShadowCheckedDevice {
+            storage_device,
+            model,
+            panic_on_divergence: false,
+            divergences: 0,
+        })
+    }
+
+    /// Panic when the device diverges from the model, instead of returning an error.
+    pub fn with_panic_on_divergence(mut self, panic_on_divergence: bool) -> Self {
+        self.panic_on_divergence = panic_on_divergence;
+        self
+    }
+
+    /// Return the number of divergences found so far.
+    pub fn divergences(&self) -> u64 {
+        self.divergences
+    }
+
+    /// Return the expected content of the device.
+    pub fn model(&self) -> &[u8] {
+        &self.model
+    }
+
+    /// Compare the whole content of the device with the model.
+    pub fn check_all(&mut self) -> StorageDeviceResult<()> {
+        let mut model = core::mem::take(&mut self.model);
+        let result = crate::copy::compare_devices(&mut self.storage_device, &mut model);
+        self.model = model;
+
+        match result? {
+            None => Ok(()),
+            Some(offset) => self.diverged(format_args!("content differs at offset {}", offset)),
+        }
+    }
+
+    /// Return a reference to the device under test.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Consume the wrapper, returning the device under test.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Report a divergence described by ``what``.
+    fn diverged(&mut self, what: core::fmt::Arguments<'_>) -> StorageDeviceResult<()> {
+        self.divergences += 1;
+        if self.panic_on_divergence {
+            panic!("device diverged from its model: {}", what);
+        }
+        Err(StorageDeviceError::VerificationFailed)
+    }
+
+    /// Return the range of the model covered by the ``len`` bytes at ``offset``, if it is inside.
+    fn model_range(&self, offset: u64, len: u64) -> Option<core::ops::Range<usize>> {
+        let end = offset.checked_add(len)?;
+        if end > self.model.len() as u64 {
+            return None;
+        }
+        Some(offset as usize..end as usize)
+    }
+
+    /// Check that the device succeeded exactly when the model did, returning the range of the
+    /// model to update if both succeeded.
+    fn check_outcome(
+        &mut self,
+        operation: &str,
+        offset: u64,
+        len: u64,
+        result: StorageDeviceResult<()>,
+    ) -> StorageDeviceResult<Option<core::ops::Range<usize>>> {
+        let range = self.model_range(offset, len);
+        match (result, range) {
+            (Ok(()), Some(range)) => Ok(Some(range)),
+            (Err(err), None) => Err(err),
+            (Ok(()), None) => {
+                self.diverged(format_args!(
+                    "{} of {} bytes at {} succeeded out of bounds",
+                    operation, len, offset
+                ))?;
+                Ok(None)
+            }
+            (Err(StorageDeviceError::OutOfBounds), Some(_)) => {
+                self.diverged(format_args!(
+                    "{} of {} bytes at {} failed as out of bounds",
+                    operation, len, offset
+                ))?;
+                Ok(None)
+            }
+            // Other failures are those of the medium, which the model doesn't simulate.
+            (Err(err), Some(_)) => Err(err),
+        }
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for ShadowCheckedDevice<S> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        let result = self.storage_device.read(offset, buf);
+        let range = match self.check_outcome("read", offset, buf.len() as u64, result)? {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+
+        if let Some(position) = (0..buf.len()).find(|&i| buf[i] != self.model[range.start + i]) {
+            let (got, expected) = (buf[position], self.model[range.start + position]);
+            return self.diverged(format_args!(
+                "read {:#04x} at offset {}, expected {:#04x}",
+                got,
+                offset + position as u64,
+                expected
+            ));
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let result = self.storage_device.write(offset, buf);
+        if let Some(range) = self.check_outcome("write", offset, buf.len() as u64, result)? {
+            self.model[range].copy_from_slice(buf);
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        let len = self.storage_device.len()?;
+        if len != self.model.len() as u64 {
+            self.diverged(format_args!(
+                "size is {}, expected {}",
+                len,
+                self.model.len()
+            ))?;
+        }
+        Ok(len)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        let result = self.storage_device.write_zeroes(offset, len);
+        if let Some(range) = self.check_outcome("write_zeroes", offset, len, result)? {
+            self.model[range].fill(0);
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        let result = self.storage_device.fill(offset, len, byte);
+        if let Some(range) = self.check_outcome("fill", offset, len, result)? {
+            self.model[range].fill(byte);
+        }
+        Ok(())
+    }
+
+    /// Discard the range, then take the new content of its part inside the device from the
+    /// device, as it is unspecified.
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.discard(offset, len)?;
+        let size = self.model.len() as u64;
+        let start = core::cmp::min(offset, size);
+        let end = core::cmp::min(offset.saturating_add(len), size);
+        let range = start as usize..end as usize;
+        self.storage_device.read(start, &mut self.model[range])?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let result = self.storage_device.write_fua(offset, buf);
+        if let Some(range) = self.check_outcome("write", offset, buf.len() as u64, result)? {
+            self.model[range].copy_from_slice(buf);
+        }
+        Ok(())
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.storage_device.capabilities()
+    }
+}
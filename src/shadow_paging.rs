@@ -0,0 +1,445 @@
+use crate::crc::crc32;
+use crate::{Block, StorageDevice, StorageDeviceError, StorageDeviceResult};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// The magic identifying a root of a [ShadowPagedDevice].
+const ROOT_MAGIC: u32 = u32::from_le_bytes(*b"SHPG");
+
+/// The version of the layout of the roots.
+const ROOT_VERSION: u32 = 1;
+
+/// The size of the fields of a root before its page table.
+const ROOT_HEADER_LEN: usize = 20;
+
+/// The entry of the page table of pages never written, which read as zeroes.
+const UNMAPPED: u32 = u32::MAX;
+
+/// A root of a [ShadowPagedDevice], mapping every page of the device to a block of its data
+/// area.
+///
+/// The device holds two roots, one after the other, each taking as many blocks as needed. They
+/// are stored in little endian as follow:
+///
+/// | Offset          | Size        | Field      |
+/// |-----------------|-------------|------------|
+/// | 0               | 4           | magic      |
+/// | 4               | 4           | version    |
+/// | 8               | 8           | generation |
+/// | 16              | 4           | pages      |
+/// | 20              | 4 * pages   | page table |
+/// | 20 + 4 * pages  | 4           | crc        |
+///
+/// The CRC-32 covers everything before it. The valid root of the highest generation is the
+/// current one.
+#[derive(Debug, Clone)]
+struct Root {
+    /// The number of the update which wrote this root.
+    generation: u64,
+
+    /// The block of the data area holding every page, or [UNMAPPED].
+    table: Vec<u32>,
+}
+
+impl Root {
+    /// Return the size of a root of a device of ``pages`` pages, in blocks.
+    fn blocks(pages: u32) -> u64 {
+        (ROOT_HEADER_LEN as u64 + 4 * u64::from(pages) + 4).div_ceil(Block::LEN_U64)
+    }
+
+    /// Serialize the root, padded to whole blocks.
+    fn to_bytes(&self) -> Vec<u8> {
+        let pages = self.table.len() as u32;
+        let mut bytes = alloc::vec![0u8; (Root::blocks(pages) * Block::LEN_U64) as usize];
+        bytes[0..4].copy_from_slice(&ROOT_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&ROOT_VERSION.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.generation.to_le_bytes());
+        bytes[16..20].copy_from_slice(&pages.to_le_bytes());
+        for (i, entry) in self.table.iter().enumerate() {
+            let start = ROOT_HEADER_LEN + 4 * i;
+            bytes[start..start + 4].copy_from_slice(&entry.to_le_bytes());
+        }
+        let crc_offset = ROOT_HEADER_LEN + 4 * self.table.len();
+        let crc = crc32(&bytes[..crc_offset]);
+        bytes[crc_offset..crc_offset + 4].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Read the number of pages from the first block of a root, returning None if it isn't one.
+    ///
+    /// The fields read are the same in every root of a device, so this holds even if the root
+    /// was only partly overwritten by the next one.
+    fn pages(block: &Block) -> Option<u32> {
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        let mut pages = [0u8; 4];
+
+        magic.copy_from_slice(&block[0..4]);
+        version.copy_from_slice(&block[4..8]);
+        pages.copy_from_slice(&block[16..20]);
+
+        if u32::from_le_bytes(magic) != ROOT_MAGIC || u32::from_le_bytes(version) != ROOT_VERSION {
+            return None;
+        }
+        Some(u32::from_le_bytes(pages))
+    }
+
+    /// Deserialize a root of a device of ``pages`` pages, returning None if it isn't valid.
+    fn from_bytes(bytes: &[u8], pages: u32) -> Option<Self> {
+        let mut block = Block::new();
+        block.copy_from_slice(&bytes[..Block::LEN]);
+        if Root::pages(&block) != Some(pages) {
+            return None;
+        }
+
+        let crc_offset = ROOT_HEADER_LEN + 4 * pages as usize;
+        let mut crc = [0u8; 4];
+        crc.copy_from_slice(&bytes[crc_offset..crc_offset + 4]);
+        if u32::from_le_bytes(crc) != crc32(&bytes[..crc_offset]) {
+            return None;
+        }
+
+        let mut generation = [0u8; 8];
+        generation.copy_from_slice(&bytes[8..16]);
+        let table = bytes[ROOT_HEADER_LEN..crc_offset]
+            .chunks_exact(4)
+            .map(|entry| {
+                let mut entry_bytes = [0u8; 4];
+                entry_bytes.copy_from_slice(entry);
+                u32::from_le_bytes(entry_bytes)
+            })
+            .collect();
+
+        Some(Root {
+            generation: u64::from_le_bytes(generation),
+            table,
+        })
+    }
+}
+
+/// A storage device never overwriting its data in place, so every write is atomic, even across a
+/// crash.
+///
+/// The device is made of pages of [Block::LEN] bytes, mapped to blocks of a data area by a page
+/// table held in a root. A write puts the new content of the pages it modifies in free blocks,
+/// then writes a new root mapping them, over the older of the two roots at the start of the inner
+/// device. Barriers order the two steps, and the root is checksummed, so the device either holds
+/// the whole write or none of it: opening it picks the latest root which was completely written.
+///
+/// Unlike a [JournaledDevice](crate::JournaledDevice), the data is written once, which suits small
+/// metadata regions on flash. Free blocks are allocated round-robin, spreading the writes over the
+/// data area. The page table is held in memory, and rewritten whole on every write, so the device
+/// is best kept small. As with any device, writes are durable once [StorageDevice::flush]
+/// returns.
+pub struct ShadowPagedDevice<S: StorageDevice> {
+    /// The device holding the roots, followed by the data area.
+    storage_device: S,
+
+    /// The current root.
+    root: Root,
+
+    /// The slot of the current root, 0 or 1.
+    slot: u64,
+
+    /// Whether every block of the data area is mapped by the current root.
+    used: Vec<bool>,
+
+    /// The block of the data area to try allocating first.
+    next: usize,
+}
+
+impl<S: StorageDevice> core::fmt::Debug for ShadowPagedDevice<S> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ShadowPagedDevice")
+            .field("storage_device", &self.storage_device)
+            .field("pages", &self.root.table.len())
+            .field("generation", &self.root.generation)
+            .finish()
+    }
+}
+
+impl<S: StorageDevice> ShadowPagedDevice<S> {
+    /// Set up a new device of ``pages`` pages of [Block::LEN] bytes on ``storage_device``, every
+    /// page reading as zeroes.
+    ///
+    /// The data area takes the rest of ``storage_device``, which must hold at least twice as many
+    /// blocks as pages, so even a write of the whole device finds enough free blocks. Return
+    /// [StorageDeviceError::OutOfSpace] otherwise.
+    pub fn create(mut storage_device: S, pages: u32) -> StorageDeviceResult<Self> {
+        if pages == 0 || pages == UNMAPPED {
+            return Err(StorageDeviceError::Unsupported);
+        }
+        let data_blocks = data_blocks(&mut storage_device, pages)?;
+        if data_blocks < 2 * pages as usize {
+            return Err(StorageDeviceError::OutOfSpace);
+        }
+
+        let root = Root {
+            generation: 1,
+            table: alloc::vec![UNMAPPED; pages as usize],
+        };
+        let root_len = Root::blocks(pages) * Block::LEN_U64;
+        storage_device.write(0, &root.to_bytes())?;
+        storage_device.write(root_len, &Block::new()[..])?;
+        storage_device.flush()?;
+
+        Ok(ShadowPagedDevice {
+            storage_device,
+            root,
+            slot: 0,
+            used: alloc::vec![false; data_blocks],
+            next: 0,
+        })
+    }
+
+    /// Open the device previously set up on ``storage_device`` with
+    /// [ShadowPagedDevice::create], using its latest valid root.
+    ///
+    /// Return [StorageDeviceError::Corrupted] if neither root is valid.
+    pub fn open(mut storage_device: S) -> StorageDeviceResult<Self> {
+        let mut block = Block::new();
+        storage_device.read(0, &mut block[..])?;
+        let pages = Root::pages(&block).ok_or(StorageDeviceError::Corrupted)?;
+        if pages == 0 || pages == UNMAPPED {
+            return Err(StorageDeviceError::Corrupted);
+        }
+        let data_blocks = data_blocks(&mut storage_device, pages)?;
+
+        let root_len = Root::blocks(pages) * Block::LEN_U64;
+        let mut bytes = alloc::vec![0u8; root_len as usize];
+        let mut current: Option<(Root, u64)> = None;
+        for slot in 0..2 {
+            storage_device.read(slot * root_len, &mut bytes)?;
+            if let Some(root) = Root::from_bytes(&bytes, pages) {
+                if current
+                    .as_ref()
+                    .is_none_or(|(current, _)| root.generation > current.generation)
+                {
+                    current = Some((root, slot));
+                }
+            }
+        }
+        let (root, slot) = current.ok_or(StorageDeviceError::Corrupted)?;
+
+        let mut used = alloc::vec![false; data_blocks];
+        for &entry in &root.table {
+            if entry == UNMAPPED {
+                continue;
+            }
+            match used.get_mut(entry as usize) {
+                Some(used) if !*used => *used = true,
+                _ => return Err(StorageDeviceError::Corrupted),
+            }
+        }
+
+        Ok(ShadowPagedDevice {
+            storage_device,
+            root,
+            slot,
+            used,
+            next: 0,
+        })
+    }
+
+    /// Return the number of pages of the device.
+    pub fn pages(&self) -> u32 {
+        self.root.table.len() as u32
+    }
+
+    /// Return the number of updates of the root since the device was created.
+    pub fn generation(&self) -> u64 {
+        self.root.generation
+    }
+
+    /// Return a reference to the inner device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Consume the device, returning the inner device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Return the offset of the data area in the inner device.
+    fn data_offset(&self) -> u64 {
+        2 * Root::blocks(self.pages()) * Block::LEN_U64
+    }
+
+    /// Check whether the ``len`` bytes at ``offset`` are inside the device.
+    fn check_bounds(&self, offset: u64, len: usize) -> StorageDeviceResult<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= u64::from(self.pages()) * Block::LEN_U64 => Ok(()),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+
+    /// Find a free block of the data area, and mark it used.
+    fn allocate(&mut self) -> StorageDeviceResult<u32> {
+        let len = self.used.len();
+        let index = (0..len)
+            .map(|i| (self.next + i) % len)
+            .find(|&index| !self.used[index])
+            .ok_or(StorageDeviceError::OutOfSpace)?;
+        self.used[index] = true;
+        self.next = (index + 1) % len;
+        Ok(index as u32)
+    }
+
+    /// Write the new content of the pages of ``buf`` at ``offset`` to free blocks, recording
+    /// them in ``table``.
+    fn write_pages(
+        &mut self,
+        table: &mut [u32],
+        mut offset: u64,
+        mut buf: &[u8],
+    ) -> StorageDeviceResult<()> {
+        let data_offset = self.data_offset();
+        while !buf.is_empty() {
+            let page = (offset / Block::LEN_U64) as usize;
+            let start = (offset % Block::LEN_U64) as usize;
+            let run = core::cmp::min(Block::LEN - start, buf.len());
+            let (head, tail) = buf.split_at(run);
+
+            let mut block = Block::new();
+            if run != Block::LEN {
+                self.read(page as u64 * Block::LEN_U64, &mut block[..])?;
+            }
+            block[start..start + run].copy_from_slice(head);
+
+            let physical = self.allocate()?;
+            table[page] = physical;
+            self.storage_device.write(
+                data_offset + u64::from(physical) * Block::LEN_U64,
+                &block[..],
+            )?;
+
+            buf = tail;
+            offset += run as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Return the number of blocks of the data area of a device of ``pages`` pages on
+/// ``storage_device``.
+fn data_blocks<S: StorageDevice>(storage_device: &mut S, pages: u32) -> StorageDeviceResult<usize> {
+    let blocks = (storage_device.len()? / Block::LEN_U64).saturating_sub(2 * Root::blocks(pages));
+    // Every block must be addressable by the page table.
+    Ok(usize::try_from(core::cmp::min(blocks, u64::from(UNMAPPED))).unwrap_or(usize::MAX))
+}
+
+impl<S: StorageDevice> StorageDevice for ShadowPagedDevice<S> {
+    fn read(&mut self, mut offset: u64, mut buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        let data_offset = self.data_offset();
+        while !buf.is_empty() {
+            let page = (offset / Block::LEN_U64) as usize;
+            let start = offset % Block::LEN_U64;
+            let run = core::cmp::min(Block::LEN - start as usize, buf.len());
+            let (head, tail) = buf.split_at_mut(run);
+            match self.root.table[page] {
+                UNMAPPED => head.fill(0),
+                physical => self.storage_device.read(
+                    data_offset + u64::from(physical) * Block::LEN_U64 + start,
+                    head,
+                )?,
+            }
+            buf = tail;
+            offset += run as u64;
+        }
+        Ok(())
+    }
+
+    /// Write the modified pages to free blocks, then switch to a new root mapping them.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let mut root = Root {
+            generation: self.root.generation + 1,
+            table: self.root.table.clone(),
+        };
+        let slot = 1 - self.slot;
+        let root_len = Root::blocks(self.pages()) * Block::LEN_U64;
+        let result = self
+            .write_pages(&mut root.table, offset, buf)
+            .and_then(|()| self.storage_device.barrier())
+            .and_then(|()| self.storage_device.write(slot * root_len, &root.to_bytes()))
+            .and_then(|()| self.storage_device.barrier());
+
+        // Release the blocks of the pages replaced, or of the pages written if the root wasn't.
+        let (old, new) = match result {
+            Ok(()) => (&self.root.table, &root.table),
+            Err(_) => (&root.table, &self.root.table),
+        };
+        for (&old, &new) in old.iter().zip(new) {
+            if old != new && old != UNMAPPED {
+                self.used[old as usize] = false;
+            }
+        }
+        result?;
+
+        self.root = root;
+        self.slot = slot;
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(u64::from(self.pages()) * Block::LEN_U64)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn torn_root_writes_fall_back_to_the_previous_root() {
+        // With 200 pages, each root takes two blocks.
+        let image = vec![0u8; 512 * Block::LEN];
+        let mut device = ShadowPagedDevice::create(image, 200).unwrap();
+        device.write(100, &[0x22; 1000]).unwrap();
+        device.write(600, &[0x33; 10]).unwrap();
+        assert_eq!(device.generation(), 3);
+
+        let mut before = vec![0u8; 200 * Block::LEN];
+        device.read(0, &mut before).unwrap();
+        let image_before = device.into_inner();
+        let mut device = ShadowPagedDevice::open(image_before.clone()).unwrap();
+        device.write(0, &[0x44; 2000]).unwrap();
+        let mut after = vec![0u8; 200 * Block::LEN];
+        device.read(0, &mut after).unwrap();
+        let image_after = device.into_inner();
+
+        // The root of the fourth generation went to the second slot, over the second generation.
+        let mut device = ShadowPagedDevice::open(image_after.clone()).unwrap();
+        assert_eq!(device.generation(), 4);
+        let mut content = vec![0u8; 200 * Block::LEN];
+        device.read(0, &mut content).unwrap();
+        assert_eq!(content, after);
+
+        // Only the first block of that root was written: it is ignored, and the third generation
+        // is picked over the intact older one it replaced.
+        let root_len = 2 * Block::LEN;
+        let mut torn = image_after;
+        torn[root_len + Block::LEN..2 * root_len]
+            .copy_from_slice(&image_before[root_len + Block::LEN..2 * root_len]);
+        let mut device = ShadowPagedDevice::open(torn).unwrap();
+        assert_eq!(device.generation(), 3);
+        device.read(0, &mut content).unwrap();
+        assert_eq!(content, before);
+
+        // The next write goes over the torn root.
+        device.write(0, &[0x55; 10]).unwrap();
+        let device = ShadowPagedDevice::open(device.into_inner()).unwrap();
+        assert_eq!(device.generation(), 4);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::{Block, BlockIndex, StorageDevice, StorageDeviceError, StorageDeviceResult};
+
+/// Where the metadata of a [SidecarStorageDevice] is stored on the backing device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SidecarLayout {
+    /// The metadata of every block directly follows its data.
+    Interleaved,
+
+    /// The data of every block comes first, followed by the metadata of every block.
+    Segregated,
+}
+
+/// A storage device associating a fixed amount of metadata with every block of data.
+///
+/// Data is exposed as an ordinary [StorageDevice] through the usual operations, while
+/// [SidecarStorageDevice::read_with_meta] and [SidecarStorageDevice::write_with_meta]
+/// also give access to the metadata of whole blocks. This is meant to be used to store
+/// checksums, authentication tags or generation numbers alongside the data they protect.
+///
+/// Blocks are [Block::LEN] bytes long.
+#[derive(Debug)]
+pub struct SidecarStorageDevice<S: StorageDevice> {
+    /// The backing storage device.
+    storage_device: S,
+
+    /// The size of the metadata of every block in bytes.
+    meta_len: usize,
+
+    /// Where the metadata is stored.
+    layout: SidecarLayout,
+
+    /// The amount of blocks of data held by the device.
+    block_count: u64,
+}
+
+impl<S: StorageDevice> SidecarStorageDevice<S> {
+    /// Create a new sidecar storage device, storing ``meta_len`` bytes of metadata for every block
+    /// of ``storage_device``.
+    ///
+    /// The amount of blocks is computed from the size of the backing device, and is fixed from then
+    /// on.
+    pub fn new(
+        mut storage_device: S,
+        meta_len: usize,
+        layout: SidecarLayout,
+    ) -> StorageDeviceResult<Self> {
+        let block_count = storage_device.len()? / (Block::LEN_U64 + meta_len as u64);
+
+        Ok(SidecarStorageDevice {
+            storage_device,
+            meta_len,
+            layout,
+            block_count,
+        })
+    }
+
+    /// Return the size of the metadata of every block in bytes.
+    pub fn meta_len(&self) -> usize {
+        self.meta_len
+    }
+
+    /// Return where the metadata is stored.
+    pub fn layout(&self) -> SidecarLayout {
+        self.layout
+    }
+
+    /// Return the amount of blocks of data held by the device.
+    pub fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    /// Consume the sidecar storage device, returning the backing storage device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Return the offset of the data of the block at ``index`` on the backing device.
+    fn data_offset(&self, index: u64) -> u64 {
+        match self.layout {
+            SidecarLayout::Interleaved => index * (Block::LEN_U64 + self.meta_len as u64),
+            SidecarLayout::Segregated => index * Block::LEN_U64,
+        }
+    }
+
+    /// Return the offset of the metadata of the block at ``index`` on the backing device.
+    fn meta_offset(&self, index: u64) -> u64 {
+        match self.layout {
+            SidecarLayout::Interleaved => self.data_offset(index) + Block::LEN_U64,
+            SidecarLayout::Segregated => {
+                self.block_count * Block::LEN_U64 + index * self.meta_len as u64
+            }
+        }
+    }
+
+    /// Check that the given range of bytes of data is inside the device.
+    fn check_bounds(&self, offset: u64, len: usize) -> StorageDeviceResult<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.block_count * Block::LEN_U64 => Ok(()),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+
+    /// Check that ``data`` and ``meta`` hold the same amount of blocks starting at ``index``,
+    /// returning that amount.
+    ///
+    /// Panics if the buffers are not made of whole blocks, or if their sizes don't match.
+    fn check_meta_buffers(
+        &self,
+        index: BlockIndex,
+        data_len: usize,
+        meta_len: usize,
+    ) -> StorageDeviceResult<u64> {
+        assert_eq!(
+            data_len % Block::LEN,
+            0,
+            "data must be made of whole blocks"
+        );
+        let count = (data_len / Block::LEN) as u64;
+        assert_eq!(
+            meta_len as u64,
+            count * self.meta_len as u64,
+            "metadata must be provided for every block"
+        );
+
+        match index.0.checked_add(count) {
+            Some(end) if end <= self.block_count => Ok(count),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+
+    /// Read whole blocks of data starting at the given ``index``, along with their metadata.
+    ///
+    /// ``data`` must be made of whole blocks, and ``meta`` must hold
+    /// [SidecarStorageDevice::meta_len] bytes per block.
+    pub fn read_with_meta(
+        &mut self,
+        index: BlockIndex,
+        data: &mut [u8],
+        meta: &mut [u8],
+    ) -> StorageDeviceResult<()> {
+        let count = self.check_meta_buffers(index, data.len(), meta.len())?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        match self.layout {
+            SidecarLayout::Interleaved => {
+                for i in 0..count as usize {
+                    let data_chunk = &mut data[i * Block::LEN..(i + 1) * Block::LEN];
+                    let meta_chunk = &mut meta[i * self.meta_len..(i + 1) * self.meta_len];
+                    let offset = self.data_offset(index.0 + i as u64);
+                    self.storage_device
+                        .read_vectored(offset, &mut [data_chunk, meta_chunk])?;
+                }
+                Ok(())
+            }
+            SidecarLayout::Segregated => {
+                let data_offset = self.data_offset(index.0);
+                let meta_offset = self.meta_offset(index.0);
+                self.storage_device.read(data_offset, data)?;
+                self.storage_device.read(meta_offset, meta)
+            }
+        }
+    }
+
+    /// Write whole blocks of data starting at the given ``index``, along with their metadata.
+    ///
+    /// ``data`` must be made of whole blocks, and ``meta`` must hold
+    /// [SidecarStorageDevice::meta_len] bytes per block.
+    pub fn write_with_meta(
+        &mut self,
+        index: BlockIndex,
+        data: &[u8],
+        meta: &[u8],
+    ) -> StorageDeviceResult<()> {
+        let count = self.check_meta_buffers(index, data.len(), meta.len())?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        match self.layout {
+            SidecarLayout::Interleaved => {
+                for i in 0..count as usize {
+                    let data_chunk = &data[i * Block::LEN..(i + 1) * Block::LEN];
+                    let meta_chunk = &meta[i * self.meta_len..(i + 1) * self.meta_len];
+                    let offset = self.data_offset(index.0 + i as u64);
+                    self.storage_device
+                        .write_vectored(offset, &[data_chunk, meta_chunk])?;
+                }
+                Ok(())
+            }
+            SidecarLayout::Segregated => {
+                let data_offset = self.data_offset(index.0);
+                let meta_offset = self.meta_offset(index.0);
+                self.storage_device.write(data_offset, data)?;
+                self.storage_device.write(meta_offset, meta)
+            }
+        }
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for SidecarStorageDevice<S> {
+    /// Read the data, skipping over the metadata.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+
+        if self.layout == SidecarLayout::Segregated {
+            return self.storage_device.read(offset, buf);
+        }
+
+        let mut read_size = 0;
+        while read_size < buf.len() {
+            let current_offset = offset + read_size as u64;
+            let block_offset = (current_offset % Block::LEN_U64) as usize;
+            let len = core::cmp::min(Block::LEN - block_offset, buf.len() - read_size);
+
+            let physical_offset =
+                self.data_offset(current_offset / Block::LEN_U64) + block_offset as u64;
+            self.storage_device
+                .read(physical_offset, &mut buf[read_size..read_size + len])?;
+
+            read_size += len;
+        }
+
+        Ok(())
+    }
+
+    /// Write the data, leaving the metadata untouched.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+
+        if self.layout == SidecarLayout::Segregated {
+            return self.storage_device.write(offset, buf);
+        }
+
+        let mut write_size = 0;
+        while write_size < buf.len() {
+            let current_offset = offset + write_size as u64;
+            let block_offset = (current_offset % Block::LEN_U64) as usize;
+            let len = core::cmp::min(Block::LEN - block_offset, buf.len() - write_size);
+
+            let physical_offset =
+                self.data_offset(current_offset / Block::LEN_U64) + block_offset as u64;
+            self.storage_device
+                .write(physical_offset, &buf[write_size..write_size + len])?;
+
+            write_size += len;
+        }
+
+        Ok(())
+    }
+
+    /// Return the size of the data held by the device.
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.block_count * Block::LEN_U64)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{StorageDevice, StorageDeviceResult};
+use core::ops::Range;
+
+/// A range of a sparse device, reported by [Ranges].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SparseRange {
+    /// A range holding data.
+    Data(Range<u64>),
+
+    /// A range that isn't allocated, reading as zeros.
+    Hole(Range<u64>),
+}
+
+impl SparseRange {
+    /// Return the bytes covered by the range.
+    pub fn range(&self) -> Range<u64> {
+        match self {
+            SparseRange::Data(range) | SparseRange::Hole(range) => range.clone(),
+        }
+    }
+
+    /// Check whether the range is a hole.
+    pub fn is_hole(&self) -> bool {
+        matches!(self, SparseRange::Hole(_))
+    }
+}
+
+/// An iterator over the data and holes of a device, returned by
+/// [StorageDeviceExt::ranges](crate::StorageDeviceExt::ranges).
+///
+/// The ranges follow each other and cover the whole device. Devices that can't report their
+/// holes are a single data range. The iteration stops after the first error.
+#[derive(Debug)]
+pub struct Ranges<'a, S: StorageDevice + ?Sized> {
+    /// The device to report the ranges of.
+    storage_device: &'a mut S,
+
+    /// The start of the next range.
+    offset: u64,
+
+    /// The size of the device, once queried.
+    len: Option<u64>,
+
+    /// The data range found after a hole, reported next.
+    pending: Option<Range<u64>>,
+}
+
+impl<'a, S: StorageDevice + ?Sized> Ranges<'a, S> {
+    /// Iterate over the ranges of ``storage_device``.
+    pub(crate) fn new(storage_device: &'a mut S) -> Self {
+        Ranges {
+            storage_device,
+            offset: 0,
+            len: None,
+            pending: None,
+        }
+    }
+
+    /// Return the next range, if any.
+    fn next_range(&mut self) -> StorageDeviceResult<Option<SparseRange>> {
+        let len = match self.len {
+            Some(len) => len,
+            None => {
+                let len = self.storage_device.len()?;
+                self.len = Some(len);
+                len
+            }
+        };
+        if self.offset >= len {
+            return Ok(None);
+        }
+
+        let data = match self.pending.take() {
+            Some(data) => Some(data),
+            None => self.storage_device.next_data(self.offset)?,
+        };
+        let range = match data {
+            Some(data) if data.start > self.offset => {
+                let hole = SparseRange::Hole(self.offset..data.start);
+                self.pending = Some(data);
+                hole
+            }
+            Some(data) => SparseRange::Data(self.offset..core::cmp::min(data.end, len)),
+            None => SparseRange::Hole(self.offset..len),
+        };
+        self.offset = range.range().end;
+        Ok(Some(range))
+    }
+}
+
+impl<S: StorageDevice + ?Sized> Iterator for Ranges<'_, S> {
+    type Item = StorageDeviceResult<SparseRange>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_range() {
+            Ok(range) => range.map(Ok),
+            Err(err) => {
+                // Don't keep failing on the same range.
+                self.offset = u64::MAX;
+                self.len = Some(0);
+                Some(Err(err))
+            }
+        }
+    }
+}
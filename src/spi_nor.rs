@@ -0,0 +1,525 @@
+use crate::nand::{NandDevice, NandGeometry};
+use crate::{
+    Block, BlockCount, BlockDevice, BlockError, BlockGeometry, BlockIndex, BlockResult,
+    Capabilities, DeviceIdentity,
+};
+use core::fmt::Write;
+use embedded_hal::spi::{Operation, SpiDevice};
+
+/// The size of the sectors erased by the driver, in bytes.
+pub const SPI_NOR_SECTOR_SIZE: usize = 4096;
+
+/// The number of pages in each sector, seen as an erase block through [NandDevice].
+///
+/// A page is a [Block], and the last [Block] of each sector holds the spare areas of the pages.
+pub const SPI_NOR_PAGES_PER_SECTOR: u32 = 7;
+
+/// The size of the spare area of each page seen through [NandDevice], in bytes.
+pub const SPI_NOR_SPARE_SIZE: usize = 64;
+
+/// The default number of times the status register is polled before giving up on an operation.
+pub const DEFAULT_MAX_STATUS_POLLS: u32 = 10_000_000;
+
+/// The number of [Block]s in a sector.
+const SECTOR_BLOCKS: usize = SPI_NOR_SECTOR_SIZE / Block::LEN;
+
+/// Read the manufacturer and device identifiers.
+const READ_JEDEC_ID: u8 = 0x9F;
+
+/// Read the Serial Flash Discoverable Parameters, with a 3-byte address and a dummy byte.
+const READ_SFDP: u8 = 0x5A;
+
+/// Allow the next program or erase.
+const WRITE_ENABLE: u8 = 0x06;
+
+/// Read the status register.
+const READ_STATUS: u8 = 0x05;
+
+/// The bit of the status register set while a program or erase is in progress.
+const STATUS_BUSY: u8 = 0x01;
+
+/// Read data, with a 3-byte address and a dummy byte.
+const FAST_READ: u8 = 0x0B;
+
+/// Read data, with a 4-byte address and a dummy byte.
+const FAST_READ_4B: u8 = 0x0C;
+
+/// Program up to a page, with a 3-byte address.
+const PAGE_PROGRAM: u8 = 0x02;
+
+/// Program up to a page, with a 4-byte address.
+const PAGE_PROGRAM_4B: u8 = 0x12;
+
+/// Erase a 4KiB sector, with a 3-byte address, on chips without SFDP.
+const SECTOR_ERASE: u8 = 0x20;
+
+/// Erase a 4KiB sector, with a 4-byte address.
+const SECTOR_ERASE_4B: u8 = 0x21;
+
+/// The signature of the SFDP header, "SFDP" in little endian.
+const SFDP_SIGNATURE: u32 = 0x5044_4653;
+
+/// The number of bytes addressable with 3-byte addresses.
+const THREE_BYTE_LIMIT: u64 = 1 << 24;
+
+/// The characteristics of a SPI NOR flash chip.
+///
+/// [SpiNorFlash::new] discovers them, but they can be given to [SpiNorFlash::with_info] for
+/// chips it doesn't know.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SpiNorInfo {
+    /// The manufacturer identifier, followed by the two bytes of the device identifier.
+    pub jedec_id: [u8; 3],
+
+    /// The size of the chip, in bytes.
+    pub capacity: u64,
+
+    /// The size of the program pages, a program must not cross, in bytes.
+    pub page_size: usize,
+
+    /// The instruction erasing a 4KiB sector with a 3-byte address.
+    pub erase_opcode: u8,
+
+    /// Whether addresses are sent on 4 bytes, with the dedicated instructions, as chips larger
+    /// than 16MiB require.
+    pub four_byte_addresses: bool,
+}
+
+impl SpiNorInfo {
+    /// Build the characteristics of a chip from its JEDEC identifier alone.
+    ///
+    /// Most manufacturers encode the capacity as its base 2 logarithm in the last byte, and
+    /// support 256-byte pages and the usual 4KiB sector erase.
+    fn from_jedec_id(jedec_id: [u8; 3]) -> BlockResult<Self> {
+        if !(16..=32).contains(&jedec_id[2]) {
+            return Err(BlockError::Unsupported);
+        }
+        let capacity = 1u64 << jedec_id[2];
+        Ok(SpiNorInfo {
+            jedec_id,
+            capacity,
+            page_size: 256,
+            erase_opcode: SECTOR_ERASE,
+            four_byte_addresses: capacity > THREE_BYTE_LIMIT,
+        })
+    }
+
+    /// Build the characteristics of a chip from its JEDEC identifier and its Basic Flash
+    /// Parameter Table, as defined by JESD216, of which ``table`` holds ``dwords`` words.
+    fn from_sfdp(jedec_id: [u8; 3], table: &[u8], dwords: usize) -> BlockResult<Self> {
+        let dword = |n: usize| {
+            let at = (n - 1) * 4;
+            u32::from_le_bytes([table[at], table[at + 1], table[at + 2], table[at + 3]])
+        };
+
+        let first = dword(1);
+        let mut erase_opcode = None;
+        if first & 0b11 == 0b01 {
+            erase_opcode = Some((first >> 8) as u8);
+        } else if dwords >= 9 {
+            // Look for a 4KiB erase among the four erase types.
+            let types = [dword(8), dword(9)];
+            for word in types.iter() {
+                for half in [*word, word >> 16].iter() {
+                    if half & 0xFF == 12 {
+                        erase_opcode = Some((half >> 8) as u8);
+                    }
+                }
+            }
+        }
+        let erase_opcode = erase_opcode.ok_or(BlockError::Unsupported)?;
+
+        let density = dword(2);
+        let capacity = if density & 0x8000_0000 == 0 {
+            (u64::from(density) + 1) / 8
+        } else {
+            let bits = density & 0x7FFF_FFFF;
+            if !(3..=66).contains(&bits) {
+                return Err(BlockError::Unsupported);
+            }
+            1 << (bits - 3)
+        };
+
+        let page_size = if dwords >= 11 {
+            1 << ((dword(11) >> 4) & 0xF)
+        } else {
+            256
+        };
+
+        let four_byte_addresses = capacity > THREE_BYTE_LIMIT;
+        let address_modes = (first >> 17) & 0b11;
+        if four_byte_addresses && address_modes == 0b00 {
+            return Err(BlockError::Unsupported);
+        }
+
+        Ok(SpiNorInfo {
+            jedec_id,
+            capacity,
+            page_size,
+            erase_opcode,
+            four_byte_addresses,
+        })
+    }
+}
+
+/// A SPI NOR flash chip, such as the ubiquitous 25-series chips, driven through an
+/// [embedded_hal::spi::SpiDevice].
+///
+/// As a [BlockDevice], writes erase and reprogram each 4KiB sector they touch, reading it
+/// first if they don't cover it whole. Sectors whose content doesn't change are left alone, and
+/// discarding erases the sectors covered whole. NOR sectors endure a limited number of erases,
+/// so filesystems writing the same blocks over and over should rather run on an
+/// [Ftl](crate::Ftl).
+///
+/// As a [NandDevice], each sector is an erase block of [SPI_NOR_PAGES_PER_SECTOR] pages of a
+/// [Block], the last [Block] of the sector holding their spare areas of [SPI_NOR_SPARE_SIZE]
+/// bytes. The two views lay data out differently, so a chip must be used through only one.
+///
+/// Chips with write protection enabled at power up must be unlocked before writing.
+pub struct SpiNorFlash<SPI: SpiDevice> {
+    /// The SPI bus, with the chip select of the chip.
+    spi: SPI,
+
+    /// The characteristics of the chip.
+    info: SpiNorInfo,
+
+    /// The number of times the status register is polled before giving up.
+    max_status_polls: u32,
+}
+
+impl<SPI: SpiDevice> core::fmt::Debug for SpiNorFlash<SPI> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SpiNorFlash")
+            .field("info", &self.info)
+            .field("max_status_polls", &self.max_status_polls)
+            .finish()
+    }
+}
+
+impl<SPI: SpiDevice> SpiNorFlash<SPI> {
+    /// Drive the chip on ``spi``, discovering its characteristics from its SFDP tables, or from
+    /// its JEDEC identifier for chips without them.
+    ///
+    /// Fail with [BlockError::Unsupported] if no chip answers, or its characteristics can't be
+    /// discovered, or it doesn't support 4KiB sector erases.
+    pub fn new(mut spi: SPI) -> BlockResult<Self> {
+        let mut jedec_id = [0; 3];
+        spi.transaction(&mut [
+            Operation::Write(&[READ_JEDEC_ID]),
+            Operation::Read(&mut jedec_id),
+        ])
+        .map_err(|_| BlockError::ReadError)?;
+        if jedec_id == [0; 3] || jedec_id == [0xFF; 3] {
+            return Err(BlockError::Unsupported);
+        }
+
+        let info = match Self::read_basic_parameters(&mut spi)? {
+            Some((table, dwords)) => SpiNorInfo::from_sfdp(jedec_id, &table, dwords)?,
+            None => SpiNorInfo::from_jedec_id(jedec_id)?,
+        };
+        Ok(Self::with_info(spi, info))
+    }
+
+    /// Drive the chip on ``spi``, whose characteristics are ``info``.
+    pub fn with_info(spi: SPI, info: SpiNorInfo) -> Self {
+        SpiNorFlash {
+            spi,
+            info,
+            max_status_polls: DEFAULT_MAX_STATUS_POLLS,
+        }
+    }
+
+    /// Give up on programs and erases once the status register was polled ``polls`` times,
+    /// instead of [DEFAULT_MAX_STATUS_POLLS].
+    pub fn with_max_status_polls(mut self, polls: u32) -> Self {
+        self.max_status_polls = polls;
+        self
+    }
+
+    /// Return the characteristics of the chip.
+    pub fn info(&self) -> &SpiNorInfo {
+        &self.info
+    }
+
+    /// Consume the driver, returning the SPI bus.
+    pub fn into_inner(self) -> SPI {
+        self.spi
+    }
+
+    /// Read the Basic Flash Parameter Table of the chip, and the number of words it holds, if it
+    /// has one.
+    fn read_basic_parameters(spi: &mut SPI) -> BlockResult<Option<([u8; 64], usize)>> {
+        let mut read_sfdp = |address: u32, buf: &mut [u8]| {
+            let [_, high, middle, low] = address.to_be_bytes();
+            spi.transaction(&mut [
+                Operation::Write(&[READ_SFDP, high, middle, low, 0]),
+                Operation::Read(buf),
+            ])
+            .map_err(|_| BlockError::ReadError)
+        };
+
+        let mut header = [0; 16];
+        read_sfdp(0, &mut header)?;
+        let signature = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        // The first parameter header must be the one of the Basic Flash Parameter Table.
+        if signature != SFDP_SIGNATURE || header[8] != 0x00 || header[15] != 0xFF {
+            return Ok(None);
+        }
+
+        let dwords = core::cmp::min(usize::from(header[11]), 16);
+        if dwords < 2 {
+            return Ok(None);
+        }
+        let pointer = u32::from_le_bytes([header[12], header[13], header[14], 0]);
+        let mut table = [0; 64];
+        read_sfdp(pointer, &mut table[..dwords * 4])?;
+        Ok(Some((table, dwords)))
+    }
+
+    /// Return the instruction ``opcode`` followed by ``address``, and how many of its bytes to
+    /// send.
+    fn command(&self, opcode: u8, address: u64) -> ([u8; 6], usize) {
+        let [_, _, _, _, a3, a2, a1, a0] = address.to_be_bytes();
+        if self.info.four_byte_addresses {
+            ([opcode, a3, a2, a1, a0, 0], 5)
+        } else {
+            ([opcode, a2, a1, a0, 0, 0], 4)
+        }
+    }
+
+    /// Read ``buf`` from ``address``.
+    fn read_bytes(&mut self, address: u64, buf: &mut [u8]) -> BlockResult<()> {
+        let opcode = if self.info.four_byte_addresses {
+            FAST_READ_4B
+        } else {
+            FAST_READ
+        };
+        // The dummy byte comes after the address.
+        let (command, len) = self.command(opcode, address);
+        self.spi
+            .transaction(&mut [Operation::Write(&command[..len + 1]), Operation::Read(buf)])
+            .map_err(|_| BlockError::ReadError)
+    }
+
+    /// Send ``operations`` after enabling writes, then wait for the chip to be done.
+    fn write_command(&mut self, operations: &mut [Operation<'_, u8>]) -> BlockResult<()> {
+        self.spi
+            .write(&[WRITE_ENABLE])
+            .and_then(|()| self.spi.transaction(operations))
+            .map_err(|_| BlockError::WriteError)?;
+
+        let mut status = [STATUS_BUSY];
+        for _ in 0..self.max_status_polls {
+            self.spi
+                .transaction(&mut [
+                    Operation::Write(&[READ_STATUS]),
+                    Operation::Read(&mut status),
+                ])
+                .map_err(|_| BlockError::WriteError)?;
+            if status[0] & STATUS_BUSY == 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockError::WriteError)
+    }
+
+    /// Program ``data`` at ``address``, one program page at a time.
+    fn program_bytes(&mut self, mut address: u64, mut data: &[u8]) -> BlockResult<()> {
+        let opcode = if self.info.four_byte_addresses {
+            PAGE_PROGRAM_4B
+        } else {
+            PAGE_PROGRAM
+        };
+        let page_size = self.info.page_size as u64;
+        while !data.is_empty() {
+            let run = core::cmp::min((page_size - address % page_size) as usize, data.len());
+            let (head, tail) = data.split_at(run);
+            // Programming erased bytes with 0xFF changes nothing.
+            if head.iter().any(|&byte| byte != 0xFF) {
+                let (command, len) = self.command(opcode, address);
+                self.write_command(&mut [
+                    Operation::Write(&command[..len]),
+                    Operation::Write(head),
+                ])?;
+            }
+            data = tail;
+            address += run as u64;
+        }
+        Ok(())
+    }
+
+    /// Erase the sector starting at ``address``.
+    fn erase_sector(&mut self, address: u64) -> BlockResult<()> {
+        let opcode = if self.info.four_byte_addresses {
+            SECTOR_ERASE_4B
+        } else {
+            self.info.erase_opcode
+        };
+        let (command, len) = self.command(opcode, address);
+        self.write_command(&mut [Operation::Write(&command[..len])])
+    }
+
+    /// Return the number of sectors of the chip.
+    fn sectors(&self) -> u64 {
+        self.info.capacity / SPI_NOR_SECTOR_SIZE as u64
+    }
+
+    /// Check that ``len`` blocks at ``index`` are inside the chip, returning ``error`` otherwise.
+    fn check_bounds(&self, index: BlockIndex, len: usize, error: BlockError) -> BlockResult<()> {
+        let blocks = self.info.capacity / Block::LEN_U64;
+        match index.0.checked_add(len as u64) {
+            Some(end) if end <= blocks => Ok(()),
+            _ => Err(error),
+        }
+    }
+
+    /// Return the address of the spare area of ``page``, seen through [NandDevice].
+    fn spare_address(&self, page: u64) -> u64 {
+        let pages = u64::from(SPI_NOR_PAGES_PER_SECTOR);
+        let sector = (page / pages) * SPI_NOR_SECTOR_SIZE as u64;
+        sector + pages * Block::LEN_U64 + (page % pages) * SPI_NOR_SPARE_SIZE as u64
+    }
+
+    /// Return the address of the data of ``page``, seen through [NandDevice], if it is inside
+    /// the chip.
+    fn page_address(&self, page: u64) -> Option<u64> {
+        if page >= self.sectors() * u64::from(SPI_NOR_PAGES_PER_SECTOR) {
+            return None;
+        }
+        let pages = u64::from(SPI_NOR_PAGES_PER_SECTOR);
+        Some((page / pages) * SPI_NOR_SECTOR_SIZE as u64 + (page % pages) * Block::LEN_U64)
+    }
+}
+
+impl<SPI: SpiDevice> BlockDevice for SpiNorFlash<SPI> {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        self.check_bounds(index, blocks.len(), BlockError::ReadError)?;
+        for (i, block) in blocks.iter_mut().enumerate() {
+            self.read_bytes(
+                index.into_offset() + (i * Block::LEN) as u64,
+                &mut block[..],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, mut blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        self.check_bounds(index, blocks.len(), BlockError::WriteError)?;
+        let mut index = index.0;
+        let mut sector: [Block; SECTOR_BLOCKS] = core::array::from_fn(|_| Block::new());
+        let mut current = [0; Block::LEN];
+        while !blocks.is_empty() {
+            let in_sector = (index % SECTOR_BLOCKS as u64) as usize;
+            let run = core::cmp::min(SECTOR_BLOCKS - in_sector, blocks.len());
+            let (head, tail) = blocks.split_at(run);
+            let address = (index - in_sector as u64) * Block::LEN_U64;
+
+            // Read the sector, to keep what isn't written and skip it if nothing changes.
+            let mut changed = false;
+            for (i, block) in sector.iter_mut().enumerate() {
+                let written = i.checked_sub(in_sector).and_then(|i| head.get(i));
+                let block_address = address + (i * Block::LEN) as u64;
+                match written {
+                    Some(written) => {
+                        self.read_bytes(block_address, &mut current)?;
+                        changed |= current[..] != written[..];
+                        block.copy_from_slice(&written[..]);
+                    }
+                    None => self.read_bytes(block_address, &mut block[..])?,
+                }
+            }
+
+            if changed {
+                self.erase_sector(address)?;
+                for (i, block) in sector.iter().enumerate() {
+                    self.program_bytes(address + (i * Block::LEN) as u64, &block[..])?;
+                }
+            }
+            blocks = tail;
+            index += run as u64;
+        }
+        Ok(())
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        Ok(BlockCount(self.info.capacity / Block::LEN_U64))
+    }
+
+    /// Erase the sectors covered whole by the range.
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        let end = index.0.saturating_add(count.0);
+        let first = index.0.div_ceil(SECTOR_BLOCKS as u64);
+        let last = core::cmp::min(end / SECTOR_BLOCKS as u64, self.sectors());
+        for sector in first..last {
+            self.erase_sector(sector * SPI_NOR_SECTOR_SIZE as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Report the erase sectors as physical blocks, as writing part of one rewrites all of it.
+    fn geometry(&mut self) -> BlockResult<BlockGeometry> {
+        Ok(BlockGeometry {
+            physical_block_size: SPI_NOR_SECTOR_SIZE,
+            optimal_transfer_size: SPI_NOR_SECTOR_SIZE,
+            ..BlockGeometry::default()
+        })
+    }
+
+    /// Report the JEDEC ID of the chip, made of its manufacturer, memory type and capacity, as
+    /// its model.
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        let mut identity = DeviceIdentity::default();
+        for byte in &self.info.jedec_id {
+            let _ = write!(identity.model, "{:02X}", byte);
+        }
+        Ok(identity)
+    }
+
+    /// Discards erase whole sectors, reclaiming them.
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        Ok(Capabilities::TRIM)
+    }
+}
+
+impl<SPI: SpiDevice> NandDevice for SpiNorFlash<SPI> {
+    fn geometry(&self) -> NandGeometry {
+        NandGeometry {
+            page_size: Block::LEN,
+            spare_size: SPI_NOR_SPARE_SIZE,
+            pages_per_erase_block: SPI_NOR_PAGES_PER_SECTOR,
+            erase_blocks: self.sectors(),
+        }
+    }
+
+    fn read_page(&mut self, page: u64, data: &mut [Block], spare: &mut [u8]) -> BlockResult<()> {
+        let address = self.page_address(page).ok_or(BlockError::ReadError)?;
+        if data.len() > 1 || spare.len() > SPI_NOR_SPARE_SIZE {
+            return Err(BlockError::ReadError);
+        }
+        if let Some(block) = data.first_mut() {
+            self.read_bytes(address, &mut block[..])?;
+        }
+        if !spare.is_empty() {
+            self.read_bytes(self.spare_address(page), spare)?;
+        }
+        Ok(())
+    }
+
+    fn program_page(&mut self, page: u64, data: &[Block], spare: &[u8]) -> BlockResult<()> {
+        let address = self.page_address(page).ok_or(BlockError::WriteError)?;
+        if data.len() > 1 || spare.len() > SPI_NOR_SPARE_SIZE {
+            return Err(BlockError::WriteError);
+        }
+        if let Some(block) = data.first() {
+            self.program_bytes(address, &block[..])?;
+        }
+        self.program_bytes(self.spare_address(page), spare)
+    }
+
+    fn erase(&mut self, erase_block: u64) -> BlockResult<()> {
+        if erase_block >= self.sectors() {
+            return Err(BlockError::WriteError);
+        }
+        self.erase_sector(erase_block * SPI_NOR_SECTOR_SIZE as u64)
+    }
+}
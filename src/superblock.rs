@@ -0,0 +1,247 @@
+use crate::crc::crc32_update;
+use crate::ext::as_bytes;
+use crate::{NoPadding, StorageDevice, StorageDeviceError, StorageDeviceResult};
+use plain::Plain;
+
+/// Size in bytes of the header preceding every copy of a superblock.
+const HEADER_LEN: usize = 24;
+
+/// Header preceding every copy of a superblock on the device.
+///
+/// It is stored in little endian as follow:
+///
+/// | Offset | Size | Field    |
+/// |--------|------|----------|
+/// | 0      | 4    | magic    |
+/// | 4      | 4    | version  |
+/// | 8      | 8    | sequence |
+/// | 16     | 4    | length   |
+/// | 20     | 4    | crc      |
+///
+/// The CRC-32 covers the header (with the crc field set to zero) followed by the payload.
+#[derive(Debug, Copy, Clone)]
+struct SuperblockHeader {
+    /// Identifies the kind of superblock.
+    magic: u32,
+
+    /// Version of the layout of the payload.
+    version: u32,
+
+    /// Incremented every time the superblock is stored, used to pick the newest copy.
+    sequence: u64,
+
+    /// Size of the payload in bytes.
+    length: u32,
+
+    /// CRC-32 of the header and payload.
+    crc: u32,
+}
+
+impl SuperblockHeader {
+    /// Serialize the header.
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.length.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialize the header.
+    fn from_bytes(bytes: &[u8; HEADER_LEN]) -> Self {
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        let mut sequence = [0u8; 8];
+        let mut length = [0u8; 4];
+        let mut crc = [0u8; 4];
+
+        magic.copy_from_slice(&bytes[0..4]);
+        version.copy_from_slice(&bytes[4..8]);
+        sequence.copy_from_slice(&bytes[8..16]);
+        length.copy_from_slice(&bytes[16..20]);
+        crc.copy_from_slice(&bytes[20..24]);
+
+        SuperblockHeader {
+            magic: u32::from_le_bytes(magic),
+            version: u32::from_le_bytes(version),
+            sequence: u64::from_le_bytes(sequence),
+            length: u32::from_le_bytes(length),
+            crc: u32::from_le_bytes(crc),
+        }
+    }
+
+    /// Compute the CRC-32 of this header and the given payload.
+    fn compute_crc(self, payload: &[u8]) -> u32 {
+        let header = SuperblockHeader { crc: 0, ..self };
+        crc32_update(crc32_update(0, &header.to_bytes()), payload)
+    }
+}
+
+/// A superblock stored redundantly on a storage device.
+///
+/// Two copies of the superblock are kept at fixed offsets (a primary and a backup one). Each copy
+/// is preceded by a header holding a magic, a version, a sequence number and a CRC-32. When
+/// loading, the valid copy with the highest sequence number is picked, so a crash while storing
+/// the superblock always leaves at least one usable copy behind.
+///
+/// Every copy takes ``24 + size_of::<T>()`` bytes on the device. ``T`` is read from any bytes
+/// found on the device, hence the [Plain] bound, and its raw bytes are written and checksummed,
+/// hence the [NoPadding] one.
+#[derive(Debug)]
+pub struct Superblock<T: Plain + NoPadding> {
+    /// The magic identifying this superblock.
+    magic: u32,
+
+    /// The version of the layout of ``T``.
+    version: u32,
+
+    /// The offsets of the primary and backup copies.
+    offsets: [u64; 2],
+
+    /// The sequence number of the last stored or loaded copy.
+    sequence: u64,
+
+    /// The content of the superblock.
+    value: T,
+}
+
+impl<T: Plain + NoPadding + Default> Superblock<T> {
+    /// Create a new superblock holding ``value``, to be stored at ``primary_offset`` and
+    /// ``backup_offset``.
+    ///
+    /// Nothing is written to the device until [Superblock::store] is called.
+    pub fn new(
+        magic: u32,
+        version: u32,
+        primary_offset: u64,
+        backup_offset: u64,
+        value: T,
+    ) -> Self {
+        Superblock {
+            magic,
+            version,
+            offsets: [primary_offset, backup_offset],
+            sequence: 0,
+            value,
+        }
+    }
+
+    /// Load the newest valid copy of a superblock stored at ``primary_offset`` and
+    /// ``backup_offset``.
+    ///
+    /// A copy is valid if its magic, version, length and CRC-32 match.
+    /// Return [StorageDeviceError::Corrupted] if no copy is valid.
+    pub fn load<S: StorageDevice>(
+        device: &mut S,
+        magic: u32,
+        version: u32,
+        primary_offset: u64,
+        backup_offset: u64,
+    ) -> StorageDeviceResult<Self> {
+        let mut superblock = Self::new(magic, version, primary_offset, backup_offset, T::default());
+        let mut found = false;
+
+        for offset in superblock.offsets.iter() {
+            let mut candidate = T::default();
+            let sequence = match superblock.load_copy(device, *offset, &mut candidate) {
+                Ok(Some(sequence)) => sequence,
+                // An unreadable copy is no different from a corrupted one, the other copy might still be fine.
+                Ok(None) | Err(_) => continue,
+            };
+
+            if !found || sequence > superblock.sequence {
+                superblock.sequence = sequence;
+                superblock.value = candidate;
+                found = true;
+            }
+        }
+
+        if !found {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        Ok(superblock)
+    }
+
+    /// Read the copy at ``offset`` into ``value``, returning its sequence number if it is valid.
+    fn load_copy<S: StorageDevice>(
+        &self,
+        device: &mut S,
+        offset: u64,
+        value: &mut T,
+    ) -> StorageDeviceResult<Option<u64>> {
+        let mut header_bytes = [0u8; HEADER_LEN];
+        device.read(offset, &mut header_bytes)?;
+        let header = SuperblockHeader::from_bytes(&header_bytes);
+
+        if header.magic != self.magic
+            || header.version != self.version
+            || header.length as usize != core::mem::size_of::<T>()
+        {
+            return Ok(None);
+        }
+
+        // Writing arbitrary bytes to a Plain type is always fine.
+        let payload = unsafe { plain::as_mut_bytes(value) };
+        device.read(offset + HEADER_LEN as u64, payload)?;
+
+        if header.compute_crc(payload) != header.crc {
+            return Ok(None);
+        }
+
+        Ok(Some(header.sequence))
+    }
+
+    /// Write both copies of the superblock to the device, bumping its sequence number.
+    ///
+    /// The backup copy is written first, followed by a [barrier](StorageDevice::barrier), so that
+    /// the primary one is only overwritten once the new content is safely stored.
+    pub fn store<S: StorageDevice>(&mut self, device: &mut S) -> StorageDeviceResult<()> {
+        let payload = as_bytes(&self.value);
+        let sequence = self.sequence + 1;
+
+        let mut header = SuperblockHeader {
+            magic: self.magic,
+            version: self.version,
+            sequence,
+            length: payload.len() as u32,
+            crc: 0,
+        };
+        header.crc = header.compute_crc(payload);
+        let header_bytes = header.to_bytes();
+
+        let [primary_offset, backup_offset] = self.offsets;
+        device.write(backup_offset, &header_bytes)?;
+        device.write(backup_offset + HEADER_LEN as u64, payload)?;
+        device.barrier()?;
+        device.write(primary_offset, &header_bytes)?;
+        device.write(primary_offset + HEADER_LEN as u64, payload)?;
+
+        self.sequence = sequence;
+        Ok(())
+    }
+
+    /// Return the sequence number of the last stored or loaded copy.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Return the content of the superblock.
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// Return a mutable reference to the content of the superblock.
+    ///
+    /// Changes are only written to the device by [Superblock::store].
+    pub fn value_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+
+    /// Consume the superblock, returning its content.
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
@@ -0,0 +1,993 @@
+use crate::{BlockError, Capabilities, DeviceIdentity, ReadBuf, StorageDeviceError};
+use std::fs::File;
+use std::io;
+
+/// Deallocate the storage backing the ``len`` bytes at ``offset`` in ``file``, leaving a hole that
+/// reads as zeros.
+///
+/// The size of the file is left unchanged.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Deallocate the storage backing the ``len`` bytes at ``offset`` in ``file``, leaving a hole that
+/// reads as zeros.
+///
+/// The file is marked as sparse beforehand, as NTFS only deallocates zeroed ranges of sparse files.
+#[cfg(windows)]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::System::Ioctl::{
+        FILE_ZERO_DATA_INFORMATION, FSCTL_SET_SPARSE, FSCTL_SET_ZERO_DATA,
+    };
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    if len == 0 {
+        return Ok(());
+    }
+
+    let handle = file.as_raw_handle();
+    let mut bytes_returned = 0;
+
+    let ret = unsafe {
+        DeviceIoControl(
+            handle,
+            FSCTL_SET_SPARSE,
+            core::ptr::null(),
+            0,
+            core::ptr::null_mut(),
+            0,
+            &mut bytes_returned,
+            core::ptr::null_mut(),
+        )
+    };
+
+    if ret == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let zero_data = FILE_ZERO_DATA_INFORMATION {
+        FileOffset: offset as i64,
+        BeyondFinalZero: (offset + len) as i64,
+    };
+
+    let ret = unsafe {
+        DeviceIoControl(
+            handle,
+            FSCTL_SET_ZERO_DATA,
+            &zero_data as *const FILE_ZERO_DATA_INFORMATION as *const _,
+            core::mem::size_of::<FILE_ZERO_DATA_INFORMATION>() as u32,
+            core::ptr::null_mut(),
+            0,
+            &mut bytes_returned,
+            core::ptr::null_mut(),
+        )
+    };
+
+    if ret == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Deallocate the storage backing the ``len`` bytes at ``offset`` in ``file``.
+///
+/// Hole punching is not supported on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+pub fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// The magics of the filesystems supporting ``FALLOC_FL_PUNCH_HOLE``, as reported by fstatfs.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const PUNCH_HOLE_FILESYSTEMS: [u32; 9] = [
+    0x0000_EF53, // ext2, ext3 and ext4
+    0x5846_5342, // XFS
+    0x9123_683E, // btrfs
+    0x0102_1994, // tmpfs
+    0xF2F5_2010, // F2FS
+    0x7461_636F, // OCFS2
+    0x0116_1970, // GFS2
+    0x2FC1_2FC1, // ZFS
+    0xCA45_1A4E, // bcachefs
+];
+
+/// Check whether holes can be punched in ``file``.
+///
+/// This is the case if the filesystem holding the file is known to support it, which is checked
+/// without modifying the file, so it works on read-only files too.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn supports_holes(file: &File) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let mut stat = core::mem::MaybeUninit::<libc::statfs>::uninit();
+    let ret = unsafe { libc::fstatfs(file.as_raw_fd(), stat.as_mut_ptr()) };
+    if ret != 0 {
+        return false;
+    }
+    // SAFETY: fstatfs succeeded, so it initialized the structure.
+    let stat = unsafe { stat.assume_init() };
+    PUNCH_HOLE_FILESYSTEMS.contains(&(stat.f_type as u32))
+}
+
+/// Check whether holes can be punched in ``file``.
+///
+/// This is the case if the volume holding the file supports sparse files.
+#[cfg(windows)]
+pub fn supports_holes(file: &File) -> bool {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::GetVolumeInformationByHandleW;
+    use windows_sys::Win32::System::SystemServices::FILE_SUPPORTS_SPARSE_FILES;
+
+    let mut flags = 0;
+    let ret = unsafe {
+        GetVolumeInformationByHandleW(
+            file.as_raw_handle(),
+            core::ptr::null_mut(),
+            0,
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+            &mut flags,
+            core::ptr::null_mut(),
+            0,
+        )
+    };
+
+    ret != 0 && flags & FILE_SUPPORTS_SPARSE_FILES != 0
+}
+
+/// Check whether holes can be punched in ``file``.
+///
+/// Hole punching is not supported on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+pub fn supports_holes(_file: &File) -> bool {
+    false
+}
+
+/// Read from ``file`` at ``offset`` without moving the file cursor.
+#[cfg(unix)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+/// Read from ``file`` at ``offset``.
+#[cfg(windows)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// Write all of ``buf`` to ``file`` at ``offset`` without moving the file cursor.
+#[cfg(unix)]
+pub fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+/// Write all of ``buf`` to ``file`` at ``offset``.
+#[cfg(windows)]
+pub fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(size) => {
+                buf = &buf[size..];
+                offset += size as u64;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Fill ``buf`` from ``file`` at ``offset`` without moving the file cursor.
+#[cfg(any(unix, windows))]
+pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match read_at(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(size) => {
+                buf = &mut buf[size..];
+                offset += size as u64;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Fill the rest of ``buf`` from ``file`` at ``offset`` without moving the file cursor, nor
+/// initializing the buffer first.
+#[cfg(unix)]
+pub fn read_buf_exact_at(file: &File, buf: &mut ReadBuf<'_>, mut offset: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    while buf.remaining() != 0 {
+        // SAFETY: pread only writes the bytes read from the file to the buffer.
+        let unfilled = unsafe { buf.unfilled_mut() };
+        let ret = unsafe {
+            libc::pread(
+                file.as_raw_fd(),
+                unfilled.as_mut_ptr().cast(),
+                unfilled.len(),
+                offset as libc::off_t,
+            )
+        };
+        match ret {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            size => {
+                // SAFETY: pread initialized the first size bytes of the buffer.
+                unsafe { buf.assume_init(size as usize) };
+                offset += size as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fill the rest of ``buf`` from ``file`` at ``offset``, initializing it first.
+#[cfg(windows)]
+pub fn read_buf_exact_at(file: &File, buf: &mut ReadBuf<'_>, offset: u64) -> io::Result<()> {
+    let unfilled = buf.initialize_unfilled();
+    let len = unfilled.len();
+    read_exact_at(file, unfilled, offset)?;
+    buf.advance(len);
+    Ok(())
+}
+
+/// Return the first range of ``file`` holding data at or after ``offset``, or None if only
+/// holes remain, using ``SEEK_DATA`` and ``SEEK_HOLE``.
+///
+/// Files that can't report their holes, such as block devices, are entirely data.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn next_data(file: &File, offset: u64) -> io::Result<Option<core::ops::Range<u64>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+    if start < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            // There is no data past ``offset``.
+            Some(libc::ENXIO) => Ok(None),
+            Some(libc::EINVAL) => all_data(file, offset),
+            _ if is_unsupported(&err) => all_data(file, offset),
+            _ => Err(err),
+        };
+    }
+
+    let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+    if end < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(Some(start as u64..end as u64))
+}
+
+/// Return the first range of ``file`` holding data at or after ``offset``, or None if only
+/// holes remain, using ``FSCTL_QUERY_ALLOCATED_RANGES``.
+///
+/// Files that can't report their holes are entirely data.
+#[cfg(windows)]
+pub fn next_data(file: &File, offset: u64) -> io::Result<Option<core::ops::Range<u64>>> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Foundation::ERROR_MORE_DATA;
+    use windows_sys::Win32::System::Ioctl::{
+        FILE_ALLOCATED_RANGE_BUFFER, FSCTL_QUERY_ALLOCATED_RANGES,
+    };
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    let len = file_len(file)?;
+    if offset >= len {
+        return Ok(None);
+    }
+
+    let query = FILE_ALLOCATED_RANGE_BUFFER {
+        FileOffset: offset as i64,
+        Length: (len - offset) as i64,
+    };
+    // Only the first range is needed, the others are reported as more data being available.
+    let mut range = FILE_ALLOCATED_RANGE_BUFFER {
+        FileOffset: 0,
+        Length: 0,
+    };
+    let mut bytes_returned = 0;
+
+    let ret = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle(),
+            FSCTL_QUERY_ALLOCATED_RANGES,
+            &query as *const FILE_ALLOCATED_RANGE_BUFFER as *const _,
+            core::mem::size_of::<FILE_ALLOCATED_RANGE_BUFFER>() as u32,
+            &mut range as *mut FILE_ALLOCATED_RANGE_BUFFER as *mut _,
+            core::mem::size_of::<FILE_ALLOCATED_RANGE_BUFFER>() as u32,
+            &mut bytes_returned,
+            core::ptr::null_mut(),
+        )
+    };
+
+    if ret == 0 {
+        let err = io::Error::last_os_error();
+        if is_unsupported(&err) {
+            return all_data(file, offset);
+        }
+        if err.raw_os_error() != Some(ERROR_MORE_DATA as i32) {
+            return Err(err);
+        }
+    }
+
+    if bytes_returned == 0 {
+        return Ok(None);
+    }
+
+    let start = core::cmp::max(offset, range.FileOffset as u64);
+    Ok(Some(start..(range.FileOffset + range.Length) as u64))
+}
+
+/// Return the first range of ``file`` holding data at or after ``offset``.
+///
+/// Holes can't be reported on this platform, so the file is entirely data.
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+pub fn next_data(file: &File, offset: u64) -> io::Result<Option<core::ops::Range<u64>>> {
+    all_data(file, offset)
+}
+
+/// Report everything past ``offset`` in ``file`` as data.
+fn all_data(file: &File, offset: u64) -> io::Result<Option<core::ops::Range<u64>>> {
+    let len = file_len(file)?;
+    Ok(if offset < len {
+        Some(offset..len)
+    } else {
+        None
+    })
+}
+
+/// Return the size of ``file`` in bytes.
+///
+/// Unlike ``metadata().len()``, this returns the real size of raw block devices, which is
+/// queried from the operating system.
+pub fn file_len(file: &File) -> io::Result<u64> {
+    match file.metadata() {
+        Ok(metadata) if metadata.is_file() => Ok(metadata.len()),
+        metadata => {
+            device_len(file).or_else(|err| metadata.map(|metadata| metadata.len()).map_err(|_| err))
+        }
+    }
+}
+
+/// ``_IOR(0x12, 114, size_t)``, on architectures where the read direction is encoded on bit 30.
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    )
+))]
+const BLKGETSIZE64: u64 = (1 << 30) | ((core::mem::size_of::<usize>() as u64) << 16) | 0x1272;
+
+/// ``_IOR(0x12, 114, size_t)``, on architectures where the read direction is encoded on bit 31.
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    ))
+))]
+const BLKGETSIZE64: u64 = (2 << 30) | ((core::mem::size_of::<usize>() as u64) << 16) | 0x1272;
+
+/// Query the size of the block device ``file`` with the ``BLKGETSIZE64`` ioctl.
+#[cfg(target_os = "linux")]
+fn device_len(file: &File) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut len: u64 = 0;
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as libc::Ioctl, &mut len) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(len)
+}
+
+/// ``_IOR('d', 24, uint32_t)``
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const DKIOCGETBLOCKSIZE: libc::c_ulong = 0x4004_6418;
+
+/// ``_IOR('d', 25, uint64_t)``
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const DKIOCGETBLOCKCOUNT: libc::c_ulong = 0x4008_6419;
+
+/// Query the size of the block device ``file`` with the ``DKIOCGETBLOCKSIZE`` and
+/// ``DKIOCGETBLOCKCOUNT`` ioctls.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn device_len(file: &File) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut block_size: u32 = 0;
+    let mut block_count: u64 = 0;
+
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKSIZE, &mut block_size) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKCOUNT, &mut block_count) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(block_count * u64::from(block_size))
+}
+
+/// Query the size of the disk or volume ``file`` with ``IOCTL_DISK_GET_LENGTH_INFO``.
+#[cfg(windows)]
+fn device_len(file: &File) -> io::Result<u64> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::System::Ioctl::{GET_LENGTH_INFORMATION, IOCTL_DISK_GET_LENGTH_INFO};
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    let mut info = GET_LENGTH_INFORMATION { Length: 0 };
+    let mut bytes_returned = 0;
+
+    let ret = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle(),
+            IOCTL_DISK_GET_LENGTH_INFO,
+            core::ptr::null(),
+            0,
+            &mut info as *mut GET_LENGTH_INFORMATION as *mut _,
+            core::mem::size_of::<GET_LENGTH_INFORMATION>() as u32,
+            &mut bytes_returned,
+            core::ptr::null_mut(),
+        )
+    };
+
+    if ret == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(info.Length as u64)
+}
+
+/// Query the logical sector size of the disk ``file`` with ``IOCTL_DISK_GET_DRIVE_GEOMETRY_EX``.
+#[cfg(windows)]
+pub fn sector_size(file: &File) -> io::Result<u32> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::System::Ioctl::{DISK_GEOMETRY_EX, IOCTL_DISK_GET_DRIVE_GEOMETRY_EX};
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    let mut geometry = DISK_GEOMETRY_EX::default();
+    let mut bytes_returned = 0;
+
+    let ret = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle(),
+            IOCTL_DISK_GET_DRIVE_GEOMETRY_EX,
+            core::ptr::null(),
+            0,
+            &mut geometry as *mut DISK_GEOMETRY_EX as *mut _,
+            core::mem::size_of::<DISK_GEOMETRY_EX>() as u32,
+            &mut bytes_returned,
+            core::ptr::null_mut(),
+        )
+    };
+
+    if ret == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(geometry.Geometry.BytesPerSector)
+}
+
+/// Query the size of the block device ``file``.
+///
+/// Raw block devices are not supported on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios", windows)))]
+fn device_len(_file: &File) -> io::Result<u64> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// ``_IO(0x12, 125)``
+#[cfg(target_os = "linux")]
+const BLKSECDISCARD: u64 = 0x127D;
+
+/// Securely discard the whole block device ``file`` with the ``BLKSECDISCARD`` ioctl, which
+/// also erases the copies of the data left by the device, e.g. in the spare area of flash.
+///
+/// Regular files are not supported.
+#[cfg(target_os = "linux")]
+pub fn secure_discard(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if file.metadata()?.is_file() {
+        return Err(io::Error::from(io::ErrorKind::Unsupported));
+    }
+
+    let range: [u64; 2] = [0, device_len(file)?];
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), BLKSECDISCARD as libc::Ioctl, &range) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Securely discard the whole block device ``file``.
+///
+/// Not supported on this platform.
+#[cfg(not(target_os = "linux"))]
+pub fn secure_discard(_file: &File) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Query the identity of ``file``.
+///
+/// Block devices are described by the attributes sysfs exposes for them, or for the disk holding
+/// them if they are partitions. Regular files are identified by their device and inode numbers.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn identity(file: &File) -> io::Result<DeviceIdentity> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = file.metadata()?;
+    if !metadata.file_type().is_block_device() {
+        return Ok(file_identity(&metadata));
+    }
+
+    let disk = sysfs_disk(&metadata)?;
+
+    // The first of the attributes ``names`` the kernel reports for this kind of disk.
+    let attribute = |names: &[&str]| {
+        names
+            .iter()
+            .filter_map(|name| std::fs::read(disk.join(name)).ok())
+            .map(|value| crate::IdentityString::from_ascii(&value))
+            .find(|value| !value.is_empty())
+            .unwrap_or_default()
+    };
+    let mut identity = DeviceIdentity {
+        model: attribute(&["device/model"]),
+        serial: attribute(&["device/serial", "serial"]),
+        firmware_revision: attribute(&["device/firmware_rev", "device/rev"]),
+        unique_id: attribute(&["wwid", "device/wwid"]),
+    };
+
+    // SCSI disks report their serial number in the unit serial number VPD page.
+    if identity.serial.is_empty() {
+        if let Ok(page) = std::fs::read(disk.join("device/vpd_pg80")) {
+            identity.serial = crate::IdentityString::from_ascii(page.get(4..).unwrap_or_default());
+        }
+    }
+
+    Ok(identity)
+}
+
+/// Return the sysfs directory of the disk holding the block device of ``metadata``, which is
+/// the device itself unless it is a partition.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sysfs_disk(metadata: &std::fs::Metadata) -> io::Result<std::path::PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let rdev = metadata.rdev();
+    let major = ((rdev >> 8) & 0xFFF) | ((rdev >> 32) & 0xFFFF_F000);
+    let minor = (rdev & 0xFF) | ((rdev >> 12) & 0xFFFF_FF00);
+    let mut disk = std::fs::canonicalize(std::format!("/sys/dev/block/{}:{}", major, minor))?;
+    if disk.join("partition").exists() {
+        disk.pop();
+    }
+    Ok(disk)
+}
+
+/// Query the capabilities of ``file``.
+///
+/// Files are flushed by syncing them, and shared through positional reads and writes. Punching
+/// holes trims them and writes zeroes for free.
+pub fn capabilities(file: &File) -> Capabilities {
+    let mut capabilities = Capabilities::FLUSH;
+    capabilities.set(Capabilities::CONCURRENT, cfg!(any(unix, windows)));
+    if supports_holes(file) {
+        capabilities |=
+            Capabilities::TRIM | Capabilities::WRITE_ZEROES | Capabilities::DISCARD_ZEROES;
+    }
+    capabilities.set(Capabilities::READ_ONLY, is_read_only(file));
+    capabilities.set(Capabilities::ROTATIONAL, is_rotational(file));
+    capabilities
+}
+
+/// Check whether ``file`` was opened read-only.
+#[cfg(unix)]
+fn is_read_only(file: &File) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    flags >= 0 && flags & libc::O_ACCMODE == libc::O_RDONLY
+}
+
+/// Check whether ``file`` was opened read-only.
+///
+/// The access rights of a handle can't be queried on this platform, so it is assumed writable.
+#[cfg(not(unix))]
+fn is_read_only(_file: &File) -> bool {
+    false
+}
+
+/// Check whether ``file`` is a block device on a rotational disk, as reported by sysfs.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn is_rotational(file: &File) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    match file.metadata() {
+        Ok(metadata) if metadata.file_type().is_block_device() => sysfs_disk(&metadata)
+            .and_then(|disk| std::fs::read(disk.join("queue/rotational")))
+            .is_ok_and(|value| value.trim_ascii() == b"1"),
+        _ => false,
+    }
+}
+
+/// Check whether ``file`` is on a rotational disk.
+///
+/// Not supported on this platform, disks are assumed to be solid state.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn is_rotational(_file: &File) -> bool {
+    false
+}
+
+/// Query the identity of ``file``.
+///
+/// Disks are described by ``IOCTL_STORAGE_QUERY_PROPERTY``. Regular files are identified by
+/// the serial number of their volume and their file index.
+#[cfg(windows)]
+pub fn identity(file: &File) -> io::Result<DeviceIdentity> {
+    use core::fmt::Write;
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{
+        GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION,
+    };
+    use windows_sys::Win32::System::Ioctl::{StorageDeviceIdProperty, StorageDeviceProperty};
+
+    let mut identity = DeviceIdentity::default();
+
+    if file.metadata()?.is_file() {
+        let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { core::mem::zeroed() };
+        let ret = unsafe { GetFileInformationByHandle(file.as_raw_handle(), &mut info) };
+        if ret == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let _ = write!(
+            identity.unique_id,
+            "file.{:x}.{:x}",
+            info.dwVolumeSerialNumber,
+            (u64::from(info.nFileIndexHigh) << 32) | u64::from(info.nFileIndexLow)
+        );
+        return Ok(identity);
+    }
+
+    // A STORAGE_DEVICE_DESCRIPTOR, holding the offsets of NUL-terminated strings.
+    let descriptor = storage_property(file, StorageDeviceProperty)?;
+    let string = |offset: usize| {
+        let offset = descriptor_u32(&descriptor, offset) as usize;
+        match descriptor.get(offset..) {
+            Some(value) if offset != 0 => crate::IdentityString::from_ascii(value),
+            _ => crate::IdentityString::new(),
+        }
+    };
+    let vendor = string(12);
+    let product = string(16);
+    if !vendor.is_empty() {
+        identity.model.push_str(&vendor);
+        identity.model.push_str(" ");
+    }
+    identity.model.push_str(&product);
+    identity.firmware_revision = string(20);
+    identity.serial = string(24);
+
+    // A STORAGE_DEVICE_ID_DESCRIPTOR, holding the SCSI device identification VPD page. Its
+    // binary NAA or EUI-64 identifier of the device is its World Wide Name.
+    if let Ok(ids) = storage_property(file, StorageDeviceIdProperty) {
+        let mut offset = 12;
+        for _ in 0..descriptor_u32(&ids, 8) {
+            let code_set = descriptor_u32(&ids, offset);
+            let id_type = descriptor_u32(&ids, offset + 4);
+            let size = descriptor_u16(&ids, offset + 8) as usize;
+            let next = descriptor_u16(&ids, offset + 10) as usize;
+            let association = descriptor_u32(&ids, offset + 12);
+            let prefix = match id_type {
+                2 => "eui.",
+                3 => "naa.",
+                _ => "",
+            };
+            if let Some(id) = ids.get(offset + 16..offset + 16 + size) {
+                if code_set == 1 && association == 0 && !prefix.is_empty() {
+                    identity.unique_id.push_str(prefix);
+                    for byte in id {
+                        let _ = write!(identity.unique_id, "{:02x}", byte);
+                    }
+                    break;
+                }
+            }
+            if next == 0 {
+                break;
+            }
+            offset += next;
+        }
+    }
+
+    Ok(identity)
+}
+
+/// Query the identity of ``file``.
+///
+/// Regular files are identified by their device and inode numbers, querying disks isn't
+/// supported on this platform.
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+pub fn identity(file: &File) -> io::Result<DeviceIdentity> {
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::from(io::ErrorKind::Unsupported));
+    }
+    Ok(file_identity(&metadata))
+}
+
+/// Query the identity of ``file``.
+///
+/// Not supported on this platform.
+#[cfg(not(any(unix, windows)))]
+pub fn identity(_file: &File) -> io::Result<DeviceIdentity> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Identify the regular file of ``metadata`` by its device and inode numbers, which stay the
+/// same as long as the file isn't moved to another filesystem.
+#[cfg(unix)]
+fn file_identity(metadata: &std::fs::Metadata) -> DeviceIdentity {
+    use core::fmt::Write;
+    use std::os::unix::fs::MetadataExt;
+
+    let mut identity = DeviceIdentity::default();
+    let _ = write!(
+        identity.unique_id,
+        "file.{:x}.{:x}",
+        metadata.dev(),
+        metadata.ino()
+    );
+    identity
+}
+
+/// Query the storage property ``property`` of the disk ``file`` with
+/// ``IOCTL_STORAGE_QUERY_PROPERTY``, returning the raw descriptor.
+#[cfg(windows)]
+fn storage_property(
+    file: &File,
+    property: windows_sys::Win32::System::Ioctl::STORAGE_PROPERTY_ID,
+) -> io::Result<std::vec::Vec<u8>> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::System::Ioctl::{
+        PropertyStandardQuery, IOCTL_STORAGE_QUERY_PROPERTY, STORAGE_PROPERTY_QUERY,
+    };
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    let query = STORAGE_PROPERTY_QUERY {
+        PropertyId: property,
+        QueryType: PropertyStandardQuery,
+        AdditionalParameters: [0],
+    };
+    let mut descriptor = std::vec![0u8; 4096];
+    let mut bytes_returned = 0;
+
+    let ret = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle(),
+            IOCTL_STORAGE_QUERY_PROPERTY,
+            &query as *const STORAGE_PROPERTY_QUERY as *const _,
+            core::mem::size_of::<STORAGE_PROPERTY_QUERY>() as u32,
+            descriptor.as_mut_ptr() as *mut _,
+            descriptor.len() as u32,
+            &mut bytes_returned,
+            core::ptr::null_mut(),
+        )
+    };
+
+    if ret == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    descriptor.truncate(bytes_returned as usize);
+    Ok(descriptor)
+}
+
+/// Read the little-endian 32 bits field at ``offset`` of ``descriptor``, or 0 if it is truncated.
+#[cfg(windows)]
+fn descriptor_u32(descriptor: &[u8], offset: usize) -> u32 {
+    descriptor.get(offset..offset + 4).map_or(0, |bytes| {
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    })
+}
+
+/// Read the little-endian 16 bits field at ``offset`` of ``descriptor``, or 0 if it is truncated.
+#[cfg(windows)]
+fn descriptor_u16(descriptor: &[u8], offset: usize) -> u16 {
+    descriptor
+        .get(offset..offset + 2)
+        .map_or(0, |bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+/// Check whether ``error`` means that the operation isn't supported by the file or platform.
+pub fn is_unsupported(error: &io::Error) -> bool {
+    if error.kind() == io::ErrorKind::Unsupported {
+        return true;
+    }
+
+    #[cfg(unix)]
+    {
+        // ENOTTY is returned by ioctls the file doesn't implement.
+        if is_os_error(error, &[libc::EOPNOTSUPP, libc::ENOTTY]) {
+            return true;
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Foundation::{ERROR_INVALID_FUNCTION, ERROR_NOT_SUPPORTED};
+
+        if let Some(code) = error.raw_os_error() {
+            let code = code as u32;
+            return code == ERROR_INVALID_FUNCTION || code == ERROR_NOT_SUPPORTED;
+        }
+    }
+
+    false
+}
+
+/// Check whether ``error`` is an OS error code among ``codes``.
+fn is_os_error(error: &io::Error, codes: &[i32]) -> bool {
+    error
+        .raw_os_error()
+        .is_some_and(|code| codes.contains(&code))
+}
+
+/// Check whether retrying the operation that failed with ``error`` may succeed.
+fn is_transient(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            return true
+        }
+        _ => {}
+    }
+
+    #[cfg(unix)]
+    {
+        if is_os_error(error, &[libc::EBUSY]) {
+            return true;
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Foundation::{ERROR_BUSY, ERROR_NOT_READY, ERROR_SEM_TIMEOUT};
+
+        if is_os_error(
+            error,
+            &[
+                ERROR_BUSY as i32,
+                ERROR_NOT_READY as i32,
+                ERROR_SEM_TIMEOUT as i32,
+            ],
+        ) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Check whether ``error`` comes from a failure of the medium.
+fn is_media_error(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        if is_os_error(error, &[libc::EIO, libc::EBADMSG]) {
+            return true;
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Foundation::{
+            ERROR_CRC, ERROR_DEVICE_HARDWARE_ERROR, ERROR_IO_DEVICE, ERROR_SECTOR_NOT_FOUND,
+        };
+
+        if is_os_error(
+            error,
+            &[
+                ERROR_CRC as i32,
+                ERROR_DEVICE_HARDWARE_ERROR as i32,
+                ERROR_IO_DEVICE as i32,
+                ERROR_SECTOR_NOT_FOUND as i32,
+            ],
+        ) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Check whether ``error`` means that the device or filesystem is full.
+fn is_out_of_space(error: &io::Error) -> bool {
+    if error.kind() == io::ErrorKind::StorageFull {
+        return true;
+    }
+
+    #[cfg(unix)]
+    {
+        if is_os_error(error, &[libc::EDQUOT]) {
+            return true;
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Foundation::ERROR_DISK_QUOTA_EXCEEDED;
+
+        if is_os_error(error, &[ERROR_DISK_QUOTA_EXCEEDED as i32]) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Convert an I/O error to a storage device error, falling back to ``default`` when the error
+/// doesn't fall in any of the classified categories.
+pub fn storage_error(error: &io::Error, default: StorageDeviceError) -> StorageDeviceError {
+    if is_transient(error) {
+        StorageDeviceError::Busy
+    } else if is_media_error(error) {
+        StorageDeviceError::MediaError
+    } else if is_out_of_space(error) {
+        StorageDeviceError::OutOfSpace
+    } else if is_unsupported(error) {
+        StorageDeviceError::Unsupported
+    } else {
+        default
+    }
+}
+
+/// Convert an I/O error to a block error, falling back to ``default`` when the error doesn't
+/// fall in any of the classified categories.
+pub fn block_error(error: &io::Error, default: BlockError) -> BlockError {
+    storage_error(error, StorageDeviceError::Unknown).into_block_error(default)
+}
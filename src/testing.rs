@@ -0,0 +1,542 @@
+use crate::remap::mix;
+use crate::{
+    AlignedBox, Block, BlockCount, BlockDevice, BlockError, BlockIndex, BlockResult,
+    ShadowCheckedDevice, StorageDevice, StorageDeviceError, StorageDeviceResult,
+};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// Fill ``buf`` with the content of a [DbgIdxBlockDevice] at ``offset``.
+///
+/// Each aligned 8-byte word of the device holds its own offset, in little endian, so data read
+/// from the wrong place, or put at the wrong place in the buffer, never matches.
+pub fn fill_index_pattern(offset: u64, buf: &mut [u8]) {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        let position = offset + i as u64;
+        *byte = (position & !7).to_le_bytes()[(position % 8) as usize];
+    }
+}
+
+/// A block device for tests, keeping its blocks in memory and counting the blocks read and
+/// written.
+#[derive(Clone)]
+pub struct DbgBlockDevice {
+    /// The content of the device.
+    blocks: Vec<Block>,
+
+    /// The number of read requests so far.
+    read_requests: u64,
+
+    /// The number of write requests so far.
+    write_requests: u64,
+
+    /// The number of blocks read so far.
+    blocks_read: u64,
+
+    /// The number of blocks written so far.
+    blocks_written: u64,
+}
+
+impl DbgBlockDevice {
+    /// Create a zeroed device of ``count`` blocks.
+    pub fn new(count: usize) -> Self {
+        DbgBlockDevice {
+            blocks: alloc::vec![Block::new(); count],
+            read_requests: 0,
+            write_requests: 0,
+            blocks_read: 0,
+            blocks_written: 0,
+        }
+    }
+
+    /// Create a device with ``contents`` at its start, padded with zeros to a whole block.
+    pub fn with_contents(contents: &[u8]) -> Self {
+        let mut device = Self::new(contents.len().div_ceil(Block::LEN));
+        for (block, data) in device.blocks.iter_mut().zip(contents.chunks(Block::LEN)) {
+            block[..data.len()].copy_from_slice(data);
+        }
+        device
+    }
+
+    /// Return the content of the device.
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// Return the content of the device, to change it behind the back of the code under test.
+    pub fn blocks_mut(&mut self) -> &mut [Block] {
+        &mut self.blocks
+    }
+
+    /// Return the number of read requests so far.
+    pub fn read_requests(&self) -> u64 {
+        self.read_requests
+    }
+
+    /// Return the number of write requests so far.
+    pub fn write_requests(&self) -> u64 {
+        self.write_requests
+    }
+
+    /// Return the number of blocks read so far.
+    pub fn blocks_read(&self) -> u64 {
+        self.blocks_read
+    }
+
+    /// Return the number of blocks written so far.
+    pub fn blocks_written(&self) -> u64 {
+        self.blocks_written
+    }
+
+    /// Return the range of the blocks of a request, if it is inside the device.
+    fn range(&self, index: BlockIndex, count: usize) -> Option<core::ops::Range<usize>> {
+        let start = usize::try_from(index.0).ok()?;
+        let end = start.checked_add(count)?;
+        if end > self.blocks.len() {
+            return None;
+        }
+        Some(start..end)
+    }
+}
+
+impl core::fmt::Debug for DbgBlockDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DbgBlockDevice")
+            .field("block_count", &self.blocks.len())
+            .field("read_requests", &self.read_requests)
+            .field("write_requests", &self.write_requests)
+            .field("blocks_read", &self.blocks_read)
+            .field("blocks_written", &self.blocks_written)
+            .finish()
+    }
+}
+
+impl BlockDevice for DbgBlockDevice {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        let range = self
+            .range(index, blocks.len())
+            .ok_or(BlockError::ReadError)?;
+        blocks.clone_from_slice(&self.blocks[range]);
+        self.read_requests += 1;
+        self.blocks_read += blocks.len() as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        let range = self
+            .range(index, blocks.len())
+            .ok_or(BlockError::WriteError)?;
+        self.blocks[range].clone_from_slice(blocks);
+        self.write_requests += 1;
+        self.blocks_written += blocks.len() as u64;
+        Ok(())
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        Ok(BlockCount(self.blocks.len() as u64))
+    }
+}
+
+/// A read-only block device for tests, whose content is given by [fill_index_pattern].
+///
+/// The content isn't stored, so the device can be as big as needed, to test offsets past 4 GiB
+/// or the handling of large requests. Writes fail with [BlockError::WriteError].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DbgIdxBlockDevice {
+    /// The number of blocks of the device.
+    count: u64,
+}
+
+impl DbgIdxBlockDevice {
+    /// Create a device of ``count`` blocks.
+    pub fn new(count: u64) -> Self {
+        DbgIdxBlockDevice { count }
+    }
+}
+
+impl BlockDevice for DbgIdxBlockDevice {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        let end = index
+            .0
+            .checked_add(blocks.len() as u64)
+            .ok_or(BlockError::ReadError)?;
+        if end > self.count {
+            return Err(BlockError::ReadError);
+        }
+        for (i, block) in blocks.iter_mut().enumerate() {
+            fill_index_pattern((index.0 + i as u64) * Block::LEN_U64, &mut block[..]);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, _blocks: &[Block], _index: BlockIndex) -> BlockResult<()> {
+        Err(BlockError::WriteError)
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        Ok(BlockCount(self.count))
+    }
+}
+
+/// A heap buffer for tests, starting a chosen number of bytes after a [Block] alignment.
+///
+/// Code taking byte buffers often has a fast path for buffers aligned for blocks, and a slow one
+/// for the others. Misaligning the buffers of a test on purpose makes it exercise both.
+pub struct AlignedBuf {
+    /// The allocation holding the buffer.
+    storage: AlignedBox,
+
+    /// The offset of the buffer in the allocation.
+    misalignment: usize,
+}
+
+impl AlignedBuf {
+    /// Allocate a zeroed buffer of ``len`` bytes aligned for [Block]s.
+    pub fn new(len: usize) -> Self {
+        Self::misaligned(len, 0)
+    }
+
+    /// Allocate a zeroed buffer of ``len`` bytes, starting ``misalignment`` bytes after an
+    /// alignment for [Block]s.
+    pub fn misaligned(len: usize, misalignment: usize) -> Self {
+        let total = len
+            .checked_add(misalignment)
+            .expect("misaligned buffer too big");
+        AlignedBuf {
+            storage: AlignedBox::new(total),
+            misalignment,
+        }
+    }
+
+    /// Return how many bytes after a [Block] alignment the buffer starts.
+    pub fn misalignment(&self) -> usize {
+        self.misalignment
+    }
+}
+
+impl core::ops::Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.storage[self.misalignment..]
+    }
+}
+
+impl core::ops::DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.storage[self.misalignment..]
+    }
+}
+
+impl core::fmt::Debug for AlignedBuf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AlignedBuf")
+            .field("len", &self.len())
+            .field("misalignment", &self.misalignment)
+            .finish()
+    }
+}
+
+/// An operation on a storage device, generated by an [OperationGenerator] or decoded from fuzzer
+/// input, and performed by [run_operations].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TestOperation {
+    /// Read ``len`` bytes at ``offset`` into a buffer starting ``misalignment`` bytes after a
+    /// [Block] alignment.
+    Read {
+        /// Where the read starts.
+        offset: u64,
+        /// The number of bytes read.
+        len: usize,
+        /// How many bytes after a [Block] alignment the buffer starts.
+        misalignment: usize,
+    },
+
+    /// Write ``len`` pseudo-random bytes generated from ``seed`` at ``offset``, from a buffer
+    /// starting ``misalignment`` bytes after a [Block] alignment.
+    Write {
+        /// Where the write starts.
+        offset: u64,
+        /// The number of bytes written.
+        len: usize,
+        /// How many bytes after a [Block] alignment the buffer starts.
+        misalignment: usize,
+        /// The seed of the bytes written.
+        seed: u64,
+    },
+
+    /// Zero ``len`` bytes at ``offset``.
+    WriteZeroes {
+        /// Where the range starts.
+        offset: u64,
+        /// The length of the range.
+        len: u64,
+    },
+
+    /// Fill ``len`` bytes at ``offset`` with ``byte``.
+    Fill {
+        /// Where the range starts.
+        offset: u64,
+        /// The length of the range.
+        len: u64,
+        /// The byte to fill the range with.
+        byte: u8,
+    },
+
+    /// Discard ``len`` bytes at ``offset``.
+    Discard {
+        /// Where the range starts.
+        offset: u64,
+        /// The length of the range.
+        len: u64,
+    },
+
+    /// Flush the device.
+    Flush,
+}
+
+/// The largest request generated, in blocks.
+const MAX_GENERATED_BLOCKS: u64 = 4;
+
+/// Return an offset in or just past a device of ``device_len`` bytes, chosen from ``value``.
+///
+/// Offsets close to block boundaries and to the end of the device are favored, as that is where
+/// the bugs are.
+fn pick_offset(value: u64, device_len: u64) -> u64 {
+    let anywhere = (value >> 8) % (device_len + 1);
+    let jitter = (value >> 4) % 16;
+    match value % 4 {
+        0 => anywhere,
+        1 => (anywhere - anywhere % Block::LEN_U64).saturating_sub(jitter % 2),
+        2 => device_len.saturating_sub(jitter),
+        _ => (anywhere - anywhere % Block::LEN_U64).saturating_add(jitter),
+    }
+}
+
+/// Return a request length chosen from ``value``.
+///
+/// Empty requests, requests smaller than a block, and requests a byte off a whole number of
+/// blocks are favored.
+fn pick_len(value: u64) -> u64 {
+    let blocks = (value >> 8) % (MAX_GENERATED_BLOCKS + 1);
+    match value % 4 {
+        0 => 0,
+        1 => (value >> 16) % Block::LEN_U64,
+        2 => (blocks * Block::LEN_U64 + 1).saturating_sub((value >> 4) % 3),
+        _ => (value >> 16) % (MAX_GENERATED_BLOCKS * Block::LEN_U64),
+    }
+}
+
+impl TestOperation {
+    /// Build an operation on a device of ``device_len`` bytes from the 64 bits of ``value``, all
+    /// values giving valid operations.
+    fn from_u64(value: u64, device_len: u64) -> Self {
+        let mixed = mix(value);
+        let len = pick_len(mixed.rotate_left(32));
+        // Whether empty requests past the end of the device fail isn't specified.
+        let offset = match len {
+            0 => core::cmp::min(pick_offset(mixed, device_len), device_len),
+            _ => pick_offset(mixed, device_len),
+        };
+        let misalignment = ((mixed >> 58) % 8) as usize;
+        match value % 8 {
+            0..=2 => TestOperation::Read {
+                offset,
+                len: len as usize,
+                misalignment,
+            },
+            3..=4 => TestOperation::Write {
+                offset,
+                len: len as usize,
+                misalignment,
+                seed: value,
+            },
+            5 => TestOperation::WriteZeroes { offset, len },
+            6 => match (value >> 3) % 3 {
+                0 => TestOperation::Discard { offset, len },
+                1 => TestOperation::Fill {
+                    offset,
+                    len,
+                    byte: (value >> 5) as u8,
+                },
+                _ => TestOperation::Flush,
+            },
+            _ => TestOperation::Read {
+                offset,
+                len: len as usize,
+                misalignment: 0,
+            },
+        }
+    }
+
+    /// Decode an operation on a device of ``device_len`` bytes from the start of ``data``,
+    /// advancing it, as when turning the input of a fuzzer into operations.
+    ///
+    /// Every input decodes to valid operations. Return None once ``data`` is empty.
+    pub fn decode(data: &mut &[u8], device_len: u64) -> Option<Self> {
+        if data.is_empty() {
+            return None;
+        }
+        let taken = core::cmp::min(data.len(), 8);
+        let mut bytes = [0; 8];
+        bytes[..taken].copy_from_slice(&data[..taken]);
+        *data = &data[taken..];
+        Some(Self::from_u64(u64::from_le_bytes(bytes), device_len))
+    }
+}
+
+/// An endless, reproducible sequence of pseudo-random [TestOperation]s on a device of a given
+/// size.
+#[derive(Debug, Clone)]
+pub struct OperationGenerator {
+    /// The size of the device the operations are for.
+    device_len: u64,
+
+    /// The state of the generator.
+    state: u64,
+}
+
+impl OperationGenerator {
+    /// Create a generator of operations on a device of ``device_len`` bytes, from ``seed``.
+    pub fn new(device_len: u64, seed: u64) -> Self {
+        OperationGenerator {
+            device_len,
+            state: seed,
+        }
+    }
+}
+
+impl Iterator for OperationGenerator {
+    type Item = TestOperation;
+
+    fn next(&mut self) -> Option<TestOperation> {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        Some(TestOperation::from_u64(mix(self.state), self.device_len))
+    }
+}
+
+/// Perform ``operations`` on ``storage_device`` wrapped in a [ShadowCheckedDevice] panicking on
+/// divergence, then check its whole content, returning the wrapper.
+///
+/// The operations can come from an [OperationGenerator], from [TestOperation::decode] over the
+/// input of a fuzzer, or from a [TestSequence]. Operations out of the bounds
+/// of the device are expected to fail with [StorageDeviceError::OutOfBounds]. Other errors abort
+/// the run and are returned.
+pub fn run_operations<S, I>(
+    storage_device: S,
+    operations: I,
+) -> StorageDeviceResult<ShadowCheckedDevice<S>>
+where
+    S: StorageDevice,
+    I: IntoIterator<Item = TestOperation>,
+{
+    let mut device = ShadowCheckedDevice::new(storage_device)?.with_panic_on_divergence(true);
+    for operation in operations {
+        let result = match operation {
+            TestOperation::Read {
+                offset,
+                len,
+                misalignment,
+            } => {
+                let mut buf = AlignedBuf::misaligned(len, misalignment);
+                device.read(offset, &mut buf)
+            }
+            TestOperation::Write {
+                offset,
+                len,
+                misalignment,
+                seed,
+            } => {
+                let mut buf = AlignedBuf::misaligned(len, misalignment);
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte = mix(seed ^ i as u64) as u8;
+                }
+                device.write(offset, &buf)
+            }
+            TestOperation::WriteZeroes { offset, len } => device.write_zeroes(offset, len),
+            TestOperation::Fill { offset, len, byte } => device.fill(offset, len, byte),
+            TestOperation::Discard { offset, len } => device.discard(offset, len),
+            TestOperation::Flush => device.flush(),
+        };
+        match result {
+            Ok(()) | Err(StorageDeviceError::OutOfBounds) => (),
+            Err(err) => return Err(err),
+        }
+    }
+    device.check_all()?;
+    Ok(device)
+}
+
+/// The largest device generated by the [Arbitrary](arbitrary::Arbitrary) implementation of
+/// [TestSequence], in bytes.
+#[cfg(feature = "arbitrary")]
+const MAX_ARBITRARY_DEVICE_LEN: u64 = 16 * Block::LEN_U64;
+
+/// A sequence of operations, with the size of the device to perform them on.
+///
+/// With the ``arbitrary`` feature, it implements ``arbitrary::Arbitrary``, to be the input of a
+/// fuzz target, and with the ``proptest`` feature, [sequence_strategy] generates it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestSequence {
+    /// The size of the device, in bytes.
+    pub device_len: u64,
+
+    /// The operations to perform.
+    pub operations: Vec<TestOperation>,
+}
+
+impl TestSequence {
+    /// Perform the operations on ``storage_device``, which must be [TestSequence::device_len]
+    /// bytes big, with [run_operations].
+    pub fn run<S: StorageDevice>(
+        &self,
+        storage_device: S,
+    ) -> StorageDeviceResult<ShadowCheckedDevice<S>> {
+        run_operations(storage_device, self.operations.iter().copied())
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for TestSequence {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let device_len = u.int_in_range(0..=MAX_ARBITRARY_DEVICE_LEN)?;
+        let operations = u
+            .arbitrary_iter::<u64>()?
+            .map(|value| value.map(|value| TestOperation::from_u64(value, device_len)))
+            .collect::<arbitrary::Result<_>>()?;
+        Ok(TestSequence {
+            device_len,
+            operations,
+        })
+    }
+}
+
+/// Return a proptest strategy generating operations on a device of ``device_len`` bytes.
+#[cfg(feature = "proptest")]
+pub fn operation_strategy(
+    device_len: u64,
+) -> impl proptest::strategy::Strategy<Value = TestOperation> {
+    use proptest::strategy::Strategy;
+
+    proptest::arbitrary::any::<u64>()
+        .prop_map(move |value| TestOperation::from_u64(value, device_len))
+}
+
+/// Return a proptest strategy generating up to ``max_operations`` operations on a device of up
+/// to ``max_device_len`` bytes.
+#[cfg(feature = "proptest")]
+pub fn sequence_strategy(
+    max_device_len: u64,
+    max_operations: usize,
+) -> impl proptest::strategy::Strategy<Value = TestSequence> {
+    use proptest::strategy::Strategy;
+
+    (0..=max_device_len).prop_flat_map(move |device_len| {
+        proptest::collection::vec(operation_strategy(device_len), 0..=max_operations).prop_map(
+            move |operations| TestSequence {
+                device_len,
+                operations,
+            },
+        )
+    })
+}
@@ -0,0 +1,401 @@
+use crate::crc::crc32;
+use crate::{Block, DeviceInfo, StorageDevice, StorageDeviceError, StorageDeviceResult};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// The magic identifying the header of a [ThinDevice].
+const THIN_MAGIC: u32 = u32::from_le_bytes(*b"THIN");
+
+/// The version of the layout of the backing device.
+const THIN_VERSION: u32 = 1;
+
+/// The offset of the mapping table in the backing device, right after the header.
+const TABLE_OFFSET: u64 = Block::LEN_U64;
+
+/// The size of an entry of the mapping table, in bytes.
+const ENTRY_LEN: u64 = 4;
+
+/// The parameters of a thin device, stored in the first block of the backing device.
+///
+/// It is stored in little endian as follow:
+///
+/// | Offset | Size | Field       |
+/// |--------|------|-------------|
+/// | 0      | 4    | magic       |
+/// | 4      | 4    | version     |
+/// | 8      | 8    | virtual_len |
+/// | 16     | 4    | chunk_size  |
+/// | 20     | 4    | crc         |
+///
+/// The CRC-32 covers the first 20 bytes. The mapping table follows, with a 32-bit entry per
+/// virtual chunk holding the number of the backing chunk it is stored in, starting at 1, or 0 if
+/// it isn't allocated. The backing chunks come after the table, aligned on the chunk size.
+#[derive(Debug, Copy, Clone)]
+struct ThinHeader {
+    /// The size of the device presented to the user, in bytes.
+    virtual_len: u64,
+
+    /// The granularity at which backing storage is allocated, in bytes.
+    chunk_size: u32,
+}
+
+impl ThinHeader {
+    /// Serialize the header into a block.
+    fn to_block(self) -> Block {
+        let mut block = Block::new();
+        block[0..4].copy_from_slice(&THIN_MAGIC.to_le_bytes());
+        block[4..8].copy_from_slice(&THIN_VERSION.to_le_bytes());
+        block[8..16].copy_from_slice(&self.virtual_len.to_le_bytes());
+        block[16..20].copy_from_slice(&self.chunk_size.to_le_bytes());
+        let crc = crc32(&block[0..20]);
+        block[20..24].copy_from_slice(&crc.to_le_bytes());
+        block
+    }
+
+    /// Deserialize the header from a block, returning None if it isn't valid.
+    fn from_block(block: &Block) -> Option<Self> {
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        let mut virtual_len = [0u8; 8];
+        let mut chunk_size = [0u8; 4];
+        let mut crc = [0u8; 4];
+
+        magic.copy_from_slice(&block[0..4]);
+        version.copy_from_slice(&block[4..8]);
+        virtual_len.copy_from_slice(&block[8..16]);
+        chunk_size.copy_from_slice(&block[16..20]);
+        crc.copy_from_slice(&block[20..24]);
+
+        if u32::from_le_bytes(magic) != THIN_MAGIC
+            || u32::from_le_bytes(version) != THIN_VERSION
+            || u32::from_le_bytes(crc) != crc32(&block[0..20])
+            || u32::from_le_bytes(chunk_size) == 0
+        {
+            return None;
+        }
+
+        Some(ThinHeader {
+            virtual_len: u64::from_le_bytes(virtual_len),
+            chunk_size: u32::from_le_bytes(chunk_size),
+        })
+    }
+
+    /// Return the number of virtual chunks.
+    fn chunks(&self) -> u64 {
+        self.virtual_len.div_ceil(u64::from(self.chunk_size))
+    }
+
+    /// Return the offset of the first backing chunk, after the mapping table.
+    fn data_offset(&self) -> u64 {
+        let table_end = TABLE_OFFSET + self.chunks() * ENTRY_LEN;
+        table_end.next_multiple_of(u64::from(self.chunk_size))
+    }
+}
+
+/// A thinly provisioned storage device, presenting a virtual size bigger than its backing device.
+///
+/// The virtual device is split in chunks, which get space on the backing device the first time
+/// they are written, one after the other. Chunks never written read as zeros, and are reported
+/// as holes by [StorageDevice::next_data]. The mapping between the virtual chunks and the
+/// backing ones is stored in a table after the header of the backing device, so the device can
+/// be reopened later.
+///
+/// Writes fail with [StorageDeviceError::OutOfSpace] once the backing device is full. Backing
+/// devices growing on writes past their end, such as a ``Vec<u8>`` or an [AutoGrowDevice], grow
+/// as chunks are allocated instead.
+///
+/// A chunk is recorded in the table after its data is written, so flushing the device makes
+/// the write durable.
+///
+/// [AutoGrowDevice]: crate::AutoGrowDevice
+#[derive(Debug)]
+pub struct ThinDevice<S: StorageDevice> {
+    /// The device holding the header, the table and the allocated chunks.
+    backing: S,
+
+    /// The parameters of the device.
+    header: ThinHeader,
+
+    /// The backing chunk of each virtual chunk, starting at 1, or 0 if it isn't allocated.
+    table: Vec<u32>,
+
+    /// The number of backing chunks allocated so far.
+    allocated: u32,
+}
+
+impl<S: StorageDevice> ThinDevice<S> {
+    /// Set up a new, empty thin device of ``virtual_len`` bytes on ``backing``, allocating
+    /// space by chunks of ``chunk_size`` bytes.
+    ///
+    /// ``chunk_size`` must be a non-zero multiple of [Block::LEN]. The existing content of
+    /// ``backing`` is meaningless afterwards.
+    pub fn create(mut backing: S, virtual_len: u64, chunk_size: u32) -> StorageDeviceResult<Self> {
+        if chunk_size == 0 || !(chunk_size as usize).is_multiple_of(Block::LEN) {
+            return Err(StorageDeviceError::Unsupported);
+        }
+
+        let header = ThinHeader {
+            virtual_len,
+            chunk_size,
+        };
+        let entries =
+            usize::try_from(header.chunks()).map_err(|_| StorageDeviceError::OutOfSpace)?;
+        backing.write(0, &header.to_block()[..])?;
+        backing
+            .write_zeroes(TABLE_OFFSET, entries as u64 * ENTRY_LEN)
+            .map_err(out_of_space)?;
+        backing.flush()?;
+
+        Ok(ThinDevice {
+            backing,
+            header,
+            table: alloc::vec![0; entries],
+            allocated: 0,
+        })
+    }
+
+    /// Open the thin device previously set up on ``backing`` with [ThinDevice::create].
+    ///
+    /// Return [StorageDeviceError::Corrupted] if the header isn't valid, or if the table maps
+    /// two virtual chunks to the same backing chunk.
+    pub fn open(mut backing: S) -> StorageDeviceResult<Self> {
+        let mut block = Block::new();
+        backing.read(0, &mut block[..])?;
+        let header = ThinHeader::from_block(&block).ok_or(StorageDeviceError::Corrupted)?;
+
+        let entries =
+            usize::try_from(header.chunks()).map_err(|_| StorageDeviceError::OutOfSpace)?;
+        let mut raw = alloc::vec![0u8; entries * ENTRY_LEN as usize];
+        backing.read(TABLE_OFFSET, &mut raw)?;
+        let table: Vec<u32> = raw
+            .chunks_exact(ENTRY_LEN as usize)
+            .map(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]))
+            .collect();
+
+        // Backing chunks are allocated in order, so the next one follows the highest in use.
+        let mut used = alloc::vec![false; entries];
+        let mut allocated = 0;
+        for &chunk in table.iter().filter(|&&chunk| chunk != 0) {
+            match used.get_mut(chunk as usize - 1) {
+                Some(used) if !*used => *used = true,
+                _ => return Err(StorageDeviceError::Corrupted),
+            }
+            allocated = core::cmp::max(allocated, chunk);
+        }
+
+        Ok(ThinDevice {
+            backing,
+            header,
+            table,
+            allocated,
+        })
+    }
+
+    /// Return the granularity at which backing storage is allocated, in bytes.
+    pub fn chunk_size(&self) -> u32 {
+        self.header.chunk_size
+    }
+
+    /// Return the number of chunks allocated on the backing device.
+    pub fn allocated_chunks(&self) -> u32 {
+        self.allocated
+    }
+
+    /// Return how many bytes of the backing device are used, including the header and table.
+    pub fn backing_usage(&self) -> u64 {
+        self.header.data_offset() + u64::from(self.allocated) * u64::from(self.header.chunk_size)
+    }
+
+    /// Return a reference to the backing device.
+    pub fn get_ref(&self) -> &S {
+        &self.backing
+    }
+
+    /// Consume the device, returning the backing device.
+    pub fn into_inner(self) -> S {
+        self.backing
+    }
+
+    /// Return the offset in the backing device of the start of ``chunk``, if it is allocated.
+    fn backing_offset(&self, chunk: u64) -> Option<u64> {
+        match self.table[chunk as usize] {
+            0 => None,
+            backing => Some(
+                self.header.data_offset()
+                    + u64::from(backing - 1) * u64::from(self.header.chunk_size),
+            ),
+        }
+    }
+
+    /// Allocate a backing chunk for the virtual ``chunk``, and write ``buf`` at ``in_chunk`` in
+    /// it, zeroing the rest.
+    fn allocate(&mut self, chunk: u64, in_chunk: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let backing = self
+            .allocated
+            .checked_add(1)
+            .ok_or(StorageDeviceError::OutOfSpace)?;
+        let chunk_size = u64::from(self.header.chunk_size);
+        let start = self.header.data_offset() + u64::from(self.allocated) * chunk_size;
+
+        // The backing chunk may hold stale data, zero what isn't overwritten. The end of the chunk
+        // goes first, so a backing device growing on writes grows to the whole chunk at once.
+        let end = in_chunk + buf.len() as u64;
+        if end < chunk_size {
+            self.backing
+                .write_zeroes(start + end, chunk_size - end)
+                .map_err(out_of_space)?;
+        }
+        if in_chunk > 0 {
+            self.backing.write_zeroes(start, in_chunk)?;
+        }
+        self.backing
+            .write(start + in_chunk, buf)
+            .map_err(out_of_space)?;
+
+        self.map_chunk(chunk, backing)
+    }
+
+    /// Record that the virtual ``chunk`` is stored in the ``backing`` chunk, and persist the
+    /// table entry.
+    fn map_chunk(&mut self, chunk: u64, backing: u32) -> StorageDeviceResult<()> {
+        self.backing
+            .write(TABLE_OFFSET + chunk * ENTRY_LEN, &backing.to_le_bytes())?;
+        self.table[chunk as usize] = backing;
+        self.allocated = backing;
+        Ok(())
+    }
+
+    /// Check whether the ``len`` bytes at ``offset`` are inside the device.
+    fn check_bounds(&self, offset: u64, len: usize) -> StorageDeviceResult<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.header.virtual_len => Ok(()),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+
+    /// Return how many of the ``len`` bytes at ``offset`` are in the same chunk.
+    fn run_len(&self, offset: u64, len: usize) -> usize {
+        let chunk_size = u64::from(self.header.chunk_size);
+        core::cmp::min(chunk_size - offset % chunk_size, len as u64) as usize
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for ThinDevice<S> {
+    fn read(&mut self, mut offset: u64, mut buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        let chunk_size = u64::from(self.header.chunk_size);
+        while !buf.is_empty() {
+            let run = self.run_len(offset, buf.len());
+            let (head, tail) = buf.split_at_mut(run);
+            match self.backing_offset(offset / chunk_size) {
+                Some(start) => self.backing.read(start + offset % chunk_size, head)?,
+                None => head.fill(0),
+            }
+            buf = tail;
+            offset += run as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, mut offset: u64, mut buf: &[u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        let chunk_size = u64::from(self.header.chunk_size);
+        while !buf.is_empty() {
+            let run = self.run_len(offset, buf.len());
+            let (head, tail) = buf.split_at(run);
+            let chunk = offset / chunk_size;
+            let in_chunk = offset % chunk_size;
+            match self.backing_offset(chunk) {
+                Some(start) => self.backing.write(start + in_chunk, head)?,
+                None => self.allocate(chunk, in_chunk, head)?,
+            }
+            buf = tail;
+            offset += run as u64;
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.header.virtual_len)
+    }
+
+    /// Report the chunks never written as holes.
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        let chunk_size = u64::from(self.header.chunk_size);
+        let chunks = self.header.chunks();
+        let mut chunk = offset / chunk_size;
+        while chunk < chunks && self.table[chunk as usize] == 0 {
+            chunk += 1;
+        }
+        if chunk >= chunks {
+            return Ok(None);
+        }
+
+        let start = core::cmp::max(offset, chunk * chunk_size);
+        while chunk < chunks && self.table[chunk as usize] != 0 {
+            chunk += 1;
+        }
+        let end = core::cmp::min(chunk * chunk_size, self.header.virtual_len);
+        Ok(Some(start..end))
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.backing.flush()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo::default())
+    }
+}
+
+/// Report writes past the end of the backing device as a lack of space.
+fn out_of_space(err: StorageDeviceError) -> StorageDeviceError {
+    match err {
+        StorageDeviceError::OutOfBounds => StorageDeviceError::OutOfSpace,
+        err => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn the_mapping_table_survives_reopening() {
+        let mut device = ThinDevice::create(Vec::new(), 64 * 1024, 4096).unwrap();
+        device.write(5 * 4096 + 100, &[0x22; 50]).unwrap();
+        // A write across two chunks allocates both, in order.
+        device.write(2 * 4096 - 10, &[0x33; 20]).unwrap();
+
+        let mut device = ThinDevice::open(device.into_inner()).unwrap();
+        assert_eq!(device.table[5], 1);
+        assert_eq!(device.table[1], 2);
+        assert_eq!(device.table[2], 3);
+        assert_eq!(device.allocated_chunks(), 3);
+        assert_eq!(device.backing_usage(), device.get_ref().len() as u64);
+
+        let mut expected = vec![0u8; 64 * 1024];
+        expected[5 * 4096 + 100..5 * 4096 + 150].fill(0x22);
+        expected[2 * 4096 - 10..2 * 4096 + 10].fill(0x33);
+        let mut content = vec![0xffu8; 64 * 1024];
+        device.read(0, &mut content).unwrap();
+        assert_eq!(content, expected);
+        assert_eq!(device.next_data(0), Ok(Some(4096..3 * 4096)));
+        assert_eq!(device.next_data(3 * 4096), Ok(Some(5 * 4096..6 * 4096)));
+
+        // The next chunk is allocated after the highest one in use.
+        device.write(0, &[0x44; 10]).unwrap();
+        assert_eq!(device.table[0], 4);
+
+        // Two virtual chunks can't share a backing chunk.
+        let mut backing = device.into_inner();
+        backing
+            .write(TABLE_OFFSET + 7 * ENTRY_LEN, &2u32.to_le_bytes())
+            .unwrap();
+        assert_eq!(
+            ThinDevice::open(backing).err(),
+            Some(StorageDeviceError::Corrupted)
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use crate::clock::{Clock, Delay};
+use crate::{Capabilities, DeviceIdentity, DeviceInfo, StorageDevice, StorageDeviceResult};
+
+/// The number of nanoseconds in a second.
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A token bucket, filling at a constant rate up to its capacity.
+#[derive(Debug, Copy, Clone)]
+struct TokenBucket {
+    /// The number of tokens added per second.
+    rate: u64,
+
+    /// The maximum number of tokens, the largest burst allowed without waiting.
+    capacity: u64,
+
+    /// The number of tokens available.
+    tokens: u64,
+
+    /// The time the bucket was last filled, in nanoseconds.
+    last: u64,
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    fn new(rate: u64, capacity: u64, now: u64) -> Self {
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    /// Add the tokens accumulated since the bucket was last filled.
+    fn fill(&mut self, now: u64) {
+        let elapsed = u128::from(now.saturating_sub(self.last));
+        let added = elapsed * u128::from(self.rate) / NANOS_PER_SEC;
+        if added > 0 {
+            let tokens = u128::from(self.tokens) + added;
+            self.tokens = core::cmp::min(tokens, u128::from(self.capacity)) as u64;
+            // Only advance by the time the added tokens account for, to not lose fractions.
+            self.last += (added * NANOS_PER_SEC / u128::from(self.rate)) as u64;
+            if self.tokens == self.capacity {
+                self.last = now;
+            }
+        }
+    }
+
+    /// Return how long to wait before ``count`` tokens are available, in nanoseconds.
+    fn wait_for(&mut self, count: u64, now: u64) -> u64 {
+        self.fill(now);
+        if count <= self.tokens || self.rate == 0 {
+            return 0;
+        }
+        let missing = u128::from(count - self.tokens);
+        (missing * NANOS_PER_SEC).div_ceil(u128::from(self.rate)) as u64
+    }
+
+    /// Take ``count`` tokens, or all the available ones if there aren't enough.
+    fn take(&mut self, count: u64, now: u64) {
+        self.fill(now);
+        self.tokens = self.tokens.saturating_sub(count);
+    }
+}
+
+/// A storage device limiting the bandwidth and the number of operations per second it serves.
+///
+/// Each limit is a token bucket: tokens accumulate at the given rate while the device is idle, up
+/// to a burst size, and each request takes as many tokens as it needs, waiting on a [Delay]
+/// for the missing ones. Background jobs such as scrubbing or backups can go through it so they
+/// don't starve the other users of the device.
+///
+/// Reads and writes count their bytes against the bandwidth. [StorageDevice::write_zeroes],
+/// [StorageDevice::fill] and [StorageDevice::discard] only count as an operation.
+#[derive(Debug)]
+pub struct ThrottledDevice<S: StorageDevice, C: Clock, D: Delay> {
+    /// The inner storage device.
+    storage_device: S,
+
+    /// The clock measuring the time between requests.
+    clock: C,
+
+    /// How to wait for tokens.
+    delay: D,
+
+    /// The limit on the bytes transferred, if any.
+    bandwidth: Option<TokenBucket>,
+
+    /// The limit on the operations, if any.
+    iops: Option<TokenBucket>,
+
+    /// The time spent waiting for tokens so far, in nanoseconds.
+    throttled: u64,
+}
+
+impl<S: StorageDevice, C: Clock, D: Delay> ThrottledDevice<S, C, D> {
+    /// Wrap ``storage_device``, measuring time on ``clock`` and waiting with ``delay``.
+    ///
+    /// Nothing is limited until [ThrottledDevice::with_bandwidth] or
+    /// [ThrottledDevice::with_iops] is called.
+    pub fn new(storage_device: S, clock: C, delay: D) -> Self {
+        ThrottledDevice {
+            storage_device,
+            clock,
+            delay,
+            bandwidth: None,
+            iops: None,
+            throttled: 0,
+        }
+    }
+
+    /// Limit the transfers to ``bytes_per_sec`` bytes per second, allowing bursts of ``burst``
+    /// bytes.
+    pub fn with_bandwidth(mut self, bytes_per_sec: u64, burst: u64) -> Self {
+        self.bandwidth = Some(TokenBucket::new(bytes_per_sec, burst, self.clock.now()));
+        self
+    }
+
+    /// Limit the requests to ``ops_per_sec`` operations per second, allowing bursts of ``burst``
+    /// operations.
+    pub fn with_iops(mut self, ops_per_sec: u64, burst: u64) -> Self {
+        self.iops = Some(TokenBucket::new(ops_per_sec, burst, self.clock.now()));
+        self
+    }
+
+    /// Return the time spent waiting because of the limits so far, in nanoseconds.
+    pub fn throttled_time(&self) -> u64 {
+        self.throttled
+    }
+
+    /// Return a reference to the clock.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Return a reference to the inner storage device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the inner storage device.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Consume the wrapper, returning the inner storage device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Wait until an operation transferring ``bytes`` bytes is allowed, and account for it.
+    fn throttle(&mut self, bytes: u64) {
+        let now = self.clock.now();
+        let wait = core::cmp::max(
+            self.bandwidth
+                .as_mut()
+                .map_or(0, |bucket| bucket.wait_for(bytes, now)),
+            self.iops
+                .as_mut()
+                .map_or(0, |bucket| bucket.wait_for(1, now)),
+        );
+        if wait > 0 {
+            self.delay.delay(wait);
+            self.throttled += wait;
+        }
+
+        let now = self.clock.now();
+        if let Some(bucket) = self.bandwidth.as_mut() {
+            bucket.take(bytes, now);
+        }
+        if let Some(bucket) = self.iops.as_mut() {
+            bucket.take(1, now);
+        }
+    }
+}
+
+impl<S: StorageDevice, C: Clock, D: Delay> StorageDevice for ThrottledDevice<S, C, D> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.throttle(buf.len() as u64);
+        self.storage_device.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.throttle(buf.len() as u64);
+        self.storage_device.write(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.storage_device.len()
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.throttle(0);
+        self.storage_device.write_zeroes(offset, len)
+    }
+
+    fn fill(&mut self, offset: u64, len: u64, byte: u8) -> StorageDeviceResult<()> {
+        self.throttle(0);
+        self.storage_device.fill(offset, len, byte)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.throttle(0);
+        self.storage_device.discard(offset, len)
+    }
+
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        self.storage_device.next_data(offset)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.throttle(buf.len() as u64);
+        self.storage_device.write_fua(offset, buf)
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.storage_device.capabilities()
+    }
+}
@@ -0,0 +1,194 @@
+use crate::clock::{Clock, Deadline};
+use crate::{
+    Capabilities, DeviceIdentity, DeviceInfo, Resizable, StorageDevice, StorageDeviceError,
+    StorageDeviceResult,
+};
+
+/// The default number of bytes transferred between two checks of the deadline.
+pub const DEFAULT_TIMEOUT_CHUNK: usize = 4096;
+
+/// A storage device abandoning requests that take longer than a timeout.
+///
+/// Reads and writes are split in chunks, and the deadline is checked before each of them: once
+/// it passed, the request fails with [StorageDeviceError::Busy], so a [RetryingDevice] on top
+/// may try it again. A chunk already handed to the inner device can't be interrupted, so drivers
+/// polling hardware should also check a [Deadline] in their busy loops.
+///
+/// A write abandoned this way may have been partially performed.
+///
+/// [RetryingDevice]: crate::RetryingDevice
+#[derive(Debug)]
+pub struct TimeoutDevice<S: StorageDevice, C: Clock> {
+    /// The inner storage device.
+    storage_device: S,
+
+    /// The clock measuring the time taken by requests.
+    clock: C,
+
+    /// How long a request may take, in nanoseconds.
+    timeout: u64,
+
+    /// The number of bytes transferred between two checks of the deadline.
+    chunk_size: usize,
+
+    /// The number of requests abandoned so far.
+    timeouts: u64,
+}
+
+impl<S: StorageDevice, C: Clock> TimeoutDevice<S, C> {
+    /// Wrap ``storage_device``, abandoning requests taking longer than ``timeout`` nanoseconds on
+    /// ``clock``.
+    pub fn new(storage_device: S, clock: C, timeout: u64) -> Self {
+        TimeoutDevice {
+            storage_device,
+            clock,
+            timeout,
+            chunk_size: DEFAULT_TIMEOUT_CHUNK,
+            timeouts: 0,
+        }
+    }
+
+    /// Check the deadline every ``chunk_size`` bytes, which is rounded up to 1.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = core::cmp::max(1, chunk_size);
+        self
+    }
+
+    /// Return the number of requests abandoned so far, a hint at the health of the device.
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts
+    }
+
+    /// Return a reference to the clock.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Return a reference to the inner storage device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the inner storage device.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Consume the wrapper, returning the inner storage device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Fail with [StorageDeviceError::Busy] if ``deadline`` passed.
+    fn check(&mut self, deadline: &Deadline) -> StorageDeviceResult<()> {
+        if deadline.expired(&self.clock) {
+            self.timeouts += 1;
+            return Err(StorageDeviceError::Busy);
+        }
+        Ok(())
+    }
+
+    /// Read into ``buf`` chunk by chunk, returning how many bytes were read before the deadline.
+    ///
+    /// Fail if nothing could be read.
+    fn read_chunks(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        let deadline = Deadline::after(&self.clock, self.timeout);
+        let mut done = 0;
+        for chunk in buf.chunks_mut(self.chunk_size) {
+            if let Err(err) = self.check(&deadline) {
+                return if done == 0 { Err(err) } else { Ok(done) };
+            }
+            self.storage_device.read(offset + done as u64, chunk)?;
+            done += chunk.len();
+        }
+        Ok(done)
+    }
+
+    /// Write ``buf`` chunk by chunk, returning how many bytes were written before the deadline.
+    ///
+    /// Fail if nothing could be written.
+    fn write_chunks(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<usize> {
+        let deadline = Deadline::after(&self.clock, self.timeout);
+        let mut done = 0;
+        for chunk in buf.chunks(self.chunk_size) {
+            if let Err(err) = self.check(&deadline) {
+                return if done == 0 { Err(err) } else { Ok(done) };
+            }
+            self.storage_device.write(offset + done as u64, chunk)?;
+            done += chunk.len();
+        }
+        Ok(done)
+    }
+}
+
+impl<S: StorageDevice, C: Clock> StorageDevice for TimeoutDevice<S, C> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        if self.read_chunks(offset, buf)? != buf.len() {
+            return Err(StorageDeviceError::Busy);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        if self.write_chunks(offset, buf)? != buf.len() {
+            return Err(StorageDeviceError::Busy);
+        }
+        Ok(())
+    }
+
+    /// Return the bytes read before the deadline.
+    fn read_partial(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        self.read_chunks(offset, buf)
+    }
+
+    /// Return the bytes written before the deadline.
+    fn write_partial(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<usize> {
+        self.write_chunks(offset, buf)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.storage_device.len()
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.write_zeroes(offset, len)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.discard(offset, len)
+    }
+
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        self.storage_device.next_data(offset)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.storage_device.capabilities()
+    }
+
+    fn sanitize(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.sanitize()
+    }
+}
+
+impl<S: Resizable, C: Clock> Resizable for TimeoutDevice<S, C> {
+    fn set_len(&mut self, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.set_len(len)
+    }
+}
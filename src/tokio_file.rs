@@ -0,0 +1,77 @@
+use crate::{AsyncStorageDevice, StorageDeviceError, StorageDeviceResult};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// An asynchronous storage device backed by a [tokio::fs::File].
+///
+/// Every operation seeks to the requested offset before transferring the data, without blocking the
+/// runtime.
+#[derive(Debug)]
+pub struct TokioFileStorageDevice {
+    /// The backing file.
+    file: File,
+}
+
+impl TokioFileStorageDevice {
+    /// Create a new storage device backed by ``file``.
+    pub fn new(file: File) -> Self {
+        TokioFileStorageDevice { file }
+    }
+
+    /// Return a reference to the backing file.
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    /// Consume the device, returning the backing file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl AsyncStorageDevice for TokioFileStorageDevice {
+    async fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.file
+            .seek(std::io::SeekFrom::Start(offset))
+            .await
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::ReadError))?;
+        self.file
+            .read_exact(buf)
+            .await
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::ReadError))?;
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.file
+            .seek(std::io::SeekFrom::Start(offset))
+            .await
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))?;
+        self.file
+            .write_all(buf)
+            .await
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))?;
+        Ok(())
+    }
+
+    async fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self
+            .file
+            .metadata()
+            .await
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))?
+            .len())
+    }
+
+    /// Write the buffered data to the file, and sync it to disk.
+    async fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.file
+            .flush()
+            .await
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))?;
+        self.file
+            .sync_all()
+            .await
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::WriteError))
+    }
+}
@@ -0,0 +1,463 @@
+use crate::clock::Clock;
+use crate::{
+    Block, BlockCount, BlockDevice, BlockGeometry, BlockIndex, BlockResult, Capabilities,
+    DeviceIdentity, IoRequest,
+};
+
+/// The kind of operation recorded in a [TraceRecord].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TraceOperation {
+    /// Blocks were read.
+    Read,
+
+    /// Blocks were written.
+    Write,
+
+    /// The device was flushed.
+    Flush,
+
+    /// Blocks were discarded.
+    Discard,
+
+    /// Blocks were written with forced unit access.
+    WriteFua,
+
+    /// A barrier ordered the writes before it and after it.
+    Barrier,
+}
+
+impl TraceOperation {
+    /// Return the value identifying the operation in the binary format.
+    fn to_u8(self) -> u8 {
+        match self {
+            TraceOperation::Read => 0,
+            TraceOperation::Write => 1,
+            TraceOperation::Flush => 2,
+            TraceOperation::Discard => 3,
+            TraceOperation::WriteFua => 4,
+            TraceOperation::Barrier => 5,
+        }
+    }
+
+    /// Return the operation identified by ``value`` in the binary format.
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(TraceOperation::Read),
+            1 => Some(TraceOperation::Write),
+            2 => Some(TraceOperation::Flush),
+            3 => Some(TraceOperation::Discard),
+            4 => Some(TraceOperation::WriteFua),
+            5 => Some(TraceOperation::Barrier),
+            _ => None,
+        }
+    }
+
+    /// Return the RWBS field used by blkparse for this operation.
+    fn rwbs(self) -> &'static str {
+        match self {
+            TraceOperation::Read => "R",
+            TraceOperation::Write => "W",
+            TraceOperation::Flush => "F",
+            TraceOperation::Discard => "D",
+            TraceOperation::WriteFua => "WF",
+            TraceOperation::Barrier => "B",
+        }
+    }
+}
+
+/// A single request performed on a traced block device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// When the request was issued, in nanoseconds.
+    pub timestamp: u64,
+
+    /// How long the request took to complete, in nanoseconds.
+    pub latency: u64,
+
+    /// The index of the first block of the request.
+    pub index: BlockIndex,
+
+    /// The amount of blocks of the request.
+    pub count: u32,
+
+    /// The kind of request.
+    pub operation: TraceOperation,
+
+    /// Whether the request failed.
+    pub failed: bool,
+
+    /// The amount of requests submitted together with this one, including itself.
+    pub queue_depth: u16,
+}
+
+impl TraceRecord {
+    /// The size of an encoded record in bytes.
+    ///
+    /// Records are encoded in little endian as follow:
+    ///
+    /// | Offset | Size | Field       |
+    /// |--------|------|-------------|
+    /// | 0      | 8    | timestamp   |
+    /// | 8      | 8    | latency     |
+    /// | 16     | 8    | index       |
+    /// | 24     | 4    | count       |
+    /// | 28     | 1    | operation   |
+    /// | 29     | 1    | failed      |
+    /// | 30     | 2    | queue_depth |
+    pub const LEN: usize = 32;
+
+    /// Encode the record in the binary trace format.
+    pub fn to_bytes(&self) -> [u8; TraceRecord::LEN] {
+        let mut bytes = [0u8; TraceRecord::LEN];
+        bytes[0..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.latency.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.index.0.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.count.to_le_bytes());
+        bytes[28] = self.operation.to_u8();
+        bytes[29] = self.failed as u8;
+        bytes[30..32].copy_from_slice(&self.queue_depth.to_le_bytes());
+        bytes
+    }
+
+    /// Decode a record from the binary trace format.
+    ///
+    /// Return None if the operation is unknown.
+    pub fn from_bytes(bytes: &[u8; TraceRecord::LEN]) -> Option<Self> {
+        let mut u64_bytes = [0u8; 8];
+        let mut u32_bytes = [0u8; 4];
+        let mut u16_bytes = [0u8; 2];
+
+        u64_bytes.copy_from_slice(&bytes[0..8]);
+        let timestamp = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&bytes[8..16]);
+        let latency = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&bytes[16..24]);
+        let index = BlockIndex(u64::from_le_bytes(u64_bytes));
+        u32_bytes.copy_from_slice(&bytes[24..28]);
+        let count = u32::from_le_bytes(u32_bytes);
+        u16_bytes.copy_from_slice(&bytes[30..32]);
+        let queue_depth = u16::from_le_bytes(u16_bytes);
+
+        Some(TraceRecord {
+            timestamp,
+            latency,
+            index,
+            count,
+            operation: TraceOperation::from_u8(bytes[28])?,
+            failed: bytes[29] != 0,
+            queue_depth,
+        })
+    }
+
+    /// Write the record as blkparse would, as a queue (``Q``) and a completion (``C``) event.
+    ///
+    /// ``sequence`` is the sequence number of the queue event, the completion event uses the next
+    /// one. As [Block::LEN] is 512 bytes, block indices are the sector numbers blkparse expects.
+    pub fn write_blkparse<W: core::fmt::Write>(
+        &self,
+        out: &mut W,
+        sequence: u64,
+    ) -> core::fmt::Result {
+        let rwbs = self.operation.rwbs();
+        let sectors = u64::from(self.count) * (Block::LEN_U64 / 512);
+        let completion = self.timestamp + self.latency;
+
+        writeln!(
+            out,
+            "  0,0    0 {:>8} {:>5}.{:09}     0  Q {:>3} {} + {} [depth {}]",
+            sequence,
+            self.timestamp / 1_000_000_000,
+            self.timestamp % 1_000_000_000,
+            rwbs,
+            self.index.0,
+            sectors,
+            self.queue_depth
+        )?;
+        writeln!(
+            out,
+            "  0,0    0 {:>8} {:>5}.{:09}     0  C {:>3} {} + {} [{}]",
+            sequence + 1,
+            completion / 1_000_000_000,
+            completion % 1_000_000_000,
+            rwbs,
+            self.index.0,
+            sectors,
+            if self.failed { -5 } else { 0 }
+        )
+    }
+}
+
+/// Destination of the records produced by a [TracingBlockDevice].
+///
+/// It is implemented for closures taking a [TraceRecord].
+pub trait TraceSink {
+    /// Store a record.
+    fn record(&mut self, record: &TraceRecord);
+}
+
+impl<F: FnMut(&TraceRecord)> TraceSink for F {
+    fn record(&mut self, record: &TraceRecord) {
+        self(record)
+    }
+}
+
+/// A [TraceSink] writing records in the binary trace format to a [std::io::Write].
+///
+/// Write errors are silently ignored, as tracing must not disturb the traced device.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct WriterTraceSink<W: std::io::Write> {
+    /// Where the records are written.
+    writer: W,
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> WriterTraceSink<W> {
+    /// Create a new sink writing to ``writer``.
+    pub fn new(writer: W) -> Self {
+        WriterTraceSink { writer }
+    }
+
+    /// Consume the sink, returning the writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> TraceSink for WriterTraceSink<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        let _ = self.writer.write_all(&record.to_bytes());
+    }
+}
+
+/// Convert a binary trace read from ``input`` into blkparse-like text written to ``output``.
+///
+/// A trailing partial record is ignored. Records with an unknown operation are reported as invalid
+/// data.
+#[cfg(feature = "std")]
+pub fn convert_to_blkparse<R: std::io::Read, W: std::io::Write>(
+    mut input: R,
+    mut output: W,
+) -> std::io::Result<()> {
+    use std::string::String;
+
+    let mut bytes = [0u8; TraceRecord::LEN];
+    let mut sequence = 1;
+    let mut line = String::new();
+
+    loop {
+        match input.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        }
+
+        let record = TraceRecord::from_bytes(&bytes)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+
+        line.clear();
+        // Writing to a String never fails.
+        let _ = record.write_blkparse(&mut line, sequence);
+        output.write_all(line.as_bytes())?;
+        sequence += 2;
+    }
+}
+
+/// A block device recording every request performed on it in a [TraceSink].
+pub struct TracingBlockDevice<B: BlockDevice, C: Clock, T: TraceSink> {
+    /// The traced block device.
+    block_device: B,
+
+    /// The clock used to timestamp requests.
+    clock: C,
+
+    /// Where the records are stored.
+    sink: T,
+}
+
+impl<B: BlockDevice, C: Clock, T: TraceSink> core::fmt::Debug for TracingBlockDevice<B, C, T> {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+        fmt.debug_struct("TracingBlockDevice")
+            .field("block_device", &self.block_device)
+            .field("clock", &self.clock)
+            .finish()
+    }
+}
+
+impl<B: BlockDevice, C: Clock, T: TraceSink> TracingBlockDevice<B, C, T> {
+    /// Create a new tracing block device, recording the requests performed on ``block_device`` to
+    /// ``sink``.
+    pub fn new(block_device: B, clock: C, sink: T) -> Self {
+        TracingBlockDevice {
+            block_device,
+            clock,
+            sink,
+        }
+    }
+
+    /// Return a mutable reference to the sink.
+    pub fn sink_mut(&mut self) -> &mut T {
+        &mut self.sink
+    }
+
+    /// Consume the tracing block device, returning the traced device and the sink.
+    pub fn into_inner(self) -> (B, T) {
+        (self.block_device, self.sink)
+    }
+
+    /// Record a request that started at ``timestamp`` and just completed.
+    fn record<R>(
+        &mut self,
+        timestamp: u64,
+        operation: TraceOperation,
+        index: BlockIndex,
+        count: u64,
+        queue_depth: usize,
+        result: &BlockResult<R>,
+    ) {
+        let record = TraceRecord {
+            timestamp,
+            latency: self.clock.now().saturating_sub(timestamp),
+            index,
+            count: count as u32,
+            operation,
+            failed: result.is_err(),
+            queue_depth: queue_depth as u16,
+        };
+        self.sink.record(&record);
+    }
+}
+
+impl<B: BlockDevice, C: Clock, T: TraceSink> BlockDevice for TracingBlockDevice<B, C, T> {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        let timestamp = self.clock.now();
+        let result = self.block_device.read(blocks, index);
+        self.record(
+            timestamp,
+            TraceOperation::Read,
+            index,
+            blocks.len() as u64,
+            1,
+            &result,
+        );
+        result
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        let timestamp = self.clock.now();
+        let result = self.block_device.write(blocks, index);
+        self.record(
+            timestamp,
+            TraceOperation::Write,
+            index,
+            blocks.len() as u64,
+            1,
+            &result,
+        );
+        result
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        self.block_device.count()
+    }
+
+    /// Record every request of the batch, with the latency of the whole batch.
+    fn submit(&mut self, requests: &mut [IoRequest<'_>]) -> BlockResult<()> {
+        let timestamp = self.clock.now();
+        let result = self.block_device.submit(requests);
+
+        for request in requests.iter() {
+            let (operation, index, count) = match request {
+                IoRequest::Read { index, blocks } => (TraceOperation::Read, *index, blocks.len()),
+                IoRequest::Write { index, blocks } => (TraceOperation::Write, *index, blocks.len()),
+            };
+            self.record(
+                timestamp,
+                operation,
+                index,
+                count as u64,
+                requests.len(),
+                &result,
+            );
+        }
+
+        result
+    }
+
+    fn flush(&mut self) -> BlockResult<()> {
+        let timestamp = self.clock.now();
+        let result = self.block_device.flush();
+        self.record(
+            timestamp,
+            TraceOperation::Flush,
+            BlockIndex(0),
+            0,
+            1,
+            &result,
+        );
+        result
+    }
+
+    fn write_fua(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        let timestamp = self.clock.now();
+        let result = self.block_device.write_fua(blocks, index);
+        self.record(
+            timestamp,
+            TraceOperation::WriteFua,
+            index,
+            blocks.len() as u64,
+            1,
+            &result,
+        );
+        result
+    }
+
+    fn barrier(&mut self) -> BlockResult<()> {
+        let timestamp = self.clock.now();
+        let result = self.block_device.barrier();
+        self.record(
+            timestamp,
+            TraceOperation::Barrier,
+            BlockIndex(0),
+            0,
+            1,
+            &result,
+        );
+        result
+    }
+
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        let timestamp = self.clock.now();
+        let result = self.block_device.discard(index, count);
+        self.record(
+            timestamp,
+            TraceOperation::Discard,
+            index,
+            count.0,
+            1,
+            &result,
+        );
+        result
+    }
+
+    /// Forward the hint without recording it.
+    fn prefetch(&mut self, ranges: &[(BlockIndex, BlockCount)]) -> BlockResult<()> {
+        self.block_device.prefetch(ranges)
+    }
+
+    /// Forward the query without recording it.
+    fn geometry(&mut self) -> BlockResult<BlockGeometry> {
+        self.block_device.geometry()
+    }
+
+    /// Forward the query without recording it.
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        self.block_device.identity()
+    }
+
+    /// Forward the query without recording it.
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        self.block_device.capabilities()
+    }
+}
@@ -0,0 +1,484 @@
+use crate::{
+    AsyncStorageDevice, Capabilities, DeviceIdentity, DeviceInfo, StorageDevice,
+    StorageDeviceError, StorageDeviceResult, StorageRequest,
+};
+use core::task::Poll;
+use io_uring::{opcode, squeue, types, IoUring};
+use std::boxed::Box;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::vec::Vec;
+
+/// Index of the file in the table of registered files of the ring.
+const FILE_INDEX: types::Fixed = types::Fixed(0);
+
+/// The size of each registered buffer, in bytes. Longer requests are split in parts this big.
+pub const FIXED_BUFFER_LEN: usize = 64 * 1024;
+
+/// The user data of the request of an [AsyncStorageDevice] operation, telling it apart from the
+/// requests of a batch, identified by their index.
+const ASYNC_USER_DATA: u64 = u64::MAX;
+
+/// A storage device backed by a file, performing its I/O through io_uring.
+///
+/// The file is registered with the ring, so the kernel doesn't have to look it up on every
+/// request, and so is a pool of one buffer of [FIXED_BUFFER_LEN] bytes per entry of the ring, so
+/// it doesn't have to map them on every request either. Data goes through these buffers with the
+/// fixed read and write opcodes, which costs a copy, but keeps the kernel from ever accessing
+/// the buffers of the caller. The pool is locked in memory, and counts against
+/// ``RLIMIT_MEMLOCK``.
+///
+/// Batches submitted through [StorageDevice::submit] are put in flight at once, up to the size of
+/// the ring.
+///
+/// As an [AsyncStorageDevice], operations are performed one part at a time, and the future polls
+/// the completion queue, waking itself up until the part completes, so it needs no reactor. A
+/// future dropped before completing leaves its part in flight, which the next operation waits
+/// for first.
+pub struct UringStorageDevice {
+    /// The ring used to submit requests, dropped first as it uses the buffers.
+    ring: IoUring,
+
+    /// The buffers registered with the ring, one per entry.
+    buffers: Vec<Box<[u8]>>,
+
+    /// Whether the request of an [AsyncStorageDevice] operation is in flight, its future having
+    /// been dropped before it completed.
+    async_in_flight: bool,
+
+    /// The backing file.
+    file: File,
+}
+
+impl core::fmt::Debug for UringStorageDevice {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+        fmt.debug_struct("UringStorageDevice")
+            .field("file", &self.file)
+            .field("fixed_buffers", &self.buffers.len())
+            .field("async_in_flight", &self.async_in_flight)
+            .finish()
+    }
+}
+
+impl UringStorageDevice {
+    /// Create a new io_uring storage device over ``file``, able to have ``entries`` requests in
+    /// flight.
+    pub fn new(file: File, entries: u32) -> std::io::Result<Self> {
+        let ring = IoUring::new(entries)?;
+        ring.submitter().register_files(&[file.as_raw_fd()])?;
+
+        let mut buffers: Vec<Box<[u8]>> = (0..entries)
+            .map(|_| std::vec![0u8; FIXED_BUFFER_LEN].into_boxed_slice())
+            .collect();
+        let iovecs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: buffer.len(),
+            })
+            .collect();
+        // The buffers live as long as the ring, and their heap allocations never move.
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+
+        Ok(UringStorageDevice {
+            ring,
+            buffers,
+            async_in_flight: false,
+            file,
+        })
+    }
+
+    /// Return a reference to the backing file.
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    /// Consume the device, returning the backing file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    /// Perform the given requests concurrently, completing short transfers afterwards.
+    ///
+    /// The requests must not overlap a write of the batch, and must fit in the ring. A request is
+    /// only pushed again once its previous part completed, and every request pushed is reaped
+    /// before returning, even on failure, as the kernel may still be using its buffer until then.
+    fn run_batch(&mut self, requests: &mut [StorageRequest<'_>]) -> StorageDeviceResult<()> {
+        self.reap_async()?;
+        let mut progress = std::vec![0usize; requests.len()];
+        let mut in_flight = std::vec![false; requests.len()];
+        let mut error = None;
+
+        loop {
+            if error.is_none() {
+                if let Err(err) = self.push_pending(requests, &progress, &mut in_flight) {
+                    error = Some(err);
+                }
+            }
+            if !in_flight.contains(&true) {
+                break;
+            }
+            self.wait(1)?;
+
+            for entry in self.ring.completion() {
+                let index = entry.user_data() as usize;
+                let request = &requests[index];
+                in_flight[index] = false;
+
+                if entry.result() < 0 {
+                    let default = if request.is_write() {
+                        StorageDeviceError::WriteError
+                    } else {
+                        StorageDeviceError::ReadError
+                    };
+                    let err = std::io::Error::from_raw_os_error(-entry.result());
+                    error = Some(crate::sys::storage_error(&err, default));
+                } else if entry.result() == 0 {
+                    // Reading past the end of the file, or a write not making any progress.
+                    error = Some(if request.is_write() {
+                        StorageDeviceError::WriteError
+                    } else {
+                        StorageDeviceError::OutOfBounds
+                    });
+                } else {
+                    let len = entry.result() as usize;
+                    if let StorageRequest::Read { buf, .. } = &mut requests[index] {
+                        let done = progress[index];
+                        buf[done..done + len].copy_from_slice(&self.buffers[index][..len]);
+                    }
+                    progress[index] += len;
+                }
+            }
+        }
+
+        match error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Submit the pushed requests and wait for at least ``want`` completions, retrying when the
+    /// wait is interrupted by a signal.
+    ///
+    /// Other failures mean the ring itself is unusable, so the requests in flight can't be waited
+    /// for anymore.
+    fn wait(&mut self, want: usize) -> StorageDeviceResult<()> {
+        loop {
+            match self.ring.submit_and_wait(want) {
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    return Err(crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+                }
+                Ok(_) => return Ok(()),
+            }
+        }
+    }
+
+    /// Push the next part of every request that isn't complete nor in flight to the submission
+    /// queue, marking it in flight.
+    ///
+    /// Each request of the batch goes through the registered buffer of its index, the data of
+    /// writes being copied to it first.
+    fn push_pending(
+        &mut self,
+        requests: &mut [StorageRequest<'_>],
+        progress: &[usize],
+        in_flight: &mut [bool],
+    ) -> StorageDeviceResult<()> {
+        let mut submission = self.ring.submission();
+
+        for (index, request) in requests.iter_mut().enumerate() {
+            if in_flight[index] {
+                continue;
+            }
+            let done = progress[index];
+            let buffer = &mut self.buffers[index];
+            let entry = match request {
+                StorageRequest::Read { offset, buf } if done < buf.len() => {
+                    let len = core::cmp::min(buf.len() - done, FIXED_BUFFER_LEN);
+                    read_fixed(buffer, index, len, *offset + done as u64)
+                }
+                StorageRequest::Write { offset, buf } if done < buf.len() => {
+                    let len = core::cmp::min(buf.len() - done, FIXED_BUFFER_LEN);
+                    buffer[..len].copy_from_slice(&buf[done..done + len]);
+                    write_fixed(buffer, index, len, *offset + done as u64)
+                }
+                _ => continue,
+            };
+
+            // The registered buffers live as long as the ring.
+            unsafe {
+                submission
+                    .push(&entry.user_data(index as u64))
+                    .map_err(|_| StorageDeviceError::Unknown)?;
+            }
+            in_flight[index] = true;
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the request of an [AsyncStorageDevice] operation whose future was dropped, so
+    /// its buffer and its completion are free again.
+    fn reap_async(&mut self) -> StorageDeviceResult<()> {
+        while self.async_in_flight {
+            self.wait(1)?;
+            if self.ring.completion().next().is_some() {
+                self.async_in_flight = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Put ``entry`` in flight as the request of an [AsyncStorageDevice] operation, and return
+    /// its result once it completes.
+    async fn run_async(&mut self, entry: squeue::Entry) -> StorageDeviceResult<i32> {
+        // Wait for the request of a dropped future first.
+        core::future::poll_fn(|cx| self.poll_async(cx)).await;
+
+        // The registered buffers live as long as the ring, and Fsync references none.
+        unsafe {
+            self.ring
+                .submission()
+                .push(&entry.user_data(ASYNC_USER_DATA))
+                .map_err(|_| StorageDeviceError::Unknown)?;
+        }
+        loop {
+            match self.ring.submit() {
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    return Err(crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+                }
+                Ok(_) => break,
+            }
+        }
+        self.async_in_flight = true;
+
+        Ok(core::future::poll_fn(|cx| self.poll_async(cx))
+            .await
+            .unwrap_or(0))
+    }
+
+    /// Poll for the completion of the request of an [AsyncStorageDevice] operation, returning
+    /// its result, or None if none is in flight.
+    fn poll_async(&mut self, cx: &mut core::task::Context<'_>) -> Poll<Option<i32>> {
+        if !self.async_in_flight {
+            return Poll::Ready(None);
+        }
+        match self.ring.completion().next() {
+            Some(entry) => {
+                self.async_in_flight = false;
+                Poll::Ready(Some(entry.result()))
+            }
+            None => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Perform ``len`` bytes of an [AsyncStorageDevice] read or write at ``offset`` through the
+    /// first registered buffer, returning how many bytes were transferred.
+    async fn transfer_async(
+        &mut self,
+        offset: u64,
+        len: usize,
+        is_write: bool,
+    ) -> StorageDeviceResult<usize> {
+        let buffer = &mut self.buffers[0];
+        let entry = if is_write {
+            write_fixed(buffer, 0, len, offset)
+        } else {
+            read_fixed(buffer, 0, len, offset)
+        };
+        let result = self.run_async(entry).await?;
+
+        if result < 0 {
+            let default = if is_write {
+                StorageDeviceError::WriteError
+            } else {
+                StorageDeviceError::ReadError
+            };
+            let err = std::io::Error::from_raw_os_error(-result);
+            Err(crate::sys::storage_error(&err, default))
+        } else if result == 0 {
+            // Reading past the end of the file, or a write not making any progress.
+            Err(if is_write {
+                StorageDeviceError::WriteError
+            } else {
+                StorageDeviceError::OutOfBounds
+            })
+        } else {
+            Ok(result as usize)
+        }
+    }
+
+    /// Return the result of an fsync of the file, as returned by the kernel.
+    fn fsync_result(result: i32) -> StorageDeviceResult<()> {
+        if result >= 0 {
+            return Ok(());
+        }
+        Err(crate::sys::storage_error(
+            &std::io::Error::from_raw_os_error(-result),
+            StorageDeviceError::WriteError,
+        ))
+    }
+
+    /// Return whether two requests access overlapping ranges of the device.
+    fn overlaps(a: &StorageRequest<'_>, b: &StorageRequest<'_>) -> bool {
+        let a_end = a.offset() + a.len() as u64;
+        let b_end = b.offset() + b.len() as u64;
+        a.offset() < b_end && b.offset() < a_end
+    }
+}
+
+impl StorageDevice for UringStorageDevice {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.run_batch(&mut [StorageRequest::Read { offset, buf }])
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.run_batch(&mut [StorageRequest::Write { offset, buf }])
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        crate::sys::file_len(&self.file)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+    }
+
+    /// Put as many requests as possible in flight at once.
+    ///
+    /// A request overlapping a write of the current group starts a new group, so the requests
+    /// are performed as if one after the other.
+    fn submit(&mut self, requests: &mut [StorageRequest<'_>]) -> StorageDeviceResult<()> {
+        let capacity = self.buffers.len();
+        let mut start = 0;
+
+        while start < requests.len() {
+            let mut end = start + 1;
+            while end < requests.len() && end - start < capacity {
+                let conflicts = requests[start..end].iter().any(|request| {
+                    (request.is_write() || requests[end].is_write())
+                        && Self::overlaps(request, &requests[end])
+                });
+                if conflicts {
+                    break;
+                }
+                end += 1;
+            }
+
+            self.run_batch(&mut requests[start..end])?;
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.reap_async()?;
+        let entry = opcode::Fsync::new(FILE_INDEX).build();
+
+        // Fsync doesn't reference any buffer.
+        unsafe {
+            self.ring
+                .submission()
+                .push(&entry)
+                .map_err(|_| StorageDeviceError::Unknown)?;
+        }
+        self.wait(1)?;
+
+        match self.ring.completion().next() {
+            Some(entry) => Self::fsync_result(entry.result()),
+            None => Err(StorageDeviceError::WriteError),
+        }
+    }
+
+    /// Punch a hole in the file if the filesystem supports it, do nothing otherwise.
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        match crate::sys::punch_hole(&self.file, offset, len) {
+            Err(err) if !crate::sys::is_unsupported(&err) => Err(crate::sys::storage_error(
+                &err,
+                StorageDeviceError::WriteError,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        Ok(DeviceInfo {
+            supports_holes: crate::sys::supports_holes(&self.file),
+            ..DeviceInfo::default()
+        })
+    }
+
+    /// Query the identity of the disk or file from the operating system.
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        crate::sys::identity(&self.file)
+            .map_err(|err| crate::sys::storage_error(&err, StorageDeviceError::Unknown))
+    }
+
+    /// Query the capabilities of the disk or file from the operating system.
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        let mut capabilities = crate::sys::capabilities(&self.file);
+        // Discards punch holes, but zeroes are written.
+        capabilities.remove(Capabilities::WRITE_ZEROES);
+        Ok(capabilities)
+    }
+}
+
+impl AsyncStorageDevice for UringStorageDevice {
+    /// Read through the first registered buffer, one part of [FIXED_BUFFER_LEN] bytes at most
+    /// at a time.
+    async fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let len = core::cmp::min(buf.len() - done, FIXED_BUFFER_LEN);
+            let len = self
+                .transfer_async(offset + done as u64, len, false)
+                .await?;
+            buf[done..done + len].copy_from_slice(&self.buffers[0][..len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write through the first registered buffer, one part of [FIXED_BUFFER_LEN] bytes at most
+    /// at a time.
+    async fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let len = core::cmp::min(buf.len() - done, FIXED_BUFFER_LEN);
+            // Wait for the request of a dropped future before reusing its buffer.
+            core::future::poll_fn(|cx| self.poll_async(cx)).await;
+            self.buffers[0][..len].copy_from_slice(&buf[done..done + len]);
+            done += self.transfer_async(offset + done as u64, len, true).await?;
+        }
+        Ok(())
+    }
+
+    async fn len(&mut self) -> StorageDeviceResult<u64> {
+        StorageDevice::len(self)
+    }
+
+    async fn flush(&mut self) -> StorageDeviceResult<()> {
+        let result = self
+            .run_async(opcode::Fsync::new(FILE_INDEX).build())
+            .await?;
+        Self::fsync_result(result)
+    }
+}
+
+/// Build a fixed read of ``len`` bytes at ``offset`` into the registered ``buffer`` of ``index``.
+fn read_fixed(buffer: &mut [u8], index: usize, len: usize, offset: u64) -> squeue::Entry {
+    opcode::ReadFixed::new(FILE_INDEX, buffer.as_mut_ptr(), len as u32, index as u16)
+        .offset(offset)
+        .build()
+}
+
+/// Build a fixed write of the first ``len`` bytes of the registered ``buffer`` of ``index`` at
+/// ``offset``.
+fn write_fixed(buffer: &[u8], index: usize, len: usize, offset: u64) -> squeue::Entry {
+    opcode::WriteFixed::new(FILE_INDEX, buffer.as_ptr(), len as u32, index as u16)
+        .offset(offset)
+        .build()
+}
@@ -0,0 +1,153 @@
+use crate::{
+    Capabilities, DeviceIdentity, DeviceInfo, Resizable, StorageDevice, StorageDeviceError,
+    StorageDeviceResult,
+};
+
+/// The size of the buffer data is read back into, in bytes.
+const VERIFY_CHUNK: usize = 512;
+
+/// A storage device reading back every write and comparing it with the data written.
+///
+/// Flash media with marginal cells may accept a write and return different data when read.
+/// This wrapper catches it, failing the write with [StorageDeviceError::VerificationFailed].
+///
+/// The data is read back through the inner device: a cache below this wrapper would answer from
+/// memory, so wrap the device closest to the medium.
+#[derive(Debug)]
+pub struct VerifiedWrites<S: StorageDevice> {
+    /// The inner storage device.
+    storage_device: S,
+
+    /// Whether to flush the inner device before reading the data back.
+    flush_before_verify: bool,
+
+    /// The number of writes that failed verification so far.
+    failures: u64,
+}
+
+impl<S: StorageDevice> VerifiedWrites<S> {
+    /// Wrap ``storage_device``, verifying every write.
+    pub fn new(storage_device: S) -> Self {
+        VerifiedWrites {
+            storage_device,
+            flush_before_verify: false,
+            failures: 0,
+        }
+    }
+
+    /// Flush the inner device before reading the data back, so it is read from the medium rather
+    /// than from the write buffer of the device.
+    pub fn with_flush_before_verify(mut self, flush_before_verify: bool) -> Self {
+        self.flush_before_verify = flush_before_verify;
+        self
+    }
+
+    /// Return the number of writes that failed verification so far.
+    pub fn failures(&self) -> u64 {
+        self.failures
+    }
+
+    /// Return a reference to the inner storage device.
+    pub fn get_ref(&self) -> &S {
+        &self.storage_device
+    }
+
+    /// Return a mutable reference to the inner storage device.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.storage_device
+    }
+
+    /// Consume the wrapper, returning the inner storage device.
+    pub fn into_inner(self) -> S {
+        self.storage_device
+    }
+
+    /// Read back the data at ``offset`` and compare it with ``buf``.
+    fn verify(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        if self.flush_before_verify {
+            self.storage_device.flush()?;
+        }
+
+        let mut read_back = [0u8; VERIFY_CHUNK];
+        let mut position = offset;
+        for chunk in buf.chunks(VERIFY_CHUNK) {
+            let read_back = &mut read_back[..chunk.len()];
+            self.storage_device.read(position, read_back)?;
+            if read_back != chunk {
+                self.failures += 1;
+                return Err(StorageDeviceError::VerificationFailed);
+            }
+            position += chunk.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for VerifiedWrites<S> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.storage_device.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.storage_device.write(offset, buf)?;
+        self.verify(offset, buf)
+    }
+
+    fn read_partial(&mut self, offset: u64, buf: &mut [u8]) -> StorageDeviceResult<usize> {
+        self.storage_device.read_partial(offset, buf)
+    }
+
+    /// Verify the bytes actually written.
+    fn write_partial(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<usize> {
+        let written = self.storage_device.write_partial(offset, buf)?;
+        self.verify(offset, &buf[..written])?;
+        Ok(written)
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        self.storage_device.len()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.discard(offset, len)
+    }
+
+    fn next_data(&mut self, offset: u64) -> StorageDeviceResult<Option<core::ops::Range<u64>>> {
+        self.storage_device.next_data(offset)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.flush()
+    }
+
+    fn write_fua(&mut self, offset: u64, buf: &[u8]) -> StorageDeviceResult<()> {
+        self.storage_device.write_fua(offset, buf)?;
+        self.verify(offset, buf)
+    }
+
+    fn barrier(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.barrier()
+    }
+
+    fn info(&mut self) -> StorageDeviceResult<DeviceInfo> {
+        self.storage_device.info()
+    }
+
+    fn identity(&mut self) -> StorageDeviceResult<DeviceIdentity> {
+        self.storage_device.identity()
+    }
+
+    fn capabilities(&mut self) -> StorageDeviceResult<Capabilities> {
+        self.storage_device.capabilities()
+    }
+
+    fn sanitize(&mut self) -> StorageDeviceResult<()> {
+        self.storage_device.sanitize()
+    }
+}
+
+impl<S: Resizable> Resizable for VerifiedWrites<S> {
+    fn set_len(&mut self, len: u64) -> StorageDeviceResult<()> {
+        self.storage_device.set_len(len)
+    }
+}
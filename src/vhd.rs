@@ -0,0 +1,608 @@
+use crate::{StorageDevice, StorageDeviceError, StorageDeviceResult};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// The size of a sector of a VHD image, and of its footer.
+const SECTOR: u64 = 512;
+
+/// The cookie at the start of the footer.
+const FOOTER_COOKIE: &[u8; 8] = b"conectix";
+
+/// The cookie at the start of the dynamic disk header.
+const DYNAMIC_COOKIE: &[u8; 8] = b"cxsparse";
+
+/// The size of the dynamic disk header.
+const DYNAMIC_HEADER_LEN: usize = 1024;
+
+/// The BAT entry of a block that isn't allocated.
+const UNALLOCATED: u32 = 0xFFFF_FFFF;
+
+/// The kind of VHD image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VhdDiskType {
+    /// The image holds the whole disk, followed by the footer.
+    Fixed,
+
+    /// The image only holds the allocated blocks of the disk, found through the block
+    /// allocation table.
+    Dynamic,
+}
+
+/// Read the big endian ``u32`` at ``offset`` in ``buf``.
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+/// Read the big endian ``u64`` at ``offset`` in ``buf``.
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Compute the checksum of a footer or dynamic disk header, the one's complement of the sum of
+/// its bytes, skipping the checksum itself at ``checksum_offset``.
+fn checksum(buf: &[u8], checksum_offset: usize) -> u32 {
+    let sum = buf
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(checksum_offset..checksum_offset + 4).contains(i))
+        .fold(0u32, |sum, (_, byte)| sum.wrapping_add(u32::from(*byte)));
+    !sum
+}
+
+/// Parse the footer in ``buf``, returning the data offset, the size of the disk and its type.
+///
+/// The footer is stored in big endian as follow, only the used fields are listed:
+///
+/// | Offset | Size | Field        |
+/// |--------|------|--------------|
+/// | 0      | 8    | cookie       |
+/// | 16     | 8    | data_offset  |
+/// | 48     | 8    | current_size |
+/// | 60     | 4    | disk_type    |
+/// | 64     | 4    | checksum     |
+fn parse_footer(buf: &[u8; SECTOR as usize]) -> StorageDeviceResult<(u64, u64, VhdDiskType)> {
+    if &buf[0..8] != FOOTER_COOKIE || be_u32(buf, 64) != checksum(buf, 64) {
+        return Err(StorageDeviceError::Corrupted);
+    }
+
+    let disk_type = match be_u32(buf, 60) {
+        2 => VhdDiskType::Fixed,
+        3 => VhdDiskType::Dynamic,
+        // Differencing disks aren't supported.
+        _ => return Err(StorageDeviceError::Unsupported),
+    };
+    Ok((be_u64(buf, 16), be_u64(buf, 48), disk_type))
+}
+
+/// The layout of a dynamic disk.
+#[derive(Debug)]
+struct DynamicLayout {
+    /// The offset of the block allocation table.
+    table_offset: u64,
+
+    /// The size of a block in bytes.
+    block_size: u64,
+
+    /// The size of the sector bitmap preceding each block, in bytes, padded to a sector.
+    bitmap_size: u64,
+
+    /// The block allocation table, the sector of each block in the image.
+    bat: Vec<u32>,
+
+    /// The offset of the footer at the end of the image, where the next block is allocated.
+    footer_offset: u64,
+
+    /// The footer, written again after every allocated block.
+    footer: [u8; SECTOR as usize],
+}
+
+/// A storage device over a VHD image, the format of Virtual PC and Hyper-V virtual disks.
+///
+/// Fixed and dynamic disks are supported. Blocks of dynamic disks written for the first time are
+/// allocated at the end of the image, where the footer is moved. Differencing disks and the
+/// newer VHDX format aren't supported.
+#[derive(Debug)]
+pub struct VhdDevice<S: StorageDevice> {
+    /// The VHD image.
+    image: S,
+
+    /// The size of the virtual disk in bytes.
+    size: u64,
+
+    /// The layout of the image, if it is a dynamic disk.
+    dynamic: Option<DynamicLayout>,
+}
+
+impl<S: StorageDevice> VhdDevice<S> {
+    /// Open the VHD image ``image``.
+    ///
+    /// Return [StorageDeviceError::Corrupted] if its footer or dynamic disk header isn't valid,
+    /// and [StorageDeviceError::Unsupported] if it is a differencing disk.
+    pub fn open(mut image: S) -> StorageDeviceResult<Self> {
+        let image_len = image.len()?;
+        if image_len < SECTOR {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        let footer_offset = image_len - SECTOR;
+        let mut footer = [0u8; SECTOR as usize];
+        image.read(footer_offset, &mut footer)?;
+        let (data_offset, size, disk_type) = match parse_footer(&footer) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                // Dynamic disks have a copy of the footer at the start.
+                image.read(0, &mut footer)?;
+                match parse_footer(&footer) {
+                    Ok(parsed) if parsed.2 == VhdDiskType::Dynamic => parsed,
+                    _ => return Err(err),
+                }
+            }
+        };
+
+        let dynamic = match disk_type {
+            VhdDiskType::Fixed => {
+                if size > footer_offset {
+                    return Err(StorageDeviceError::Corrupted);
+                }
+                None
+            }
+            VhdDiskType::Dynamic => Some(Self::read_layout(
+                &mut image,
+                data_offset,
+                size,
+                footer_offset,
+                footer,
+            )?),
+        };
+
+        Ok(VhdDevice {
+            image,
+            size,
+            dynamic,
+        })
+    }
+
+    /// Read the dynamic disk header at ``header_offset``, and the block allocation table.
+    ///
+    /// The header is stored in big endian as follow, only the used fields are listed:
+    ///
+    /// | Offset | Size | Field             |
+    /// |--------|------|-------------------|
+    /// | 0      | 8    | cookie            |
+    /// | 16     | 8    | table_offset      |
+    /// | 28     | 4    | max_table_entries |
+    /// | 32     | 4    | block_size        |
+    /// | 36     | 4    | checksum          |
+    fn read_layout(
+        image: &mut S,
+        header_offset: u64,
+        size: u64,
+        footer_offset: u64,
+        footer: [u8; SECTOR as usize],
+    ) -> StorageDeviceResult<DynamicLayout> {
+        let mut header = [0u8; DYNAMIC_HEADER_LEN];
+        image.read(header_offset, &mut header)?;
+        if &header[0..8] != DYNAMIC_COOKIE || be_u32(&header, 36) != checksum(&header, 36) {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        let table_offset = be_u64(&header, 16);
+        let entries = u64::from(be_u32(&header, 28));
+        let block_size = u64::from(be_u32(&header, 32));
+        if block_size == 0
+            || !block_size.is_multiple_of(SECTOR)
+            || entries * block_size < size
+            || table_offset.saturating_add(entries * 4) > footer_offset
+        {
+            return Err(StorageDeviceError::Corrupted);
+        }
+
+        let mut table = alloc::vec![0u8; entries as usize * 4];
+        image.read(table_offset, &mut table)?;
+        let bat = table
+            .chunks_exact(4)
+            .map(|entry| be_u32(entry, 0))
+            .collect();
+
+        let sectors = block_size / SECTOR;
+        Ok(DynamicLayout {
+            table_offset,
+            block_size,
+            bitmap_size: sectors.div_ceil(8).next_multiple_of(SECTOR),
+            bat,
+            footer_offset,
+            footer,
+        })
+    }
+
+    /// Return the kind of the image.
+    pub fn disk_type(&self) -> VhdDiskType {
+        match self.dynamic {
+            None => VhdDiskType::Fixed,
+            Some(_) => VhdDiskType::Dynamic,
+        }
+    }
+
+    /// Return a reference to the image.
+    pub fn get_ref(&self) -> &S {
+        &self.image
+    }
+
+    /// Consume the device, returning the image.
+    pub fn into_inner(self) -> S {
+        self.image
+    }
+
+    /// Check whether the ``len`` bytes at ``offset`` are inside the virtual disk.
+    fn check_bounds(&self, offset: u64, len: usize) -> StorageDeviceResult<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(StorageDeviceError::OutOfBounds),
+        }
+    }
+}
+
+impl DynamicLayout {
+    /// Return how many of the ``len`` bytes at ``offset`` are in the same block.
+    fn run_len(&self, offset: u64, len: usize) -> usize {
+        core::cmp::min(self.block_size - offset % self.block_size, len as u64) as usize
+    }
+
+    /// Return the offset of the bitmap of the block holding ``offset``, if it is allocated.
+    fn block_offset(&self, offset: u64) -> Option<u64> {
+        match self.bat[(offset / self.block_size) as usize] {
+            UNALLOCATED => None,
+            sector => Some(u64::from(sector) * SECTOR),
+        }
+    }
+
+    /// Read ``buf`` at ``offset``, inside a single block.
+    fn read<S: StorageDevice>(
+        &self,
+        image: &mut S,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> StorageDeviceResult<()> {
+        let block = match self.block_offset(offset) {
+            Some(block) => block,
+            None => {
+                buf.fill(0);
+                return Ok(());
+            }
+        };
+
+        let mut bitmap = alloc::vec![0u8; self.bitmap_size as usize];
+        image.read(block, &mut bitmap)?;
+        let data = block + self.bitmap_size;
+        let in_block = offset % self.block_size;
+
+        // Sectors whose bit is clear don't hold data, and read as zeros.
+        let mut done = 0;
+        while done < buf.len() {
+            let position = in_block + done as u64;
+            let sector = position / SECTOR;
+            let len = core::cmp::min(SECTOR - position % SECTOR, (buf.len() - done) as u64);
+            let chunk = &mut buf[done..done + len as usize];
+            if bitmap[(sector / 8) as usize] & (0x80 >> (sector % 8)) != 0 {
+                image.read(data + position, chunk)?;
+            } else {
+                chunk.fill(0);
+            }
+            done += len as usize;
+        }
+        Ok(())
+    }
+
+    /// Allocate the block holding ``offset`` at the end of the image, returning its offset.
+    ///
+    /// Its sectors are zeroed and marked as holding data, then the footer is moved after it,
+    /// and finally the block allocation table is updated.
+    fn allocate<S: StorageDevice>(
+        &mut self,
+        image: &mut S,
+        offset: u64,
+    ) -> StorageDeviceResult<u64> {
+        let index = (offset / self.block_size) as usize;
+        let block = self.footer_offset.next_multiple_of(SECTOR);
+        let sector = u32::try_from(block / SECTOR).map_err(|_| StorageDeviceError::OutOfSpace)?;
+
+        image.fill(block, self.bitmap_size, 0xFF)?;
+        image.write_zeroes(block + self.bitmap_size, self.block_size)?;
+        self.footer_offset = block + self.bitmap_size + self.block_size;
+        image.write(self.footer_offset, &self.footer)?;
+
+        image.write(self.table_offset + index as u64 * 4, &sector.to_be_bytes())?;
+        self.bat[index] = sector;
+        Ok(block)
+    }
+
+    /// Write ``buf`` at ``offset``, inside a single block.
+    fn write<S: StorageDevice>(
+        &mut self,
+        image: &mut S,
+        offset: u64,
+        buf: &[u8],
+    ) -> StorageDeviceResult<()> {
+        let block = match self.block_offset(offset) {
+            Some(block) => block,
+            None => self.allocate(image, offset)?,
+        };
+
+        let mut bitmap = alloc::vec![0u8; self.bitmap_size as usize];
+        image.read(block, &mut bitmap)?;
+        let data = block + self.bitmap_size;
+        let in_block = offset % self.block_size;
+
+        // Sectors getting data for the first time must read as zeros around the written bytes.
+        let first = in_block / SECTOR;
+        let last = (in_block + buf.len() as u64).div_ceil(SECTOR);
+        let mut bitmap_changed = false;
+        for sector in first..last {
+            let mask = 0x80 >> (sector % 8);
+            if bitmap[(sector / 8) as usize] & mask == 0 {
+                image.write_zeroes(data + sector * SECTOR, SECTOR)?;
+                bitmap[(sector / 8) as usize] |= mask;
+                bitmap_changed = true;
+            }
+        }
+
+        image.write(data + in_block, buf)?;
+        if bitmap_changed {
+            image.write(block, &bitmap)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: StorageDevice> StorageDevice for VhdDevice<S> {
+    fn read(&mut self, mut offset: u64, mut buf: &mut [u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        let layout = match &self.dynamic {
+            None => return self.image.read(offset, buf),
+            Some(layout) => layout,
+        };
+
+        while !buf.is_empty() {
+            let run = layout.run_len(offset, buf.len());
+            let (head, tail) = buf.split_at_mut(run);
+            layout.read(&mut self.image, offset, head)?;
+            buf = tail;
+            offset += run as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, mut offset: u64, mut buf: &[u8]) -> StorageDeviceResult<()> {
+        self.check_bounds(offset, buf.len())?;
+        let layout = match &mut self.dynamic {
+            None => return self.image.write(offset, buf),
+            Some(layout) => layout,
+        };
+
+        while !buf.is_empty() {
+            let run = layout.run_len(offset, buf.len());
+            let (head, tail) = buf.split_at(run);
+            layout.write(&mut self.image, offset, head)?;
+            buf = tail;
+            offset += run as u64;
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> StorageDeviceResult<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&mut self) -> StorageDeviceResult<()> {
+        self.image.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// The size of the virtual disk of the test images.
+    const DISK_LEN: u64 = 16 * 1024;
+
+    /// The size of the blocks of the dynamic test images, with a bitmap of one sector.
+    const BLOCK_LEN: u64 = 4096;
+
+    /// The offset of the block allocation table of the dynamic test images.
+    const TABLE_OFFSET: u64 = 3 * SECTOR;
+
+    /// Build a footer of the given type, with a valid checksum.
+    fn footer(disk_type: u32, data_offset: u64) -> [u8; SECTOR as usize] {
+        let mut footer = [0u8; SECTOR as usize];
+        footer[0..8].copy_from_slice(FOOTER_COOKIE);
+        footer[16..24].copy_from_slice(&data_offset.to_be_bytes());
+        footer[48..56].copy_from_slice(&DISK_LEN.to_be_bytes());
+        footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+        let sum = checksum(&footer, 64);
+        footer[64..68].copy_from_slice(&sum.to_be_bytes());
+        footer
+    }
+
+    /// Build a fixed image of ``DISK_LEN`` bytes filled with ``byte``.
+    fn fixed_image(byte: u8) -> Vec<u8> {
+        let mut image = vec![byte; DISK_LEN as usize];
+        image.extend_from_slice(&footer(2, u64::MAX));
+        image
+    }
+
+    /// Build an empty dynamic image.
+    ///
+    /// It holds the copy of the footer, the dynamic disk header, the block allocation table padded
+    /// to a sector, then the footer.
+    fn dynamic_image() -> Vec<u8> {
+        let footer = footer(3, SECTOR);
+        let mut image = footer.to_vec();
+
+        let mut header = [0u8; DYNAMIC_HEADER_LEN];
+        header[0..8].copy_from_slice(DYNAMIC_COOKIE);
+        header[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        header[16..24].copy_from_slice(&TABLE_OFFSET.to_be_bytes());
+        header[24..28].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        header[28..32].copy_from_slice(&((DISK_LEN / BLOCK_LEN) as u32).to_be_bytes());
+        header[32..36].copy_from_slice(&(BLOCK_LEN as u32).to_be_bytes());
+        let sum = checksum(&header, 36);
+        header[36..40].copy_from_slice(&sum.to_be_bytes());
+        image.extend_from_slice(&header);
+
+        image.extend_from_slice(&[0xFF; SECTOR as usize]);
+        image.extend_from_slice(&footer);
+        image
+    }
+
+    /// Read the whole disk of ``device``.
+    fn content<S: StorageDevice>(device: &mut VhdDevice<S>) -> Vec<u8> {
+        let mut content = vec![0u8; DISK_LEN as usize];
+        device.read(0, &mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn fixed_images_round_trip() {
+        let mut device = VhdDevice::open(fixed_image(0x11)).unwrap();
+        assert_eq!(device.disk_type(), VhdDiskType::Fixed);
+        assert_eq!(StorageDevice::len(&mut device), Ok(DISK_LEN));
+        device.write(1000, &[0x22; 3000]).unwrap();
+        assert_eq!(
+            device.write(DISK_LEN - 1, &[0; 2]),
+            Err(StorageDeviceError::OutOfBounds)
+        );
+
+        // Fixed images are written in place, and keep their footer.
+        let image = device.into_inner();
+        assert_eq!(image.len() as u64, DISK_LEN + SECTOR);
+        let mut device = VhdDevice::open(image).unwrap();
+        let mut expected = vec![0x11u8; DISK_LEN as usize];
+        expected[1000..4000].fill(0x22);
+        assert_eq!(content(&mut device), expected);
+    }
+
+    #[test]
+    fn dynamic_allocation_moves_the_footer() {
+        let mut device = VhdDevice::open(dynamic_image()).unwrap();
+        assert_eq!(device.disk_type(), VhdDiskType::Dynamic);
+        assert!(content(&mut device).iter().all(|byte| *byte == 0));
+
+        device.write(2 * BLOCK_LEN + 700, &[0x22; 100]).unwrap();
+        let image = device.into_inner();
+
+        // The block and its bitmap took the place of the footer, which follows them.
+        let block = TABLE_OFFSET + SECTOR;
+        assert_eq!(image.len() as u64, block + SECTOR + BLOCK_LEN + SECTOR);
+        assert_eq!(
+            &image[image.len() - SECTOR as usize..],
+            &footer(3, SECTOR)[..]
+        );
+        let entry = TABLE_OFFSET as usize + 2 * 4;
+        assert_eq!(be_u32(&image, entry) as u64, block / SECTOR);
+        assert_eq!(be_u32(&image, entry - 4), UNALLOCATED);
+
+        let mut device = VhdDevice::open(image).unwrap();
+        let mut expected = vec![0u8; DISK_LEN as usize];
+        expected[2 * BLOCK_LEN as usize + 700..2 * BLOCK_LEN as usize + 800].fill(0x22);
+        assert_eq!(content(&mut device), expected);
+
+        // Writing the allocated block again doesn't grow the image.
+        let len = device.get_ref().len();
+        device.write(2 * BLOCK_LEN, &[0x33; 10]).unwrap();
+        assert_eq!(device.get_ref().len(), len);
+    }
+
+    #[test]
+    fn sectors_missing_from_the_bitmap_are_zeroed_when_written() {
+        // Allocate the first block by hand, with only its first sector holding data, and garbage
+        // in the others.
+        let mut image = dynamic_image();
+        let footer_offset = image.len() - SECTOR as usize;
+        let block = footer_offset as u64;
+        image.truncate(footer_offset);
+        let mut bitmap = [0u8; SECTOR as usize];
+        bitmap[0] = 0x80;
+        image.extend_from_slice(&bitmap);
+        image.extend_from_slice(&[0xAA; BLOCK_LEN as usize]);
+        image.extend_from_slice(&footer(3, SECTOR));
+        image[TABLE_OFFSET as usize..TABLE_OFFSET as usize + 4]
+            .copy_from_slice(&((block / SECTOR) as u32).to_be_bytes());
+
+        let mut device = VhdDevice::open(image).unwrap();
+        let mut expected = vec![0u8; DISK_LEN as usize];
+        expected[..SECTOR as usize].fill(0xAA);
+        assert_eq!(content(&mut device), expected);
+
+        device.write(SECTOR + 100, &[0x22; 10]).unwrap();
+        expected[SECTOR as usize + 100..SECTOR as usize + 110].fill(0x22);
+        assert_eq!(content(&mut device), expected);
+
+        // Only the written sector was added to the bitmap, and the garbage around the written
+        // bytes was cleared.
+        let image = device.into_inner();
+        assert_eq!(image[block as usize], 0xC0);
+        let data = (block + SECTOR) as usize;
+        assert!(image[data + SECTOR as usize..data + SECTOR as usize + 100]
+            .iter()
+            .all(|byte| *byte == 0));
+        assert!(image[data + 2 * SECTOR as usize..data + BLOCK_LEN as usize]
+            .iter()
+            .all(|byte| *byte == 0xAA));
+    }
+
+    #[test]
+    fn bad_footer_checksums_are_rejected() {
+        let mut image = fixed_image(0);
+        image[DISK_LEN as usize + 50] ^= 1;
+        assert_eq!(
+            VhdDevice::open(image).err(),
+            Some(StorageDeviceError::Corrupted)
+        );
+
+        // Dynamic images are rejected when both copies are damaged.
+        let mut image = dynamic_image();
+        let footer_offset = image.len() - SECTOR as usize;
+        image[footer_offset + 50] ^= 1;
+        image[50] ^= 1;
+        assert_eq!(
+            VhdDevice::open(image).err(),
+            Some(StorageDeviceError::Corrupted)
+        );
+    }
+
+    #[test]
+    fn dynamic_images_fall_back_to_the_footer_copy() {
+        let mut image = dynamic_image();
+        let footer_offset = image.len() - SECTOR as usize;
+        image[footer_offset + 50] ^= 1;
+
+        let mut device = VhdDevice::open(image).unwrap();
+        assert_eq!(device.disk_type(), VhdDiskType::Dynamic);
+        device.write(100, &[0x22; 10]).unwrap();
+
+        // The allocated block replaced the damaged footer, and was followed by a valid one.
+        let image = device.into_inner();
+        assert_eq!(
+            &image[image.len() - SECTOR as usize..],
+            &footer(3, SECTOR)[..]
+        );
+        let mut device = VhdDevice::open(image).unwrap();
+        let mut expected = vec![0u8; DISK_LEN as usize];
+        expected[100..110].fill(0x22);
+        assert_eq!(content(&mut device), expected);
+
+        // Fixed images have no copy to fall back to.
+        let mut image = fixed_image(0);
+        image[..SECTOR as usize].copy_from_slice(&footer(2, u64::MAX));
+        image[DISK_LEN as usize + 50] ^= 1;
+        assert_eq!(
+            VhdDevice::open(image).err(),
+            Some(StorageDeviceError::Corrupted)
+        );
+    }
+}
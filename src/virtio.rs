@@ -0,0 +1,275 @@
+use crate::{
+    Block, BlockCount, BlockDevice, BlockError, BlockGeometry, BlockIndex, BlockResult,
+    Capabilities, DeviceIdentity, IdentityString,
+};
+use core::convert::TryFrom;
+
+/// The feature bit of read-only devices.
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+/// The feature bit of devices supporting flush requests.
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+/// The feature bit of devices supporting discard requests.
+pub const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+
+/// The size of the identifier of a device, in bytes.
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+/// The default maximum number of blocks transferred by a single request.
+pub const DEFAULT_MAX_REQUEST_BLOCKS: usize = 256;
+
+/// The type of read requests.
+const VIRTIO_BLK_T_IN: u32 = 0;
+
+/// The type of write requests.
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+/// The type of flush requests.
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+/// The type of requests for the identifier of the device.
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+/// The type of discard requests.
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+
+/// The status of successful requests.
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// The status of requests which failed because of the device or its medium.
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+
+/// The status of requests the device doesn't support.
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// A virtqueue of a virtio device, managed by the caller.
+///
+/// The caller owns the descriptor table, the available and used rings and the notifications of
+/// the device, through the PCI or MMIO transport. This keeps the memory management of the
+/// kernel out of this crate: a hobby kernel identity mapping its memory can simply use the
+/// addresses of the buffers, others translate them or copy them to bounce buffers.
+pub trait VirtQueue: core::fmt::Debug {
+    /// Make the device process a descriptor chain made of the ``readable`` buffers, followed by
+    /// the ``writable`` ones, wait until it is used, and return the number of bytes the device
+    /// wrote.
+    fn submit(&mut self, readable: &[&[u8]], writable: &mut [&mut [u8]]) -> BlockResult<usize>;
+}
+
+/// A block device over the request virtqueue of a virtio-blk device, as exposed by QEMU and
+/// most hypervisors.
+///
+/// The caller negotiates the features, sets up the virtqueue, and reads the capacity of the
+/// device from its configuration space, as these depend on the transport. Requests are
+/// submitted one at a time, and split in requests of at most [DEFAULT_MAX_REQUEST_BLOCKS]
+/// blocks, which can be changed to honor the ``seg_max`` and ``size_max`` limits of the device.
+///
+/// Flushes and discards are only sent if their feature was negotiated, and do nothing
+/// otherwise. Writing to a read-only device fails with [BlockError::WriteError].
+#[derive(Debug)]
+pub struct VirtioBlkDevice<Q: VirtQueue> {
+    /// The request virtqueue.
+    queue: Q,
+
+    /// The number of 512-byte sectors of the device.
+    capacity: u64,
+
+    /// The negotiated features.
+    features: u64,
+
+    /// The maximum number of blocks transferred by a single request.
+    max_request_blocks: usize,
+}
+
+impl<Q: VirtQueue> VirtioBlkDevice<Q> {
+    /// Send requests to the device through ``queue``, the device holding ``capacity`` sectors of
+    /// 512 bytes, as read from its configuration space.
+    pub fn new(queue: Q, capacity: u64) -> Self {
+        VirtioBlkDevice {
+            queue,
+            capacity,
+            features: 0,
+            max_request_blocks: DEFAULT_MAX_REQUEST_BLOCKS,
+        }
+    }
+
+    /// Use the ``features`` negotiated with the device, such as [VIRTIO_BLK_F_FLUSH].
+    pub fn with_features(mut self, features: u64) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// Transfer at most ``blocks`` blocks by request, instead of [DEFAULT_MAX_REQUEST_BLOCKS].
+    pub fn with_max_request_blocks(mut self, blocks: usize) -> Self {
+        self.max_request_blocks = core::cmp::max(blocks, 1);
+        self
+    }
+
+    /// Return the features negotiated with the device.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Ask the device for its identifier, usually its serial number, padded with zeroes.
+    pub fn device_id(&mut self) -> BlockResult<[u8; VIRTIO_BLK_ID_BYTES]> {
+        let mut id = [0; VIRTIO_BLK_ID_BYTES];
+        self.request(VIRTIO_BLK_T_GET_ID, 0, &[], &mut id, BlockError::ReadError)?;
+        Ok(id)
+    }
+
+    /// Return a reference to the virtqueue.
+    pub fn get_ref(&self) -> &Q {
+        &self.queue
+    }
+
+    /// Return a mutable reference to the virtqueue.
+    pub fn get_mut(&mut self) -> &mut Q {
+        &mut self.queue
+    }
+
+    /// Consume the device, returning the virtqueue.
+    pub fn into_inner(self) -> Q {
+        self.queue
+    }
+
+    /// Check whether the feature ``bit`` was negotiated.
+    fn has_feature(&self, bit: u64) -> bool {
+        self.features & bit != 0
+    }
+
+    /// Perform a request of ``request_type`` at ``sector``, sending ``data_out`` and receiving
+    /// ``data_in``, failing with ``error`` if the device reports an error.
+    fn request(
+        &mut self,
+        request_type: u32,
+        sector: u64,
+        data_out: &[u8],
+        data_in: &mut [u8],
+        error: BlockError,
+    ) -> BlockResult<()> {
+        let mut header = [0; 16];
+        header[..4].copy_from_slice(&request_type.to_le_bytes());
+        header[8..].copy_from_slice(&sector.to_le_bytes());
+        let mut status = [0xFF];
+
+        let written = match (data_out.is_empty(), data_in.is_empty()) {
+            (true, true) => self.queue.submit(&[&header], &mut [&mut status])?,
+            (false, _) => self
+                .queue
+                .submit(&[&header, data_out], &mut [&mut status])?,
+            (true, false) => self.queue.submit(&[&header], &mut [data_in, &mut status])?,
+        };
+
+        // The status is the last byte written, after the data.
+        if written == 0 {
+            return Err(error);
+        }
+        match status[0] {
+            VIRTIO_BLK_S_OK => Ok(()),
+            VIRTIO_BLK_S_IOERR => Err(error),
+            VIRTIO_BLK_S_UNSUPP => Err(BlockError::Unsupported),
+            _ => Err(BlockError::Unknown),
+        }
+    }
+
+    /// Check that ``len`` blocks at ``index`` are on the device, returning ``error`` otherwise.
+    fn check_bounds(&self, index: BlockIndex, len: usize, error: BlockError) -> BlockResult<()> {
+        match index.0.checked_add(len as u64) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(error),
+        }
+    }
+}
+
+impl<Q: VirtQueue> BlockDevice for VirtioBlkDevice<Q> {
+    fn read(&mut self, blocks: &mut [Block], index: BlockIndex) -> BlockResult<()> {
+        self.check_bounds(index, blocks.len(), BlockError::ReadError)?;
+        let max = self.max_request_blocks;
+        for (i, chunk) in blocks.chunks_mut(max).enumerate() {
+            let sector = index.0 + (i * max) as u64;
+            let data = Block::slice_as_bytes_mut(chunk);
+            self.request(VIRTIO_BLK_T_IN, sector, &[], data, BlockError::ReadError)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, blocks: &[Block], index: BlockIndex) -> BlockResult<()> {
+        if self.has_feature(VIRTIO_BLK_F_RO) {
+            return Err(BlockError::WriteError);
+        }
+        self.check_bounds(index, blocks.len(), BlockError::WriteError)?;
+        let max = self.max_request_blocks;
+        for (i, chunk) in blocks.chunks(max).enumerate() {
+            let sector = index.0 + (i * max) as u64;
+            let data = Block::slice_as_bytes(chunk);
+            self.request(
+                VIRTIO_BLK_T_OUT,
+                sector,
+                data,
+                &mut [],
+                BlockError::WriteError,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn count(&mut self) -> BlockResult<BlockCount> {
+        Ok(BlockCount(self.capacity))
+    }
+
+    fn flush(&mut self) -> BlockResult<()> {
+        if !self.has_feature(VIRTIO_BLK_F_FLUSH) {
+            return Ok(());
+        }
+        self.request(VIRTIO_BLK_T_FLUSH, 0, &[], &mut [], BlockError::WriteError)
+    }
+
+    fn discard(&mut self, index: BlockIndex, count: BlockCount) -> BlockResult<()> {
+        if !self.has_feature(VIRTIO_BLK_F_DISCARD) || self.has_feature(VIRTIO_BLK_F_RO) {
+            return Ok(());
+        }
+        let end = core::cmp::min(index.0.saturating_add(count.0), self.capacity);
+        let mut sector = index.0;
+        while sector < end {
+            let sectors = u32::try_from(end - sector).unwrap_or(u32::MAX);
+            // A single segment: sector, number of sectors and flags.
+            let mut segment = [0; 16];
+            segment[..8].copy_from_slice(&sector.to_le_bytes());
+            segment[8..12].copy_from_slice(&sectors.to_le_bytes());
+            self.request(
+                VIRTIO_BLK_T_DISCARD,
+                0,
+                &segment,
+                &mut [],
+                BlockError::WriteError,
+            )?;
+            sector += u64::from(sectors);
+        }
+        Ok(())
+    }
+
+    /// Report the maximum number of blocks transferred by a single request.
+    fn geometry(&mut self) -> BlockResult<BlockGeometry> {
+        Ok(BlockGeometry {
+            max_transfer_size: self.max_request_blocks * Block::LEN,
+            ..BlockGeometry::default()
+        })
+    }
+
+    /// Report the identifier of the device as its serial number.
+    fn identity(&mut self) -> BlockResult<DeviceIdentity> {
+        Ok(DeviceIdentity {
+            serial: IdentityString::from_ascii(&self.device_id()?),
+            ..DeviceIdentity::default()
+        })
+    }
+
+    /// Report the capabilities matching the negotiated features.
+    fn capabilities(&mut self) -> BlockResult<Capabilities> {
+        let mut capabilities = Capabilities::empty();
+        capabilities.set(Capabilities::FLUSH, self.has_feature(VIRTIO_BLK_F_FLUSH));
+        capabilities.set(Capabilities::TRIM, self.has_feature(VIRTIO_BLK_F_DISCARD));
+        capabilities.set(Capabilities::READ_ONLY, self.has_feature(VIRTIO_BLK_F_RO));
+        Ok(capabilities)
+    }
+}